@@ -0,0 +1,142 @@
+//! Message throughput of a server with 1000 simulated clients.
+//!
+//! Every client connects over TCP, completes the handshake and then sends chat messages that
+//! take the whole server path: the backend reading the socket, decoding, the registry lookup,
+//! the client lock and an `on_message` handler that replies through the client's outbound
+//! queue and writer. Each `Backend` is measured on its own server.
+//!
+//! A server holds several descriptors per client, raise the limit before running, e.g.
+//! `ulimit -n 8192 && cargo bench --bench server`.
+
+use bakalib::io::Read;
+use bakalib::protoutils::{BakaMessage, Hello, MessageKind, Welcome};
+use bakalib::socket::{Backend, Context, Event, ServerBuilder, Socket};
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CLIENTS: usize = 1000;
+const THREADS: usize = 8;
+const MESSAGES_PER_CLIENT: usize = 100;
+
+/// Start a server that counts and answers every message
+fn serve(address: &str, backend: Backend) -> Arc<AtomicUsize> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    let mut server = ServerBuilder::new(address);
+
+    server.backend(backend);
+    server.event(
+        "on_message",
+        Box::new(move |ctx: &mut Context, event: Event| {
+            if let Event::Message(message) = event {
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                let _ = ctx.reply(BakaMessage::from(message));
+            }
+        }),
+    );
+
+    thread::spawn(move || server.startup());
+    thread::sleep(Duration::from_millis(200));
+
+    received
+}
+
+/// Connect a client and complete the handshake
+fn connect(address: &str) -> Socket {
+    let mut socket = Socket::connect(address).unwrap();
+    let author = socket.local_address();
+
+    socket
+        .send_message(Hello::new(vec![]).to_message(&author))
+        .unwrap();
+
+    let (reply, _) = socket.read_stream().unwrap();
+
+    Welcome::parse(&BakaMessage::parse(&reply).unwrap()).unwrap();
+
+    socket
+}
+
+fn run(address: &str, backend: Backend) -> Duration {
+    let received = serve(address, backend);
+    let ready = Arc::new(Barrier::new(THREADS + 1));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let address = address.to_string();
+            let ready = ready.clone();
+
+            thread::spawn(move || {
+                let mut sockets: Vec<Socket> =
+                    (0..CLIENTS / THREADS).map(|_| connect(&address)).collect();
+
+                ready.wait();
+
+                for i in 0..MESSAGES_PER_CLIENT {
+                    for socket in sockets.iter_mut() {
+                        let author = socket.local_address();
+
+                        socket
+                            .send_message(BakaMessage::new(
+                                MessageKind::Chat,
+                                &author,
+                                &format!("message {}", i),
+                            ))
+                            .unwrap();
+                    }
+                }
+
+                // Keep the connections open until the server has handled everything
+                ready.wait();
+            })
+        })
+        .collect();
+
+    ready.wait();
+
+    let start = Instant::now();
+    let total = (CLIENTS / THREADS) * THREADS * MESSAGES_PER_CLIENT;
+
+    while received.load(Ordering::Relaxed) < total {
+        thread::sleep(Duration::from_micros(100));
+    }
+
+    let elapsed = start.elapsed();
+
+    ready.wait();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    elapsed
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let messages = ((CLIENTS / THREADS) * THREADS * MESSAGES_PER_CLIENT) as f64;
+
+    println!(
+        "{:<10} {:>10.2?} {:>14.0} msg/s",
+        name,
+        elapsed,
+        messages / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!("{} clients, {} messages each", CLIENTS, MESSAGES_PER_CLIENT);
+
+    report(
+        "reactor",
+        black_box(run("127.0.0.1:47301", Backend::Reactor)),
+    );
+    report(
+        "threaded",
+        black_box(run("127.0.0.1:47302", Backend::Threaded)),
+    );
+}
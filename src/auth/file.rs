@@ -0,0 +1,134 @@
+use crate::auth::{invalid_credentials, Authenticator, Credentials};
+use crate::socket::Error;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// ## CredentialFile
+///
+/// Authenticates against a file of argon2 password hashes, one `user:hash` pair per line.
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// Example file:
+/// ```text
+/// # user:argon2 PHC string
+/// alice:$argon2id$v=19$m=19456,t=2,p=1$...
+/// ```
+///
+/// Properties:
+///
+/// * `path`: Where the credentials are loaded from.
+/// * `users`: A HashMap of user names and their password hashes.
+pub struct CredentialFile {
+    path: PathBuf,
+    users: RwLock<HashMap<String, String>>,
+}
+
+impl CredentialFile {
+    /// Load the credentials from a file
+    ///
+    /// Example:
+    /// ```rs
+    /// let auth = CredentialFile::load("users.passwd")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The credential file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = CredentialFile {
+            path: path.as_ref().to_path_buf(),
+            users: RwLock::new(HashMap::new()),
+        };
+
+        file.reload()?;
+
+        Ok(file)
+    }
+
+    /// Read the file again, replacing the loaded credentials
+    pub fn reload(&self) -> Result<(), Error> {
+        let contents = fs::read_to_string(&self.path).map_err(|e| {
+            Error::new(&format!(
+                "Unable to read credential file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })?;
+
+        let mut users = HashMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(':') {
+                Some((user, hash)) if PasswordHash::new(hash).is_ok() => {
+                    users.insert(user.to_string(), hash.to_string());
+                }
+                _ => {
+                    return Err(Error::new(&format!(
+                        "{}:{}: expected `user:argon2-hash`",
+                        self.path.display(),
+                        number + 1
+                    )));
+                }
+            }
+        }
+
+        *self.users.write().unwrap() = users;
+
+        Ok(())
+    }
+
+    /// Hash a password for storing in a credential file
+    ///
+    /// Arguments:
+    ///
+    /// * `password`: The plain password.
+    pub fn hash(password: &str) -> Result<String, Error> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| Error::new(&format!("Unable to hash password: {}", e)))
+    }
+}
+
+impl Authenticator for CredentialFile {
+    fn authenticate(&self, credentials: &Credentials) -> Result<String, Error> {
+        match credentials {
+            Credentials::Password { user, password } => {
+                let users = self.users.read().unwrap();
+                let hash = users.get(user).ok_or_else(invalid_credentials)?;
+
+                if verify_password(hash, password) {
+                    Ok(user.clone())
+                } else {
+                    Err(invalid_credentials())
+                }
+            }
+            Credentials::Token(_) => Err(Error::new("Token login is not supported")),
+        }
+    }
+}
+
+/// Check a password against an argon2 PHC string, `false` for a malformed hash
+pub(crate) fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,68 @@
+use crate::auth::{constant_time_eq, invalid_credentials, Authenticator, Credentials};
+use crate::socket::Error;
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// ## MemoryAuthenticator
+///
+/// Keeps user names and plain passwords in memory. Meant for tests and small deployments.
+///
+/// Properties:
+///
+/// * `users`: A HashMap of user names and their passwords.
+pub struct MemoryAuthenticator {
+    users: RwLock<HashMap<String, String>>,
+}
+
+impl MemoryAuthenticator {
+    /// Initialize new instance of the `MemoryAuthenticator`
+    ///
+    /// Example:
+    /// ```rs
+    /// let auth = MemoryAuthenticator::new();
+    /// auth.add_user("alice", "hunter2");
+    /// ```
+    pub fn new() -> Self {
+        MemoryAuthenticator {
+            users: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add a user or change their password
+    pub fn add_user(&self, user: &str, password: &str) {
+        self.users
+            .write()
+            .unwrap()
+            .insert(user.to_string(), password.to_string());
+    }
+
+    /// Remove a user, returns whether they existed
+    pub fn remove_user(&self, user: &str) -> bool {
+        self.users.write().unwrap().remove(user).is_some()
+    }
+}
+
+impl Default for MemoryAuthenticator {
+    fn default() -> Self {
+        MemoryAuthenticator::new()
+    }
+}
+
+impl Authenticator for MemoryAuthenticator {
+    fn authenticate(&self, credentials: &Credentials) -> Result<String, Error> {
+        match credentials {
+            Credentials::Password { user, password } => {
+                let users = self.users.read().unwrap();
+
+                match users.get(user) {
+                    Some(stored) if constant_time_eq(stored.as_bytes(), password.as_bytes()) => {
+                        Ok(user.clone())
+                    }
+                    _ => Err(invalid_credentials()),
+                }
+            }
+            Credentials::Token(_) => Err(Error::new("Token login is not supported")),
+        }
+    }
+}
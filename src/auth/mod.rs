@@ -0,0 +1,50 @@
+mod file;
+mod memory;
+mod throttle;
+mod token;
+
+pub use file::*;
+pub use memory::*;
+pub use throttle::*;
+pub use token::*;
+
+use crate::socket::Error;
+
+/// ## Credentials
+///
+/// What a client presents when logging in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Credentials {
+    /// `LOGIN {user} :password`
+    Password { user: String, password: String },
+    /// `TOKEN :token`
+    Token(String),
+}
+
+/// ## Authenticator
+///
+/// Checks client credentials for a `Server`.
+pub trait Authenticator: core::marker::Send + Sync {
+    /// Verify the credentials, returns the name of the authenticated user
+    ///
+    /// Arguments:
+    ///
+    /// * `credentials`: What the client presented.
+    fn authenticate(&self, credentials: &Credentials) -> Result<String, Error>;
+}
+
+/// Compare two byte strings in time that depends only on their lengths
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+pub(crate) fn invalid_credentials() -> Error {
+    Error::new("Invalid credentials")
+}
@@ -0,0 +1,237 @@
+use crate::socket::Error;
+use crate::{Clock, SystemClock};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Failed attempts allowed before a peer is locked out
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// How long the first lockout lasts, every further failure doubles it
+pub const DEFAULT_LOCKOUT: Duration = Duration::from_secs(30);
+
+/// Longest lockout the `Throttle` imposes
+pub const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long a peer's failures are remembered after its last failed attempt, no lockout lasts
+/// longer
+pub const FORGET_AFTER: Duration = MAX_LOCKOUT;
+
+/// How often forgotten peers are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Failures {
+    count: u32,
+    until: Option<Instant>,
+    last: Instant,
+}
+
+struct State {
+    failures: HashMap<String, Failures>,
+    pruned: Instant,
+}
+
+/// ## Throttle
+///
+/// Counts failed login attempts per peer and locks the peer out once it has too many.
+/// Peers are forgotten `FORGET_AFTER` their last failure.
+///
+/// Properties:
+///
+/// * `max_attempts`: Failed attempts allowed before the first lockout.
+/// * `lockout`: Length of the first lockout.
+/// * `state`: Failed attempts per peer and when forgotten peers were last removed.
+/// * `clock`: Measures the lockouts, see `Throttle::set_clock`.
+pub struct Throttle {
+    max_attempts: u32,
+    lockout: Duration,
+    state: Mutex<State>,
+    clock: RwLock<Arc<dyn Clock>>,
+}
+
+impl Throttle {
+    /// Initialize new instance of the `Throttle`
+    ///
+    /// Arguments:
+    ///
+    /// * `max_attempts`: Failed attempts allowed before the first lockout.
+    /// * `lockout`: Length of the first lockout.
+    pub fn new(max_attempts: u32, lockout: Duration) -> Self {
+        let clock = SystemClock::shared();
+
+        Throttle {
+            max_attempts: max_attempts.max(1),
+            lockout: lockout,
+            state: Mutex::new(State {
+                failures: HashMap::new(),
+                pruned: clock.now(),
+            }),
+            clock: RwLock::new(clock),
+        }
+    }
+
+    /// Measure lockouts with another clock, e.g. a `MockClock` in tests
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        self.set_clock(clock);
+        self
+    }
+
+    /// Replace the clock of a throttle that is already shared, every peer's failures are
+    /// forgotten
+    ///
+    /// Arguments:
+    ///
+    /// * `clock`: The clock, see `ServerBuilder::clock`.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.failures.clear();
+        state.pruned = clock.now();
+        *self.clock.write().unwrap() = clock;
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.read().unwrap().now()
+    }
+
+    /// Number of peers with failures on record
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().failures.len()
+    }
+
+    /// Check whether no peer has failures on record
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether the peer may try to log in now
+    ///
+    /// Arguments:
+    ///
+    /// * `peer`: Usually the peer's IP address.
+    pub fn check(&self, peer: &str) -> Result<(), Error> {
+        let state = self.state.lock().unwrap();
+        let now = self.now();
+
+        match state.failures.get(peer).and_then(|f| f.until) {
+            Some(until) if until > now => Err(Error::new(&format!(
+                "Too many failed login attempts, retry in {}s",
+                (until - now).as_secs() + 1
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a failed attempt, returns the number of failures so far
+    pub fn failure(&self, peer: &str) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let now = self.now();
+
+        if now.saturating_duration_since(state.pruned) >= PRUNE_INTERVAL {
+            state.pruned = now;
+            state
+                .failures
+                .retain(|_, f| now.saturating_duration_since(f.last) < FORGET_AFTER);
+        }
+
+        let entry = state.failures.entry(peer.to_string()).or_insert(Failures {
+            count: 0,
+            until: None,
+            last: now,
+        });
+
+        entry.count += 1;
+        entry.last = now;
+
+        if entry.count >= self.max_attempts {
+            let doublings = (entry.count - self.max_attempts).min(16);
+            let lockout = self.lockout.saturating_mul(1 << doublings).min(MAX_LOCKOUT);
+
+            entry.until = Some(now + lockout);
+        }
+
+        entry.count
+    }
+
+    /// Forget the failures of a peer after a successful login
+    pub fn success(&self, peer: &str) {
+        self.state.lock().unwrap().failures.remove(peer);
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(DEFAULT_MAX_ATTEMPTS, DEFAULT_LOCKOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn doubles_the_lockout_until_it_expires() {
+        let clock = Arc::new(MockClock::new());
+        let throttle = Throttle::new(2, Duration::from_secs(30)).with_clock(clock.clone());
+
+        throttle.failure("203.0.113.1");
+        assert!(throttle.check("203.0.113.1").is_ok());
+
+        throttle.failure("203.0.113.1");
+        assert!(throttle.check("203.0.113.1").is_err());
+
+        clock.advance(Duration::from_secs(30));
+        assert!(throttle.check("203.0.113.1").is_ok());
+
+        throttle.failure("203.0.113.1");
+        clock.advance(Duration::from_secs(59));
+        assert!(throttle.check("203.0.113.1").is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(throttle.check("203.0.113.1").is_ok());
+
+        throttle.success("203.0.113.1");
+        assert!(throttle.is_empty());
+    }
+
+    #[test]
+    fn follows_a_clock_set_later() {
+        let throttle = Arc::new(Throttle::new(1, Duration::from_secs(30)));
+        let clock = Arc::new(MockClock::new());
+
+        throttle.set_clock(clock.clone());
+        throttle.failure("203.0.113.1");
+        assert!(throttle.check("203.0.113.1").is_err());
+
+        clock.advance(Duration::from_secs(30));
+        assert!(throttle.check("203.0.113.1").is_ok());
+    }
+
+    #[test]
+    fn forgets_quiet_peers() {
+        let clock = Arc::new(MockClock::new());
+        let throttle = Throttle::new(5, DEFAULT_LOCKOUT).with_clock(clock.clone());
+
+        throttle.failure("203.0.113.1");
+        clock.advance(FORGET_AFTER);
+        throttle.failure("203.0.113.2");
+
+        assert_eq!(throttle.len(), 1);
+        assert!(throttle.check("203.0.113.1").is_ok());
+    }
+
+    #[test]
+    fn keeps_recent_peers() {
+        let clock = Arc::new(MockClock::new());
+        let throttle = Throttle::new(1, MAX_LOCKOUT).with_clock(clock.clone());
+
+        throttle.failure("203.0.113.1");
+        clock.advance(FORGET_AFTER - Duration::from_secs(1));
+        throttle.failure("203.0.113.2");
+
+        assert_eq!(throttle.len(), 2);
+        assert!(throttle.check("203.0.113.1").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::auth::{constant_time_eq, invalid_credentials, Authenticator, Credentials};
+use crate::extensions::string::StringExtension;
+use crate::socket::Error;
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Length of the tokens issued by `TokenAuthenticator::issue`
+pub const TOKEN_LENGTH: usize = 32;
+
+/// ## TokenAuthenticator
+///
+/// Accepts bearer tokens issued for a user, for bots and session resumption.
+///
+/// Properties:
+///
+/// * `tokens`: A HashMap of tokens and the users they were issued for.
+pub struct TokenAuthenticator {
+    tokens: RwLock<HashMap<String, String>>,
+}
+
+impl TokenAuthenticator {
+    /// Initialize new instance of the `TokenAuthenticator`
+    pub fn new() -> Self {
+        TokenAuthenticator {
+            tokens: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Issue a new random token for a user
+    ///
+    /// Arguments:
+    ///
+    /// * `user`: The user the token logs in as.
+    pub fn issue(&self, user: &str) -> String {
+        let token = String::random_secure(TOKEN_LENGTH);
+
+        self.insert(&token, user);
+
+        token
+    }
+
+    /// Register an existing token for a user
+    pub fn insert(&self, token: &str, user: &str) {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(token.to_string(), user.to_string());
+    }
+
+    /// Revoke a token, returns whether it existed
+    pub fn revoke(&self, token: &str) -> bool {
+        self.tokens.write().unwrap().remove(token).is_some()
+    }
+}
+
+impl Default for TokenAuthenticator {
+    fn default() -> Self {
+        TokenAuthenticator::new()
+    }
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn authenticate(&self, credentials: &Credentials) -> Result<String, Error> {
+        match credentials {
+            Credentials::Token(token) => {
+                let tokens = self.tokens.read().unwrap();
+
+                tokens
+                    .iter()
+                    .find(|(stored, _)| constant_time_eq(stored.as_bytes(), token.as_bytes()))
+                    .map(|(_, user)| user.clone())
+                    .ok_or_else(invalid_credentials)
+            }
+            Credentials::Password { .. } => Err(Error::new("Password login is not supported")),
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//! Terminal chat client.
+//!
+//! Usage: `baka-client [address] [--user <name>]`, the password is read from `BAKA_PASSWORD`.
+//!
+//! Commands: `/join #channel`, `/part [#channel]`, `/msg nick text`, `/nick name`, `/quit`.
+//! Anything else is sent to the current channel. Tab completes commands, Up and Down browse
+//! the lines entered before.
+
+use bakalib::auth::Credentials;
+use bakalib::protoutils::{BakaMessage, MessageKind};
+use bakalib::socket::{Events, Socket, SocketBuilder};
+use bakalib::{Edit, LineEditor};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use std::env;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Delay before reconnecting after a connection drops, doubled after every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnect attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Lines of input history kept
+const HISTORY_SIZE: usize = 100;
+
+/// Lines the message pane keeps, older ones are dropped
+const SCROLLBACK: usize = 1000;
+
+/// Commands completed with Tab
+const COMMANDS: [&str; 5] = ["/join", "/part", "/msg", "/nick", "/quit"];
+
+/// What the network thread reports to the interface
+enum Incoming {
+    Connected(Socket),
+    Message(BakaMessage),
+    Error(String),
+    Disconnected,
+    Retrying(Duration),
+    Stopped(String),
+}
+
+/// ## App
+///
+/// Properties:
+///
+/// * `address`: The server address.
+/// * `socket`: Connection to the server, `None` while disconnected.
+/// * `status`: Connection state shown in the message pane's title.
+/// * `lines`: The message pane, at most `SCROLLBACK` lines.
+/// * `scroll`: Lines scrolled up from the bottom of the message pane.
+/// * `editor`: The input line.
+/// * `channels`: Channels joined, rejoined after a reconnect.
+/// * `target`: Channel plain text is sent to.
+/// * `nick`: Nickname chosen with `/nick`, requested again after a reconnect.
+/// * `quit`: Set once the user asked to quit.
+struct App {
+    address: String,
+    socket: Option<Socket>,
+    status: String,
+    lines: Vec<Line<'static>>,
+    scroll: usize,
+    editor: LineEditor,
+    channels: Vec<String>,
+    target: Option<String>,
+    nick: Option<String>,
+    quit: bool,
+}
+
+impl App {
+    fn new(address: &str) -> Self {
+        let mut editor = LineEditor::new();
+
+        editor.history_size(HISTORY_SIZE);
+        editor.completer(Box::new(|line: &str| {
+            COMMANDS
+                .iter()
+                .filter(|command| !line.contains(' ') && command.starts_with(line))
+                .map(|command| command.to_string())
+                .collect()
+        }));
+
+        App {
+            address: address.to_string(),
+            socket: None,
+            status: "connecting".to_string(),
+            lines: vec![],
+            scroll: 0,
+            editor: editor,
+            channels: vec![],
+            target: None,
+            nick: None,
+            quit: false,
+        }
+    }
+
+    fn info(&mut self, text: &str) {
+        self.push(Line::from(Span::styled(
+            format!("-- {}", text),
+            Style::default().fg(Color::DarkGray),
+        )));
+    }
+
+    fn push(&mut self, line: Line<'static>) {
+        self.lines.push(line);
+
+        if self.lines.len() > SCROLLBACK {
+            let excess = self.lines.len() - SCROLLBACK;
+
+            self.lines.drain(..excess);
+        }
+
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.lines.len().saturating_sub(1));
+        }
+    }
+
+    fn send(&mut self, kind: MessageKind, content: &str, target: Option<&str>) {
+        let socket = match self.socket.as_mut() {
+            Some(socket) => socket,
+            None => {
+                self.info("not connected");
+                return;
+            }
+        };
+
+        let mut message = BakaMessage::new(kind, &socket.local_address(), content);
+
+        if let Some(target) = target {
+            message = message.with_target(target);
+        }
+
+        if let Err(e) = socket.send_message(message) {
+            self.info(&format!("send failed: {}", e));
+        }
+    }
+
+    fn command(&mut self, content: &str) {
+        self.send(MessageKind::Command, content, None);
+    }
+
+    fn handle(&mut self, incoming: Incoming) {
+        match incoming {
+            Incoming::Connected(socket) => {
+                self.socket = Some(socket);
+                self.status = "connected".to_string();
+                self.info(&format!("connected to {}", self.address));
+
+                if let Some(nick) = self.nick.clone() {
+                    self.command(&format!("NICK {{{}}}", nick));
+                }
+
+                for channel in self.channels.clone() {
+                    self.command(&format!("JOIN {{{}}}", channel));
+                }
+            }
+            Incoming::Message(message) => {
+                let line = App::format(&message);
+
+                self.push(line);
+            }
+            Incoming::Error(e) => self.info(&e),
+            Incoming::Disconnected => {
+                self.socket = None;
+                self.status = "disconnected".to_string();
+                self.info("disconnected");
+            }
+            Incoming::Retrying(delay) => {
+                self.status = format!("reconnecting in {}s", delay.as_secs());
+            }
+            Incoming::Stopped(reason) => {
+                self.socket = None;
+                self.status = "disconnected".to_string();
+                self.info(&reason);
+            }
+        }
+    }
+
+    /// Handle a line typed by the user
+    fn input(&mut self, line: &str) {
+        let line = line.trim();
+
+        if line.is_empty() {
+            return;
+        }
+
+        if !line.starts_with('/') {
+            match self.target.clone() {
+                Some(target) => self.send(MessageKind::Chat, line, Some(&target)),
+                None => self.info("join a channel first: /join #channel"),
+            }
+            return;
+        }
+
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        match command {
+            "/join" if rest.starts_with('#') => {
+                self.command(&format!("JOIN {{{}}}", rest));
+
+                if !self.channels.iter().any(|c| c == rest) {
+                    self.channels.push(rest.to_string());
+                }
+
+                self.target = Some(rest.to_string());
+            }
+            "/part" => {
+                let channel = match (rest, self.target.clone()) {
+                    ("", Some(target)) => target,
+                    ("", None) => {
+                        self.info("usage: /part #channel");
+                        return;
+                    }
+                    (channel, _) => channel.to_string(),
+                };
+
+                self.command(&format!("PART {{{}}}", channel));
+                self.channels.retain(|c| *c != channel);
+
+                if self.target.as_deref() == Some(channel.as_str()) {
+                    self.target = self.channels.last().cloned();
+                }
+            }
+            "/msg" => match rest.split_once(' ') {
+                Some((nick, text)) => {
+                    self.send(MessageKind::Chat, text, Some(nick));
+                    self.push(Line::from(format!("[-> {}] {}", nick, text)));
+                }
+                None => self.info("usage: /msg nick text"),
+            },
+            "/nick" if !rest.is_empty() => {
+                self.nick = Some(rest.to_string());
+                self.command(&format!("NICK {{{}}}", rest));
+            }
+            "/quit" => self.quit = true,
+            _ => self.info(
+                "commands: /join #channel, /part [#channel], /msg nick text, /nick name, /quit",
+            ),
+        }
+    }
+
+    /// Render a message from the server as a line of the message pane
+    fn format(message: &BakaMessage) -> Line<'static> {
+        let seconds = message.timestamp / 1000;
+        let time = format!("{:02}:{:02} ", seconds / 3600 % 24, seconds / 60 % 60);
+        let target = message.target.clone().unwrap_or_default();
+
+        let (style, text) = match message.kind {
+            MessageKind::Chat if target.starts_with('#') => (
+                Style::default(),
+                format!("[{}] <{}> {}", target, message.author, message.content),
+            ),
+            MessageKind::Chat => (
+                Style::default().fg(Color::Magenta),
+                format!("[{} ->] {}", message.author, message.content),
+            ),
+            MessageKind::Join => (
+                Style::default().fg(Color::Green),
+                format!("{} joined {}", message.content, target),
+            ),
+            MessageKind::Part => (
+                Style::default().fg(Color::Yellow),
+                format!("{} left {}", message.content, target),
+            ),
+            MessageKind::Notice => (
+                Style::default().fg(Color::Cyan),
+                format!("-{}- {}", message.author, message.content),
+            ),
+            MessageKind::Command => (Style::default().fg(Color::Blue), message.content.clone()),
+            MessageKind::Error => (
+                Style::default().fg(Color::Red),
+                format!("! {}", message.content),
+            ),
+        };
+
+        Line::from(vec![
+            Span::styled(time, Style::default().fg(Color::DarkGray)),
+            Span::styled(text, style),
+        ])
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [messages, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+
+        let height = messages.height.saturating_sub(2) as usize;
+        let end = self.lines.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+
+        frame.render_widget(
+            Paragraph::new(self.lines[start..end].to_vec()).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!(" {} ({}) ", self.address, self.status)),
+            ),
+            messages,
+        );
+
+        let prompt = self.target.clone().unwrap_or_else(|| "-".to_string());
+        let offset = self.editor.cursor().min(u16::MAX as usize - 1) as u16 + 1;
+
+        frame.render_widget(
+            Paragraph::new(self.editor.text()).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!(" {} ", prompt)),
+            ),
+            input,
+        );
+        frame.set_cursor_position(Position::new(
+            (input.x + offset).min(input.right().saturating_sub(2)),
+            input.y + 1,
+        ));
+    }
+
+    fn key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::PageUp => {
+                self.scroll = (self.scroll + 10).min(self.lines.len().saturating_sub(1));
+                return;
+            }
+            KeyCode::PageDown => {
+                self.scroll = self.scroll.saturating_sub(10);
+                return;
+            }
+            _ => {}
+        }
+
+        match self.editor.key(key) {
+            Edit::Submit(line) => {
+                self.editor.remember(&line);
+                self.input(&line);
+            }
+            Edit::Interrupt | Edit::Eof => self.quit = true,
+            Edit::Candidates(candidates) => self.info(&candidates.join("  ")),
+            Edit::Changed => {}
+        }
+    }
+}
+
+/// Keep a connection open from a background thread, reconnecting whenever it drops
+///
+/// The delay between attempts grows while they fail, and starts over once a connection was
+/// made. Refused credentials stop reconnecting, the same ones would be refused again.
+fn connect(address: String, credentials: Option<Credentials>, sender: Sender<Incoming>) {
+    thread::spawn(move || {
+        let mut delay = RECONNECT_DELAY;
+
+        loop {
+            let session = Arc::new(AtomicBool::new(false));
+            let started = session.clone();
+            let connected = sender.clone();
+            let messages = sender.clone();
+            let errors = sender.clone();
+            let disconnected = sender.clone();
+
+            let events = Events {
+                on_connect: Box::new(move |socket, _| {
+                    started.store(true, Ordering::SeqCst);
+
+                    let _ = connected.send(Incoming::Connected(socket.clone()));
+                }),
+                on_message: Box::new(move |_, message| {
+                    if let Ok(message) = message {
+                        let _ = messages.send(Incoming::Message(BakaMessage::from(message)));
+                    }
+                }),
+                on_error: Box::new(move |_, error| {
+                    if let Err(e) = error {
+                        let _ = errors.send(Incoming::Error(e.to_string()));
+                    }
+                }),
+                on_disconnect: Box::new(move |_, _| {
+                    let _ = disconnected.send(Incoming::Disconnected);
+                }),
+            };
+
+            match SocketBuilder::connect(&address, events) {
+                Ok(mut builder) => {
+                    if let Some(credentials) = credentials.clone() {
+                        builder.credentials(credentials);
+                    }
+
+                    builder.startup();
+
+                    if builder.login_rejected() {
+                        let _ = sender.send(Incoming::Stopped(
+                            "login refused, not reconnecting".to_string(),
+                        ));
+                        return;
+                    }
+
+                    if session.load(Ordering::SeqCst) {
+                        delay = RECONNECT_DELAY;
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Incoming::Error(e.to_string()));
+                }
+            }
+
+            if sender.send(Incoming::Retrying(delay)).is_err() {
+                return;
+            }
+
+            thread::sleep(delay);
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    });
+}
+
+fn run(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    receiver: Receiver<Incoming>,
+) -> io::Result<()> {
+    while !app.quit {
+        while let Ok(incoming) = receiver.try_recv() {
+            app.handle(incoming);
+        }
+
+        terminal.draw(|frame| app.draw(frame))?;
+
+        if event::poll(Duration::from_millis(50))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.key(key);
+                }
+            }
+        }
+    }
+
+    if let Some(socket) = app.socket.as_mut() {
+        socket.shutdown();
+    }
+
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let mut address = "127.0.0.1:65432".to_string();
+    let mut user = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--user" => user = args.next(),
+            _ => address = arg,
+        }
+    }
+
+    let credentials = user.map(|user| Credentials::Password {
+        user: user,
+        password: env::var("BAKA_PASSWORD").unwrap_or_default(),
+    });
+
+    let (sender, receiver) = channel();
+    connect(address.clone(), credentials, sender);
+
+    let mut app = App::new(&address);
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut app, receiver);
+
+    ratatui::restore();
+    result
+}
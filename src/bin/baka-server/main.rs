@@ -0,0 +1,110 @@
+//! Reference relay server.
+//!
+//! Usage: `baka-server [--config <path>] [--data <dir>]`.
+//!
+//! The configuration is read from the file when one is given, environment variables
+//! (`BAKA_*`) override it. Listening on the `[tls]` addresses needs the `tls` feature as well.
+//! The file is watched and reloaded while the server runs. History and bans are kept in
+//! `history.db` and `moderation.db` inside the data directory, the current directory by
+//! default. SIGINT and SIGTERM disconnect every client before exiting. Events are
+//! logged to standard error, filtered by `RUST_LOG`, `info` by default. Plugins are
+//! registered in `plugins.rs`, see `plugins::register`.
+
+mod plugins;
+
+use bakalib::config::ServerConfig;
+use bakalib::lagerung::Lagerung;
+use bakalib::middleware::Logging;
+use bakalib::socket::{History, Moderation, ServerBuilder, DEFAULT_HISTORY_CAPACITY};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing_subscriber::EnvFilter;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+/// How often the configuration file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long clients get to receive the shutdown notice
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+fn fail(message: &str) -> ! {
+    eprintln!("baka-server: {}", message);
+    process::exit(1);
+}
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let mut args = env::args().skip(1);
+    let mut config_path = None;
+    let mut data = PathBuf::from(".");
+
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--config", Some(path)) => config_path = Some(PathBuf::from(path)),
+            ("--data", Some(dir)) => data = PathBuf::from(dir),
+            _ => fail("usage: baka-server [--config <path>] [--data <dir>]"),
+        }
+    }
+
+    let config = match &config_path {
+        Some(path) => ServerConfig::load(path).and_then(|config| config.with_env()),
+        None => ServerConfig::from_env(),
+    }
+    .unwrap_or_else(|e| fail(&e.to_string()));
+
+    let mut server = ServerBuilder::from_config(&config).unwrap_or_else(|e| fail(&e.to_string()));
+
+    let history = Lagerung::open(data.join("history.db")).unwrap_or_else(|e| fail(e.message()));
+    let moderation =
+        Lagerung::open(data.join("moderation.db")).unwrap_or_else(|e| fail(e.message()));
+
+    server.history(History::persistent(DEFAULT_HISTORY_CAPACITY, history));
+    server
+        .moderation(Moderation::persistent(moderation))
+        .unwrap_or_else(|e| fail(e.message()));
+    let addresses = server
+        .addresses()
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    server.middleware(Logging::new(&addresses));
+    plugins::register(&mut server);
+
+    let handle = server.handle();
+
+    if let Some(path) = config_path {
+        handle.watch(path, WATCH_INTERVAL, |result| match result {
+            Ok(report) => eprintln!("baka-server: reloaded {}", report.applied.join(", ")),
+            Err(e) => eprintln!("baka-server: reload failed: {}", e),
+        });
+    }
+
+    let mut signals = Signals::new([SIGINT, SIGTERM]).unwrap_or_else(|e| fail(&e.to_string()));
+
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            eprintln!("baka-server: shutting down");
+
+            handle.shutdown("Server shutting down", SHUTDOWN_GRACE);
+            process::exit(0);
+        }
+    });
+
+    eprintln!("baka-server: listening on {}", addresses);
+
+    server.startup();
+}
@@ -0,0 +1,23 @@
+//! Plugins built into `baka-server`.
+//!
+//! `register` is called once the configuration, persistence and logging are set up, right
+//! before the server starts. Deployments add their own commands, middleware and event handlers
+//! here and leave the rest of the binary as it is.
+
+use bakalib::socket::ServerBuilder;
+
+/// Register the plugins on the server
+///
+/// Example:
+/// ```rs
+/// pub fn register(server: &mut ServerBuilder) {
+///     server.event("on_client_connect", Box::new(|ctx: &mut Context, event: Event| {
+///         println!("{} connected", ctx.nick());
+///     }));
+/// }
+/// ```
+///
+/// Arguments:
+///
+/// * `server`: The configured server, not started yet.
+pub fn register(_server: &mut ServerBuilder) {}
@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// ## Clock
+///
+/// Source of time for timers, timeouts and expirations.
+///
+/// `SystemClock` follows the real time. `MockClock` only moves when it is advanced, so code
+/// taking a clock can be tested without sleeping.
+pub trait Clock: Send + Sync {
+    /// Get the current monotonic time
+    fn now(&self) -> Instant;
+
+    /// Get the current wall-clock time
+    fn system_time(&self) -> SystemTime;
+
+    /// Get the clock as a `MockClock`, `None` for clocks that move by themselves
+    fn as_mock(&self) -> Option<&MockClock> {
+        None
+    }
+}
+
+/// Something that runs work once a `MockClock` reaches a point in time, like a `Scheduler`
+pub(crate) trait Waiter: Send + Sync {
+    /// Get the earliest time work is due at
+    fn next_due(&self) -> Option<Instant>;
+
+    /// Run the work that is due now
+    fn tick(self: Arc<Self>);
+}
+
+/// ## SystemClock
+///
+/// The real time, the clock used unless another one is given.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl SystemClock {
+    /// Get a shared `SystemClock`, ready to hand to anything taking an `Arc<dyn Clock>`
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+}
+
+/// ## MockClock
+///
+/// Clock that stands still until `advance` is called.
+///
+/// A `Scheduler` using a `MockClock` has no thread of its own: `advance` runs its timers on
+/// the calling thread, in the order they are due, with the clock set to each due time.
+///
+/// Example:
+/// ```rs
+/// let clock = Arc::new(MockClock::new());
+/// let scheduler = Scheduler::with_clock(clock.clone());
+///
+/// scheduler.set_interval(Duration::from_secs(10), Box::new(|_| println!("tick")));
+///
+/// // Prints "tick" three times, instantly
+/// clock.advance(Duration::from_secs(30));
+/// ```
+///
+/// Properties:
+///
+/// * `start`: The monotonic time the clock started at.
+/// * `epoch`: The wall-clock time the clock started at.
+/// * `elapsed`: How far the clock was advanced.
+/// * `waiters`: Schedulers to run while advancing.
+pub struct MockClock {
+    start: Instant,
+    epoch: SystemTime,
+    elapsed: Mutex<Duration>,
+    waiters: Mutex<Vec<Arc<dyn Waiter>>>,
+}
+
+impl MockClock {
+    /// Initialize new instance of the `MockClock`, its wall-clock time starts at the Unix epoch
+    pub fn new() -> Self {
+        MockClock::at(UNIX_EPOCH)
+    }
+
+    /// Initialize new instance of the `MockClock` starting at a wall-clock time
+    ///
+    /// Arguments:
+    ///
+    /// * `epoch`: What `system_time` returns until the clock is advanced.
+    pub fn at(epoch: SystemTime) -> Self {
+        MockClock {
+            start: Instant::now(),
+            epoch: epoch,
+            elapsed: Mutex::new(Duration::ZERO),
+            waiters: Mutex::new(vec![]),
+        }
+    }
+
+    /// Get how far the clock was advanced
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    /// Move the clock forward, running every timer that becomes due on the way
+    ///
+    /// Arguments:
+    ///
+    /// * `duration`: How far to move.
+    pub fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+
+        loop {
+            let waiters = self.waiters.lock().unwrap().clone();
+            let next = waiters
+                .iter()
+                .filter_map(|waiter| waiter.next_due())
+                .filter(|due| *due <= target)
+                .min();
+
+            let due = match next {
+                Some(due) => due,
+                None => break,
+            };
+
+            self.set(due.max(self.now()));
+
+            for waiter in waiters {
+                waiter.tick();
+            }
+        }
+
+        self.set(target);
+    }
+
+    fn set(&self, now: Instant) {
+        *self.elapsed.lock().unwrap() = now - self.start;
+    }
+
+    pub(crate) fn attach(&self, waiter: Arc<dyn Waiter>) {
+        self.waiters.lock().unwrap().push(waiter);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.epoch + self.elapsed()
+    }
+
+    fn as_mock(&self) -> Option<&MockClock> {
+        Some(self)
+    }
+}
@@ -40,23 +40,34 @@ impl CommandParser {
         CommandParser { groups: dict }
     }
 
+    fn group(&self, name: &str) -> &str {
+        self.groups.get(name).map(|g| g.as_str()).unwrap_or("")
+    }
+
     /// Get the target of the command
     pub fn target(&mut self) -> &str {
-        self.groups["target"].as_str().trim()
+        self.group("target").trim()
     }
 
     /// Get the command
     pub fn command(&mut self) -> &str {
-        self.groups["command"].as_str()
+        self.group("command")
     }
 
     /// Get command arguments
     pub fn args(&mut self) -> Vec<String> {
-        self.groups["args"].clone().baka_split(" ")
+        self.group("args").to_string().baka_split(" ")
     }
 
     /// Get command tail
     pub fn tail(&mut self) -> &str {
-        self.groups["tail"].as_str()
+        self.group("tail")
+    }
+
+    /// Get command tail without its leading `:`, further colons are part of the text
+    pub fn text(&mut self) -> &str {
+        let tail = self.group("tail");
+
+        tail.strip_prefix(':').unwrap_or(tail)
     }
 }
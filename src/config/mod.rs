@@ -0,0 +1,736 @@
+use crate::socket::{BanMask, Error};
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Prefix of the environment variables read by `ServerConfig::with_env`
+pub const ENV_PREFIX: &str = "BAKA_";
+
+/// ## ConfigError
+///
+/// A configuration value that could not be used.
+///
+/// Properties:
+///
+/// * `key`: Path of the offending key, e.g. `limits.max_clients`, or the environment variable.
+/// * `message`: What is wrong with it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    /// Initialize new instance of the `ConfigError`
+    ///
+    /// Arguments:
+    ///
+    /// * `key`: The offending key.
+    /// * `message`: What is wrong with it.
+    pub fn new(key: &str, message: &str) -> Self {
+        ConfigError {
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "baka::config::ConfigError: {}: {}",
+            self.key, self.message
+        )
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl std::convert::From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::new(&err.to_string())
+    }
+}
+
+/// ## RateLimitConfig
+///
+/// Properties:
+///
+/// * `burst`: Messages a client may send at once.
+/// * `per`: Time it takes to refill a whole burst.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+/// ## TlsConfig
+///
+/// Addresses the server accepts TLS connections on, served by a `Server` built with the `tls`
+/// feature.
+///
+/// Properties:
+///
+/// * `bind`: Addresses listened on for TLS connections.
+/// * `cert`: PEM file with the certificate chain, the server's own certificate first.
+/// * `key`: PEM file with the private key of the certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub bind: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// ## ServerConfig
+///
+/// Everything a `ServerBuilder` can be configured with, loaded from TOML or environment
+/// variables.
+///
+/// Example file:
+/// ```toml
+/// bind = ["0.0.0.0:65432", "[::]:65432"]
+/// motd = "Welcome!"
+///
+/// [tls]
+/// bind = ["0.0.0.0:6697"]
+/// cert = "/etc/baka/cert.pem"
+/// key = "/etc/baka/key.pem"
+///
+/// [limits]
+/// max_clients = 1000
+/// max_connections_per_ip = 10
+/// max_message_size = 65536
+/// max_link_message_size = 16777216
+/// read_buffer = 4096
+/// queue_capacity = 256
+///
+/// [timeouts]
+/// poll_interval_ms = 100
+/// handshake_secs = 10
+/// idle_secs = 600
+///
+/// [rate_limit]
+/// burst = 10
+/// per_secs = 5
+///
+/// [operators]
+/// alice = "$argon2id$v=19$..."
+///
+/// [bans]
+/// "*@203.0.113.0/24" = "Spam"
+/// ```
+///
+/// Properties:
+///
+/// * `bind`: Addresses the server listens on for plain TCP connections.
+/// * `tls`: Addresses the server listens on for TLS connections, `None` for none.
+/// * `max_clients`: Open connections allowed at once, `None` for no limit.
+/// * `max_connections_per_ip`: Open connections allowed from one IP address, `None` for no
+///   limit.
+/// * `max_message_size`: Largest message accepted from a client, in bytes.
+/// * `max_link_message_size`: Largest message accepted from a linked server, in bytes.
+/// * `read_buffer`: Size of the chunks read from a socket, in bytes.
+/// * `queue_capacity`: Messages each client's outbound queue can hold.
+/// * `poll_interval`: How often waiting loops, like `ServerHandle::shutdown`, check for progress.
+/// * `handshake_timeout`: Time a client has to finish the handshake and log in, `None` for no
+///   limit.
+/// * `idle_timeout`: Time a connected client may stay silent, `None` for no limit.
+/// * `rate_limit`: Messages a client may send, `None` for no limit.
+/// * `motd`: Message of the day sent to every client that connects.
+/// * `operators`: Argon2 password hashes of the operators by user name, see `OPER`.
+/// * `bans`: Ban reasons by `BanMask`, applied on top of the server's `Moderation`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub bind: Vec<String>,
+    pub tls: Option<TlsConfig>,
+    pub max_clients: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_message_size: usize,
+    pub max_link_message_size: usize,
+    pub read_buffer: usize,
+    pub queue_capacity: usize,
+    pub poll_interval: Duration,
+    pub handshake_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub motd: Option<String>,
+    pub operators: HashMap<String, String>,
+    pub bans: HashMap<String, String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: vec!["127.0.0.1:65432".to_string()],
+            tls: None,
+            max_clients: None,
+            max_connections_per_ip: None,
+            max_message_size: crate::socket::DEFAULT_MAX_MESSAGE_SIZE,
+            max_link_message_size: crate::socket::DEFAULT_MAX_LINK_MESSAGE_SIZE,
+            read_buffer: crate::socket::DEFAULT_BUFFER_SIZE,
+            queue_capacity: crate::socket::DEFAULT_QUEUE_CAPACITY,
+            poll_interval: crate::socket::DEFAULT_POLL_INTERVAL,
+            handshake_timeout: Some(crate::socket::DEFAULT_HANDSHAKE_TIMEOUT),
+            idle_timeout: None,
+            rate_limit: None,
+            motd: None,
+            operators: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+}
+
+/// A value read from a file or the environment
+///
+/// Properties:
+///
+/// * `key`: Where the value came from, reported in errors: a TOML key or an environment variable.
+/// * `path`: The TOML key the value is for.
+/// * `value`: The value.
+struct Field<'a> {
+    key: String,
+    path: String,
+    value: Value<'a>,
+}
+
+enum Value<'a> {
+    Toml(&'a toml::Value),
+    Env(String),
+}
+
+impl<'a> Field<'a> {
+    fn string(&self) -> Result<String, ConfigError> {
+        match &self.value {
+            Value::Toml(toml::Value::String(s)) => Ok(s.clone()),
+            Value::Env(s) => Ok(s.clone()),
+            _ => Err(ConfigError::new(&self.key, "expected a string")),
+        }
+    }
+
+    fn integer(&self, min: u64) -> Result<u64, ConfigError> {
+        let value = match &self.value {
+            Value::Toml(toml::Value::Integer(i)) => u64::try_from(*i).ok(),
+            Value::Env(s) => s.trim().parse::<u64>().ok(),
+            _ => None,
+        };
+
+        match value {
+            Some(value) if value >= min => Ok(value),
+            _ if min == 0 => Err(ConfigError::new(
+                &self.key,
+                "expected a non-negative integer",
+            )),
+            _ => Err(ConfigError::new(
+                &self.key,
+                &format!("expected an integer of at least {}", min),
+            )),
+        }
+    }
+
+    /// An integer where `0` means "no limit"
+    fn optional(&self) -> Result<Option<u64>, ConfigError> {
+        self.integer(0).map(|n| if n == 0 { None } else { Some(n) })
+    }
+
+    /// One address or a list of them, comma-separated in the environment
+    fn addresses(&self) -> Result<Vec<String>, ConfigError> {
+        let addresses = match &self.value {
+            Value::Toml(toml::Value::String(s)) => vec![s.clone()],
+            Value::Toml(toml::Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    toml::Value::String(s) => Ok(s.clone()),
+                    _ => Err(ConfigError::new(&self.key, "expected a list of addresses")),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Value::Env(s) => s
+                .split(',')
+                .map(|address| address.trim())
+                .filter(|address| !address.is_empty())
+                .map(|address| address.to_string())
+                .collect(),
+            _ => return Err(ConfigError::new(&self.key, "expected a list of addresses")),
+        };
+
+        for address in &addresses {
+            if address.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::new(
+                    &self.key,
+                    &format!("expected an address like 0.0.0.0:65432, got {}", address),
+                ));
+            }
+        }
+
+        Ok(addresses)
+    }
+}
+
+/// Every key except the operators
+const KEYS: [&str; 16] = [
+    "bind",
+    "motd",
+    "tls.bind",
+    "tls.cert",
+    "tls.key",
+    "limits.max_clients",
+    "limits.max_connections_per_ip",
+    "limits.max_message_size",
+    "limits.max_link_message_size",
+    "limits.read_buffer",
+    "limits.queue_capacity",
+    "timeouts.poll_interval_ms",
+    "timeouts.handshake_secs",
+    "timeouts.idle_secs",
+    "rate_limit.burst",
+    "rate_limit.per_secs",
+];
+
+impl ServerConfig {
+    /// Load the configuration from a TOML file
+    ///
+    /// Example:
+    /// ```rs
+    /// let config = ServerConfig::load("server.toml")?.with_env()?;
+    /// let mut server = ServerBuilder::from_config(&config)?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The configuration file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| {
+            ConfigError::new(
+                &path.display().to_string(),
+                &format!("unable to read: {}", e),
+            )
+        })?;
+
+        ServerConfig::from_toml(&contents)
+    }
+
+    /// Parse the configuration from TOML text, missing keys keep their defaults
+    ///
+    /// Arguments:
+    ///
+    /// * `contents`: The TOML text.
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        let table = contents
+            .parse::<toml::Table>()
+            .map_err(|e| ConfigError::new("<toml>", e.to_string().trim()))?;
+
+        let mut fields = vec![];
+        ServerConfig::flatten("", &table, &mut fields);
+
+        let mut config = ServerConfig::default();
+        config.apply(fields)?;
+
+        Ok(config)
+    }
+
+    /// Read the configuration from environment variables only, see `ServerConfig::with_env`
+    pub fn from_env() -> Result<Self, ConfigError> {
+        ServerConfig::default().with_env()
+    }
+
+    /// Override values with environment variables
+    ///
+    /// Each key is read from `BAKA_` followed by the key in upper case with dots replaced by
+    /// underscores, e.g. `BAKA_BIND` or `BAKA_LIMITS_MAX_CLIENTS`. Lists of addresses are
+    /// comma-separated. Operators are read from `BAKA_OPERATORS` as comma-separated
+    /// `user:hash` pairs. Bans can only be set in a file.
+    ///
+    /// Example:
+    /// ```rs
+    /// // BAKA_BIND=0.0.0.0:7000,[::]:7000 BAKA_TIMEOUTS_IDLE_SECS=300
+    /// let config = ServerConfig::load("server.toml")?.with_env()?;
+    /// ```
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        self.with_vars(|key| env::var(key).ok())
+    }
+
+    /// Override values with variables looked up by name, see `ServerConfig::with_env`
+    ///
+    /// Example:
+    /// ```rs
+    /// let vars = HashMap::from([("BAKA_TIMEOUTS_IDLE_SECS", "300")]);
+    /// let config = ServerConfig::load("server.toml")?
+    ///     .with_vars(|key| vars.get(key).map(|value| value.to_string()))?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `vars`: Gets the value of a variable, `None` when it is not set.
+    pub fn with_vars<F: Fn(&str) -> Option<String>>(
+        mut self,
+        vars: F,
+    ) -> Result<Self, ConfigError> {
+        let mut fields = vec![];
+
+        for path in KEYS {
+            let key = format!("{}{}", ENV_PREFIX, path.replace('.', "_").to_uppercase());
+
+            if let Some(value) = vars(&key) {
+                fields.push(Field {
+                    key: key,
+                    path: path.to_string(),
+                    value: Value::Env(value),
+                });
+            }
+        }
+
+        let key = format!("{}OPERATORS", ENV_PREFIX);
+
+        if let Some(operators) = vars(&key) {
+            for pair in operators
+                .split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+            {
+                match pair.split_once(':') {
+                    Some((user, hash)) => fields.push(Field {
+                        key: key.clone(),
+                        path: format!("operators.{}", user),
+                        value: Value::Env(hash.to_string()),
+                    }),
+                    None => {
+                        return Err(ConfigError::new(
+                            &key,
+                            "expected comma-separated `user:hash` pairs",
+                        ))
+                    }
+                }
+            }
+        }
+
+        self.apply(fields)?;
+
+        Ok(self)
+    }
+
+    fn flatten<'a>(prefix: &str, table: &'a toml::Table, fields: &mut Vec<Field<'a>>) {
+        for (key, value) in table {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+
+            match value {
+                toml::Value::Table(table) => ServerConfig::flatten(&path, table, fields),
+                value => fields.push(Field {
+                    key: path.clone(),
+                    path: path,
+                    value: Value::Toml(value),
+                }),
+            }
+        }
+    }
+
+    /// Apply values on top of the current ones, then check the result
+    fn apply(&mut self, fields: Vec<Field>) -> Result<(), ConfigError> {
+        let mut burst = self.rate_limit.as_ref().map(|limit| limit.burst);
+        let mut per = self.rate_limit.as_ref().map(|limit| limit.per);
+        let mut tls_bind = self.tls.as_ref().map(|tls| tls.bind.clone());
+        let mut tls_cert = self.tls.as_ref().map(|tls| tls.cert.clone());
+        let mut tls_key = self.tls.as_ref().map(|tls| tls.key.clone());
+
+        for field in fields {
+            match field.path.as_str() {
+                "bind" => self.bind = field.addresses()?,
+                "motd" => self.motd = Some(field.string()?),
+                "tls.bind" => tls_bind = Some(field.addresses()?),
+                "tls.cert" => tls_cert = Some(PathBuf::from(field.string()?)),
+                "tls.key" => tls_key = Some(PathBuf::from(field.string()?)),
+                "limits.max_clients" => {
+                    self.max_clients = field.optional()?.map(|n| n as usize);
+                }
+                "limits.max_connections_per_ip" => {
+                    self.max_connections_per_ip = field.optional()?.map(|n| n as usize);
+                }
+                "limits.max_message_size" => {
+                    self.max_message_size = field.integer(64)? as usize;
+                }
+                "limits.max_link_message_size" => {
+                    self.max_link_message_size = field.integer(64)? as usize;
+                }
+                "limits.read_buffer" => self.read_buffer = field.integer(64)? as usize,
+                "limits.queue_capacity" => self.queue_capacity = field.integer(1)? as usize,
+                "timeouts.poll_interval_ms" => {
+                    self.poll_interval = Duration::from_millis(field.integer(1)?);
+                }
+                "timeouts.handshake_secs" => {
+                    self.handshake_timeout = field.optional()?.map(Duration::from_secs);
+                }
+                "timeouts.idle_secs" => {
+                    self.idle_timeout = field.optional()?.map(Duration::from_secs);
+                }
+                "rate_limit.burst" => burst = Some(field.integer(1)? as u32),
+                "rate_limit.per_secs" => per = Some(Duration::from_secs(field.integer(1)?)),
+                path if path.starts_with("operators.") => {
+                    let hash = field.string()?;
+
+                    if !hash.starts_with("$argon2") {
+                        return Err(ConfigError::new(
+                            &field.key,
+                            "expected an argon2 hash, see `CredentialFile::hash`",
+                        ));
+                    }
+
+                    self.operators
+                        .insert(path["operators.".len()..].to_string(), hash);
+                }
+                path if path.starts_with("bans.") => {
+                    let mask = &path["bans.".len()..];
+
+                    BanMask::parse(mask).map_err(|e| ConfigError::new(&field.key, e.message()))?;
+
+                    self.bans.insert(mask.to_string(), field.string()?);
+                }
+                _ => return Err(ConfigError::new(&field.key, "unknown key")),
+            }
+        }
+
+        self.rate_limit = match (burst, per) {
+            (Some(burst), Some(per)) => Some(RateLimitConfig {
+                burst: burst,
+                per: per,
+            }),
+            (None, None) => None,
+            (Some(_), None) => {
+                return Err(ConfigError::new(
+                    "rate_limit.per_secs",
+                    "missing, rate_limit.burst is set",
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::new(
+                    "rate_limit.burst",
+                    "missing, rate_limit.per_secs is set",
+                ))
+            }
+        };
+
+        self.tls = match (tls_bind, tls_cert, tls_key) {
+            (None, None, None) => None,
+            (Some(bind), Some(cert), Some(key)) if !bind.is_empty() => Some(TlsConfig {
+                bind: bind,
+                cert: cert,
+                key: key,
+            }),
+            (Some(_), Some(_), Some(_)) => {
+                return Err(ConfigError::new(
+                    "tls.bind",
+                    "expected at least one address",
+                ))
+            }
+            (bind, cert, _) => {
+                let missing = if bind.is_none() {
+                    "tls.bind"
+                } else if cert.is_none() {
+                    "tls.cert"
+                } else {
+                    "tls.key"
+                };
+
+                return Err(ConfigError::new(
+                    missing,
+                    "missing, tls.bind, tls.cert and tls.key are set together",
+                ));
+            }
+        };
+
+        if self.bind.is_empty() && self.tls.is_none() {
+            return Err(ConfigError::new("bind", "expected at least one address"));
+        }
+
+        if self.read_buffer > self.max_message_size {
+            return Err(ConfigError::new(
+                "limits.read_buffer",
+                "must not exceed limits.max_message_size",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(contents: &str) -> ConfigError {
+        ServerConfig::from_toml(contents).unwrap_err()
+    }
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        assert_eq!(
+            ServerConfig::from_toml("").unwrap(),
+            ServerConfig::default()
+        );
+    }
+
+    #[test]
+    fn reads_every_section() {
+        let config = ServerConfig::from_toml(
+            r#"
+            bind = "0.0.0.0:7000"
+            motd = "Welcome!"
+
+            [limits]
+            max_clients = 0
+            max_connections_per_ip = 10
+            max_message_size = 8192
+            max_link_message_size = 1048576
+            read_buffer = 1024
+
+            [timeouts]
+            handshake_secs = 0
+            idle_secs = 300
+
+            [rate_limit]
+            burst = 10
+            per_secs = 5
+
+            [operators]
+            alice = "$argon2id$v=19$hash"
+
+            [bans]
+            "*@203.0.113.0/24" = "Spam"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, vec!["0.0.0.0:7000"]);
+        assert_eq!(config.motd.as_deref(), Some("Welcome!"));
+        assert_eq!(config.max_clients, None);
+        assert_eq!(config.max_connections_per_ip, Some(10));
+        assert_eq!(config.max_message_size, 8192);
+        assert_eq!(config.max_link_message_size, 1048576);
+        assert_eq!(config.read_buffer, 1024);
+        assert_eq!(config.handshake_timeout, None);
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(300)));
+        assert_eq!(
+            config.rate_limit,
+            Some(RateLimitConfig {
+                burst: 10,
+                per: Duration::from_secs(5),
+            })
+        );
+        assert_eq!(config.operators["alice"], "$argon2id$v=19$hash");
+        assert_eq!(config.bans["*@203.0.113.0/24"], "Spam");
+    }
+
+    #[test]
+    fn errors_point_to_the_offending_key() {
+        assert_eq!(error("bind = \"nowhere\"").key, "bind");
+        assert_eq!(
+            error("[limits]\nmax_clients = -1").key,
+            "limits.max_clients"
+        );
+        assert_eq!(
+            error("[limits]\nqueue_capacity = 0").key,
+            "limits.queue_capacity"
+        );
+        assert_eq!(
+            error("[limits]\nmax_message_size = \"big\"").key,
+            "limits.max_message_size"
+        );
+        assert_eq!(error("[limits]\nunknown = 1").key, "limits.unknown");
+        assert_eq!(
+            error("[operators]\nalice = \"hunter2\"").key,
+            "operators.alice"
+        );
+        assert_eq!(error("[bans]\n\"a b\" = \"Spam\"").key, "bans.a b");
+        assert_eq!(error("not toml").key, "<toml>");
+    }
+
+    #[test]
+    fn checks_related_keys_together() {
+        assert_eq!(error("[rate_limit]\nburst = 10").key, "rate_limit.per_secs");
+        assert_eq!(error("[rate_limit]\nper_secs = 5").key, "rate_limit.burst");
+        assert_eq!(
+            error("[limits]\nmax_message_size = 1024\nread_buffer = 2048").key,
+            "limits.read_buffer"
+        );
+    }
+
+    #[test]
+    fn reads_lists_of_addresses() {
+        let config = ServerConfig::from_toml(
+            r#"
+            bind = ["0.0.0.0:7000", "[::]:7000"]
+
+            [tls]
+            bind = "0.0.0.0:6697"
+            cert = "cert.pem"
+            key = "key.pem"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, vec!["0.0.0.0:7000", "[::]:7000"]);
+        assert_eq!(
+            config.tls,
+            Some(TlsConfig {
+                bind: vec!["0.0.0.0:6697".to_string()],
+                cert: PathBuf::from("cert.pem"),
+                key: PathBuf::from("key.pem"),
+            })
+        );
+        assert_eq!(error("bind = [\"0.0.0.0:7000\", 7001]").key, "bind");
+        assert_eq!(error("bind = []").key, "bind");
+    }
+
+    #[test]
+    fn needs_every_tls_key() {
+        assert_eq!(
+            error("[tls]\nbind = \"0.0.0.0:6697\"\ncert = \"cert.pem\"").key,
+            "tls.key"
+        );
+        assert_eq!(
+            error("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"").key,
+            "tls.bind"
+        );
+        assert_eq!(
+            error("[tls]\nbind = []\ncert = \"cert.pem\"\nkey = \"key.pem\"").key,
+            "tls.bind"
+        );
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let vars = HashMap::from([
+            ("BAKA_BIND", "0.0.0.0:7000, [::]:7000"),
+            ("BAKA_TIMEOUTS_IDLE_SECS", "60"),
+            ("BAKA_OPERATORS", "bob:$argon2id$v=19$hash"),
+        ]);
+
+        let config = ServerConfig::from_toml("[timeouts]\nidle_secs = 300")
+            .unwrap()
+            .with_vars(|key| vars.get(key).map(|value| value.to_string()))
+            .unwrap();
+
+        assert_eq!(config.bind, vec!["0.0.0.0:7000", "[::]:7000"]);
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(config.operators["bob"], "$argon2id$v=19$hash");
+        assert_eq!(
+            ServerConfig::default()
+                .with_vars(|key| (key == "BAKA_OPERATORS").then(|| "bob".to_string()))
+                .unwrap_err()
+                .key,
+            "BAKA_OPERATORS"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// ## Interner
+///
+/// Shares one copy of equal strings, the alternative to `StringExtension::to_static_str` that
+/// does not leak: a string is freed once nothing holds it and `purge` runs.
+///
+/// Example:
+/// ```rs
+/// let interner = Interner::new();
+///
+/// let a = interner.intern("#general");
+/// let b = interner.intern("#general");
+/// assert!(Arc::ptr_eq(&a, &b));
+/// ```
+///
+/// Properties:
+///
+/// * `strings`: The interned strings.
+pub struct Interner {
+    strings: Mutex<HashSet<Arc<str>>>,
+}
+
+impl Interner {
+    /// Initialize new instance of the `Interner`
+    pub fn new() -> Self {
+        Interner {
+            strings: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Get the shared copy of a string, adding it if needed
+    pub fn intern(&self, s: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap();
+
+        match strings.get(s) {
+            Some(interned) => interned.clone(),
+            None => {
+                let interned: Arc<str> = Arc::from(s);
+
+                strings.insert(interned.clone());
+                interned
+            }
+        }
+    }
+
+    /// Get the number of interned strings
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    /// Check whether no strings are interned
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Free the strings only the interner still holds, returns how many were freed
+    pub fn purge(&self) -> usize {
+        let mut strings = self.strings.lock().unwrap();
+        let before = strings.len();
+
+        strings.retain(|s| Arc::strong_count(s) > 1);
+
+        before - strings.len()
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        Interner::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::names::NameRules;
+
+    #[test]
+    fn shares_one_copy() {
+        let interner = Interner::new();
+        let a = interner.intern("#general");
+        let b = interner.intern(&String::from("#general"));
+
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(interner.len(), 1);
+    }
+
+    #[test]
+    fn folded_keys_share_an_entry() {
+        let interner = Interner::new();
+        let a = interner.intern(&NameRules::key("Alice"));
+        let b = interner.intern(&NameRules::key("ALICE"));
+
+        assert!(Arc::ptr_eq(&a, &b));
+        // Names are interned as given, only their keys are folded
+        assert!(!Arc::ptr_eq(
+            &interner.intern("Alice"),
+            &interner.intern("ALICE")
+        ));
+    }
+
+    #[test]
+    fn frees_strings_nothing_holds() {
+        let interner = Interner::new();
+        let kept = interner.intern("#general");
+        let dropped = Arc::downgrade(&interner.intern("#random"));
+
+        assert_eq!(interner.purge(), 1);
+        assert!(dropped.upgrade().is_none());
+        assert_eq!(interner.len(), 1);
+
+        drop(kept);
+
+        assert_eq!(interner.purge(), 1);
+        assert!(interner.is_empty());
+    }
+}
@@ -1 +1,3 @@
-pub mod string;
+pub mod intern;
+pub mod names;
+pub mod string;
@@ -0,0 +1,233 @@
+use crate::extensions::string::StringExtension;
+use crate::socket::Error;
+
+use unicode_security::MixedScript;
+
+/// Invisible characters that make two names look the same: zero-width spaces and joiners,
+/// bidirectional controls, word joiners and the byte order mark
+const INVISIBLE: [(char, char); 4] = [
+    ('\u{200b}', '\u{200f}'),
+    ('\u{202a}', '\u{202e}'),
+    ('\u{2060}', '\u{2064}'),
+    ('\u{feff}', '\u{feff}'),
+];
+
+/// ## NameRules
+///
+/// What a nickname or channel name may look like.
+///
+/// Names are normalized to NFC before they are checked. Whitespace, control characters and
+/// invisible characters are never allowed.
+///
+/// Example:
+/// ```rs
+/// let nick = NameRules::nickname().validate("Zoë")?;
+///
+/// let rules = NameRules {
+///     max_length: 16,
+///     ..NameRules::nickname()
+/// };
+/// ```
+///
+/// Properties:
+///
+/// * `min_length`: Fewest characters, including the prefix.
+/// * `max_length`: Most characters, including the prefix.
+/// * `prefix`: Character every name starts with, e.g. `#` for channels.
+/// * `forbidden`: Characters not allowed anywhere in the name.
+/// * `forbidden_first`: Characters a name may not start with.
+/// * `mixed_script`: Whether one name may mix scripts, e.g. Latin and Cyrillic letters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameRules {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub prefix: Option<char>,
+    pub forbidden: String,
+    pub forbidden_first: String,
+    pub mixed_script: bool,
+}
+
+impl NameRules {
+    /// Rules for nicknames: 1 to 32 characters, no `:`, `,` or braces, not starting with `#`,
+    /// one script
+    pub fn nickname() -> Self {
+        NameRules {
+            min_length: 1,
+            max_length: 32,
+            prefix: None,
+            forbidden: ":,{}".to_string(),
+            forbidden_first: "#".to_string(),
+            mixed_script: false,
+        }
+    }
+
+    /// Rules for channel names: `#` and 1 to 63 more characters, no `:`, `,` or braces, one
+    /// script
+    pub fn channel() -> Self {
+        NameRules {
+            min_length: 2,
+            max_length: 64,
+            prefix: Some('#'),
+            forbidden: ":,{}".to_string(),
+            forbidden_first: String::new(),
+            mixed_script: false,
+        }
+    }
+
+    /// Check a name, returns it normalized to NFC
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: The name to check.
+    pub fn validate(&self, name: &str) -> Result<String, Error> {
+        let name = name.to_string().nfc();
+        let length = name.chars().count();
+
+        if length < self.min_length || length > self.max_length {
+            return Err(Error::new(&format!(
+                "Names are {} to {} characters long",
+                self.min_length, self.max_length
+            )));
+        }
+
+        let rest = match self.prefix {
+            Some(prefix) => match name.strip_prefix(prefix) {
+                Some(rest) => rest,
+                None => return Err(Error::new(&format!("Names start with {}", prefix))),
+            },
+            None => name.as_str(),
+        };
+
+        if rest
+            .chars()
+            .next()
+            .is_some_and(|c| self.forbidden_first.contains(c))
+        {
+            return Err(Error::new(&format!(
+                "Names do not start with any of {}",
+                self.forbidden_first
+            )));
+        }
+
+        if let Some(c) = name.chars().find(|c| {
+            c.is_whitespace()
+                || c.is_control()
+                || self.forbidden.contains(*c)
+                || INVISIBLE.iter().any(|(from, to)| (*from..=*to).contains(c))
+        }) {
+            return Err(Error::new(&format!(
+                "Names may not contain U+{:04X}",
+                c as u32
+            )));
+        }
+
+        if !self.mixed_script && !rest.is_single_script() {
+            return Err(Error::new("Names may not mix scripts"));
+        }
+
+        Ok(name)
+    }
+
+    /// Check whether a name follows the rules
+    pub fn is_valid(&self, name: &str) -> bool {
+        self.validate(name).is_ok()
+    }
+
+    /// Get the key two names are the same under: case-folded and in NFC
+    ///
+    /// `Alice` and `alice` share a key, `Zoë` typed with a combining diaeresis and with a
+    /// precomposed `ë` too.
+    pub fn key(name: &str) -> String {
+        name.to_string().case_fold()
+    }
+
+    /// Find a name that looks like another one without being the same name
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: The new name.
+    /// * `names`: The names in use.
+    pub fn confusable<'a, I>(name: &str, names: I) -> Option<String>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let key = NameRules::key(name);
+        let skeleton = key.skeleton();
+
+        names
+            .into_iter()
+            .find(|other| {
+                NameRules::key(other) != key && NameRules::key(other).skeleton() == skeleton
+            })
+            .cloned()
+    }
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules::nickname()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn validates_nicknames() {
+        let rules = NameRules::nickname();
+
+        assert_eq!(rules.validate("Zoe\u{308}").unwrap(), "Zo\u{eb}");
+        assert!(rules.is_valid("alice"));
+        assert!(!rules.is_valid(""));
+        assert!(!rules.is_valid(&"a".repeat(33)));
+        assert!(!rules.is_valid("#alice"));
+        assert!(!rules.is_valid("al ice"));
+        assert!(!rules.is_valid("al{ice}"));
+        assert!(!rules.is_valid("al\u{200b}ice"));
+        assert!(!rules.is_valid("p\u{430}ypal"));
+        assert!(NameRules {
+            mixed_script: true,
+            ..NameRules::nickname()
+        }
+        .is_valid("p\u{430}ypal"));
+    }
+
+    #[test]
+    fn validates_channel_names() {
+        let rules = NameRules::channel();
+
+        assert!(rules.is_valid("#rust"));
+        assert!(!rules.is_valid("#"));
+        assert!(!rules.is_valid("rust"));
+        assert!(!rules.is_valid("#ru st"));
+    }
+
+    #[test]
+    fn keys_ignore_case_and_normalization() {
+        assert_eq!(NameRules::key("Alice"), NameRules::key("aLICE"));
+        assert_eq!(NameRules::key("Zoe\u{308}"), NameRules::key("ZO\u{cb}"));
+        assert_eq!(NameRules::key("#General"), NameRules::key("#general"));
+        assert_ne!(NameRules::key("alice"), NameRules::key("alicia"));
+    }
+
+    #[test]
+    fn finds_confusable_names() {
+        let taken = names(&["alice", "bob"]);
+
+        assert_eq!(
+            NameRules::confusable("\u{430}lice", &taken),
+            Some("alice".to_string())
+        );
+        assert_eq!(NameRules::confusable("ALICE", &taken), None);
+        assert_eq!(NameRules::confusable("carol", &taken), None);
+        assert_eq!(
+            NameRules::confusable("#ru\u{455}t", &names(&["#rust"])),
+            Some("#rust".to_string())
+        );
+    }
+}
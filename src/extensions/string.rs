@@ -1,8 +1,32 @@
 use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use rand::Rng;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+use unicode_security::{skeleton, MixedScript};
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Letters and digits
+pub const ALPHANUMERIC: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Characters that need no escaping in URLs and file names
+pub const URL_SAFE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Lowercase hexadecimal digits
+pub const HEX: &str = "0123456789abcdef";
+
+/// Crockford's base 32, the alphabet of `generate_id`
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Bits of randomness in an id from `generate_id`
+const ID_RANDOM_BITS: u32 = 80;
 
 /// Extension to built-in `String` type
 pub trait StringExtension {
-    /// Generate random alphanumeric string
+    /// Generate random alphanumeric string, not suitable for secrets, see `random_secure`
+    ///
+    /// A negative length gives an empty string.
     ///
     /// Example:
     /// ```rs
@@ -10,6 +34,44 @@ pub trait StringExtension {
     /// ```
     fn random(len: i64) -> String;
 
+    /// Generate random alphanumeric string from the operating system's secure generator
+    ///
+    /// Example:
+    /// ```rs
+    /// let token = String::random_secure(32);
+    /// ```
+    fn random_secure(len: usize) -> String;
+
+    /// Generate random string of characters from an alphabet, with the operating system's
+    /// secure generator
+    ///
+    /// Every character of the alphabet is equally likely, an empty alphabet gives an empty
+    /// string.
+    ///
+    /// Example:
+    /// ```rs
+    /// let pin = String::random_with(6, "0123456789");
+    /// ```
+    fn random_with(len: usize, alphabet: &str) -> String;
+
+    /// Generate random string safe to put in URLs, see `URL_SAFE`
+    fn random_url_safe(len: usize) -> String;
+
+    /// Generate random lowercase hexadecimal string
+    fn random_hex(len: usize) -> String;
+
+    /// Generate unique id that sorts by creation time
+    ///
+    /// Ids are ULIDs: 26 characters of Crockford's base 32 holding the time in milliseconds
+    /// and 80 random bits. Ids generated by one process are strictly increasing, even within
+    /// the same millisecond.
+    ///
+    /// Example:
+    /// ```rs
+    /// let id = String::generate_id(); // 01HF8Z3K1V9Q4M2X7B5N6C8D0E
+    /// ```
+    fn generate_id() -> String;
+
     /// Generate random alphanumeric string
     ///
     /// Example:
@@ -22,12 +84,93 @@ pub trait StringExtension {
     fn to_str(&mut self) -> &str;
 
     /// Convert `String` to `&'static str`
+    ///
+    /// The string is leaked on every call, `Interner::intern` shares strings without leaking.
+    #[deprecated(note = "leaks memory on every call, use `Interner::intern`")]
     fn to_static_str(&mut self) -> &'static str;
+
+    /// Normalize to NFC, so characters typed precomposed or with combining marks compare equal
+    fn nfc(&self) -> String;
+
+    /// Check whether the string is in NFC
+    fn is_nfc(&self) -> bool;
+
+    /// Fold case with Unicode's full case folding, after normalizing to NFC
+    ///
+    /// Example:
+    /// ```rs
+    /// assert_eq!("Straße".to_string().case_fold(), "strasse");
+    /// ```
+    fn case_fold(&self) -> String;
+
+    /// Compare ignoring case and normalization
+    fn eq_ignore_case(&self, other: &str) -> bool;
+
+    /// Get the confusable skeleton of Unicode's security mechanisms (UTS #39), strings that
+    /// look alike share it
+    fn skeleton(&self) -> String;
+
+    /// Check whether two different strings look alike, e.g. `paypal` with a Cyrillic `а`
+    fn is_confusable_with(&self, other: &str) -> bool;
+
+    /// Check whether the string uses a single script, digits and punctuation go with any
+    fn is_single_script(&self) -> bool;
 }
 
 impl StringExtension for String {
     fn random(len: i64) -> String {
-        Alphanumeric.sample_string(&mut rand::thread_rng(), len.try_into().unwrap())
+        Alphanumeric.sample_string(&mut rand::thread_rng(), len.max(0) as usize)
+    }
+
+    fn random_secure(len: usize) -> String {
+        String::random_with(len, ALPHANUMERIC)
+    }
+
+    fn random_with(len: usize, alphabet: &str) -> String {
+        let alphabet: Vec<char> = alphabet.chars().collect();
+
+        if alphabet.is_empty() {
+            return String::new();
+        }
+
+        (0..len)
+            .map(|_| alphabet[OsRng.gen_range(0..alphabet.len())])
+            .collect()
+    }
+
+    fn random_url_safe(len: usize) -> String {
+        String::random_with(len, URL_SAFE)
+    }
+
+    fn random_hex(len: usize) -> String {
+        String::random_with(len, HEX)
+    }
+
+    fn generate_id() -> String {
+        // Time and random part of the last id, the next one in the same millisecond is one more
+        static LAST: Mutex<(u64, u128)> = Mutex::new((0, 0));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let mask = (1u128 << ID_RANDOM_BITS) - 1;
+        let mut last = LAST.lock().unwrap();
+
+        // A clock that went back keeps the last time, so ids stay increasing
+        *last = if now > last.0 {
+            (now, OsRng.gen::<u128>() & mask)
+        } else if last.1 < mask {
+            (last.0, last.1 + 1)
+        } else {
+            (last.0 + 1, OsRng.gen::<u128>() & mask)
+        };
+
+        let value = (last.0 as u128) << ID_RANDOM_BITS | last.1;
+
+        (0..26)
+            .map(|i| CROCKFORD[(value >> (125 - i * 5) & 31) as usize] as char)
+            .collect()
     }
 
     fn baka_split(&mut self, delim: &str) -> Vec<String> {
@@ -46,4 +189,92 @@ impl StringExtension for String {
     fn to_static_str(&mut self) -> &'static str {
         Box::leak(self.clone().into_boxed_str())
     }
+
+    fn nfc(&self) -> String {
+        UnicodeNormalization::nfc(self.as_str()).collect()
+    }
+
+    fn is_nfc(&self) -> bool {
+        is_nfc(self)
+    }
+
+    fn case_fold(&self) -> String {
+        StringExtension::nfc(&caseless::default_case_fold_str(&StringExtension::nfc(
+            self,
+        )))
+    }
+
+    fn eq_ignore_case(&self, other: &str) -> bool {
+        self.case_fold() == other.to_string().case_fold()
+    }
+
+    fn skeleton(&self) -> String {
+        skeleton(self).collect()
+    }
+
+    fn is_confusable_with(&self, other: &str) -> bool {
+        self != other && self.skeleton() == other.to_string().skeleton()
+    }
+
+    fn is_single_script(&self) -> bool {
+        MixedScript::is_single_script(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Milliseconds since the Unix epoch held by an id
+    fn time_of(id: &str) -> u64 {
+        id[..10].bytes().fold(0, |time, c| {
+            time << 5 | CROCKFORD.iter().position(|d| *d == c).unwrap() as u64
+        })
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[test]
+    fn ids_are_ulids() {
+        let id = String::generate_id();
+
+        assert_eq!(id.len(), 26);
+        assert!(id.bytes().all(|c| CROCKFORD.contains(&c)));
+        assert!(id.as_bytes()[0] <= b'7');
+    }
+
+    #[test]
+    fn ids_hold_their_creation_time() {
+        let before = now();
+        let id = String::generate_id();
+        let after = now();
+
+        assert!(time_of(&id) >= before);
+        assert!(time_of(&id) <= after);
+    }
+
+    #[test]
+    fn ids_increase_within_a_millisecond() {
+        let ids: Vec<String> = (0..1000).map(|_| String::generate_id()).collect();
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn random_strings_use_their_alphabet() {
+        assert_eq!(String::random_secure(32).len(), 32);
+        assert!(String::random_hex(64)
+            .bytes()
+            .all(|c| HEX.contains(c as char)));
+        assert!(String::random_url_safe(64)
+            .bytes()
+            .all(|c| URL_SAFE.contains(c as char)));
+        assert_eq!(String::random_with(6, ""), "");
+        assert_eq!(String::random(-1), "");
+    }
 }
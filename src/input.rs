@@ -1,13 +1,669 @@
-use std::io;
-use std::io::*;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, style, terminal};
 
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Default number of lines an `Input` remembers
+pub const DEFAULT_INPUT_HISTORY: usize = 500;
+
+/// Completion callback registered with `Input::completer`
+///
+/// Receives the line up to the cursor and returns candidates for its last word.
+pub type BoxCompleter = Box<dyn Fn(&str) -> Vec<String> + Send + 'static>;
+
+/// ## Edit
+///
+/// What a key pressed in a `LineEditor` did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edit {
+    /// The line or the cursor may have moved
+    Changed,
+    /// Enter was pressed, the line is returned and the editor starts a new one
+    Submit(String),
+    /// Ctrl-D on an empty line
+    Eof,
+    /// Ctrl-C
+    Interrupt,
+    /// Tab was pressed again while several candidates share no longer prefix
+    Candidates(Vec<String>),
+}
+
+/// ## LineEditor
+///
+/// The editing, history and tab completion behind `Input`, for programs that draw the line
+/// themselves, e.g. a full-screen terminal interface.
+///
+/// Example:
+/// ```rs
+/// let mut editor = LineEditor::new();
+///
+/// if let Edit::Submit(line) = editor.key(key) {
+///     editor.remember(&line);
+/// }
+/// ```
+///
+/// Properties:
+///
+/// * `chars`: The line being edited.
+/// * `cursor`: Cursor position in characters.
+/// * `history`: Lines entered before, oldest first.
+/// * `history_size`: How many lines `history` keeps.
+/// * `browsing`: Position in `history` while browsing it with Up and Down.
+/// * `tabbed`: Whether the last key was Tab, a second one lists the candidates.
+/// * `completer`: Called when Tab is pressed.
+pub struct LineEditor {
+    chars: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    history_size: usize,
+    browsing: Option<usize>,
+    tabbed: bool,
+    completer: Option<BoxCompleter>,
+}
+
+/// ## Input
+///
+/// Line prompt with editing, history and tab completion.
+///
+/// On a terminal the line can be edited with the arrow keys, Home, End, Backspace, Delete,
+/// Ctrl-A, Ctrl-E, Ctrl-U, Ctrl-K and Ctrl-W, Up and Down browse the history and Tab completes
+/// the word before the cursor, see `LineEditor`. When standard input is not a terminal lines
+/// are read as they come, invalid UTF-8 is replaced with U+FFFD.
+///
+/// Example:
+/// ```rs
+/// let mut input = Input::new("> ");
+/// input.history_file(".baka_history")?;
+/// input.completer(Box::new(|line: &str| {
+///     ["/join", "/part", "/quit"]
+///         .iter()
+///         .filter(|c| c.starts_with(line))
+///         .map(|c| c.to_string())
+///         .collect()
+/// }));
+///
+/// while let Some(line) = input.read_line()? {
+///     println!("{}", line);
+/// }
+/// ```
+///
+/// Properties:
+///
+/// * `prompt`: Written before the line.
+/// * `editor`: The line being edited and the history.
+/// * `history_path`: File the history is saved to, `None` to keep it in memory.
+pub struct Input {
+    prompt: String,
+    editor: LineEditor,
+    history_path: Option<PathBuf>,
+}
+
+/// Turns raw mode off again when the line is done, however it ends
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        LineEditor::new()
+    }
+}
+
+impl LineEditor {
+    /// Initialize new instance of the `LineEditor` with an empty line and history
+    pub fn new() -> Self {
+        LineEditor {
+            chars: vec![],
+            cursor: 0,
+            history: vec![],
+            history_size: DEFAULT_INPUT_HISTORY,
+            browsing: None,
+            tabbed: false,
+            completer: None,
+        }
+    }
+
+    /// Get the line being edited
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Get the cursor position in characters
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Replace the line, moving the cursor to its end
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    /// Get the lines entered so far, oldest first
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Replace the history, keeping the newest lines that fit
+    ///
+    /// Arguments:
+    ///
+    /// * `history`: The lines, oldest first.
+    pub fn set_history(&mut self, history: Vec<String>) {
+        self.history = history;
+        self.browsing = None;
+        self.trim_history();
+    }
+
+    /// Set how many lines the history keeps, `DEFAULT_INPUT_HISTORY` by default
+    pub fn history_size(&mut self, size: usize) {
+        self.history_size = size;
+        self.trim_history();
+    }
+
+    /// Set the tab completion callback
+    ///
+    /// Arguments:
+    ///
+    /// * `completer`: Receives the line up to the cursor and returns candidates for its last
+    ///   word. A single candidate replaces the word, several are completed to their common
+    ///   prefix and returned as `Edit::Candidates` when Tab is pressed again.
+    pub fn completer(&mut self, completer: BoxCompleter) {
+        self.completer = Some(completer);
+    }
+
+    /// Add a line to the history
+    ///
+    /// Returns `false` for blank lines and repeats of the newest line, which are not added.
+    ///
+    /// Arguments:
+    ///
+    /// * `line`: The line, usually one returned by `Edit::Submit`.
+    pub fn remember(&mut self, line: &str) -> bool {
+        if line.trim().is_empty() || self.history.last().map(|l| l.as_str()) == Some(line) {
+            return false;
+        }
+
+        self.history.push(line.to_string());
+        self.trim_history();
+
+        true
+    }
+
+    /// Insert pasted text at the cursor, dropping control characters
+    pub fn paste(&mut self, text: &str) {
+        for c in text.chars().filter(|c| !c.is_control()) {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    /// Apply a key press
+    ///
+    /// Arguments:
+    ///
+    /// * `key`: The key, releases are ignored.
+    pub fn key(&mut self, key: KeyEvent) -> Edit {
+        let KeyEvent {
+            code,
+            modifiers,
+            kind,
+            ..
+        } = key;
+        let control = modifiers.contains(KeyModifiers::CONTROL);
+
+        if kind == KeyEventKind::Release {
+            return Edit::Changed;
+        }
+
+        if code != KeyCode::Tab {
+            self.tabbed = false;
+        }
+
+        match code {
+            KeyCode::Enter => {
+                let line = self.text();
+
+                self.set("");
+                self.browsing = None;
+
+                return Edit::Submit(line);
+            }
+            KeyCode::Char('c') if control => return Edit::Interrupt,
+            KeyCode::Char('d') if control => {
+                if self.chars.is_empty() {
+                    return Edit::Eof;
+                }
+
+                if self.cursor < self.chars.len() {
+                    self.chars.remove(self.cursor);
+                }
+            }
+            KeyCode::Char('a') if control => self.cursor = 0,
+            KeyCode::Char('e') if control => self.cursor = self.chars.len(),
+            KeyCode::Char('u') if control => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('k') if control => self.chars.truncate(self.cursor),
+            KeyCode::Char('w') if control => {
+                let start = self.word_start();
+
+                self.chars.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            KeyCode::Char(_) if control => {}
+            KeyCode::Char(c) => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            KeyCode::Up => {
+                let index = match self.browsing {
+                    Some(i) => i.saturating_sub(1),
+                    None if self.history.is_empty() => return Edit::Changed,
+                    None => self.history.len() - 1,
+                };
+
+                let line = self.history[index].clone();
+
+                self.browsing = Some(index);
+                self.set(&line);
+            }
+            KeyCode::Down => match self.browsing {
+                Some(i) if i + 1 < self.history.len() => {
+                    let line = self.history[i + 1].clone();
+
+                    self.browsing = Some(i + 1);
+                    self.set(&line);
+                }
+                Some(_) => {
+                    self.browsing = None;
+                    self.set("");
+                }
+                None => {}
+            },
+            KeyCode::Tab => {
+                let list = self.tabbed;
+
+                self.tabbed = true;
+
+                if let Some(candidates) = self.complete(list) {
+                    return Edit::Candidates(candidates);
+                }
+            }
+            _ => {}
+        }
+
+        Edit::Changed
+    }
+
+    /// Start of the word before the cursor
+    fn word_start(&self) -> usize {
+        let mut start = self.cursor;
+
+        while start > 0 && !self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+
+        start
+    }
+
+    /// Complete the word before the cursor, returning the candidates on a second Tab
+    fn complete(&mut self, list: bool) -> Option<Vec<String>> {
+        let completer = self.completer.as_ref()?;
+        let before: String = self.chars[..self.cursor].iter().collect();
+        let candidates = completer(&before);
+        let start = self.word_start();
+        let word = self.cursor - start;
+        let mut listed = None;
+
+        let completion = match candidates.as_slice() {
+            [] => return None,
+            [candidate] => format!("{} ", candidate),
+            [first, rest @ ..] => {
+                let prefix: String = rest.iter().fold(first.clone(), |prefix, candidate| {
+                    prefix
+                        .chars()
+                        .zip(candidate.chars())
+                        .take_while(|(a, b)| a == b)
+                        .map(|(a, _)| a)
+                        .collect()
+                });
+
+                if list && prefix.chars().count() <= word {
+                    listed = Some(candidates.clone());
+                }
+
+                prefix
+            }
+        };
+
+        if completion.chars().count() >= word {
+            self.chars.splice(start..self.cursor, completion.chars());
+            self.cursor = start + completion.chars().count();
+        }
+
+        listed
+    }
+
+    fn trim_history(&mut self) {
+        if self.history.len() > self.history_size {
+            let excess = self.history.len() - self.history_size;
+
+            self.history.drain(..excess);
+        }
+    }
+}
+
+impl Input {
+    /// Initialize new instance of the `Input`
+    ///
+    /// Arguments:
+    ///
+    /// * `prompt`: Written before the line.
+    pub fn new(prompt: &str) -> Self {
+        Input {
+            prompt: prompt.to_string(),
+            editor: LineEditor::new(),
+            history_path: None,
+        }
+    }
+
+    /// Change the prompt
+    pub fn prompt(&mut self, prompt: &str) {
+        self.prompt = prompt.to_string();
+    }
+
+    /// Load the history from a file and save every new line to it
+    ///
+    /// A missing file is created once the first line is entered.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The history file, one line per entry.
+    pub fn history_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+
+        if path.exists() {
+            let contents = fs::read(&path)?;
+
+            self.editor.set_history(
+                String::from_utf8_lossy(&contents)
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.to_string())
+                    .collect(),
+            );
+        }
+
+        self.history_path = Some(path);
+
+        Ok(())
+    }
+
+    /// Set how many lines the history keeps, `DEFAULT_INPUT_HISTORY` by default
+    pub fn history_size(&mut self, size: usize) {
+        self.editor.history_size(size);
+    }
+
+    /// Get the lines entered so far, oldest first
+    pub fn history(&self) -> &[String] {
+        self.editor.history()
+    }
+
+    /// Set the tab completion callback
+    ///
+    /// Arguments:
+    ///
+    /// * `completer`: Receives the line up to the cursor and returns candidates for its last
+    ///   word. A single candidate replaces the word, several are completed to their common
+    ///   prefix and listed when Tab is pressed again.
+    pub fn completer(&mut self, completer: BoxCompleter) {
+        self.editor.completer(completer);
+    }
+
+    /// Prompt for a line
+    ///
+    /// Returns `Ok(None)` at the end of input: Ctrl-D on an empty line or a closed standard
+    /// input. Ctrl-C returns an `io::ErrorKind::Interrupted` error.
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let line = if io::stdin().is_terminal() {
+            self.read_terminal()?
+        } else {
+            self.read_plain()?
+        };
+
+        if let Some(line) = &line {
+            self.remember(line)?;
+        }
+
+        Ok(line)
+    }
+
+    fn read_plain(&self) -> io::Result<Option<String>> {
+        print!("{}", self.prompt);
+        io::stdout().flush()?;
+
+        let mut buffer = vec![];
+
+        if io::stdin().lock().read_until(b'\n', &mut buffer)? == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            String::from_utf8_lossy(&buffer).trim_end().to_string(),
+        ))
+    }
+
+    fn read_terminal(&mut self) -> io::Result<Option<String>> {
+        let _raw = RawMode::enable()?;
+        let mut stdout = io::stdout();
+
+        self.editor.set("");
+        self.render(&mut stdout)?;
+
+        loop {
+            let edit = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => self.editor.key(key),
+                Event::Paste(text) => {
+                    self.editor.paste(&text);
+                    Edit::Changed
+                }
+                _ => continue,
+            };
+
+            match edit {
+                Edit::Submit(line) => {
+                    queue!(stdout, style::Print("\r\n"))?;
+                    stdout.flush()?;
+
+                    return Ok(Some(line));
+                }
+                Edit::Interrupt => {
+                    queue!(stdout, style::Print("^C\r\n"))?;
+                    stdout.flush()?;
+
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "Interrupted"));
+                }
+                Edit::Eof => {
+                    queue!(stdout, style::Print("\r\n"))?;
+                    stdout.flush()?;
+
+                    return Ok(None);
+                }
+                Edit::Candidates(candidates) => {
+                    queue!(
+                        stdout,
+                        style::Print("\r\n"),
+                        style::Print(candidates.join("  ")),
+                        style::Print("\r\n")
+                    )?;
+                }
+                Edit::Changed => {}
+            }
+
+            self.render(&mut stdout)?;
+        }
+    }
+
+    fn render(&self, stdout: &mut io::Stdout) -> io::Result<()> {
+        let column = self.prompt.chars().count() + self.editor.cursor();
+
+        queue!(
+            stdout,
+            cursor::MoveToColumn(0),
+            style::Print(&self.prompt),
+            style::Print(self.editor.text()),
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+            cursor::MoveToColumn(column.min(u16::MAX as usize) as u16)
+        )?;
+
+        stdout.flush()
+    }
+
+    /// Add a line to the history and save it
+    fn remember(&mut self, line: &str) -> io::Result<()> {
+        if !self.editor.remember(line) {
+            return Ok(());
+        }
+
+        match &self.history_path {
+            Some(path) => {
+                let mut contents = self.editor.history().join("\n");
+
+                contents.push('\n');
+                fs::write(path, contents)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Prompt for a line, see `Input` for editing and history
+///
+/// Returns an `io::ErrorKind::UnexpectedEof` error at the end of input.
+///
+/// Arguments:
+///
+/// * `prompt`: Written before the line.
 pub fn input(prompt: &str) -> io::Result<String> {
-    print!("{}", prompt);
-    io::stdout().flush()?;
-    io::stdin()
-        .lock()
-        .lines()
-        .next()
-        .unwrap()
-        .map(|x| x.trim_end().to_owned())
+    Input::new(prompt)
+        .read_line()?
+        .map(|line| line.trim_end().to_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "End of input"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(editor: &mut LineEditor, code: KeyCode) -> Edit {
+        editor.key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn control(editor: &mut LineEditor, c: char) -> Edit {
+        editor.key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL))
+    }
+
+    fn typed(text: &str) -> LineEditor {
+        let mut editor = LineEditor::new();
+
+        for c in text.chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+
+        editor
+    }
+
+    #[test]
+    fn edits_at_the_cursor() {
+        let mut editor = typed("hello world");
+
+        control(&mut editor, 'w');
+        assert_eq!(editor.text(), "hello ");
+
+        press(&mut editor, KeyCode::Home);
+        press(&mut editor, KeyCode::Delete);
+        press(&mut editor, KeyCode::Char('j'));
+        assert_eq!((editor.text().as_str(), editor.cursor()), ("jello ", 1));
+
+        control(&mut editor, 'k');
+        assert_eq!(
+            press(&mut editor, KeyCode::Enter),
+            Edit::Submit("j".to_string())
+        );
+        assert_eq!(editor.text(), "");
+        assert_eq!(control(&mut editor, 'd'), Edit::Eof);
+    }
+
+    #[test]
+    fn browses_the_history() {
+        let mut editor = LineEditor::new();
+
+        editor.history_size(2);
+
+        for line in ["one", "two", "two", "three", " "] {
+            editor.remember(line);
+        }
+
+        assert_eq!(editor.history(), ["two", "three"]);
+
+        press(&mut editor, KeyCode::Up);
+        press(&mut editor, KeyCode::Up);
+        assert_eq!(editor.text(), "two");
+
+        press(&mut editor, KeyCode::Down);
+        assert_eq!(editor.text(), "three");
+
+        press(&mut editor, KeyCode::Down);
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn completes_the_word_before_the_cursor() {
+        let mut editor = typed("/j");
+
+        editor.completer(Box::new(|line: &str| {
+            ["/join", "/jump", "/part"]
+                .iter()
+                .filter(|c| c.starts_with(line))
+                .map(|c| c.to_string())
+                .collect()
+        }));
+
+        assert_eq!(press(&mut editor, KeyCode::Tab), Edit::Changed);
+        assert_eq!(editor.text(), "/j");
+        assert_eq!(
+            press(&mut editor, KeyCode::Tab),
+            Edit::Candidates(vec!["/join".to_string(), "/jump".to_string()])
+        );
+
+        press(&mut editor, KeyCode::Char('o'));
+        press(&mut editor, KeyCode::Tab);
+        assert_eq!(editor.text(), "/join ");
+    }
 }
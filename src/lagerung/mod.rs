@@ -1,19 +1,305 @@
+use crate::socket::Error;
+
 use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
+/// ## Lagerung
+///
+/// Simple key-value storage, optionally backed by a file.
+///
+/// The file holds one `key<TAB>value` pair per line, with backslashes, tabs and newlines
+/// escaped. `append` and `delete` add a line to the end of the file instead of rewriting it,
+/// later lines win and a line holding only a key removes it. `save` rewrites the file with
+/// just the current pairs.
+///
+/// Properties:
+///
+/// * `kv`: The stored pairs.
+/// * `path`: The backing file, `None` for in-memory storage.
+/// * `appended`: Lines appended since the file was last read or rewritten.
 pub struct Lagerung {
     kv: HashMap<String, String>,
+    path: Option<PathBuf>,
+    appended: usize,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+
+    out
 }
 
 impl Lagerung {
+    /// Initialize new in-memory instance of the `Lagerung`
+    pub fn new() -> Self {
+        Lagerung {
+            kv: HashMap::new(),
+            path: None,
+            appended: 0,
+        }
+    }
+
+    /// Open a file-backed `Lagerung`, the file is created on the first `save`
+    ///
+    /// Example:
+    /// ```rs
+    /// let mut storage = Lagerung::open("server.db")?;
+    /// storage.add("motd", "Hello!");
+    /// storage.save()?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The backing file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut kv = HashMap::new();
+
+        if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| Error::new(&format!("Unable to read {}: {}", path.display(), e)))?;
+
+            for line in contents.lines().filter(|line| !line.is_empty()) {
+                match line.split_once('\t') {
+                    Some((key, value)) => kv.insert(unescape(key), unescape(value)),
+                    None => kv.remove(&unescape(line)),
+                };
+            }
+        }
+
+        Ok(Lagerung {
+            kv: kv,
+            path: Some(path),
+            appended: 0,
+        })
+    }
+
+    /// Write the pairs to the backing file, does nothing for in-memory storage
+    pub fn save(&mut self) -> Result<(), Error> {
+        self.appended = 0;
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut keys: Vec<&String> = self.kv.keys().collect();
+        keys.sort();
+
+        let contents: String = keys
+            .into_iter()
+            .map(|key| format!("{}\t{}\n", escape(key), escape(&self.kv[key])))
+            .collect();
+
+        let temp = path.with_extension("tmp");
+
+        fs::write(&temp, contents)
+            .and_then(|_| fs::rename(&temp, path))
+            .map_err(|e| Error::new(&format!("Unable to write {}: {}", path.display(), e)))
+    }
+
+    /// Store a pair and append it to the backing file, without rewriting the file. Nothing is
+    /// stored when the file cannot be written.
+    ///
+    /// Example:
+    /// ```rs
+    /// storage.append("history:#rust:01HF8Z3K1V9Q4M2X7B5N6C8D0E", &encoded)?;
+    /// ```
+    pub fn append(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        self.write_line(&format!("{}\t{}\n", escape(key), escape(value)))?;
+        self.add(key, value);
+
+        Ok(())
+    }
+
+    /// Remove a pair and append the removal to the backing file, without rewriting the file.
+    /// Nothing is removed when the file cannot be written.
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.write_line(&format!("{}\n", escape(key)))?;
+        self.remove(key);
+
+        Ok(())
+    }
+
+    /// Number of lines `append` and `delete` added since the file was read or rewritten,
+    /// compare with `len` to decide when a `save` is worth it
+    pub fn appended(&self) -> usize {
+        self.appended
+    }
+
+    /// Number of stored pairs
+    pub fn len(&self) -> usize {
+        self.kv.len()
+    }
+
+    /// Check whether nothing is stored
+    pub fn is_empty(&self) -> bool {
+        self.kv.is_empty()
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| Error::new(&format!("Unable to write {}: {}", path.display(), e)))?;
+
+        self.appended += 1;
+
+        Ok(())
+    }
+
     pub fn has(&mut self, key: &str) -> bool {
-        self.kv.contains_key(&key.to_string)
+        self.kv.contains_key(&key.to_string())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.kv.get(key).map(|value| value.as_str())
     }
 
     pub fn add(&mut self, key: &str, value: &str) {
-        self.kv.insert(key.to_string, value.to_string());
+        self.kv.insert(key.to_string(), value.to_string());
     }
 
     pub fn remove(&mut self, key: &str) {
-        self.kv.remove(&key.to_string);
+        self.kv.remove(&key.to_string());
+    }
+
+    /// Get every key starting with the prefix
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        self.kv
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for Lagerung {
+    fn default() -> Self {
+        Lagerung::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::string::StringExtension;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("baka-lagerung-{}.db", String::generate_id()))
+    }
+
+    const AWKWARD: [(&str, &str); 4] = [
+        ("tab\tkey", "tab\tvalue"),
+        ("newline\nkey", "newline\nvalue"),
+        ("back\\slash\\", "\\back\\tslash\\n"),
+        ("mixed\\\t\n", "\n\t\\"),
+    ];
+
+    #[test]
+    fn save_round_trips_escaped_pairs() {
+        let path = temp_path();
+        let mut storage = Lagerung::open(&path).unwrap();
+
+        for (key, value) in AWKWARD {
+            storage.add(key, value);
+        }
+        storage.save().unwrap();
+
+        let reopened = Lagerung::open(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(reopened.len(), AWKWARD.len());
+        for (key, value) in AWKWARD {
+            assert_eq!(reopened.get(key), Some(value));
+        }
+    }
+
+    #[test]
+    fn append_round_trips_escaped_pairs() {
+        let path = temp_path();
+        let mut storage = Lagerung::open(&path).unwrap();
+
+        for (key, value) in AWKWARD {
+            storage.append(key, value).unwrap();
+        }
+
+        let reopened = Lagerung::open(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(reopened.len(), AWKWARD.len());
+        for (key, value) in AWKWARD {
+            assert_eq!(reopened.get(key), Some(value));
+        }
+    }
+
+    #[test]
+    fn append_then_delete_survives_reopening() {
+        let path = temp_path();
+        let mut storage = Lagerung::open(&path).unwrap();
+
+        storage.append("kept", "1").unwrap();
+        storage.append("deleted\tkey", "2").unwrap();
+        storage.append("kept", "3").unwrap();
+        storage.delete("deleted\tkey").unwrap();
+
+        assert_eq!(storage.appended(), 4);
+
+        let mut reopened = Lagerung::open(&path).unwrap();
+
+        assert_eq!(reopened.get("kept"), Some("3"));
+        assert!(!reopened.has("deleted\tkey"));
+        assert_eq!(reopened.appended(), 0);
+
+        reopened.save().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(contents, "kept\t3\n");
+    }
+
+    #[test]
+    fn failed_writes_leave_memory_unchanged() {
+        let path = temp_path().join("missing").join("storage.db");
+        let mut storage = Lagerung::open(&path).unwrap();
+
+        assert!(storage.append("key", "value").is_err());
+        assert!(!storage.has("key"));
+        assert_eq!(storage.appended(), 0);
+
+        storage.add("key", "value");
+
+        assert!(storage.delete("key").is_err());
+        assert_eq!(storage.get("key"), Some("value"));
     }
 }
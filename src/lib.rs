@@ -52,16 +52,22 @@
     c_variadic
 )]
 
+pub mod auth;
 pub mod command;
+pub mod config;
 pub mod extensions;
 pub mod io;
 pub mod lagerung;
+pub mod middleware;
 pub mod protoutils;
 pub mod socket;
 pub mod utils;
 
+mod clock;
 mod input;
 mod timeout;
+mod trace;
 
+pub use clock::*;
 pub use input::*;
 pub use timeout::*;
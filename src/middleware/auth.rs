@@ -0,0 +1,47 @@
+use crate::middleware::{refusal, Action, Middleware, Peer};
+
+use bakaproto::proto::*;
+
+/// ## RequireLogin
+///
+/// Refuses messages from clients that did not log in, replying `AUTH_REQUIRED :reason`, or
+/// from users that are not listed, replying `AUTH_FAILED :reason`.
+///
+/// Properties:
+///
+/// * `users`: Users allowed to send messages, any logged-in user when empty.
+pub struct RequireLogin {
+    users: Vec<String>,
+}
+
+impl RequireLogin {
+    /// Initialize new instance of the `RequireLogin` accepting any logged-in user
+    pub fn new() -> Self {
+        RequireLogin { users: vec![] }
+    }
+
+    /// Initialize new instance of the `RequireLogin` accepting only the listed users
+    pub fn only(users: &[&str]) -> Self {
+        RequireLogin {
+            users: users.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for RequireLogin {
+    fn default() -> Self {
+        RequireLogin::new()
+    }
+}
+
+impl Middleware for RequireLogin {
+    fn inbound(&self, peer: &Peer, message: message::Message) -> Action {
+        match &peer.user {
+            Some(user) if self.users.is_empty() || self.users.contains(user) => {
+                Action::Continue(message)
+            }
+            Some(_) => Action::ShortCircuit(refusal("AUTH_FAILED", "Permission denied")),
+            None => Action::ShortCircuit(refusal("AUTH_REQUIRED", "Login required")),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::middleware::{Action, Middleware, Peer};
+use crate::trace::event;
+
+use bakaproto::proto::*;
+
+/// ## Logging
+///
+/// Emits an `info` event for every message passing the pipeline. Events are only emitted with
+/// the `tracing` feature, and go wherever the application's subscriber sends them.
+///
+/// Properties:
+///
+/// * `prefix`: Recorded with every event, e.g. the server's name.
+pub struct Logging {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    prefix: String,
+}
+
+impl Logging {
+    /// Initialize new instance of the `Logging`
+    pub fn new(prefix: &str) -> Self {
+        Logging {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn log(&self, direction: &str, peer: &Peer, message: &message::Message) {
+        event!(
+            info,
+            prefix = %self.prefix,
+            direction = %direction,
+            peer = %peer.nick.as_deref().unwrap_or(&peer.address),
+            author = %message.author,
+            content = %message.content,
+            "message"
+        );
+    }
+}
+
+impl Middleware for Logging {
+    fn inbound(&self, peer: &Peer, message: message::Message) -> Action {
+        self.log("<-", peer, &message);
+        Action::Continue(message)
+    }
+
+    fn outbound(&self, peer: &Peer, message: message::Message) -> Action {
+        self.log("->", peer, &message);
+        Action::Continue(message)
+    }
+}
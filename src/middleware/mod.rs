@@ -0,0 +1,180 @@
+mod auth;
+mod logging;
+mod profanity;
+mod rate_limit;
+
+pub use auth::*;
+pub use logging::*;
+pub use profanity::*;
+pub use rate_limit::*;
+
+use crate::protoutils::{BakaMessage, MessageKind};
+
+use bakaproto::proto::*;
+
+use std::sync::Arc;
+
+/// ## Peer
+///
+/// The other side of the connection a message travels on.
+///
+/// Properties:
+///
+/// * `address`: The peer's address.
+/// * `nick`: The client's nickname, `None` where it is not known, e.g. on a `SocketBuilder`.
+/// * `user`: The user the client logged in as, `None` if it did not log in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub address: String,
+    pub nick: Option<String>,
+    pub user: Option<String>,
+}
+
+impl Peer {
+    /// Initialize new instance of the `Peer` knowing only its address
+    pub fn new(address: &str) -> Self {
+        Peer {
+            address: address.to_string(),
+            nick: None,
+            user: None,
+        }
+    }
+}
+
+/// ## Action
+///
+/// What a `Middleware` layer decided about a message.
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Pass the message, possibly transformed, on to the next layer
+    Continue(message::Message),
+    /// Discard the message silently
+    Drop,
+    /// Skip the remaining layers. An inbound message is not handled and this message is sent
+    /// back to the peer instead; an outbound message is sent as this message.
+    ShortCircuit(message::Message),
+}
+
+/// ## Middleware
+///
+/// A layer that inspects messages travelling between a server and a client. Both methods pass
+/// the message on unchanged unless overridden.
+///
+/// Example:
+/// ```rs
+/// struct Shout;
+///
+/// impl Middleware for Shout {
+///     fn inbound(&self, _: &Peer, mut message: message::Message) -> Action {
+///         message.content = message.content.to_uppercase();
+///         Action::Continue(message)
+///     }
+/// }
+///
+/// server.middleware(Shout);
+/// ```
+pub trait Middleware: core::marker::Send + Sync {
+    /// Called for every message received from the peer
+    fn inbound(&self, _peer: &Peer, message: message::Message) -> Action {
+        Action::Continue(message)
+    }
+
+    /// Called for every message sent to the peer
+    fn outbound(&self, _peer: &Peer, message: message::Message) -> Action {
+        Action::Continue(message)
+    }
+}
+
+/// A shared layer, so the caller can keep a handle to it, e.g. to change a `RateLimit`
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn inbound(&self, peer: &Peer, message: message::Message) -> Action {
+        (**self).inbound(peer, message)
+    }
+
+    fn outbound(&self, peer: &Peer, message: message::Message) -> Action {
+        (**self).outbound(peer, message)
+    }
+}
+
+/// ## Pipeline
+///
+/// Ordered chain of `Middleware` layers. Inbound messages pass the layers in the order they
+/// were added, outbound messages in reverse order.
+///
+/// Properties:
+///
+/// * `layers`: The layers, outermost first.
+pub struct Pipeline {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl Clone for Pipeline {
+    fn clone(&self) -> Self {
+        Pipeline {
+            layers: self.layers.clone(),
+        }
+    }
+}
+
+impl Pipeline {
+    /// Initialize new empty instance of the `Pipeline`
+    pub fn new() -> Self {
+        Pipeline { layers: vec![] }
+    }
+
+    /// Add a layer after the existing ones
+    pub fn push<M: Middleware + 'static>(&mut self, layer: M) {
+        self.layers.push(Arc::new(layer));
+    }
+
+    /// Check whether the pipeline has no layers
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Run a message received from the peer through the layers
+    pub fn inbound(&self, peer: &Peer, message: message::Message) -> Action {
+        Pipeline::run(
+            self.layers.iter(),
+            |layer, message| layer.inbound(peer, message),
+            message,
+        )
+    }
+
+    /// Run a message about to be sent to the peer through the layers
+    pub fn outbound(&self, peer: &Peer, message: message::Message) -> Action {
+        Pipeline::run(
+            self.layers.iter().rev(),
+            |layer, message| layer.outbound(peer, message),
+            message,
+        )
+    }
+
+    fn run<'a, I, F>(layers: I, call: F, message: message::Message) -> Action
+    where
+        I: Iterator<Item = &'a Arc<dyn Middleware>>,
+        F: Fn(&Arc<dyn Middleware>, message::Message) -> Action,
+    {
+        let mut message = message;
+
+        for layer in layers {
+            match call(layer, message) {
+                Action::Continue(next) => message = next,
+                action => return action,
+            }
+        }
+
+        Action::Continue(message)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+/// Error message a layer sends back to a peer, in the form `CODE :reason`
+pub(crate) fn refusal(code: &str, reason: &str) -> message::Message {
+    BakaMessage::new(MessageKind::Error, "", &format!("{} :{}", code, reason)).build()
+}
@@ -0,0 +1,61 @@
+use crate::middleware::{Action, Middleware, Peer};
+
+use bakaproto::proto::*;
+
+/// ## ProfanityFilter
+///
+/// Masks listed words in messages received from clients, matching whole words regardless of
+/// case.
+///
+/// Example:
+/// ```rs
+/// server.middleware(ProfanityFilter::new(&["heck", "darn"]));
+/// ```
+///
+/// Properties:
+///
+/// * `words`: The lowercased words to mask.
+pub struct ProfanityFilter {
+    words: Vec<String>,
+}
+
+impl ProfanityFilter {
+    /// Initialize new instance of the `ProfanityFilter`
+    pub fn new(words: &[&str]) -> Self {
+        ProfanityFilter {
+            words: words.iter().map(|w| w.to_lowercase()).collect(),
+        }
+    }
+
+    /// Replace every listed word in the text with asterisks
+    pub fn filter(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut word = String::new();
+
+        for c in text.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+
+            if self.words.contains(&word.to_lowercase()) {
+                out.extend(word.chars().map(|_| '*'));
+            } else {
+                out.push_str(&word);
+            }
+
+            word.clear();
+            out.push(c);
+        }
+
+        out.pop();
+        out
+    }
+}
+
+impl Middleware for ProfanityFilter {
+    fn inbound(&self, _peer: &Peer, mut message: message::Message) -> Action {
+        message.content = self.filter(&message.content);
+        Action::Continue(message)
+    }
+}
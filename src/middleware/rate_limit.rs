@@ -0,0 +1,251 @@
+use crate::middleware::{refusal, Action, Middleware, Peer};
+use crate::{Clock, SystemClock};
+
+use bakaproto::proto::*;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often full buckets are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    peers: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+/// ## RateLimit
+///
+/// Token bucket per peer IP address for messages received from clients, so reconnecting does
+/// not refill it. A peer over its limit is sent `RATE_LIMITED :reason` and the message is not
+/// handled. Buckets that have refilled completely are forgotten. The limit can be changed
+/// while the server runs, see `RateLimit::set_limit`.
+///
+/// Example:
+/// ```rs
+/// // Bursts of 10 messages, 10 more every 5 seconds
+/// server.middleware(RateLimit::new(10, Duration::from_secs(5)));
+/// ```
+///
+/// Properties:
+///
+/// * `limit`: Messages a peer may send at once and the time it takes to refill them, `None`
+///   for no limit.
+/// * `buckets`: Remaining tokens per peer and when full buckets were last removed.
+/// * `clock`: Measures how far the buckets have refilled, see `RateLimit::set_clock`.
+pub struct RateLimit {
+    limit: RwLock<Option<(u32, Duration)>>,
+    buckets: Mutex<Buckets>,
+    clock: RwLock<Arc<dyn Clock>>,
+}
+
+impl RateLimit {
+    /// Initialize new instance of the `RateLimit`
+    ///
+    /// Arguments:
+    ///
+    /// * `burst`: Messages a peer may send at once.
+    /// * `per`: Time it takes to refill a whole burst.
+    pub fn new(burst: u32, per: Duration) -> Self {
+        RateLimit::with_limit(Some((burst.max(1), per)))
+    }
+
+    /// Initialize new instance of the `RateLimit` that lets every message through until a
+    /// limit is set
+    pub fn unlimited() -> Self {
+        RateLimit::with_limit(None)
+    }
+
+    fn with_limit(limit: Option<(u32, Duration)>) -> Self {
+        let clock = SystemClock::shared();
+
+        RateLimit {
+            limit: RwLock::new(limit),
+            buckets: Mutex::new(Buckets {
+                peers: HashMap::new(),
+                pruned: clock.now(),
+            }),
+            clock: RwLock::new(clock),
+        }
+    }
+
+    /// Refill the buckets by another clock, e.g. a `MockClock` in tests
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        self.set_clock(clock);
+        self
+    }
+
+    /// Replace the clock of a limit that is already shared, every peer starts over with a
+    /// full bucket
+    ///
+    /// Arguments:
+    ///
+    /// * `clock`: The clock, see `ServerBuilder::clock`.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        buckets.peers.clear();
+        buckets.pruned = clock.now();
+        *self.clock.write().unwrap() = clock;
+    }
+
+    /// Number of peers with a bucket that is not full
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().peers.len()
+    }
+
+    /// Check whether every peer has a full bucket
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the current limit as `(burst, per)`
+    pub fn limit(&self) -> Option<(u32, Duration)> {
+        *self.limit.read().unwrap()
+    }
+
+    /// Replace the limit, every peer starts over with a full bucket
+    ///
+    /// Arguments:
+    ///
+    /// * `limit`: Messages a peer may send at once and the time it takes to refill them,
+    ///   `None` for no limit.
+    pub fn set_limit(&self, limit: Option<(u32, Duration)>) {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        *self.limit.write().unwrap() = limit.map(|(burst, per)| (burst.max(1), per));
+        buckets.peers.clear();
+    }
+
+    /// Take a token for the peer, returns `false` if it has none left
+    ///
+    /// Arguments:
+    ///
+    /// * `peer`: Usually the peer's IP address.
+    pub fn take(&self, peer: &str) -> bool {
+        let (burst, per) = match self.limit() {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let now = self.clock.read().unwrap().now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // A bucket left alone for `per` has refilled completely, like a new one
+        if now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL.min(per) {
+            buckets.pruned = now;
+            buckets
+                .peers
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < per);
+        }
+
+        let bucket = buckets.peers.entry(peer.to_string()).or_insert(Bucket {
+            tokens: burst as f64,
+            updated: now,
+        });
+
+        let refill = if per.is_zero() {
+            burst as f64
+        } else {
+            now.duration_since(bucket.updated).as_secs_f64() / per.as_secs_f64() * burst as f64
+        };
+
+        bucket.tokens = (bucket.tokens + refill).min(burst as f64);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Middleware for RateLimit {
+    fn inbound(&self, peer: &Peer, message: message::Message) -> Action {
+        let ip = match peer.address.parse::<SocketAddr>() {
+            Ok(address) => address.ip().to_string(),
+            Err(_) => peer.address.clone(),
+        };
+
+        if self.take(&ip) {
+            Action::Continue(message)
+        } else {
+            Action::ShortCircuit(refusal("RATE_LIMITED", "Too many messages, slow down"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn refills_over_time() {
+        let clock = Arc::new(MockClock::new());
+        let limit = RateLimit::new(2, Duration::from_secs(10)).with_clock(clock.clone());
+
+        assert!(limit.take("10.0.0.1"));
+        assert!(limit.take("10.0.0.1"));
+        assert!(!limit.take("10.0.0.1"));
+        assert!(limit.take("10.0.0.2"));
+
+        clock.advance(Duration::from_secs(5));
+
+        assert!(limit.take("10.0.0.1"));
+        assert!(!limit.take("10.0.0.1"));
+    }
+
+    #[test]
+    fn forgets_full_buckets() {
+        let clock = Arc::new(MockClock::new());
+        let limit = RateLimit::new(2, Duration::from_secs(10)).with_clock(clock.clone());
+
+        limit.take("10.0.0.1");
+        limit.take("10.0.0.2");
+
+        assert_eq!(limit.len(), 2);
+
+        clock.advance(Duration::from_secs(10));
+        limit.take("10.0.0.3");
+
+        assert_eq!(limit.len(), 1);
+    }
+
+    #[test]
+    fn follows_a_clock_set_later() {
+        let limit = Arc::new(RateLimit::new(1, Duration::from_secs(10)));
+        let clock = Arc::new(MockClock::new());
+
+        limit.set_clock(clock.clone());
+
+        assert!(limit.take("10.0.0.1"));
+        assert!(!limit.take("10.0.0.1"));
+
+        clock.advance(Duration::from_secs(10));
+
+        assert!(limit.take("10.0.0.1"));
+    }
+
+    #[test]
+    fn reconnecting_keeps_the_bucket() {
+        let limit = RateLimit::new(1, Duration::from_secs(60));
+        assert!(matches!(
+            limit.inbound(&Peer::new("10.0.0.1:4000"), message::Message::new()),
+            Action::Continue(_)
+        ));
+        assert!(matches!(
+            limit.inbound(&Peer::new("10.0.0.1:4001"), message::Message::new()),
+            Action::ShortCircuit(_)
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::command::CommandParser;
+use crate::protoutils::{BakaMessage, MessageKind};
+use crate::socket::Error;
+
+/// Protocol version spoken by this library
+///
+/// Since version 2 every message is framed with its length, see `socket::FRAME_HEADER_SIZE`.
+/// Version 1 sent bare messages, so the two cannot talk to each other.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version this library still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// ## Hello
+///
+/// First message a client sends after connecting: `HELLO {version} :capability capability...`
+///
+/// Properties:
+///
+/// * `version`: The newest protocol version the client speaks.
+/// * `capabilities`: Optional features the client supports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// ## Welcome
+///
+/// Server reply to an accepted `Hello`: `WELCOME {version} :capability capability...`
+///
+/// Properties:
+///
+/// * `version`: The protocol version both sides will use.
+/// * `capabilities`: Capabilities supported by both sides.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Welcome {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+fn encode(command: &str, version: u32, capabilities: &Vec<String>) -> String {
+    format!("{} {{{}}} :{}", command, version, capabilities.join(" "))
+}
+
+fn decode(message: &BakaMessage, expected: &str) -> Result<(u32, Vec<String>), Error> {
+    if message.kind == MessageKind::Error {
+        let mut parser = CommandParser::new(message.content.clone());
+
+        return Err(Error::new(&format!(
+            "Handshake rejected: {}",
+            parser.text()
+        )));
+    }
+
+    let mut parser = CommandParser::new(message.content.clone());
+
+    if parser.command() != expected {
+        return Err(Error::new(&format!(
+            "Handshake failed: expected {}, got {:?}",
+            expected,
+            parser.command()
+        )));
+    }
+
+    let version = match parser.args().first().map(|v| v.parse::<u32>()) {
+        Some(Ok(version)) => version,
+        _ => {
+            return Err(Error::new(
+                "Handshake failed: missing or invalid protocol version",
+            ))
+        }
+    };
+
+    let capabilities = parser
+        .text()
+        .split_whitespace()
+        .map(|c| c.to_string())
+        .collect();
+
+    Ok((version, capabilities))
+}
+
+impl Hello {
+    /// Initialize new instance of the `Hello` for `PROTOCOL_VERSION`
+    ///
+    /// Arguments:
+    ///
+    /// * `capabilities`: Optional features the client supports.
+    pub fn new(capabilities: Vec<String>) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities,
+        }
+    }
+
+    /// Encode as a command message
+    pub fn to_message(&self, author: &str) -> BakaMessage {
+        BakaMessage::new(
+            MessageKind::Command,
+            author,
+            &encode("HELLO", self.version, &self.capabilities),
+        )
+    }
+
+    /// Decode from a command message
+    pub fn parse(message: &BakaMessage) -> Result<Self, Error> {
+        let (version, capabilities) = decode(message, "HELLO")?;
+
+        Ok(Hello {
+            version: version,
+            capabilities: capabilities,
+        })
+    }
+
+    /// Agree on a protocol version and the shared capabilities
+    ///
+    /// Both sides use the lower of the two versions, as long as it is not older than
+    /// `MIN_PROTOCOL_VERSION`.
+    ///
+    /// Arguments:
+    ///
+    /// * `supported`: Capabilities supported by the server.
+    pub fn negotiate(&self, supported: &Vec<String>) -> Result<Welcome, Error> {
+        let version = self.version.min(PROTOCOL_VERSION);
+
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(Error::new(&format!(
+                "Unsupported protocol version {}, server accepts {} to {}",
+                self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+
+        Ok(Welcome {
+            version: version,
+            capabilities: self
+                .capabilities
+                .iter()
+                .filter(|c| supported.contains(c))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+impl Welcome {
+    /// Encode as a command message
+    pub fn to_message(&self, author: &str) -> BakaMessage {
+        BakaMessage::new(
+            MessageKind::Command,
+            author,
+            &encode("WELCOME", self.version, &self.capabilities),
+        )
+    }
+
+    /// Decode from a command message, an error message from the server becomes `Err`
+    pub fn parse(message: &BakaMessage) -> Result<Self, Error> {
+        let (version, capabilities) = decode(message, "WELCOME")?;
+
+        if version < MIN_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+            return Err(Error::new(&format!(
+                "Server selected unsupported protocol version {}",
+                version
+            )));
+        }
+
+        Ok(Welcome {
+            version: version,
+            capabilities: capabilities,
+        })
+    }
+
+    /// Check whether a capability was accepted by both sides
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Build the message a server sends when it rejects a handshake
+///
+/// Arguments:
+///
+/// * `author`: The server address.
+/// * `error`: Why the handshake was rejected.
+pub fn reject(author: &str, error: &Error) -> BakaMessage {
+    BakaMessage::new(
+        MessageKind::Error,
+        author,
+        &format!("REJECT :{}", error.message()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(names: &[&str]) -> Vec<String> {
+        names.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello::new(capabilities(&["history", "away"]));
+
+        assert_eq!(Hello::parse(&hello.to_message("client")).unwrap(), hello);
+    }
+
+    #[test]
+    fn negotiate_keeps_shared_capabilities() {
+        let hello = Hello::new(capabilities(&["history", "away"]));
+        let welcome = hello.negotiate(&capabilities(&["away", "link"])).unwrap();
+
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.capabilities, capabilities(&["away"]));
+        assert!(welcome.has_capability("away"));
+        assert!(!welcome.has_capability("history"));
+    }
+
+    #[test]
+    fn rejects_old_versions() {
+        let hello = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: vec![],
+        };
+
+        assert!(hello.negotiate(&vec![]).is_err());
+    }
+
+    #[test]
+    fn rejection_keeps_colons_in_the_reason() {
+        let reject = reject("server", &Error::new(":( try again"));
+        let error = Welcome::parse(&reject).unwrap_err();
+
+        assert_eq!(error.message(), "Handshake rejected: :( try again");
+    }
+
+    #[test]
+    fn missing_version_is_an_error() {
+        let message = BakaMessage::new(MessageKind::Command, "client", "HELLO :history");
+
+        assert!(Hello::parse(&message).is_err());
+    }
+}
@@ -1,21 +1,171 @@
+use crate::extensions::string::StringExtension;
+use crate::{Clock, SystemClock};
+
 use bakaproto::proto::message;
 
-use protobuf::Message;
+use protobuf::{EnumOrUnknown, Message};
+
+use std::time::UNIX_EPOCH;
+
+/// ## MessageKind
+///
+/// What a message is, so clients can tell chat lines from server notices.
+/// Numbered like the `Message.Kind` enum of the schema in `protoutils`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Chat,
+    Notice,
+    Join,
+    Part,
+    Command,
+    Error,
+}
+
+impl Default for MessageKind {
+    fn default() -> Self {
+        MessageKind::Chat
+    }
+}
+
+impl MessageKind {
+    /// Get the number of the kind on the wire
+    pub fn number(&self) -> u64 {
+        match self {
+            MessageKind::Chat => 0,
+            MessageKind::Notice => 1,
+            MessageKind::Join => 2,
+            MessageKind::Part => 3,
+            MessageKind::Command => 4,
+            MessageKind::Error => 5,
+        }
+    }
+
+    /// Get the kind with a number on the wire, unknown numbers are `Chat`
+    pub fn from_number(number: u64) -> Self {
+        match number {
+            1 => MessageKind::Notice,
+            2 => MessageKind::Join,
+            3 => MessageKind::Part,
+            4 => MessageKind::Command,
+            5 => MessageKind::Error,
+            _ => MessageKind::Chat,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch by a clock
+///
+/// Arguments:
+///
+/// * `clock`: The clock to read the wall-clock time from.
+pub fn timestamp(clock: &dyn Clock) -> u64 {
+    clock
+        .system_time()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
 
+/// ## BakaMessage
+///
+/// Properties:
+///
+/// * `id`: Unique id of the message, sortable by creation time. The server replaces the id of
+///   every message it receives from a client, so ids cannot be chosen or reused by clients.
+/// * `timestamp`: Milliseconds since the Unix epoch, set by the server.
+/// * `kind`: What the message is.
+/// * `author`: Who sent the message.
+/// * `content`: The message text.
+/// * `target`: Optional nickname or channel the message is addressed to.
+#[derive(Clone, Debug)]
 pub struct BakaMessage {
+    pub id: String,
+    pub timestamp: u64,
+    pub kind: MessageKind,
     pub author: String,
     pub content: String,
+    pub target: Option<String>,
 }
 
 impl BakaMessage {
+    /// Initialize new instance of the `BakaMessage` with a fresh id and timestamp
+    ///
+    /// Example:
+    /// ```rs
+    /// let message = BakaMessage::new(MessageKind::Notice, "127.0.0.1:65432", "Hello!");
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `kind`: What the message is.
+    /// * `author`: Who sent the message.
+    /// * `content`: The message text.
+    pub fn new(kind: MessageKind, author: &str, content: &str) -> Self {
+        BakaMessage {
+            id: String::generate_id(),
+            timestamp: timestamp(&SystemClock),
+            kind: kind,
+            author: author.to_string(),
+            content: content.to_string(),
+            target: None,
+        }
+    }
+
+    /// Address the message to a nickname or channel
+    pub fn with_target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    /// Set the server id and timestamp, replacing whatever the sender put there
+    ///
+    /// Arguments:
+    ///
+    /// * `clock`: The server's clock, see `ServerBuilder::clock`.
+    pub fn stamped(mut self, clock: &dyn Clock) -> Self {
+        self.id = String::generate_id();
+        self.timestamp = timestamp(clock);
+        self
+    }
+
     pub fn build(&mut self) -> message::Message {
         let mut message = message::Message::new();
 
         message.author = self.author.clone();
         message.content = self.content.clone();
+        message.id = self.id.clone();
+        message.timestamp = self.timestamp;
+        message.kind = EnumOrUnknown::from_i32(self.kind.number() as i32);
+        message.target = self.target.clone();
 
         return message;
     }
+
+    /// Decode a message from raw bytes without panicking
+    ///
+    /// Arguments:
+    ///
+    /// * `buf`: &[u8] - The encoded message.
+    pub fn parse(buf: &[u8]) -> Result<Self, protobuf::Error> {
+        let mut msg = message::Message::new();
+
+        msg.merge_from_bytes(buf)?;
+
+        Ok(BakaMessage::from(msg))
+    }
+}
+
+impl std::convert::From<message::Message> for BakaMessage {
+    fn from(msg: message::Message) -> Self {
+        BakaMessage {
+            id: msg.id,
+            timestamp: msg.timestamp,
+            kind: MessageKind::from_number(msg.kind.value().max(0) as u64),
+            target: msg.target,
+            author: msg.author,
+            content: msg.content,
+        }
+    }
 }
 
 impl std::convert::From<String> for BakaMessage {
@@ -27,9 +177,67 @@ impl std::convert::From<String> for BakaMessage {
             Err(e) => panic!("{}", e),
         }
 
-        BakaMessage {
-            author: msg.author,
-            content: msg.content,
-        }
+        BakaMessage::from(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    use std::time::Duration;
+
+    #[test]
+    fn metadata_survives_the_wire() {
+        let mut sent =
+            BakaMessage::new(MessageKind::Command, "alice", "JOIN {#rust}").with_target("#rust");
+        let bytes = sent.build().write_to_bytes().unwrap();
+        let received = BakaMessage::parse(&bytes).unwrap();
+
+        assert_eq!(received.id, sent.id);
+        assert_eq!(received.timestamp, sent.timestamp);
+        assert_eq!(received.kind, MessageKind::Command);
+        assert_eq!(received.author, "alice");
+        assert_eq!(received.content, "JOIN {#rust}");
+        assert_eq!(received.target.as_deref(), Some("#rust"));
+    }
+
+    #[test]
+    fn missing_metadata_has_defaults() {
+        let mut message = message::Message::new();
+
+        message.author = "alice".to_string();
+        message.content = "hi".to_string();
+
+        let received = BakaMessage::from(message);
+
+        assert_eq!(received.id, "");
+        assert_eq!(received.kind, MessageKind::Chat);
+        assert_eq!(received.target, None);
+    }
+
+    #[test]
+    fn stamped_replaces_the_id() {
+        let mut spoofed = BakaMessage::new(MessageKind::Chat, "alice", "hi");
+        spoofed.id = "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string();
+
+        let first = spoofed.clone().stamped(&SystemClock);
+        let second = spoofed.stamped(&SystemClock);
+
+        assert_ne!(first.id, "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+        assert!(second.id > first.id);
+    }
+
+    #[test]
+    fn stamped_uses_the_clock() {
+        let clock = MockClock::at(UNIX_EPOCH + Duration::from_secs(5));
+        let message = BakaMessage::new(MessageKind::Chat, "alice", "hi").stamped(&clock);
+
+        assert_eq!(message.timestamp, 5000);
+
+        clock.advance(Duration::from_millis(250));
+
+        assert_eq!(message.stamped(&clock).timestamp, 5250);
     }
 }
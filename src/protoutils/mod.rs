@@ -1,3 +1,30 @@
+//! Helpers around the bakaproto `Message` type.
+//!
+//! `BakaMessage` maps onto the bakaproto `Message`, which declares the metadata fields since
+//! bakaproto 0.2:
+//!
+//! ```proto
+//! message Message {
+//!     enum Kind {
+//!         CHAT = 0;
+//!         NOTICE = 1;
+//!         JOIN = 2;
+//!         PART = 3;
+//!         COMMAND = 4;
+//!         ERROR = 5;
+//!     }
+//!
+//!     string author = 1;
+//!     string content = 2;
+//!     string id = 3;
+//!     uint64 timestamp = 4;
+//!     Kind kind = 5;
+//!     optional string target = 6;
+//! }
+//! ```
+
+mod handshake;
 mod message;
 
+pub use handshake::*;
 pub use message::*;
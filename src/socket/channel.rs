@@ -0,0 +1,130 @@
+use crate::extensions::names::NameRules;
+use crate::protoutils::BakaMessage;
+use crate::socket::{ClientEntry, Server, ServerBuilder};
+
+use std::collections::HashSet;
+
+/// ## Channel
+///
+/// Properties:
+///
+/// * `name`: The channel name, starting with `#`, as the first member spelled it.
+/// * `members`: Keys of the member clients in `Server::clients`.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    pub members: HashSet<String>,
+}
+
+impl Channel {
+    /// Initialize new empty instance of the `Channel`
+    pub fn new(name: &str) -> Self {
+        Channel {
+            name: name.to_string(),
+            members: HashSet::new(),
+        }
+    }
+
+    /// Check whether a name follows `NameRules::channel`
+    pub fn is_valid_name(name: &str) -> bool {
+        NameRules::channel().is_valid(name)
+    }
+}
+
+impl Server {
+    /// Add a client to a channel, creating the channel if needed
+    ///
+    /// Returns `false` if the client already was a member.
+    ///
+    /// Arguments:
+    ///
+    /// * `channel`: The channel name.
+    /// * `address`: The client's key in `clients`.
+    pub fn join(&self, channel: &str, address: &str) -> bool {
+        self.channels
+            .write()
+            .unwrap()
+            .entry(NameRules::key(channel))
+            .or_insert_with(|| Channel::new(channel))
+            .members
+            .insert(address.to_string())
+    }
+
+    /// Remove a client from a channel, empty channels are dropped
+    ///
+    /// Returns `false` if the client was not a member.
+    pub fn part(&self, channel: &str, address: &str) -> bool {
+        let mut channels = self.channels.write().unwrap();
+        let key = NameRules::key(channel);
+
+        let removed = match channels.get_mut(&key) {
+            Some(c) => c.members.remove(address),
+            None => false,
+        };
+
+        if channels.get(&key).is_some_and(|c| c.members.is_empty()) {
+            channels.remove(&key);
+        }
+
+        removed
+    }
+
+    /// Check whether a client is a member of a channel
+    pub fn is_member(&self, channel: &str, address: &str) -> bool {
+        self.channels
+            .read()
+            .unwrap()
+            .get(&NameRules::key(channel))
+            .is_some_and(|c| c.members.contains(address))
+    }
+
+    /// Get the connected members of a channel
+    pub fn members(&self, channel: &str) -> Vec<ClientEntry> {
+        let addresses: Vec<String> =
+            match self.channels.read().unwrap().get(&NameRules::key(channel)) {
+                Some(c) => c.members.iter().cloned().collect(),
+                None => vec![],
+            };
+
+        addresses
+            .iter()
+            .filter_map(|address| self.clients.get(address))
+            .collect()
+    }
+
+    /// Get the names of the channels a client is a member of
+    pub fn channels_of(&self, address: &str) -> Vec<String> {
+        self.channels
+            .read()
+            .unwrap()
+            .values()
+            .filter(|c| c.members.contains(address))
+            .map(|c| c.name.clone())
+            .collect()
+    }
+
+    /// Queue a message for every member of a channel, without waiting on full queues
+    ///
+    /// Arguments:
+    ///
+    /// * `channel`: The channel name.
+    /// * `message`: The message to send.
+    /// * `except`: Key of a member that should not receive the message, usually its sender.
+    pub fn send_to_channel(&self, channel: &str, message: &BakaMessage, except: Option<&str>) {
+        let addresses: Vec<String> =
+            match self.channels.read().unwrap().get(&NameRules::key(channel)) {
+                Some(c) => c.members.iter().cloned().collect(),
+                None => return,
+            };
+
+        for address in addresses {
+            if Some(address.as_str()) == except {
+                continue;
+            }
+
+            if let Some(entry) = self.clients.get(&address) {
+                ServerBuilder::offer(&entry, message.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,574 @@
+use crate::auth::verify_password;
+use crate::command::CommandParser;
+use crate::extensions::names::NameRules;
+use crate::protoutils::{BakaMessage, MessageKind};
+use crate::socket::{
+    ClientEntry, Error, History, HistoryQuery, Server, ServerBuilder, Whois, OPERATOR_FLAG,
+};
+
+impl ServerBuilder {
+    /// Handle a command the server understands, returns `false` for anything else
+    ///
+    /// Understood commands:
+    ///
+    /// * `JOIN {#channel}`
+    /// * `PART {#channel}`
+    /// * `HISTORY {target count}`, `HISTORY {target} :id <id>` or `HISTORY {target} :since <ms>`,
+    ///   where the target is a channel or the user name of the other side of a private
+    ///   conversation. Private history is only kept between logged-in users, by user name, so a
+    ///   client taking a nickname later cannot read it.
+    /// * `AWAY :message` to set an away message, `AWAY` to clear it
+    /// * `WHO` or `WHO {#channel}`
+    /// * `WHOIS {nick}`
+    /// * `NICK {nick}` to change nickname
+    /// * `OPER {user} :password` to become an operator, see `ServerBuilder::operator`
+    ///
+    /// Operators, clients with the `operator` flag, may also use:
+    ///
+    /// * `KICK {nick} :reason`
+    /// * `BAN {mask} :reason` and `UNBAN {mask}`
+    /// * `MUTE {#channel nick}` and `UNMUTE {#channel nick}`
+    pub(crate) fn command(
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        message: &BakaMessage,
+    ) -> bool {
+        let mut parser = CommandParser::new(message.content.clone());
+        let args = parser.args();
+        let target = args.first().cloned().unwrap_or_default();
+
+        match parser.command() {
+            "JOIN" => ServerBuilder::join(server, entry, address, &target),
+            "PART" => ServerBuilder::part(server, entry, address, &target),
+            "HISTORY" => {
+                let query = match (
+                    args.get(1),
+                    parser.tail().trim_start_matches(':').split_once(' '),
+                ) {
+                    (Some(count), _) => count.parse::<usize>().ok().map(HistoryQuery::Last),
+                    (None, Some(("id", id))) => Some(HistoryQuery::SinceId(id.trim().to_string())),
+                    (None, Some(("since", timestamp))) => timestamp
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                        .map(HistoryQuery::SinceTimestamp),
+                    (None, _) => Some(HistoryQuery::Last(server.history_replay)),
+                };
+
+                match query {
+                    Some(query) => ServerBuilder::history(server, entry, address, &target, &query),
+                    None => ServerBuilder::fail(
+                        server,
+                        entry,
+                        "INVALID_QUERY",
+                        &Error::new("Expected a count, `:id <id>` or `:since <timestamp>`"),
+                    ),
+                }
+            }
+            "AWAY" => {
+                let away = parser.text().trim().to_string();
+
+                ServerBuilder::away(
+                    server,
+                    entry,
+                    if away.is_empty() { None } else { Some(away) },
+                );
+            }
+            "WHO" => ServerBuilder::who(server, entry, args.first().filter(|t| !t.is_empty())),
+            "WHOIS" => ServerBuilder::whois(server, entry, &target),
+            "NICK" => ServerBuilder::nick(server, entry, address, &target),
+            "OPER" => {
+                let password = parser.text().to_string();
+
+                ServerBuilder::oper(server, entry, &target, &password);
+            }
+            "KICK" | "BAN" | "UNBAN" | "MUTE" | "UNMUTE" => {
+                if !entry.client.lock().unwrap().is_operator() {
+                    ServerBuilder::fail(
+                        server,
+                        entry,
+                        &format!("NOT_OPERATOR {{{}}}", parser.command()),
+                        &Error::new("Permission denied"),
+                    );
+                    return true;
+                }
+
+                let reason = parser.text().trim().to_string();
+                let nick = args.get(1).cloned().unwrap_or_default();
+
+                ServerBuilder::moderate(server, entry, parser.command(), &target, &nick, &reason);
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Notice from the server about a client, addressed to a channel
+    pub(crate) fn channel_notice(
+        server: &Server,
+        kind: MessageKind,
+        nick: &str,
+        channel: &str,
+    ) -> BakaMessage {
+        BakaMessage::new(kind, &server.address.to_string(), nick).with_target(channel)
+    }
+
+    /// Join a channel, creating it if needed
+    ///
+    /// The name must follow `Server::channel_rules` and may not look like the name of another
+    /// channel, e.g. `#rust` spelled with a Cyrillic `ѕ` while `#rust` exists.
+    fn join(server: &mut Server, entry: &ClientEntry, address: &str, channel: &str) {
+        let channel = match server.channel_rules.validate(channel) {
+            Ok(channel) => channel,
+            Err(e) => {
+                ServerBuilder::fail(
+                    server,
+                    entry,
+                    &format!("INVALID_CHANNEL {{{}}}", channel),
+                    &e,
+                );
+                return;
+            }
+        };
+        let channel = channel.as_str();
+
+        let lookalike = {
+            let channels = server.channels.read().unwrap();
+
+            NameRules::confusable(channel, channels.keys())
+        };
+
+        if lookalike.is_some() {
+            ServerBuilder::fail(
+                server,
+                entry,
+                &format!("INVALID_CHANNEL {{{}}}", channel),
+                &Error::new("Channel name looks like one that already exists"),
+            );
+            return;
+        }
+
+        if !server.join(channel, address) {
+            return;
+        }
+
+        let nick = entry.client.lock().unwrap().nick();
+
+        for message in server.history.query(
+            &History::channel(channel),
+            &HistoryQuery::Last(server.history_replay),
+        ) {
+            ServerBuilder::reply(entry, message);
+        }
+
+        server.send_to_channel(
+            channel,
+            &ServerBuilder::channel_notice(server, MessageKind::Join, &nick, channel),
+            None,
+        );
+        ServerBuilder::announce(server, &format!("JOIN {{{} {}}}", nick, channel));
+    }
+
+    fn part(server: &mut Server, entry: &ClientEntry, address: &str, channel: &str) {
+        let nick = entry.client.lock().unwrap().nick();
+        let notice = ServerBuilder::channel_notice(server, MessageKind::Part, &nick, channel);
+
+        if !server.is_member(channel, address) {
+            ServerBuilder::fail(
+                server,
+                entry,
+                &format!("NOT_ON_CHANNEL {{{}}}", channel),
+                &Error::new("You are not on that channel"),
+            );
+            return;
+        }
+
+        server.send_to_channel(channel, &notice, None);
+        server.part(channel, address);
+
+        ServerBuilder::announce(server, &format!("PART {{{} {}}}", nick, channel));
+    }
+
+    /// Leave every channel, used when a client disconnects
+    pub(crate) fn part_all(server: &mut Server, entry: &ClientEntry, address: &str) {
+        let nick = entry.client.lock().unwrap().nick();
+
+        for channel in server.channels_of(address) {
+            server.part(&channel, address);
+            server.send_to_channel(
+                &channel,
+                &ServerBuilder::channel_notice(server, MessageKind::Part, &nick, &channel),
+                None,
+            );
+        }
+    }
+
+    fn history(
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        target: &str,
+        query: &HistoryQuery,
+    ) {
+        let conversation = if target.starts_with('#') {
+            if !server.is_member(target, address) {
+                ServerBuilder::fail(
+                    server,
+                    entry,
+                    &format!("NOT_ON_CHANNEL {{{}}}", target),
+                    &Error::new("You are not on that channel"),
+                );
+                return;
+            }
+
+            History::channel(target)
+        } else {
+            let user = entry.client.lock().unwrap().flags.get("user").cloned();
+
+            match user {
+                Some(user) => History::conversation(&user, target),
+                None => {
+                    ServerBuilder::fail(
+                        server,
+                        entry,
+                        &format!("NOT_LOGGED_IN {{{}}}", target),
+                        &Error::new("Private history is only kept for logged-in users"),
+                    );
+                    return;
+                }
+            }
+        };
+
+        let messages = server.history.query(&conversation, query);
+        let count = messages.len();
+
+        for message in messages {
+            ServerBuilder::reply(entry, message);
+        }
+
+        ServerBuilder::reply(
+            entry,
+            BakaMessage::new(
+                MessageKind::Command,
+                &server.address.to_string(),
+                &format!("HISTORY_END {{{} {}}}", target, count),
+            ),
+        );
+    }
+
+    /// Reply with a command message from the server
+    fn respond(server: &Server, entry: &ClientEntry, content: &str) {
+        ServerBuilder::reply(
+            entry,
+            BakaMessage::new(MessageKind::Command, &server.address.to_string(), content),
+        );
+    }
+
+    fn away(server: &mut Server, entry: &ClientEntry, message: Option<String>) {
+        let nick = entry.client.lock().unwrap().nick();
+        let state = if message.is_some() { "on" } else { "off" };
+
+        server.presence.set_away(&nick, message);
+
+        ServerBuilder::respond(server, entry, &format!("AWAY {{{}}}", state));
+    }
+
+    /// Reply with `WHO_REPLY {nick idle-seconds here|away server} :away message` per client,
+    /// followed by `WHO_END {target count}`
+    fn who(server: &mut Server, entry: &ClientEntry, channel: Option<&String>) {
+        let entries = server.who(channel.map(|c| c.as_str()));
+
+        for who in &entries {
+            ServerBuilder::respond(
+                server,
+                entry,
+                &format!(
+                    "WHO_REPLY {{{} {} {} {}}} :{}",
+                    who.nick,
+                    who.idle.as_secs(),
+                    if who.away.is_some() { "away" } else { "here" },
+                    who.server,
+                    who.away.clone().unwrap_or_default()
+                ),
+            );
+        }
+
+        ServerBuilder::respond(
+            server,
+            entry,
+            &format!(
+                "WHO_END {{{} {}}}",
+                channel.map(|c| c.as_str()).unwrap_or("*"),
+                entries.len()
+            ),
+        );
+    }
+
+    /// Reply with `WHOIS_REPLY {nick online idle-seconds connected-at #a,#b} :away message`,
+    /// `WHOIS_REPLY {nick remote server #a,#b}` for a client of another server or
+    /// `WHOIS_REPLY {nick offline last-seen}`, timestamps in milliseconds since the Unix epoch
+    fn whois(server: &mut Server, entry: &ClientEntry, nick: &str) {
+        match server.whois(nick) {
+            Some(Whois::Online {
+                nick,
+                away,
+                idle,
+                connected_at,
+                channels,
+                ..
+            }) => ServerBuilder::respond(
+                server,
+                entry,
+                &format!(
+                    "WHOIS_REPLY {{{} online {} {} {}}} :{}",
+                    nick,
+                    idle.as_secs(),
+                    connected_at,
+                    if channels.is_empty() {
+                        "-".to_string()
+                    } else {
+                        channels.join(",")
+                    },
+                    away.unwrap_or_default()
+                ),
+            ),
+            Some(Whois::Remote {
+                nick,
+                server: origin,
+                channels,
+            }) => ServerBuilder::respond(
+                server,
+                entry,
+                &format!(
+                    "WHOIS_REPLY {{{} remote {} {}}} :",
+                    nick,
+                    origin,
+                    if channels.is_empty() {
+                        "-".to_string()
+                    } else {
+                        channels.join(",")
+                    }
+                ),
+            ),
+            Some(Whois::Offline { nick, last_seen }) => ServerBuilder::respond(
+                server,
+                entry,
+                &format!("WHOIS_REPLY {{{} offline {}}} :", nick, last_seen),
+            ),
+            None => ServerBuilder::fail(
+                server,
+                entry,
+                &format!("NO_SUCH_NICK {{{}}}", nick),
+                &Error::new("No such nick"),
+            ),
+        }
+    }
+
+    /// Change a client's nickname
+    ///
+    /// The client and the members of its channels are sent `NICK {old new}`. Nicknames must
+    /// pass the server's `NameRules`, `NameRules::nickname` unless changed with
+    /// `ServerBuilder::nick_rules`. A client switching to a nickname matched by a ban is
+    /// disconnected.
+    fn nick(server: &mut Server, entry: &ClientEntry, address: &str, nick: &str) {
+        let nick = match server.nick_rules.validate(nick) {
+            Ok(nick) => nick,
+            Err(e) => {
+                ServerBuilder::fail(server, entry, &format!("INVALID_NICK {{{}}}", nick), &e);
+                return;
+            }
+        };
+        let nick = nick.as_str();
+        let key = NameRules::key(nick);
+
+        let old = entry.client.lock().unwrap().nick();
+        let old_key = NameRules::key(&old);
+
+        if old == nick {
+            return;
+        }
+
+        let ip = entry.client.lock().unwrap().socket.address.ip();
+
+        if let Some(ban) = server.moderation.banned(Some(nick), &ip) {
+            ServerBuilder::banned(server, entry, &ban);
+            return;
+        }
+
+        if ServerBuilder::lookalike(server, nick, address).is_some() {
+            ServerBuilder::fail(
+                server,
+                entry,
+                &format!("NICK_IN_USE {{{}}}", nick),
+                &Error::new("Nickname looks like one that is already in use"),
+            );
+            return;
+        }
+
+        let remote = server
+            .federation
+            .as_ref()
+            .is_some_and(|federation| federation.locate(nick).is_some());
+
+        if remote || !ServerBuilder::claim(server, nick, address) {
+            ServerBuilder::fail(
+                server,
+                entry,
+                &format!("NICK_IN_USE {{{}}}", nick),
+                &Error::new("Nickname is already in use"),
+            );
+            return;
+        }
+
+        if old_key != key && server.nicks.get(&old_key).as_deref() == Some(address) {
+            server.nicks.remove(&old_key);
+        }
+
+        entry.client.lock().unwrap().add_flag("nick", nick);
+        entry.span.nick(nick);
+        server.presence.rename(&old, nick);
+
+        let channels = server.channels_of(address);
+        let notice = BakaMessage::new(
+            MessageKind::Command,
+            &server.address.to_string(),
+            &format!("NICK {{{} {}}}", old, nick),
+        );
+
+        ServerBuilder::reply(entry, notice.clone());
+
+        for channel in &channels {
+            server.send_to_channel(channel, &notice, Some(address));
+        }
+
+        ServerBuilder::announce(server, &format!("QUIT {{{}}} :Renamed", old));
+        ServerBuilder::announce(server, &format!("NICK {{{}}}", nick));
+
+        for channel in &channels {
+            ServerBuilder::announce(server, &format!("JOIN {{{} {}}}", nick, channel));
+        }
+    }
+
+    /// Grant the operator flag to a client presenting an operator's password
+    ///
+    /// Failed attempts count towards the peer's `Throttle` like failed logins.
+    fn oper(server: &mut Server, entry: &ClientEntry, user: &str, password: &str) {
+        let peer = entry.client.lock().unwrap().socket.address.ip().to_string();
+
+        if let Err(e) = server.throttle.check(&peer) {
+            ServerBuilder::fail(server, entry, "OPER_FAILED", &e);
+            return;
+        }
+
+        let hash = server.operators.read().unwrap().get(user).cloned();
+
+        match hash {
+            Some(hash) if verify_password(&hash, password) => {
+                server.throttle.success(&peer);
+                entry.client.lock().unwrap().add_flag(OPERATOR_FLAG, user);
+
+                ServerBuilder::respond(server, entry, &format!("OPER {{{}}}", user));
+            }
+            _ => {
+                server.throttle.failure(&peer);
+                ServerBuilder::fail(
+                    server,
+                    entry,
+                    "OPER_FAILED",
+                    &Error::new("Invalid operator credentials"),
+                );
+            }
+        }
+    }
+
+    /// Run an operator command, replying with the command and its arguments once it is done
+    fn moderate(
+        server: &mut Server,
+        entry: &ClientEntry,
+        command: &str,
+        target: &str,
+        nick: &str,
+        reason: &str,
+    ) {
+        let result = match command {
+            "KICK" => match server.find(target) {
+                Some(kicked) => {
+                    server.kick_entry(&kicked, target, reason);
+                    Ok(())
+                }
+                None => Err(("NO_SUCH_NICK", Error::new("No such nick"))),
+            },
+            "BAN" => server.ban(target, reason).map_err(|e| ("INVALID_MASK", e)),
+            "UNBAN" => match server.moderation.unban(target) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(("NO_SUCH_BAN", Error::new("No such ban"))),
+                Err(e) => Err(("ERROR", e)),
+            },
+            _ if !server.channel_rules.is_valid(target) || nick.is_empty() => Err((
+                "INVALID_QUERY",
+                Error::new("Expected a channel and a nickname"),
+            )),
+            "MUTE" => server
+                .moderation
+                .mute(target, nick)
+                .map_err(|e| ("ERROR", e)),
+            _ => server
+                .moderation
+                .unmute(target, nick)
+                .map_err(|e| ("ERROR", e)),
+        };
+
+        let args = if nick.is_empty() {
+            target.to_string()
+        } else {
+            format!("{} {}", target, nick)
+        };
+
+        match result {
+            Ok(()) => ServerBuilder::respond(server, entry, &format!("{} {{{}}}", command, args)),
+            Err((code, e)) => {
+                ServerBuilder::fail(server, entry, &format!("{} {{{}}}", code, args), &e)
+            }
+        }
+    }
+
+    /// Relay a message to the other members of a channel and record it
+    pub(crate) fn channel_message(
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        mut message: BakaMessage,
+    ) {
+        let channel = message.target.clone().unwrap_or_default();
+
+        if !server.is_member(&channel, address) {
+            ServerBuilder::fail(
+                server,
+                entry,
+                &format!("NOT_ON_CHANNEL {{{}}}", channel),
+                &Error::new("You are not on that channel"),
+            );
+            return;
+        }
+
+        message.author = entry.client.lock().unwrap().nick();
+
+        if server.moderation.is_muted(&channel, &message.author) {
+            ServerBuilder::fail(
+                server,
+                entry,
+                &format!("MUTED {{{}}}", channel),
+                &Error::new("You are muted on that channel"),
+            );
+            return;
+        }
+
+        let message = message.stamped(&*server.clock);
+        let _ = server.history.record(&History::channel(&channel), &message);
+
+        server.send_to_channel(&channel, &message, Some(address));
+        ServerBuilder::relay_channel(server, &message, None);
+    }
+}
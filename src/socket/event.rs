@@ -0,0 +1,134 @@
+use crate::extensions::names::NameRules;
+use crate::protoutils::BakaMessage;
+use crate::socket::{Client, Error, Server};
+
+use bakaproto::proto::*;
+use protobuf::Message;
+
+/// Handler registered with `ServerBuilder::event`
+pub type BoxEvent = Box<dyn Fn(&mut Context, Event) + core::marker::Send + Sync + 'static>;
+
+/// ## DisconnectReason
+///
+/// Why a client's connection ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection
+    Closed,
+    /// The server kicked or banned the client, with the reason it was given
+    Kicked(String),
+    /// Reading from the connection failed
+    Error(String),
+}
+
+/// ## Event
+///
+/// What a server event handler is called for.
+///
+/// * `on_client_connect` receives `Connected` once the client is registered.
+/// * `on_message` receives `Message` for every message the server does not handle itself.
+/// * `on_error` receives `Error` when a message from the client cannot be decoded.
+/// * `on_client_disconnect` receives `Disconnected` after the client left its channels.
+#[derive(Debug)]
+pub enum Event {
+    Connected,
+    Message(message::Message),
+    Disconnected { reason: DisconnectReason },
+    Error(Error),
+}
+
+/// ## Context
+///
+/// Handle passed to event handlers, giving access to the server and to the client the event
+/// is about. Only that client is locked while the handler runs.
+///
+/// Example:
+/// ```rs
+/// server.event("on_message", Box::new(|ctx: &mut Context, event: Event| {
+///     if let Event::Message(message) = event {
+///         let nick = ctx.nick();
+///         ctx.server().broadcast(&format!("{}: {}", nick, message.content));
+///     }
+/// }));
+/// ```
+pub struct Context<'a> {
+    server: &'a mut Server,
+    client: &'a mut Client,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(server: &'a mut Server, client: &'a mut Client) -> Self {
+        Context {
+            server: server,
+            client: client,
+        }
+    }
+
+    /// Get the server
+    pub fn server(&mut self) -> &mut Server {
+        self.server
+    }
+
+    /// Get the client the event is about
+    pub fn client(&mut self) -> &mut Client {
+        self.client
+    }
+
+    /// Get the client's nickname
+    pub fn nick(&self) -> String {
+        self.client.nick()
+    }
+
+    /// Queue a message for the client the event is about
+    pub fn reply(&mut self, mut message: BakaMessage) -> Result<(), Error> {
+        self.client.queue(message.build().write_to_bytes().unwrap())
+    }
+
+    /// Kick a client by nickname, the client the event is about included
+    ///
+    /// Use this instead of `Server::kick`, which needs the client locked and so cannot reach
+    /// the client the handler runs for.
+    ///
+    /// Example:
+    /// ```rs
+    /// server.event("on_message", Box::new(|ctx: &mut Context, event: Event| {
+    ///     if let Event::Message(message) = event {
+    ///         if message.content.contains("spam") {
+    ///             let nick = ctx.nick();
+    ///             let _ = ctx.kick(&nick, "No spam");
+    ///         }
+    ///     }
+    /// }));
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `nick`: The nickname of the client to kick.
+    /// * `reason`: Why, sent to the client.
+    pub fn kick(&mut self, nick: &str, reason: &str) -> Result<(), Error> {
+        let address = self
+            .server
+            .nicks
+            .get(&NameRules::key(nick))
+            .ok_or_else(|| Error::new("No such nick"))?;
+
+        if address == self.client.socket.address.to_string() {
+            self.server.kick(self.client, reason);
+        } else if let Some(entry) = self.server.clients.get(&address) {
+            self.server.kick_entry(&entry, nick, reason);
+        }
+
+        Ok(())
+    }
+
+    /// Ban a mask and kick the connected clients matching it, the client the event is about
+    /// included, see `Server::ban`
+    ///
+    /// Arguments:
+    ///
+    /// * `mask`: A `BanMask`, e.g. `*@10.0.0.0/8` or `spammer*`.
+    /// * `reason`: Why, sent to the matching clients.
+    pub fn ban(&mut self, mask: &str, reason: &str) -> Result<(), Error> {
+        self.server.ban_with(mask, reason, Some(&mut *self.client))
+    }
+}
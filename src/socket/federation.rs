@@ -0,0 +1,1034 @@
+use crate::auth::constant_time_eq;
+use crate::command::CommandParser;
+use crate::extensions::names::NameRules;
+use crate::extensions::string::StringExtension;
+use crate::protoutils::{BakaMessage, Hello, MessageKind, Welcome};
+use crate::socket::{
+    BoxEvent, ClientEntry, ClientState, DisconnectReason, Error, Frame, History, Outbound,
+    OverflowPolicy, Server, ServerBuilder, Socket,
+};
+use crate::trace::event;
+use crate::Scheduler;
+
+use hmac::{Hmac, Mac};
+use protobuf::Message;
+use sha2::Sha256;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::TcpStream;
+use std::sync::{mpsc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Capability a server requests in its `Hello` when it opens a link
+pub const LINK_CAPABILITY: &str = "link";
+
+/// How long an outgoing link waits before reconnecting after it dropped, by `Server::clock`
+pub const LINK_RETRY: Duration = Duration::from_secs(5);
+
+/// Number of frames a link's outbound queue holds, a link that falls further behind is
+/// dropped and bursts again once it is back
+pub const LINK_QUEUE_CAPACITY: usize = 4096;
+
+/// Length of the random challenges exchanged when linking
+const CHALLENGE_LENGTH: usize = 32;
+
+/// Number of recent frame ids remembered to drop frames that already passed through
+const SEEN_CAPACITY: usize = 4096;
+
+/// ## Link
+///
+/// A connection to a neighbouring server.
+///
+/// Properties:
+///
+/// * `name`: The neighbour's server name.
+/// * `outbound`: The outbound queue of the connection.
+#[derive(Clone)]
+pub struct Link {
+    pub name: String,
+    pub outbound: Outbound,
+}
+
+/// ## RemoteUser
+///
+/// A client connected to another server of the network.
+///
+/// Properties:
+///
+/// * `nick`: The client's nickname.
+/// * `server`: Name of the server the client is connected to.
+/// * `link`: Name of the neighbour the client is reached through.
+/// * `channels`: Channels the client is a member of, as its server spelled them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteUser {
+    pub nick: String,
+    pub server: String,
+    pub link: String,
+    pub channels: HashSet<String>,
+}
+
+impl RemoteUser {
+    /// Check whether the client is a member of a channel, ignoring case
+    pub fn is_member(&self, channel: &str) -> bool {
+        let key = NameRules::key(channel);
+
+        self.channels.iter().any(|c| NameRules::key(c) == key)
+    }
+}
+
+/// ## Federation
+///
+/// State of a server that is part of a network of linked servers.
+///
+/// Servers exchange frames over their links: `NICK {nick}`, `QUIT {nick...} :reason`,
+/// `JOIN {nick #channel}` and `PART {nick #channel}` authored by the server the client is
+/// connected to, plus the channel and private chat messages themselves. Every frame is
+/// forwarded to every other link, and a frame whose id was already seen is dropped, so a
+/// network with a cycle does not loop forever. Ids are assigned by the server a message
+/// entered the network on. When a link drops, the clients reached through it leave their
+/// channels and the split is announced to the rest of the network.
+///
+/// Links authenticate with a challenge: each side sends a random challenge and the other
+/// answers with an HMAC-SHA256 of it keyed with the shared secret, so the secret itself never
+/// crosses the network. Failed attempts count against `Server::throttle` like failed logins.
+/// A link has its own outbound queue of `LINK_QUEUE_CAPACITY` frames under
+/// `OverflowPolicy::Disconnect`, so a link that cannot keep up splits instead of silently
+/// losing frames.
+///
+/// A server that learns of a nickname it already knows from another server answers with
+/// `KILL {nick server} :reason`, and that server disconnects its client. When two clients
+/// with the same nickname meet as their servers link, both are disconnected, since neither
+/// side can tell which one came first.
+///
+/// Properties:
+///
+/// * `name`: This server's name, unique in the network.
+/// * `secret`: Shared secret every server of the network links with.
+/// * `links`: Established links by neighbour name.
+/// * `remote`: Clients of other servers by `NameRules::key` of their nickname.
+/// * `seen`: Ids of recent frames, oldest first.
+pub struct Federation {
+    name: String,
+    secret: String,
+    links: RwLock<HashMap<String, Link>>,
+    remote: RwLock<HashMap<String, RemoteUser>>,
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl Federation {
+    /// Initialize new instance of the `Federation`
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: This server's name, unique in the network.
+    /// * `secret`: Shared secret every server of the network links with.
+    pub fn new(name: &str, secret: &str) -> Self {
+        Federation {
+            name: name.to_string(),
+            secret: secret.to_string(),
+            links: RwLock::new(HashMap::new()),
+            remote: RwLock::new(HashMap::new()),
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+        }
+    }
+
+    /// Get this server's name
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Get the names of the linked neighbours
+    pub fn links(&self) -> Vec<String> {
+        self.links.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Get every client of the other servers
+    pub fn remote_users(&self) -> Vec<RemoteUser> {
+        self.remote.read().unwrap().values().cloned().collect()
+    }
+
+    /// Find a client of another server by nickname, ignoring case
+    pub fn locate(&self, nick: &str) -> Option<RemoteUser> {
+        self.remote
+            .read()
+            .unwrap()
+            .get(&NameRules::key(nick))
+            .cloned()
+    }
+
+    /// Remember a frame id, returns `true` if it was already seen
+    fn seen(&self, id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let (ids, order) = &mut *seen;
+
+        if !ids.insert(id.to_string()) {
+            return true;
+        }
+
+        order.push_back(id.to_string());
+
+        if order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+
+        false
+    }
+
+    /// Answer to a challenge, proving this server knows the shared secret
+    ///
+    /// Arguments:
+    ///
+    /// * `step`: `LINK` or `LINKED`, so an answer cannot be replayed for the other step.
+    /// * `challenge`: The challenge the other side sent.
+    /// * `name`: Name of the answering server.
+    fn proof(&self, step: &str, challenge: &str, name: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC takes keys of any length");
+
+        mac.update(format!("{} {} {}", step, challenge, name).as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Check an answer to a challenge this server sent
+    fn verify(&self, proof: &str, step: &str, challenge: &str, name: &str) -> bool {
+        !challenge.is_empty()
+            && constant_time_eq(
+                proof.as_bytes(),
+                self.proof(step, challenge, name).as_bytes(),
+            )
+    }
+
+    fn add_link(&self, name: &str, outbound: Outbound) -> Result<(), Error> {
+        let mut links = self.links.write().unwrap();
+
+        if name == self.name || links.contains_key(name) {
+            return Err(Error::new(&format!("Server {} is already linked", name)));
+        }
+
+        links.insert(
+            name.to_string(),
+            Link {
+                name: name.to_string(),
+                outbound: outbound,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Drop a link, returns the clients that were reached through it
+    fn remove_link(&self, name: &str) -> Vec<RemoteUser> {
+        self.links.write().unwrap().remove(name);
+
+        let mut remote = self.remote.write().unwrap();
+        let split: Vec<RemoteUser> = remote
+            .values()
+            .filter(|user| user.link == name)
+            .cloned()
+            .collect();
+
+        for user in &split {
+            remote.remove(&NameRules::key(&user.nick));
+        }
+
+        split
+    }
+
+    /// Queue a frame for one neighbour
+    fn send(&self, link: &str, message: &BakaMessage) {
+        if let Some(link) = self.links.read().unwrap().get(link) {
+            let _ = link
+                .outbound
+                .push(message.clone().build().write_to_bytes().unwrap());
+        }
+    }
+
+    /// Queue a frame for every neighbour except the one it came from
+    fn flood(&self, message: &BakaMessage, except: Option<&str>) {
+        for link in self.links.read().unwrap().values() {
+            if Some(link.name.as_str()) != except {
+                let _ = link
+                    .outbound
+                    .push(message.clone().build().write_to_bytes().unwrap());
+            }
+        }
+    }
+
+    /// Encode the known clients for a new neighbour, one `nick server #a,#b` line each
+    fn burst(&self, server: &Server, except: &str) -> String {
+        let mut lines = vec![];
+
+        for (nick, presence) in server.presence.online() {
+            let mut channels = server.channels_of(&presence.address);
+            channels.sort();
+
+            lines.push(format!(
+                "{} {} {}",
+                nick,
+                self.name,
+                Federation::list(&channels)
+            ));
+        }
+
+        for user in self.remote.read().unwrap().values() {
+            if user.link != except {
+                let mut channels: Vec<String> = user.channels.iter().cloned().collect();
+                channels.sort();
+
+                lines.push(format!(
+                    "{} {} {}",
+                    user.nick,
+                    user.server,
+                    Federation::list(&channels)
+                ));
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    fn list(channels: &Vec<String>) -> String {
+        if channels.is_empty() {
+            "-".to_string()
+        } else {
+            channels.join(",")
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Open the links set with `ServerBuilder::link`, reconnecting `LINK_RETRY` after one
+    /// drops or cannot be opened
+    pub(crate) fn connect_links(&self) {
+        if self.server.federation.is_none() {
+            return;
+        }
+
+        for address in self.links.clone() {
+            let events = self.events.clone();
+            let mut server = self.server.clone();
+
+            thread::spawn(move || {
+                // Follows `Server::clock`, so tests can retry without waiting
+                let timers = Scheduler::with_clock(server.clock.clone());
+
+                loop {
+                    if let Ok(stream) = TcpStream::connect(&address) {
+                        ServerBuilder::open_link(&events, &mut server, Socket::from(stream));
+                    }
+
+                    let (retry, wait) = mpsc::channel();
+
+                    timers.set_timeout(
+                        LINK_RETRY,
+                        Box::new(move |_| {
+                            let _ = retry.send(());
+                        }),
+                    );
+
+                    let _ = wait.recv();
+                }
+            });
+        }
+    }
+
+    /// Run an outgoing link on the calling thread until it drops
+    fn open_link(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        server: &mut Server,
+        mut socket: Socket,
+    ) {
+        let federation = server.federation.clone().unwrap();
+
+        socket.set_max_frame_size(Some(server.max_link_message_size));
+
+        let peer = socket.address.to_string();
+        let entry = ServerBuilder::accept(server, &socket);
+
+        {
+            let mut client = entry.client.lock().unwrap();
+
+            client.state = ClientState::Linking;
+            client.add_flag("link_outgoing", "true");
+        }
+
+        entry
+            .outbound
+            .reconfigure(LINK_QUEUE_CAPACITY, OverflowPolicy::Disconnect);
+
+        ServerBuilder::reply(
+            &entry,
+            Hello::new(vec![LINK_CAPABILITY.to_string()]).to_message(federation.name()),
+        );
+
+        loop {
+            match socket.read_frame() {
+                Ok(Some(frame @ Frame::Message(_))) => {
+                    ServerBuilder::receive(events, server, &entry, &peer, frame);
+                }
+                result => {
+                    let reason = match result {
+                        Ok(Some(frame)) => {
+                            // Nothing after an oversized frame can be read
+                            ServerBuilder::receive(events, server, &entry, &peer, frame);
+
+                            DisconnectReason::Closed
+                        }
+                        Err(e) => DisconnectReason::Error(e.message().to_string()),
+                        Ok(None) => DisconnectReason::Closed,
+                    };
+
+                    ServerBuilder::close(events, server, &entry, &peer, reason);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Start the link handshake on an accepted connection that asked for the link capability,
+    /// called once it was welcomed
+    pub(crate) fn challenge(server: &Server, entry: &ClientEntry) {
+        let federation = server.federation.clone().unwrap();
+        let challenge = String::random_secure(CHALLENGE_LENGTH);
+
+        {
+            let mut client = entry.client.lock().unwrap();
+
+            client.state = ClientState::Linking;
+            client.add_flag("link_challenge", &challenge);
+        }
+
+        entry
+            .outbound
+            .reconfigure(LINK_QUEUE_CAPACITY, OverflowPolicy::Disconnect);
+
+        ServerBuilder::reply(
+            entry,
+            BakaMessage::new(
+                MessageKind::Command,
+                federation.name(),
+                &format!("CHALLENGE :{}", challenge),
+            ),
+        );
+    }
+
+    /// Handle a frame on a link that is not established yet
+    ///
+    /// The opening side sends `HELLO` with the `link` capability and the accepting side
+    /// answers `WELCOME` and `CHALLENGE :challenge`. The opening side sends
+    /// `LINK {name} :proof challenge`, proving it knows the secret and challenging back. The
+    /// accepting side checks the proof and answers `LINKED {name} :proof` followed by its
+    /// burst, then the opening side checks that proof and answers with its own `BURST`.
+    pub(crate) fn linking(server: &mut Server, entry: &ClientEntry, message: BakaMessage) {
+        let federation = server.federation.clone().unwrap();
+        let mut lines = message.content.lines();
+        let mut parser = CommandParser::new(lines.next().unwrap_or("").to_string());
+        let name = parser.args().first().cloned().unwrap_or_default();
+        let (outgoing, challenge, ip) = {
+            let client = entry.client.lock().unwrap();
+
+            (
+                client.flags.contains_key("link_outgoing"),
+                client
+                    .flags
+                    .get("link_challenge")
+                    .cloned()
+                    .unwrap_or_default(),
+                client.socket.address.ip().to_string(),
+            )
+        };
+
+        if message.kind == MessageKind::Error {
+            entry.outbound.close();
+            return;
+        }
+
+        let linked = match parser.command() {
+            "WELCOME" if outgoing => {
+                let welcome = Welcome::parse(&message);
+
+                if !welcome.is_ok_and(|w| w.has_capability(LINK_CAPABILITY)) {
+                    entry.outbound.close();
+                }
+                return;
+            }
+            "CHALLENGE" if outgoing => {
+                let answer = String::random_secure(CHALLENGE_LENGTH);
+
+                entry
+                    .client
+                    .lock()
+                    .unwrap()
+                    .add_flag("link_challenge", &answer);
+
+                ServerBuilder::reply(
+                    entry,
+                    BakaMessage::new(
+                        MessageKind::Command,
+                        federation.name(),
+                        &format!(
+                            "LINK {{{}}} :{} {}",
+                            federation.name(),
+                            federation.proof("LINK", parser.text(), federation.name()),
+                            answer
+                        ),
+                    ),
+                );
+                return;
+            }
+            "LINK" if !outgoing => {
+                let text = parser.text().to_string();
+                let (proof, answer) = text.split_once(' ').unwrap_or((text.as_str(), ""));
+
+                if let Err(e) = server.throttle.check(&ip) {
+                    Err(e)
+                } else if !federation.verify(proof, "LINK", &challenge, &name) {
+                    server.throttle.failure(&ip);
+
+                    Err(Error::new("Invalid link secret"))
+                } else {
+                    server.throttle.success(&ip);
+
+                    federation.add_link(&name, entry.outbound.clone()).map(|_| {
+                        // Before `LINKED`, the burst answering it may be larger than any
+                        // client message
+                        entry
+                            .client
+                            .lock()
+                            .unwrap()
+                            .socket
+                            .set_max_frame_size(Some(server.max_link_message_size));
+
+                        ServerBuilder::reply(
+                            entry,
+                            BakaMessage::new(
+                                MessageKind::Command,
+                                federation.name(),
+                                &format!(
+                                    "LINKED {{{}}} :{}\n{}",
+                                    federation.name(),
+                                    federation.proof("LINKED", answer, federation.name()),
+                                    federation.burst(server, &name)
+                                ),
+                            ),
+                        );
+                    })
+                }
+            }
+            "LINKED" if outgoing => {
+                if !federation.verify(parser.text(), "LINKED", &challenge, &name) {
+                    Err(Error::new("Invalid link secret"))
+                } else {
+                    federation.add_link(&name, entry.outbound.clone()).map(|_| {
+                        ServerBuilder::reply(
+                            entry,
+                            BakaMessage::new(
+                                MessageKind::Command,
+                                federation.name(),
+                                &format!("BURST\n{}", federation.burst(server, &name)),
+                            ),
+                        );
+                    })
+                }
+            }
+            _ => Err(Error::new("Expected LINK")),
+        };
+
+        match linked {
+            Ok(()) => {
+                event!(info, link = %name, "server linked");
+
+                {
+                    let mut client = entry.client.lock().unwrap();
+
+                    client.add_flag("link", &name);
+                    client.state = ClientState::Link;
+                }
+
+                if parser.command() == "LINKED" {
+                    ServerBuilder::apply_burst(server, &name, &message);
+                }
+            }
+            Err(e) => {
+                event!(warn, error = %e, "link refused");
+
+                ServerBuilder::fail(server, entry, "LINK_FAILED", &e);
+                entry.outbound.close();
+            }
+        }
+    }
+
+    /// Handle a frame from an established link
+    pub(crate) fn link_frame(server: &mut Server, link: &str, message: BakaMessage) {
+        let federation = server.federation.clone().unwrap();
+
+        if federation.seen(&message.id) {
+            return;
+        }
+
+        if message.kind == MessageKind::Chat {
+            let target = message.target.clone().unwrap_or_default();
+
+            if target.starts_with('#') {
+                let _ = server.history.record(&History::channel(&target), &message);
+
+                server.send_to_channel(&target, &message, None);
+                ServerBuilder::relay_channel(server, &message, Some(link));
+            } else if let Some(recipient) = server.find(&target) {
+                // Private history is only kept between logged-in users of this server
+                ServerBuilder::reply(&recipient, message);
+            } else if let Some(user) = federation.locate(&target) {
+                if user.link != link {
+                    federation.send(&user.link, &message);
+                }
+            }
+
+            return;
+        }
+
+        let first = message.content.lines().next().unwrap_or("").to_string();
+        let mut parser = CommandParser::new(first);
+        let args = parser.args();
+        let nick = args.first().cloned().unwrap_or_default();
+        let channel = args.get(1).cloned().unwrap_or_default();
+
+        match parser.command() {
+            "NICK" => {
+                ServerBuilder::remote_nick(server, link, &nick, &message.author);
+            }
+            "QUIT" => {
+                for nick in &args {
+                    ServerBuilder::remote_quit(server, nick, &message.author);
+                }
+            }
+            "JOIN" => ServerBuilder::remote_join(server, &nick, &message.author, &channel),
+            "PART" => ServerBuilder::remote_part(server, &nick, &message.author, &channel),
+            "KILL" => ServerBuilder::remote_kill(server, &nick, &channel, parser.text()),
+            "BURST" => ServerBuilder::apply_burst(server, link, &message),
+            _ => return,
+        }
+
+        federation.flood(&message, Some(link));
+    }
+
+    /// Add the clients listed in a `LINKED` or `BURST` frame
+    fn apply_burst(server: &mut Server, link: &str, message: &BakaMessage) {
+        for line in message.content.lines().skip(1) {
+            let fields: Vec<&str> = line.split(' ').collect();
+
+            if let [nick, origin, channels] = fields.as_slice() {
+                if !ServerBuilder::remote_nick(server, link, nick, origin) {
+                    continue;
+                }
+
+                for channel in channels.split(',').filter(|c| *c != "-") {
+                    ServerBuilder::remote_join(server, nick, origin, channel);
+                }
+            }
+        }
+
+        if message.content.starts_with("LINKED") {
+            let federation = server.federation.clone().unwrap();
+            let mut burst = message.clone();
+
+            burst.content = message.content.replacen("LINKED", "BURST", 1);
+            burst.id = String::generate_id();
+
+            federation.seen(&burst.id);
+            federation.flood(&burst, Some(link));
+        }
+    }
+
+    /// Add a client of another server, returns `false` if the nickname is already taken by a
+    /// client of this or a third server, in which case the newcomer's server is told to
+    /// disconnect it
+    fn remote_nick(server: &mut Server, link: &str, nick: &str, origin: &str) -> bool {
+        let federation = server.federation.clone().unwrap();
+
+        if server.find(nick).is_none() {
+            let mut remote = federation.remote.write().unwrap();
+            let key = NameRules::key(nick);
+
+            match remote.get(&key) {
+                Some(user) if user.server != origin => {}
+                Some(_) => return true,
+                None => {
+                    remote.insert(
+                        key,
+                        RemoteUser {
+                            nick: nick.to_string(),
+                            server: origin.to_string(),
+                            link: link.to_string(),
+                            channels: HashSet::new(),
+                        },
+                    );
+                    return true;
+                }
+            }
+        }
+
+        event!(warn, nick = %nick, server = %origin, "nickname collision");
+
+        let kill = BakaMessage::new(
+            MessageKind::Command,
+            federation.name(),
+            &format!("KILL {{{} {}}} :Nickname collision", nick, origin),
+        );
+
+        federation.seen(&kill.id);
+        federation.send(link, &kill);
+
+        false
+    }
+
+    /// Disconnect a client of this server that collided with a client of another server
+    fn remote_kill(server: &mut Server, nick: &str, origin: &str, reason: &str) {
+        let federation = server.federation.clone().unwrap();
+
+        if origin != federation.name() {
+            return;
+        }
+
+        if let Some(entry) = server.find(nick) {
+            server.kick(&mut entry.client.lock().unwrap(), reason);
+        }
+    }
+
+    fn remote_quit(server: &mut Server, nick: &str, origin: &str) {
+        let federation = server.federation.clone().unwrap();
+        let user = {
+            let mut remote = federation.remote.write().unwrap();
+            let key = NameRules::key(nick);
+
+            match remote.get(&key) {
+                Some(user) if user.server == origin => remote.remove(&key),
+                _ => None,
+            }
+        };
+
+        if let Some(user) = user {
+            ServerBuilder::remote_left(server, &user);
+        }
+    }
+
+    /// Tell the local members of a remote client's channels that it left
+    fn remote_left(server: &mut Server, user: &RemoteUser) {
+        for channel in &user.channels {
+            server.send_to_channel(
+                channel,
+                &ServerBuilder::channel_notice(server, MessageKind::Part, &user.nick, channel),
+                None,
+            );
+        }
+    }
+
+    fn remote_join(server: &mut Server, nick: &str, origin: &str, channel: &str) {
+        let federation = server.federation.clone().unwrap();
+        let joined = match federation
+            .remote
+            .write()
+            .unwrap()
+            .get_mut(&NameRules::key(nick))
+        {
+            Some(user) if user.server == origin && !user.is_member(channel) => {
+                user.channels.insert(channel.to_string())
+            }
+            _ => false,
+        };
+
+        if joined {
+            server.send_to_channel(
+                channel,
+                &ServerBuilder::channel_notice(server, MessageKind::Join, nick, channel),
+                None,
+            );
+        }
+    }
+
+    fn remote_part(server: &mut Server, nick: &str, origin: &str, channel: &str) {
+        let federation = server.federation.clone().unwrap();
+        let parted = match federation
+            .remote
+            .write()
+            .unwrap()
+            .get_mut(&NameRules::key(nick))
+        {
+            Some(user) if user.server == origin && user.is_member(channel) => {
+                let key = NameRules::key(channel);
+
+                user.channels.retain(|c| NameRules::key(c) != key);
+                true
+            }
+            _ => false,
+        };
+
+        if parted {
+            server.send_to_channel(
+                channel,
+                &ServerBuilder::channel_notice(server, MessageKind::Part, nick, channel),
+                None,
+            );
+        }
+    }
+
+    /// Handle a dropped link: its clients leave their channels and the split is announced
+    pub(crate) fn split(server: &mut Server, link: &str) {
+        let federation = server.federation.clone().unwrap();
+        let split = federation.remove_link(link);
+
+        event!(warn, link = %link, clients = split.len(), "link dropped");
+
+        if split.is_empty() {
+            return;
+        }
+
+        for user in &split {
+            ServerBuilder::remote_left(server, user);
+        }
+
+        let mut origins: HashMap<String, Vec<String>> = HashMap::new();
+
+        for user in split {
+            origins.entry(user.server).or_default().push(user.nick);
+        }
+
+        // Each server's clients quit on its behalf, other servers only drop their own clients
+        for (origin, nicks) in origins {
+            ServerBuilder::announce_as(
+                server,
+                &origin,
+                &format!(
+                    "QUIT {{{}}} :Netsplit {} {}",
+                    nicks.join(" "),
+                    federation.name(),
+                    link
+                ),
+            );
+        }
+    }
+
+    /// Send a frame about a local client to the whole network
+    pub(crate) fn announce(server: &Server, content: &str) {
+        if let Some(federation) = &server.federation {
+            ServerBuilder::announce_as(server, federation.name(), content);
+        }
+    }
+
+    /// Send a frame about a client of `origin` to the whole network
+    fn announce_as(server: &Server, origin: &str, content: &str) {
+        if let Some(federation) = &server.federation {
+            let message = BakaMessage::new(MessageKind::Command, origin, content);
+
+            federation.seen(&message.id);
+            federation.flood(&message, None);
+        }
+    }
+
+    /// Forward a channel message to the neighbours that reach members of the channel
+    pub(crate) fn relay_channel(server: &Server, message: &BakaMessage, except: Option<&str>) {
+        let federation = match &server.federation {
+            Some(federation) => federation,
+            None => return,
+        };
+
+        let channel = message.target.clone().unwrap_or_default();
+        let links: HashSet<String> = federation
+            .remote
+            .read()
+            .unwrap()
+            .values()
+            .filter(|user| user.is_member(&channel))
+            .map(|user| user.link.clone())
+            .collect();
+
+        federation.seen(&message.id);
+
+        for link in links {
+            if Some(link.as_str()) != except {
+                federation.send(&link, message);
+            }
+        }
+    }
+
+    /// Forward a private message to the server of a remote client, returns `false` if the
+    /// nickname is unknown in the network
+    pub(crate) fn relay_private(server: &Server, message: &BakaMessage) -> bool {
+        let federation = match &server.federation {
+            Some(federation) => federation,
+            None => return false,
+        };
+
+        match federation.locate(&message.target.clone().unwrap_or_default()) {
+            Some(user) => {
+                federation.seen(&message.id);
+                federation.send(&user.link, message);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Server {
+    /// Get the server's name in the network, its address when it is not part of one
+    pub fn name(&self) -> String {
+        self.federation
+            .as_ref()
+            .map(|f| f.name().to_string())
+            .unwrap_or_else(|| self.address.to_string())
+    }
+
+    /// Find a client anywhere in the network, returns the name of its server
+    pub fn locate(&self, nick: &str) -> Option<String> {
+        if self.find(nick).is_some() {
+            return Some(self.name());
+        }
+
+        self.federation
+            .as_ref()
+            .and_then(|f| f.locate(nick))
+            .map(|user| user.server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::testing::{self, expect, join};
+    use crate::MockClock;
+
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    /// Build a server of the network, opening a link to `link`
+    fn server(address: &str, name: &str, secret: &str, link: Option<&str>) -> ServerBuilder {
+        let mut server = ServerBuilder::new(address);
+
+        server.federation(name, secret);
+
+        if let Some(link) = link {
+            server.link(link);
+        }
+
+        server
+    }
+
+    fn links(server: &Server) -> Vec<String> {
+        server.federation.as_ref().unwrap().links()
+    }
+
+    #[test]
+    fn servers_form_one_network() {
+        let a = server("127.0.0.1:0", "a", "secret", None);
+        let a_handle = a.server.clone();
+        let a_address = testing::start(a);
+        let b_address = testing::start(server("127.0.0.1:0", "b", "secret", Some(&a_address)));
+        let c = server("127.0.0.1:0", "c", "secret", Some(&b_address));
+        let c_handle = c.server.clone();
+        let c_address = testing::start(c);
+
+        let (mut alice, alice_inbox) = join(&a_address, "alice");
+        let (mut bob, bob_inbox) = join(&c_address, "bob");
+
+        testing::wait_for(|| {
+            a_handle.locate("bob").is_some() && c_handle.locate("alice").is_some()
+        });
+
+        alice
+            .send_message(BakaMessage::new(
+                MessageKind::Command,
+                "alice",
+                "WHOIS {bob}",
+            ))
+            .unwrap();
+
+        expect(&alice_inbox, |m| {
+            m.content == "WHOIS_REPLY {bob remote c -} :"
+        });
+
+        bob.send_message(BakaMessage::new(MessageKind::Command, "bob", "WHO"))
+            .unwrap();
+
+        expect(&bob_inbox, |m| m.content == "WHO_REPLY {alice 0 here a} :");
+
+        alice
+            .send_message(BakaMessage::new(MessageKind::Chat, "alice", "hi bob").with_target("bob"))
+            .unwrap();
+
+        let message = expect(&bob_inbox, |m| m.kind == MessageKind::Chat);
+
+        assert_eq!(message.author, "alice");
+        assert_eq!(message.content, "hi bob");
+    }
+
+    #[test]
+    fn nick_collisions_disconnect_both_clients() {
+        // `d` first reaches a listener that hangs up and retries after `LINK_RETRY` by its
+        // clock, so both servers already have a client called carol when they link
+        let clock = Arc::new(MockClock::new());
+        let refusing = TcpListener::bind("127.0.0.1:0").unwrap();
+        let e_address = refusing.local_addr().unwrap().to_string();
+        let mut d = server("127.0.0.1:0", "d", "secret", Some(&e_address));
+        let d_handle = d.server.clone();
+
+        d.clock(clock.clone());
+        d.handshake_timeout(None);
+
+        let d_address = testing::start(d);
+
+        drop(refusing.accept().unwrap());
+        drop(refusing);
+
+        let (_d, d_inbox) = join(&d_address, "carol");
+        let e = server(&e_address, "e", "secret", None);
+        let e_handle = e.server.clone();
+
+        testing::start(e);
+
+        let (_e, e_inbox) = join(&e_address, "carol");
+
+        testing::wait_for(|| d_handle.find("carol").is_some() && e_handle.find("carol").is_some());
+        testing::wait_for(|| {
+            clock.advance(LINK_RETRY);
+
+            !links(&d_handle).is_empty()
+        });
+
+        let kicked = |m: &BakaMessage| m.content == "KICKED :Nickname collision";
+
+        expect(&e_inbox, kicked);
+        expect(&d_inbox, kicked);
+    }
+
+    #[test]
+    fn refuses_links_with_the_wrong_secret() {
+        let f = server("127.0.0.1:0", "f", "secret", None);
+        let f_handle = f.server.clone();
+        let f_address = testing::start(f);
+
+        testing::start(server("127.0.0.1:0", "g", "guess", Some(&f_address)));
+        testing::wait_for(|| f_handle.throttle.len() == 1);
+
+        assert!(links(&f_handle).is_empty());
+    }
+
+    #[test]
+    fn proofs_depend_on_the_secret_step_and_challenge() {
+        let federation = Federation::new("a", "secret");
+        let proof = federation.proof("LINK", "challenge", "b");
+
+        assert!(federation.verify(&proof, "LINK", "challenge", "b"));
+        assert!(!federation.verify(&proof, "LINKED", "challenge", "b"));
+        assert!(!federation.verify(&proof, "LINK", "other", "b"));
+        assert!(!federation.verify(&proof, "LINK", "challenge", "c"));
+        assert!(!Federation::new("a", "guess").verify(&proof, "LINK", "challenge", "b"));
+        assert!(!federation.verify(&federation.proof("LINK", "", "b"), "LINK", "", "b"));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::extensions::names::NameRules;
+use crate::lagerung::Lagerung;
+use crate::protoutils::BakaMessage;
+use crate::socket::Error;
+
+use protobuf::Message;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Default number of messages kept per conversation
+pub const DEFAULT_HISTORY_CAPACITY: usize = 100;
+
+/// Default number of messages replayed to a client joining a channel
+pub const DEFAULT_HISTORY_REPLAY: usize = 20;
+
+const KEY_PREFIX: &str = "history:";
+
+/// ## HistoryQuery
+///
+/// Which part of a conversation to return.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HistoryQuery {
+    /// The last N messages
+    Last(usize),
+    /// Every message after the one with this id, or the whole buffer if the id is unknown
+    SinceId(String),
+    /// Every message with a timestamp after this one, in milliseconds since the Unix epoch
+    SinceTimestamp(u64),
+}
+
+/// ## History
+///
+/// Bounded message history per channel and per private conversation, kept as ring buffers and
+/// optionally written through to a file-backed `Lagerung`.
+///
+/// Every recorded message is appended to the file as its own pair, and a message dropped from
+/// a full buffer is appended as a removal, so recording costs the same however long the
+/// history is. The file is rewritten once removed and replaced pairs outnumber the live ones.
+///
+/// Properties:
+///
+/// * `capacity`: Number of messages kept per conversation.
+/// * `conversations`: The ring buffers, keyed by channel name or `History::conversation`.
+/// * `store`: Persistent storage, `None` for memory only.
+pub struct History {
+    capacity: usize,
+    conversations: Mutex<HashMap<String, VecDeque<BakaMessage>>>,
+    store: Option<Mutex<Lagerung>>,
+}
+
+/// Lines appended to the file beyond the live pairs before it is rewritten
+const COMPACT_SLACK: usize = 1024;
+
+/// Key of a message in the `Lagerung`
+fn key(conversation: &str, message: &BakaMessage) -> String {
+    format!("{}{}:{}", KEY_PREFIX, conversation, message.id)
+}
+
+fn encode(message: &BakaMessage) -> String {
+    message
+        .clone()
+        .build()
+        .write_to_bytes()
+        .unwrap()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode(hex: &str) -> Option<BakaMessage> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    BakaMessage::parse(bytes.as_slice()).ok()
+}
+
+impl History {
+    /// Initialize new in-memory instance of the `History`
+    ///
+    /// Arguments:
+    ///
+    /// * `capacity`: Number of messages kept per conversation.
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity: capacity,
+            conversations: Mutex::new(HashMap::new()),
+            store: None,
+        }
+    }
+
+    /// Initialize new instance of the `History` persisted in a `Lagerung`
+    ///
+    /// Conversations already in the storage are loaded, and the storage is rewritten with
+    /// just the messages that fit the capacity.
+    ///
+    /// Example:
+    /// ```rs
+    /// let history = History::persistent(100, Lagerung::open("history.db")?);
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `capacity`: Number of messages kept per conversation.
+    /// * `store`: Where the conversations are written.
+    pub fn persistent(capacity: usize, mut store: Lagerung) -> Self {
+        let mut conversations: HashMap<String, VecDeque<BakaMessage>> = HashMap::new();
+
+        for stored in store.keys(KEY_PREFIX) {
+            let message = decode(store.get(&stored).unwrap_or(""));
+
+            match (stored[KEY_PREFIX.len()..].rsplit_once(':'), message) {
+                (Some((conversation, id)), Some(message)) if message.id == id => conversations
+                    .entry(conversation.to_string())
+                    .or_insert_with(VecDeque::new)
+                    .push_back(message),
+                _ => {}
+            }
+
+            store.remove(&stored);
+        }
+
+        for (conversation, messages) in conversations.iter_mut() {
+            messages.make_contiguous().sort_by(|a, b| a.id.cmp(&b.id));
+
+            while messages.len() > capacity {
+                messages.pop_front();
+            }
+
+            for message in messages.iter() {
+                store.add(&key(conversation, message), &encode(message));
+            }
+        }
+
+        let _ = store.save();
+
+        History {
+            capacity: capacity,
+            conversations: Mutex::new(conversations),
+            store: Some(Mutex::new(store)),
+        }
+    }
+
+    /// Key of a channel's history, the same however the channel name is capitalized
+    pub fn channel(name: &str) -> String {
+        NameRules::key(name)
+    }
+
+    /// Key of the private conversation between two logged-in users, the same in both
+    /// directions and however the user names are capitalized
+    ///
+    /// The first user name is prefixed with its length, so names containing spaces cannot
+    /// collide.
+    pub fn conversation(a: &str, b: &str) -> String {
+        let (a, b) = (NameRules::key(a), NameRules::key(b));
+        let (a, b) = if a < b { (a, b) } else { (b, a) };
+
+        format!("{} {} {}", a.len(), a, b)
+    }
+
+    /// Append a message, dropping the oldest one when the buffer is full
+    ///
+    /// Arguments:
+    ///
+    /// * `conversation`: Channel name or `History::conversation` key.
+    /// * `message`: The message to record.
+    pub fn record(&self, conversation: &str, message: &BakaMessage) -> Result<(), Error> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+
+        let dropped = {
+            let mut conversations = self.conversations.lock().unwrap();
+            let messages = conversations
+                .entry(conversation.to_string())
+                .or_insert_with(VecDeque::new);
+
+            let dropped = if messages.len() >= self.capacity {
+                messages.pop_front()
+            } else {
+                None
+            };
+
+            messages.push_back(message.clone());
+            dropped
+        };
+
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        let mut store = store.lock().unwrap();
+
+        store.append(&key(conversation, message), &encode(message))?;
+
+        if let Some(dropped) = dropped {
+            store.delete(&key(conversation, &dropped))?;
+        }
+
+        if store.appended() > store.len() + COMPACT_SLACK {
+            store.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Get messages of a conversation, oldest first
+    ///
+    /// Arguments:
+    ///
+    /// * `conversation`: Channel name or `History::conversation` key.
+    /// * `query`: Which messages to return.
+    pub fn query(&self, conversation: &str, query: &HistoryQuery) -> Vec<BakaMessage> {
+        let conversations = self.conversations.lock().unwrap();
+        let messages = match conversations.get(conversation) {
+            Some(messages) => messages,
+            None => return vec![],
+        };
+
+        let skip = match query {
+            HistoryQuery::Last(n) => messages.len().saturating_sub(*n),
+            HistoryQuery::SinceId(id) => messages
+                .iter()
+                .position(|m| &m.id == id)
+                .map(|i| i + 1)
+                .unwrap_or(0),
+            HistoryQuery::SinceTimestamp(timestamp) => messages
+                .iter()
+                .position(|m| m.timestamp > *timestamp)
+                .unwrap_or(messages.len()),
+        };
+
+        messages.iter().skip(skip).cloned().collect()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_HISTORY_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::string::StringExtension;
+    use crate::protoutils::MessageKind;
+
+    use std::fs;
+
+    fn message(content: &str) -> BakaMessage {
+        BakaMessage::new(MessageKind::Chat, "alice", content).with_target("#rust")
+    }
+
+    fn contents(messages: Vec<BakaMessage>) -> Vec<String> {
+        messages.into_iter().map(|m| m.content).collect()
+    }
+
+    #[test]
+    fn keeps_the_last_messages() {
+        let history = History::new(3);
+
+        for content in ["a", "b", "c", "d"] {
+            history.record("#rust", &message(content)).unwrap();
+        }
+
+        assert_eq!(
+            contents(history.query("#rust", &HistoryQuery::Last(10))),
+            ["b", "c", "d"]
+        );
+        assert_eq!(
+            contents(history.query("#rust", &HistoryQuery::Last(1))),
+            ["d"]
+        );
+        assert!(history.query("#go", &HistoryQuery::Last(10)).is_empty());
+    }
+
+    #[test]
+    fn queries_since_an_id_or_timestamp() {
+        let history = History::new(10);
+        let mut messages = vec![];
+
+        for (i, content) in ["a", "b", "c"].into_iter().enumerate() {
+            let mut message = message(content);
+
+            message.timestamp = 1000 + i as u64;
+            history.record("#rust", &message).unwrap();
+            messages.push(message);
+        }
+
+        assert_eq!(
+            contents(history.query("#rust", &HistoryQuery::SinceId(messages[0].id.clone()))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            contents(history.query("#rust", &HistoryQuery::SinceId("unknown".to_string()))),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            contents(history.query("#rust", &HistoryQuery::SinceTimestamp(1001))),
+            ["c"]
+        );
+        assert!(history
+            .query("#rust", &HistoryQuery::SinceTimestamp(2000))
+            .is_empty());
+    }
+
+    #[test]
+    fn conversations_do_not_collide() {
+        assert_eq!(
+            History::conversation("alice", "bob"),
+            History::conversation("bob", "alice")
+        );
+        assert_ne!(
+            History::conversation("a|b", "c"),
+            History::conversation("a", "b|c")
+        );
+        assert_ne!(
+            History::conversation("a b", "c"),
+            History::conversation("a", "b c")
+        );
+        assert_eq!(
+            History::conversation("Alice", "bob"),
+            History::conversation("BOB", "alice")
+        );
+        assert_eq!(History::channel("#Rust"), History::channel("#rust"));
+    }
+
+    #[test]
+    fn persists_across_restarts() {
+        let path = std::env::temp_dir().join(format!("baka-history-{}.db", String::generate_id()));
+
+        {
+            let history = History::persistent(2, Lagerung::open(&path).unwrap());
+
+            for content in ["a", "b", "c"] {
+                history.record("#rust", &message(content)).unwrap();
+            }
+        }
+
+        let history = History::persistent(2, Lagerung::open(&path).unwrap());
+
+        assert_eq!(
+            contents(history.query("#rust", &HistoryQuery::Last(10))),
+            ["b", "c"]
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+}
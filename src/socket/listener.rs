@@ -0,0 +1,118 @@
+#[cfg(feature = "tls")]
+use crate::socket::TlsAcceptor;
+use crate::socket::{Error, Socket};
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// ## Listener
+///
+/// One of the addresses a `Server` accepts connections on.
+///
+/// Properties:
+///
+/// * `listener`: The TCP listener bound to the address.
+/// * `address`: The address listened on, with the port picked when bound to port 0.
+/// * `tls`: Encrypts the connections accepted here, plain TCP when `None`.
+#[derive(Clone)]
+pub struct Listener {
+    pub listener: Arc<Mutex<TcpListener>>,
+    pub address: SocketAddr,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsAcceptor>,
+}
+
+impl Listener {
+    /// Listen on an address for plain TCP connections
+    ///
+    /// Example:
+    /// ```rs
+    /// let listener = Listener::bind("0.0.0.0:65432")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address to listen on.
+    pub fn bind(address: &str) -> Result<Self, Error> {
+        let parsed = address
+            .parse::<SocketAddr>()
+            .map_err(|e| Error::new(&format!("Invalid address {}: {}", address, e)))?;
+        let listener = TcpListener::bind(parsed)
+            .map_err(|e| Error::new(&format!("Unable to bind {}: {}", address, e)))?;
+        // The bound port, when asked for any free one with port 0
+        let parsed = listener.local_addr().unwrap_or(parsed);
+
+        Ok(Listener {
+            listener: Arc::new(Mutex::new(listener)),
+            address: parsed,
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Listen on an address for TLS connections
+    ///
+    /// Example:
+    /// ```rs
+    /// let listener = Listener::bind_tls("0.0.0.0:6697", TlsAcceptor::from_pem("cert.pem", "key.pem")?)?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address to listen on.
+    /// * `acceptor`: The certificate and key presented to clients.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls(address: &str, acceptor: TlsAcceptor) -> Result<Self, Error> {
+        Ok(Listener {
+            tls: Some(acceptor),
+            ..Listener::bind(address)?
+        })
+    }
+
+    /// Check whether the connections accepted here use TLS
+    pub fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    /// Wrap a connection accepted by this listener
+    pub(crate) fn socket(&self, stream: TcpStream) -> Result<Socket, Error> {
+        #[allow(unused_mut)]
+        let mut socket = Socket::from(stream);
+
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &self.tls {
+            socket.secure(acceptor.session()?);
+        }
+
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::socket::testing;
+    use crate::socket::{Backend, ServerBuilder};
+
+    #[test]
+    fn serves_every_listener() {
+        for backend in [Backend::Reactor, Backend::Threaded] {
+            let mut server = ServerBuilder::new("127.0.0.1:0");
+
+            server.backend(backend);
+            server.listen("127.0.0.1:0").unwrap();
+
+            let addresses = server.addresses();
+
+            testing::start(server);
+
+            for address in addresses {
+                // Panics unless the server welcomes the client
+                testing::connect(&address.to_string());
+            }
+        }
+    }
+}
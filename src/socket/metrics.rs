@@ -0,0 +1,304 @@
+use crate::socket::{DisconnectReason, Server, ServerBuilder};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the message rates of `Stats` are measured
+pub const RATE_TICK: Duration = Duration::from_secs(1);
+
+struct Window {
+    at: Instant,
+    messages_in: u64,
+    messages_out: u64,
+    rate_in: f64,
+    rate_out: f64,
+}
+
+/// ## Metrics
+///
+/// Counters a `Server` updates while it runs, read through `Server::stats`.
+///
+/// Properties:
+///
+/// * `started`: When the server was created.
+/// * `connections`: Connections accepted so far.
+/// * `messages_in`, `bytes_in`: Data received from clients.
+/// * `messages_out`, `bytes_out`: Data written to clients.
+/// * `decode_errors`: Received data that could not be decoded.
+/// * `refused_full`, `refused_per_ip`: Connections refused by `Server::max_clients` and
+///   `Server::max_connections_per_ip`.
+/// * `oversized`: Messages over `Server::max_message_size` or `Server::max_link_message_size`.
+/// * `handler_calls`, `handler_nanos`, `handler_max_nanos`: Time spent in event handlers.
+/// * `disconnects`: Disconnects by reason.
+/// * `window`: Message counts at the last `RATE_TICK` and the rates measured then.
+pub struct Metrics {
+    started: Instant,
+    connections: AtomicU64,
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    decode_errors: AtomicU64,
+    refused_full: AtomicU64,
+    refused_per_ip: AtomicU64,
+    oversized: AtomicU64,
+    handler_calls: AtomicU64,
+    handler_nanos: AtomicU64,
+    handler_max_nanos: AtomicU64,
+    disconnects: Mutex<HashMap<String, u64>>,
+    window: Mutex<Window>,
+}
+
+/// ## Stats
+///
+/// Snapshot of a server's `Metrics`.
+///
+/// Properties:
+///
+/// * `uptime`: Time since the server was created.
+/// * `connected_clients`: Clients in `Server::clients`.
+/// * `connections_total`: Connections accepted so far, including unfinished handshakes.
+/// * `messages_in`, `bytes_in`: Data received from clients so far.
+/// * `messages_out`, `bytes_out`: Data written to clients so far.
+/// * `messages_in_per_sec`, `messages_out_per_sec`: Rates over the last `RATE_TICK`, measured
+///   while the server runs whether or not anything reads them.
+/// * `decode_errors`: Received data that could not be decoded.
+/// * `refused_server_full`: Connections refused because the server was full.
+/// * `refused_per_ip`: Connections refused because their address had too many open.
+/// * `oversized_messages`: Clients disconnected for sending a message over the size limit.
+/// * `handler_calls`: Event handler calls so far.
+/// * `handler_time_avg`, `handler_time_max`: Event handler execution time.
+/// * `queued_messages`: Messages waiting in all outbound queues.
+/// * `max_queue_depth`: Longest outbound queue.
+/// * `disconnects`: Disconnects by reason: `closed`, `kicked` or `error`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub uptime: Duration,
+    pub connected_clients: usize,
+    pub connections_total: u64,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    pub messages_in_per_sec: f64,
+    pub messages_out_per_sec: f64,
+    pub decode_errors: u64,
+    pub refused_server_full: u64,
+    pub refused_per_ip: u64,
+    pub oversized_messages: u64,
+    pub handler_calls: u64,
+    pub handler_time_avg: Duration,
+    pub handler_time_max: Duration,
+    pub queued_messages: usize,
+    pub max_queue_depth: usize,
+    pub disconnects: HashMap<String, u64>,
+}
+
+impl Metrics {
+    /// Initialize new instance of the `Metrics` with every counter at zero
+    pub fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            messages_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            decode_errors: AtomicU64::new(0),
+            refused_full: AtomicU64::new(0),
+            refused_per_ip: AtomicU64::new(0),
+            oversized: AtomicU64::new(0),
+            handler_calls: AtomicU64::new(0),
+            handler_nanos: AtomicU64::new(0),
+            handler_max_nanos: AtomicU64::new(0),
+            disconnects: Mutex::new(HashMap::new()),
+            window: Mutex::new(Window {
+                at: Instant::now(),
+                messages_in: 0,
+                messages_out: 0,
+                rate_in: 0.0,
+                rate_out: 0.0,
+            }),
+        }
+    }
+
+    pub(crate) fn connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn refused_full(&self) {
+        self.refused_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn refused_per_ip(&self) {
+        self.refused_per_ip.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn oversized(&self) {
+        self.oversized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handler(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+
+        self.handler_calls.fetch_add(1, Ordering::Relaxed);
+        self.handler_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.handler_max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn disconnect(&self, reason: &DisconnectReason) {
+        let label = match reason {
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::Kicked(_) => "kicked",
+            DisconnectReason::Error(_) => "error",
+        };
+
+        *self
+            .disconnects
+            .lock()
+            .unwrap()
+            .entry(label.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Measure the message rates since the previous tick
+    pub(crate) fn tick(&self) {
+        let messages_in = self.messages_in.load(Ordering::Relaxed);
+        let messages_out = self.messages_out.load(Ordering::Relaxed);
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        let seconds = now.duration_since(window.at).as_secs_f64();
+
+        if seconds > 0.0 {
+            window.rate_in = (messages_in - window.messages_in) as f64 / seconds;
+            window.rate_out = (messages_out - window.messages_out) as f64 / seconds;
+        }
+
+        window.messages_in = messages_in;
+        window.messages_out = messages_out;
+        window.at = now;
+    }
+
+    /// Message rates measured at the last tick
+    fn rates(&self) -> (f64, f64) {
+        let window = self.window.lock().unwrap();
+
+        (window.rate_in, window.rate_out)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Server {
+    /// Take a snapshot of the server's metrics
+    ///
+    /// Example:
+    /// ```rs
+    /// let stats = server.stats();
+    /// println!("{} clients, {:.1} msg/s in", stats.connected_clients, stats.messages_in_per_sec);
+    /// ```
+    pub fn stats(&self) -> Stats {
+        let metrics = &self.metrics;
+        let messages_in = metrics.messages_in.load(Ordering::Relaxed);
+        let messages_out = metrics.messages_out.load(Ordering::Relaxed);
+        let (rate_in, rate_out) = metrics.rates();
+        let handler_calls = metrics.handler_calls.load(Ordering::Relaxed);
+        let depths: Vec<usize> = self
+            .clients
+            .values()
+            .iter()
+            .map(|entry| entry.outbound.len())
+            .collect();
+
+        Stats {
+            uptime: metrics.started.elapsed(),
+            connected_clients: self.clients.len(),
+            connections_total: metrics.connections.load(Ordering::Relaxed),
+            messages_in: messages_in,
+            bytes_in: metrics.bytes_in.load(Ordering::Relaxed),
+            messages_out: messages_out,
+            bytes_out: metrics.bytes_out.load(Ordering::Relaxed),
+            messages_in_per_sec: rate_in,
+            messages_out_per_sec: rate_out,
+            decode_errors: metrics.decode_errors.load(Ordering::Relaxed),
+            refused_server_full: metrics.refused_full.load(Ordering::Relaxed),
+            refused_per_ip: metrics.refused_per_ip.load(Ordering::Relaxed),
+            oversized_messages: metrics.oversized.load(Ordering::Relaxed),
+            handler_calls: handler_calls,
+            handler_time_avg: Duration::from_nanos(
+                metrics.handler_nanos.load(Ordering::Relaxed) / handler_calls.max(1),
+            ),
+            handler_time_max: Duration::from_nanos(
+                metrics.handler_max_nanos.load(Ordering::Relaxed),
+            ),
+            queued_messages: depths.iter().sum(),
+            max_queue_depth: depths.iter().cloned().max().unwrap_or(0),
+            disconnects: metrics.disconnects.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Measure the message rates of `Server::stats` once per `RATE_TICK` from a background
+    /// thread, until the server's metrics are dropped
+    pub(crate) fn measure_rates(&self) {
+        let metrics = Arc::downgrade(&self.server.metrics);
+
+        crate::Scheduler::global().set_interval(
+            RATE_TICK,
+            Box::new(move |timer| match metrics.upgrade() {
+                Some(metrics) => metrics.tick(),
+                None => {
+                    timer.cancel();
+                }
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn rates_change_only_on_ticks() {
+        let metrics = Metrics::new();
+
+        for _ in 0..10 {
+            metrics.received(8);
+        }
+
+        assert_eq!(metrics.rates(), (0.0, 0.0));
+
+        thread::sleep(Duration::from_millis(10));
+        metrics.tick();
+
+        let (rate_in, rate_out) = metrics.rates();
+
+        assert!(rate_in > 0.0);
+        assert_eq!(rate_out, 0.0);
+        assert_eq!(metrics.rates(), (rate_in, rate_out));
+    }
+}
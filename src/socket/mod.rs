@@ -1,8 +1,40 @@
+mod channel;
+mod commands;
+mod event;
+mod federation;
+mod history;
+mod listener;
+mod metrics;
+mod moderation;
+mod presence;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod queue;
+mod reactor;
+mod registry;
+mod reload;
 mod server;
+mod session;
 mod socket;
+#[cfg(test)]
+mod testing;
+mod tls;
 
+pub use channel::*;
+pub use event::*;
+pub use federation::*;
+pub use history::*;
+pub use listener::*;
+pub use metrics::*;
+pub use moderation::*;
+pub use presence::*;
+pub use queue::*;
+pub use reactor::*;
+pub use registry::*;
+pub use reload::*;
 pub use server::*;
 pub use socket::*;
+pub use tls::*;
 
 use std::fmt;
 
@@ -11,12 +43,36 @@ pub struct Error {
     message: String,
 }
 
+impl Error {
+    /// Initialize new instance of the `Error`
+    ///
+    /// Arguments:
+    ///
+    /// * `message`: Human readable description of what went wrong.
+    pub fn new(message: &str) -> Self {
+        Error {
+            message: message.to_string(),
+        }
+    }
+
+    /// Get the error message
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "baka::socket::Error: {}", self.message)
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
 impl std::convert::From<Error> for std::io::Error {
     fn from(err: Error) -> Self {
         std::io::Error::new(std::io::ErrorKind::Other, err.message)
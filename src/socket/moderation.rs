@@ -0,0 +1,738 @@
+use crate::extensions::names::NameRules;
+use crate::lagerung::Lagerung;
+use crate::protoutils::{BakaMessage, MessageKind};
+use crate::socket::{Client, ClientEntry, Error, Server};
+use crate::trace::event;
+
+use protobuf::Message;
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, RwLock};
+
+/// Client flag marking an operator, required for moderation commands
+pub const OPERATOR_FLAG: &str = "operator";
+
+const BAN_PREFIX: &str = "ban:";
+const MUTE_PREFIX: &str = "mute:";
+
+/// Match a glob pattern where `*` matches any run of characters and `?` any single one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0usize, 0usize);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((bp, bt)) = backtrack {
+            p = bp + 1;
+            t = bt + 1;
+            backtrack = Some((bp, bt + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// ## HostMask
+///
+/// The host part of a `BanMask`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostMask {
+    /// Glob over the textual IP address, e.g. `10.0.*`
+    Glob(String),
+    /// Network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`
+    Cidr(IpAddr, u8),
+}
+
+impl HostMask {
+    fn parse(mask: &str) -> Result<Self, Error> {
+        match mask.split_once('/') {
+            Some((address, prefix)) => {
+                let address = address
+                    .parse::<IpAddr>()
+                    .map_err(|_| Error::new(&format!("Invalid network address in {}", mask)))?;
+                let prefix = prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|p| *p <= if address.is_ipv4() { 32 } else { 128 })
+                    .ok_or_else(|| Error::new(&format!("Invalid prefix length in {}", mask)))?;
+
+                Ok(HostMask::Cidr(address, prefix))
+            }
+            None => Ok(HostMask::Glob(mask.to_string())),
+        }
+    }
+
+    /// Check whether an address matches the mask
+    pub fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            HostMask::Glob(pattern) => glob_match(pattern, &ip.to_string()),
+            HostMask::Cidr(network, prefix) => match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+
+                    u32::from(*network) & mask == u32::from(*ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+
+                    u128::from(*network) & mask == u128::from(*ip) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// ## BanMask
+///
+/// A `nick@host` pattern. The nick is a glob matched ignoring case, the host a glob or a CIDR
+/// network, and a mask without `@` only matches nicknames.
+///
+/// Example:
+/// ```rs
+/// BanMask::parse("*@10.0.0.0/8")?;
+/// BanMask::parse("spammer*")?;
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BanMask {
+    pub mask: String,
+    pub nick: String,
+    pub host: HostMask,
+}
+
+impl BanMask {
+    /// Parse a mask
+    pub fn parse(mask: &str) -> Result<Self, Error> {
+        let mask = mask.trim();
+
+        if mask.is_empty() || mask.contains(char::is_whitespace) {
+            return Err(Error::new("Ban masks are non-empty and contain no spaces"));
+        }
+
+        let (nick, host) = match mask.split_once('@') {
+            Some((nick, host)) => (nick, HostMask::parse(host)?),
+            None => (mask, HostMask::Glob("*".to_string())),
+        };
+
+        Ok(BanMask {
+            mask: mask.to_string(),
+            nick: if nick.is_empty() { "*" } else { nick }.to_string(),
+            host: host,
+        })
+    }
+
+    /// Check whether a client matches the mask
+    ///
+    /// Arguments:
+    ///
+    /// * `nick`: The client's nickname, `None` while it is not known yet. Only masks whose nick
+    ///   part is `*` can match then.
+    /// * `ip`: The client's address.
+    pub fn matches(&self, nick: Option<&str>, ip: &IpAddr) -> bool {
+        let nick = match nick {
+            Some(nick) => glob_match(&NameRules::key(&self.nick), &NameRules::key(nick)),
+            None => self.nick == "*",
+        };
+
+        nick && self.host.matches(ip)
+    }
+}
+
+/// ## Ban
+///
+/// Properties:
+///
+/// * `mask`: Who is banned.
+/// * `reason`: Why, sent to the client when it is rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    pub mask: BanMask,
+    pub reason: String,
+}
+
+/// ## Moderation
+///
+/// Ban list and per-channel mutes of a `Server`, optionally persisted in a `Lagerung`.
+///
+/// Properties:
+///
+/// * `bans`: The ban list.
+/// * `mutes`: Muted nicknames per channel, both by `NameRules::key`.
+/// * `store`: Persistent storage, `None` for memory only.
+pub struct Moderation {
+    bans: RwLock<Vec<Ban>>,
+    mutes: RwLock<HashMap<String, HashSet<String>>>,
+    store: Option<Mutex<Lagerung>>,
+}
+
+impl Moderation {
+    /// Initialize new in-memory instance of the `Moderation`
+    pub fn new() -> Self {
+        Moderation {
+            bans: RwLock::new(vec![]),
+            mutes: RwLock::new(HashMap::new()),
+            store: None,
+        }
+    }
+
+    /// Initialize new instance of the `Moderation` persisted in a `Lagerung`
+    ///
+    /// Bans and mutes already in the storage are loaded, invalid entries are skipped.
+    ///
+    /// Example:
+    /// ```rs
+    /// server.moderation(Moderation::persistent(Lagerung::open("moderation.db")?))?;
+    /// ```
+    pub fn persistent(store: Lagerung) -> Self {
+        let mut bans = vec![];
+        let mut mutes: HashMap<String, HashSet<String>> = HashMap::new();
+
+        for key in store.keys(BAN_PREFIX) {
+            if let Ok(mask) = BanMask::parse(&key[BAN_PREFIX.len()..]) {
+                bans.push(Ban {
+                    mask: mask,
+                    reason: store.get(&key).unwrap_or("").to_string(),
+                });
+            }
+        }
+
+        for key in store.keys(MUTE_PREFIX) {
+            if let Some((channel, nick)) = key[MUTE_PREFIX.len()..].split_once(' ') {
+                mutes
+                    .entry(NameRules::key(channel))
+                    .or_default()
+                    .insert(NameRules::key(nick));
+            }
+        }
+
+        Moderation {
+            bans: RwLock::new(bans),
+            mutes: RwLock::new(mutes),
+            store: Some(Mutex::new(store)),
+        }
+    }
+
+    /// Store or, with `None`, remove a pair, leaving the storage as it was when it cannot be
+    /// written. Callers update the in-memory lists only after this succeeded.
+    fn persist(&self, key: &str, value: Option<&str>) -> Result<(), Error> {
+        let mut store = match &self.store {
+            Some(store) => store.lock().unwrap(),
+            None => return Ok(()),
+        };
+
+        let previous = store.get(key).map(|v| v.to_string());
+
+        match value {
+            Some(value) => store.add(key, value),
+            None => store.remove(key),
+        }
+
+        if let Err(e) = store.save() {
+            match previous {
+                Some(previous) => store.add(key, &previous),
+                None => store.remove(key),
+            }
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Add a ban, replacing an existing ban with the same mask
+    pub fn ban(&self, mask: &str, reason: &str) -> Result<Ban, Error> {
+        let ban = Ban {
+            mask: BanMask::parse(mask)?,
+            reason: reason.to_string(),
+        };
+
+        self.persist(&format!("{}{}", BAN_PREFIX, ban.mask.mask), Some(reason))?;
+
+        let mut bans = self.bans.write().unwrap();
+
+        bans.retain(|b| b.mask.mask != ban.mask.mask);
+        bans.push(ban.clone());
+
+        Ok(ban)
+    }
+
+    /// Remove a ban, returns whether it existed
+    pub fn unban(&self, mask: &str) -> Result<bool, Error> {
+        self.persist(&format!("{}{}", BAN_PREFIX, mask), None)?;
+
+        let mut bans = self.bans.write().unwrap();
+        let before = bans.len();
+
+        bans.retain(|b| b.mask.mask != mask);
+
+        Ok(bans.len() != before)
+    }
+
+    /// Lift and add several bans at once, either all of them or, when a mask is invalid or the
+    /// storage cannot be written, none. Returns the added bans.
+    ///
+    /// Arguments:
+    ///
+    /// * `lift`: Masks of the bans to remove.
+    /// * `add`: Masks and reasons of the bans to add, replacing bans with the same mask.
+    pub fn update_bans(
+        &self,
+        lift: &[String],
+        add: &[(String, String)],
+    ) -> Result<Vec<Ban>, Error> {
+        let added = add
+            .iter()
+            .map(|(mask, reason)| {
+                Ok(Ban {
+                    mask: BanMask::parse(mask)?,
+                    reason: reason.clone(),
+                })
+            })
+            .collect::<Result<Vec<Ban>, Error>>()?;
+
+        if let Some(store) = &self.store {
+            let mut store = store.lock().unwrap();
+            let key = |mask: &str| format!("{}{}", BAN_PREFIX, mask);
+            let previous: Vec<(String, Option<String>)> = lift
+                .iter()
+                .chain(added.iter().map(|ban| &ban.mask.mask))
+                .map(|mask| (key(mask), store.get(&key(mask)).map(|v| v.to_string())))
+                .collect();
+
+            for mask in lift {
+                store.remove(&key(mask));
+            }
+
+            for ban in &added {
+                store.add(&key(&ban.mask.mask), &ban.reason);
+            }
+
+            if let Err(e) = store.save() {
+                for (key, value) in previous {
+                    match value {
+                        Some(value) => store.add(&key, &value),
+                        None => store.remove(&key),
+                    }
+                }
+
+                return Err(e);
+            }
+        }
+
+        let mut bans = self.bans.write().unwrap();
+
+        bans.retain(|b| {
+            !lift.contains(&b.mask.mask) && !added.iter().any(|a| a.mask.mask == b.mask.mask)
+        });
+        bans.extend(added.iter().cloned());
+
+        Ok(added)
+    }
+
+    /// Get the ban list
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.read().unwrap().clone()
+    }
+
+    /// Find the first ban matching a client
+    pub fn banned(&self, nick: Option<&str>, ip: &IpAddr) -> Option<Ban> {
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .find(|b| b.mask.matches(nick, ip))
+            .cloned()
+    }
+
+    /// Mute a nickname in a channel, ignoring the case of both
+    pub fn mute(&self, channel: &str, nick: &str) -> Result<(), Error> {
+        let (channel, nick) = (NameRules::key(channel), NameRules::key(nick));
+
+        self.persist(&format!("{}{} {}", MUTE_PREFIX, channel, nick), Some("1"))?;
+
+        self.mutes
+            .write()
+            .unwrap()
+            .entry(channel)
+            .or_default()
+            .insert(nick);
+
+        Ok(())
+    }
+
+    /// Unmute a nickname in a channel
+    pub fn unmute(&self, channel: &str, nick: &str) -> Result<(), Error> {
+        let (channel, nick) = (NameRules::key(channel), NameRules::key(nick));
+
+        self.persist(&format!("{}{} {}", MUTE_PREFIX, channel, nick), None)?;
+
+        if let Some(muted) = self.mutes.write().unwrap().get_mut(&channel) {
+            muted.remove(&nick);
+        }
+
+        Ok(())
+    }
+
+    /// Check whether a nickname is muted in a channel
+    pub fn is_muted(&self, channel: &str, nick: &str) -> bool {
+        self.mutes
+            .read()
+            .unwrap()
+            .get(&NameRules::key(channel))
+            .is_some_and(|muted| muted.contains(&NameRules::key(nick)))
+    }
+}
+
+impl Default for Moderation {
+    fn default() -> Self {
+        Moderation::new()
+    }
+}
+
+impl Client {
+    /// Check whether the client may use moderation commands
+    pub fn is_operator(&self) -> bool {
+        self.flags.contains_key(OPERATOR_FLAG)
+    }
+}
+
+impl Server {
+    fn kicked_message(&self, reason: &str) -> Vec<u8> {
+        BakaMessage::new(
+            MessageKind::Error,
+            &self.address.to_string(),
+            &format!("KICKED :{}", reason),
+        )
+        .build()
+        .write_to_bytes()
+        .unwrap()
+    }
+
+    /// Disconnect a client, telling it why
+    ///
+    /// The reason is sent as `KICKED :reason`, then the connection is closed once the client's
+    /// outbound queue is flushed and `on_client_disconnect` fires as usual. This never waits for
+    /// the client: when its queue is full under `OverflowPolicy::Block` the reason is dropped and
+    /// the connection is closed all the same.
+    ///
+    /// Arguments:
+    ///
+    /// * `client`: The client to disconnect.
+    /// * `reason`: Why the client is disconnected, passed on as `DisconnectReason::Kicked`.
+    pub fn kick(&self, client: &mut Client, reason: &str) {
+        event!(info, nick = %client.nick(), reason = %reason, "kicking client");
+
+        client.kicked = Some(reason.to_string());
+
+        let _ = client.outbound.try_push(self.kicked_message(reason));
+
+        client.outbound.close();
+    }
+
+    /// Disconnect a client that is locked by a running handler, without waiting for it
+    fn kick_busy(&self, entry: &ClientEntry, nick: &str, reason: &str) {
+        event!(info, nick = %nick, reason = %reason, "kicking busy client");
+
+        *entry.kicked.lock().unwrap() = Some(reason.to_string());
+
+        let _ = entry.outbound.try_push(self.kicked_message(reason));
+
+        entry.outbound.close();
+    }
+
+    /// Disconnect a client by its registry entry, without waiting if it is running a handler
+    pub(crate) fn kick_entry(&self, entry: &ClientEntry, nick: &str, reason: &str) {
+        match entry.client.try_lock() {
+            Ok(mut client) => self.kick(&mut client, reason),
+            Err(_) => self.kick_busy(entry, nick, reason),
+        }
+    }
+
+    /// Ban a mask and kick the connected clients matching it
+    ///
+    /// Clients that are running a handler, like the one calling `ban` from its own handler,
+    /// are not waited for. They are matched by the nickname in `Server::presence` and their
+    /// outbound queue is closed right away.
+    ///
+    /// Arguments:
+    ///
+    /// * `mask`: A `BanMask`, e.g. `*@10.0.0.0/8` or `spammer*`.
+    /// * `reason`: Why, sent to the matching clients.
+    pub fn ban(&self, mask: &str, reason: &str) -> Result<(), Error> {
+        self.ban_with(mask, reason, None)
+    }
+
+    /// Ban a mask, kicking `own`, the client already locked by the caller, directly
+    pub(crate) fn ban_with(
+        &self,
+        mask: &str,
+        reason: &str,
+        own: Option<&mut Client>,
+    ) -> Result<(), Error> {
+        let ban = self.moderation.ban(mask, reason)?;
+
+        self.enforce(&ban, own);
+
+        Ok(())
+    }
+
+    /// Kick the connected clients matching a ban, `own` being the client locked by the caller
+    pub(crate) fn enforce(&self, ban: &Ban, mut own: Option<&mut Client>) {
+        let reason = format!("Banned: {}", ban.reason);
+        let mut nicks: Option<HashMap<String, String>> = None;
+
+        for (address, entry) in self.clients.entries() {
+            let ip = match address.parse::<SocketAddr>() {
+                Ok(address) => address.ip(),
+                Err(_) => continue,
+            };
+
+            if let Some(client) = own.as_deref_mut() {
+                if client.socket.address.to_string() == address {
+                    if ban.mask.matches(Some(&client.nick()), &ip) {
+                        self.kick(client, &reason);
+                    }
+                    continue;
+                }
+            }
+
+            match entry.client.try_lock() {
+                Ok(mut client) => {
+                    if ban.mask.matches(Some(&client.nick()), &ip) {
+                        self.kick(&mut client, &reason);
+                    }
+                }
+                Err(_) => {
+                    let nicks = nicks.get_or_insert_with(|| {
+                        self.presence
+                            .online()
+                            .into_iter()
+                            .map(|(nick, presence)| (presence.address, nick))
+                            .collect()
+                    });
+                    let nick = nicks.get(&address).unwrap_or(&address);
+
+                    if ban.mask.matches(Some(nick), &ip) {
+                        self.kick_busy(&entry, nick, &reason);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::testing;
+    use crate::socket::{
+        BoxEvent, ClientState, Context, Event, Outbound, OverflowPolicy, ServerBuilder, Socket,
+    };
+
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn glob_matches_runs_and_single_characters() {
+        assert!(glob_match("spam*", "spammer"));
+        assert!(glob_match("*er", "spammer"));
+        assert!(glob_match("sp?mmer", "spammer"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("spam?", "spam"));
+        assert!(!glob_match("*x*", "spammer"));
+    }
+
+    #[test]
+    fn masks_without_host_match_nicks_only() {
+        let mask = BanMask::parse("spammer*").unwrap();
+
+        assert!(mask.matches(Some("spammer42"), &ip("10.0.0.1")));
+        assert!(!mask.matches(Some("alice"), &ip("10.0.0.1")));
+        assert!(!mask.matches(None, &ip("10.0.0.1")));
+        assert!(mask.matches(Some("SpammerBot"), &ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_masks_match_networks() {
+        let v4 = BanMask::parse("*@10.0.0.0/8").unwrap();
+        let v6 = BanMask::parse("*@2001:db8::/32").unwrap();
+
+        assert!(v4.matches(None, &ip("10.20.30.40")));
+        assert!(!v4.matches(None, &ip("11.0.0.1")));
+        assert!(!v4.matches(None, &ip("2001:db8::1")));
+        assert!(v6.matches(Some("alice"), &ip("2001:db8:ffff::1")));
+        assert!(!v6.matches(Some("alice"), &ip("2001:db9::1")));
+        assert!(BanMask::parse("*@0.0.0.0/0")
+            .unwrap()
+            .matches(None, &ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn glob_hosts_match_the_address_text() {
+        let mask = BanMask::parse("bob@192.168.*").unwrap();
+
+        assert!(mask.matches(Some("bob"), &ip("192.168.1.1")));
+        assert!(!mask.matches(Some("bob"), &ip("10.0.0.1")));
+        assert!(!mask.matches(Some("alice"), &ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_masks() {
+        assert!(BanMask::parse("").is_err());
+        assert!(BanMask::parse("a b").is_err());
+        assert!(BanMask::parse("*@10.0.0.0/33").is_err());
+        assert!(BanMask::parse("*@2001:db8::/129").is_err());
+        assert!(BanMask::parse("*@nonsense/8").is_err());
+        assert_eq!(BanMask::parse("@10.*").unwrap().nick, "*");
+    }
+
+    #[test]
+    fn mutes_ignore_case() {
+        let moderation = Moderation::new();
+
+        moderation.mute("#Rust", "Alice").unwrap();
+
+        assert!(moderation.is_muted("#rust", "ALICE"));
+
+        moderation.unmute("#RUST", "alice").unwrap();
+
+        assert!(!moderation.is_muted("#Rust", "Alice"));
+    }
+
+    #[test]
+    fn failed_writes_leave_the_lists_unchanged() {
+        let path = std::env::temp_dir()
+            .join("baka-missing-directory")
+            .join("moderation.db");
+        let moderation = Moderation::persistent(Lagerung::open(path).unwrap());
+
+        assert!(moderation.ban("spammer", "spam").is_err());
+        assert!(moderation.bans().is_empty());
+        assert!(moderation.mute("#rust", "spammer").is_err());
+        assert!(!moderation.is_muted("#rust", "spammer"));
+    }
+
+    /// Start a server with an `on_message` handler, send it a message and wait for the reason
+    /// the client is kicked with
+    fn kicked_by(handler: BoxEvent) -> String {
+        let mut server = ServerBuilder::new("127.0.0.1:0");
+
+        server.event("on_message", handler);
+
+        refusal(server, MessageKind::Chat, "spam")
+    }
+
+    /// Start a server, send it a message once connected and wait for the first error
+    fn refusal(server: ServerBuilder, kind: MessageKind, content: &str) -> String {
+        let mut socket = testing::connect(&testing::start(server));
+        let inbox = testing::inbox(&socket);
+        let author = socket.local_address();
+
+        socket
+            .send_message(BakaMessage::new(kind, &author, content))
+            .unwrap();
+
+        testing::first_error(&inbox).unwrap()
+    }
+
+    #[test]
+    fn bans_from_inside_a_handler() {
+        let kicked = kicked_by(Box::new(|ctx: &mut Context, _: Event| {
+            ctx.server().ban("*@127.0.0.1", "spam").unwrap();
+        }));
+
+        assert_eq!(kicked, "KICKED :Banned: spam");
+    }
+
+    #[test]
+    fn context_bans_its_own_client() {
+        let kicked = kicked_by(Box::new(|ctx: &mut Context, _: Event| {
+            ctx.ban("*@127.0.0.1", "spam").unwrap();
+        }));
+
+        assert_eq!(kicked, "KICKED :Banned: spam");
+    }
+
+    #[test]
+    fn context_kicks_its_own_client() {
+        let kicked = kicked_by(Box::new(|ctx: &mut Context, _: Event| {
+            let nick = ctx.nick();
+
+            ctx.kick(&nick, "No spam").unwrap();
+        }));
+
+        assert_eq!(kicked, "KICKED :No spam");
+    }
+
+    #[test]
+    fn bans_nicks_taken_after_connecting() {
+        let server = ServerBuilder::new("127.0.0.1:0");
+
+        server
+            .server
+            .moderation
+            .ban("baddie", "No baddies")
+            .unwrap();
+
+        let refused = refusal(server, MessageKind::Command, "NICK {baddie}");
+
+        assert_eq!(refused, "BANNED {baddie} :No baddies");
+    }
+
+    #[test]
+    fn kicks_blocked_clients_without_waiting() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = Socket::connect(&listener.local_addr().unwrap().to_string()).unwrap();
+        // Accepted but never read, so the writer ends up stuck on a full socket buffer
+        let (_peer, _) = listener.accept().unwrap();
+        let outbound = Outbound::new(socket.clone(), 1, OverflowPolicy::Block);
+        let chunk = vec![0; 1 << 20];
+
+        loop {
+            while outbound.try_push(chunk.clone()).is_ok() {}
+
+            thread::sleep(Duration::from_millis(50));
+
+            if outbound.len() == 1 {
+                break;
+            }
+        }
+
+        let mut client = Client {
+            socket: socket,
+            flags: HashMap::new(),
+            outbound: outbound.clone(),
+            state: ClientState::Connected,
+            kicked: None,
+        };
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            server.kick(&mut client, "Too slow");
+            let _ = sender.send(client.kicked);
+        });
+
+        let kicked = receiver.recv_timeout(testing::TIMEOUT).unwrap();
+
+        assert_eq!(kicked.as_deref(), Some("Too slow"));
+        assert!(outbound.is_closed());
+    }
+}
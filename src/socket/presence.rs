@@ -0,0 +1,454 @@
+use crate::extensions::names::NameRules;
+use crate::protoutils;
+use crate::socket::Server;
+use crate::{Clock, SystemClock};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a disconnected nickname is remembered by `PresenceTracker::last_seen`
+pub const LAST_SEEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Most disconnected nicknames remembered at once, the oldest is forgotten first
+pub const MAX_LAST_SEEN: usize = 100_000;
+
+/// When disconnected nicknames left, with the nicknames in the order they were remembered,
+/// oldest first. A nickname remembered again keeps its older entry in `order`, which is
+/// skipped once it no longer matches `nicks`.
+struct LastSeen {
+    nicks: HashMap<String, u64>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl LastSeen {
+    /// Forget the oldest nickname, skipping entries that were remembered again since
+    fn forget_oldest(&mut self) {
+        while let Some((nick, seen)) = self.order.pop_front() {
+            if self.nicks.get(&nick) == Some(&seen) {
+                self.nicks.remove(&nick);
+                return;
+            }
+        }
+    }
+}
+
+/// ## Presence
+///
+/// Presence of a connected client.
+///
+/// Properties:
+///
+/// * `nick`: The client's nickname as it chose it.
+/// * `address`: The client's key in `Server::clients`.
+/// * `away`: Away message, `None` while the client is present.
+/// * `connected_at`: When the client connected, in milliseconds since the Unix epoch.
+/// * `last_active`: When the client last sent a message, by the tracker's clock, see
+///   `PresenceTracker::idle`.
+#[derive(Clone, Debug)]
+pub struct Presence {
+    pub nick: String,
+    pub address: String,
+    pub away: Option<String>,
+    pub connected_at: u64,
+    pub last_active: Instant,
+}
+
+/// ## WhoEntry
+///
+/// One line of a WHO query.
+///
+/// Properties:
+///
+/// * `nick`: The client's nickname.
+/// * `away`: Away message, `None` while the client is present.
+/// * `idle`: Time since the client last sent a message, zero for clients of other servers.
+/// * `server`: Name of the server the client is connected to, see `Server::name`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WhoEntry {
+    pub nick: String,
+    pub away: Option<String>,
+    pub idle: Duration,
+    pub server: String,
+}
+
+/// ## Whois
+///
+/// Result of a WHOIS query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Whois {
+    Online {
+        nick: String,
+        address: String,
+        away: Option<String>,
+        idle: Duration,
+        connected_at: u64,
+        channels: Vec<String>,
+    },
+    Remote {
+        nick: String,
+        server: String,
+        channels: Vec<String>,
+    },
+    Offline {
+        nick: String,
+        last_seen: u64,
+    },
+}
+
+/// ## PresenceTracker
+///
+/// Tracks who is online, who is away, and when disconnected nicknames were last seen.
+/// Nicknames are forgotten `LAST_SEEN_TTL` after they left, and clients that never chose a
+/// nickname are not remembered at all. Nicknames are looked up by `NameRules::key`, so
+/// `Alice` and `alice` are the same nickname.
+///
+/// Properties:
+///
+/// * `online`: Presence of connected clients by nickname key.
+/// * `last_seen`: When disconnected nicknames left, in milliseconds since the Unix epoch, by
+///   nickname key.
+/// * `clock`: Measures idle times and connection times.
+pub struct PresenceTracker {
+    online: Mutex<HashMap<String, Presence>>,
+    last_seen: Mutex<LastSeen>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PresenceTracker {
+    /// Initialize new instance of the `PresenceTracker`
+    pub fn new() -> Self {
+        PresenceTracker::with_clock(SystemClock::shared())
+    }
+
+    /// Initialize new instance of the `PresenceTracker` measuring time with a clock
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        PresenceTracker {
+            online: Mutex::new(HashMap::new()),
+            last_seen: Mutex::new(LastSeen {
+                nicks: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            clock: clock,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch by the tracker's clock
+    fn timestamp(&self) -> u64 {
+        protoutils::timestamp(&*self.clock)
+    }
+
+    /// Remember when a nickname left, unless it is the client's address
+    fn remember(&self, nick: &str, presence: &Presence) {
+        if nick == presence.address {
+            return;
+        }
+
+        let key = NameRules::key(nick);
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let now = self.timestamp();
+        let ttl = LAST_SEEN_TTL.as_millis() as u64;
+
+        while last_seen
+            .order
+            .front()
+            .is_some_and(|(_, seen)| now.saturating_sub(*seen) >= ttl)
+        {
+            last_seen.forget_oldest();
+        }
+
+        if last_seen.nicks.len() >= MAX_LAST_SEEN && !last_seen.nicks.contains_key(&key) {
+            last_seen.forget_oldest();
+        }
+
+        last_seen.nicks.insert(key.clone(), now);
+        last_seen.order.push_back((key, now));
+
+        // Drop the entries of nicknames remembered again once they outnumber the live ones
+        if last_seen.order.len() > 2 * MAX_LAST_SEEN {
+            let LastSeen { nicks, order } = &mut *last_seen;
+
+            order.retain(|(nick, seen)| nicks.get(nick) == Some(seen));
+        }
+    }
+
+    /// Time since a client last sent a message
+    pub fn idle(&self, presence: &Presence) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(presence.last_active)
+    }
+
+    /// Mark a nickname as online
+    pub fn connect(&self, nick: &str, address: &str) {
+        self.online.lock().unwrap().insert(
+            NameRules::key(nick),
+            Presence {
+                nick: nick.to_string(),
+                address: address.to_string(),
+                away: None,
+                connected_at: self.timestamp(),
+                last_active: self.clock.now(),
+            },
+        );
+    }
+
+    /// Mark a nickname as offline and remember when it was last seen
+    pub fn disconnect(&self, nick: &str) {
+        let presence = self.online.lock().unwrap().remove(&NameRules::key(nick));
+
+        if let Some(presence) = presence {
+            self.remember(nick, &presence);
+        }
+    }
+
+    /// Move the presence of a nickname to a new one, the old nickname is marked as last seen now
+    pub fn rename(&self, nick: &str, new: &str) {
+        let presence = self.online.lock().unwrap().remove(&NameRules::key(nick));
+
+        if let Some(mut presence) = presence {
+            // A nickname that only changed case did not leave
+            if NameRules::key(nick) != NameRules::key(new) {
+                self.remember(nick, &presence);
+            }
+
+            presence.nick = new.to_string();
+            self.online
+                .lock()
+                .unwrap()
+                .insert(NameRules::key(new), presence);
+        }
+    }
+
+    /// Reset the idle time of a nickname
+    pub fn touch(&self, nick: &str) {
+        if let Some(presence) = self.online.lock().unwrap().get_mut(&NameRules::key(nick)) {
+            presence.last_active = self.clock.now();
+        }
+    }
+
+    /// Set or clear the away message of a nickname
+    ///
+    /// Arguments:
+    ///
+    /// * `nick`: The nickname.
+    /// * `message`: The away message, `None` to mark the nickname as present.
+    pub fn set_away(&self, nick: &str, message: Option<String>) {
+        if let Some(presence) = self.online.lock().unwrap().get_mut(&NameRules::key(nick)) {
+            presence.away = message;
+        }
+    }
+
+    /// Get the presence of a connected nickname
+    pub fn get(&self, nick: &str) -> Option<Presence> {
+        self.online
+            .lock()
+            .unwrap()
+            .get(&NameRules::key(nick))
+            .cloned()
+    }
+
+    /// Get when a disconnected nickname was last seen, `None` once it is forgotten
+    pub fn last_seen(&self, nick: &str) -> Option<u64> {
+        let now = self.timestamp();
+
+        self.last_seen
+            .lock()
+            .unwrap()
+            .nicks
+            .get(&NameRules::key(nick))
+            .cloned()
+            .filter(|seen| now.saturating_sub(*seen) < LAST_SEEN_TTL.as_millis() as u64)
+    }
+
+    /// Get every connected nickname with its presence
+    pub fn online(&self) -> Vec<(String, Presence)> {
+        self.online
+            .lock()
+            .unwrap()
+            .values()
+            .map(|presence| (presence.nick.clone(), presence.clone()))
+            .collect()
+    }
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        PresenceTracker::new()
+    }
+}
+
+impl Server {
+    /// List who is online in the network, optionally only the members of a channel
+    ///
+    /// Arguments:
+    ///
+    /// * `channel`: The channel name, `None` for every connected client.
+    pub fn who(&self, channel: Option<&str>) -> Vec<WhoEntry> {
+        let members: Option<HashSet<String>> = channel.map(|channel| {
+            self.channels
+                .read()
+                .unwrap()
+                .get(&NameRules::key(channel))
+                .map(|c| c.members.clone())
+                .unwrap_or_default()
+        });
+
+        let name = self.name();
+        let mut entries: Vec<WhoEntry> = self
+            .presence
+            .online()
+            .into_iter()
+            .filter(|(_, presence)| match &members {
+                Some(members) => members.contains(&presence.address),
+                None => true,
+            })
+            .map(|(nick, presence)| WhoEntry {
+                nick: nick,
+                idle: self.presence.idle(&presence),
+                away: presence.away,
+                server: name.clone(),
+            })
+            .collect();
+
+        if let Some(federation) = &self.federation {
+            entries.extend(
+                federation
+                    .remote_users()
+                    .into_iter()
+                    .filter(|user| match channel {
+                        Some(channel) => user.is_member(channel),
+                        None => true,
+                    })
+                    .map(|user| WhoEntry {
+                        nick: user.nick,
+                        away: None,
+                        idle: Duration::ZERO,
+                        server: user.server,
+                    }),
+            );
+        }
+
+        entries.sort_by(|a, b| a.nick.cmp(&b.nick));
+        entries
+    }
+
+    /// Look up a nickname in the network, `None` if it was never seen
+    pub fn whois(&self, nick: &str) -> Option<Whois> {
+        if let Some(presence) = self.presence.get(nick) {
+            return Some(Whois::Online {
+                nick: presence.nick.clone(),
+                channels: self.channels_of(&presence.address),
+                idle: self.presence.idle(&presence),
+                address: presence.address,
+                away: presence.away,
+                connected_at: presence.connected_at,
+            });
+        }
+
+        if let Some(user) = self.federation.as_ref().and_then(|f| f.locate(nick)) {
+            let mut channels: Vec<String> = user.channels.into_iter().collect();
+            channels.sort();
+
+            return Some(Whois::Remote {
+                nick: user.nick,
+                server: user.server,
+                channels: channels,
+            });
+        }
+
+        self.presence
+            .last_seen(nick)
+            .map(|last_seen| Whois::Offline {
+                nick: nick.to_string(),
+                last_seen: last_seen,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn remembers_nicks_until_they_expire() {
+        let clock = Arc::new(MockClock::new());
+        let tracker = PresenceTracker::with_clock(clock.clone());
+
+        tracker.connect("alice", "127.0.0.1:4000");
+        clock.advance(Duration::from_secs(5));
+        tracker.disconnect("alice");
+
+        assert_eq!(tracker.last_seen("alice"), Some(5000));
+
+        clock.advance(LAST_SEEN_TTL);
+
+        assert_eq!(tracker.last_seen("alice"), None);
+    }
+
+    #[test]
+    fn forgets_address_nicks() {
+        let tracker = PresenceTracker::with_clock(Arc::new(MockClock::new()));
+
+        tracker.connect("127.0.0.1:4000", "127.0.0.1:4000");
+        tracker.disconnect("127.0.0.1:4000");
+
+        assert_eq!(tracker.last_seen("127.0.0.1:4000"), None);
+
+        tracker.connect("127.0.0.1:4001", "127.0.0.1:4001");
+        tracker.rename("127.0.0.1:4001", "bob");
+
+        assert_eq!(tracker.last_seen("127.0.0.1:4001"), None);
+        assert!(tracker.get("bob").is_some());
+    }
+
+    #[test]
+    fn looks_up_nicks_ignoring_case() {
+        let clock = Arc::new(MockClock::new());
+        let tracker = PresenceTracker::with_clock(clock.clone());
+
+        tracker.connect("Alice", "127.0.0.1:4000");
+        clock.advance(Duration::from_secs(30));
+        tracker.set_away("ALICE", Some("lunch".to_string()));
+
+        let presence = tracker.get("alice").unwrap();
+
+        assert_eq!(presence.nick, "Alice");
+        assert_eq!(presence.away.as_deref(), Some("lunch"));
+        assert_eq!(tracker.idle(&presence), Duration::from_secs(30));
+
+        tracker.rename("alice", "ALICE");
+
+        assert_eq!(tracker.get("alice").unwrap().nick, "ALICE");
+        assert_eq!(tracker.last_seen("alice"), None);
+
+        tracker.disconnect("Alice");
+
+        assert_eq!(tracker.last_seen("aLiCe"), Some(30_000));
+    }
+
+    #[test]
+    fn forgets_the_oldest_nick_when_full() {
+        let clock = Arc::new(MockClock::new());
+        let tracker = PresenceTracker::with_clock(clock.clone());
+
+        for i in 0..MAX_LAST_SEEN {
+            let nick = format!("nick{}", i);
+
+            tracker.connect(&nick, "127.0.0.1:4000");
+            tracker.disconnect(&nick);
+            clock.advance(Duration::from_millis(1));
+        }
+
+        tracker.connect("nick0", "127.0.0.1:4000");
+        tracker.disconnect("nick0");
+        tracker.connect("latecomer", "127.0.0.1:4000");
+        tracker.disconnect("latecomer");
+
+        assert!(tracker.last_seen("nick0").is_some());
+        assert_eq!(tracker.last_seen("nick1"), None);
+        assert!(tracker.last_seen("nick2").is_some());
+        assert!(tracker.last_seen("latecomer").is_some());
+    }
+}
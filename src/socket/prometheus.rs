@@ -0,0 +1,218 @@
+use crate::socket::{Error, Server, Stats};
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long the metrics endpoint waits for a scraper to send its request or read the response
+const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Threads answering metrics requests
+const METRICS_WORKERS: usize = 2;
+
+/// Connections waiting for a metrics worker, more are closed right away
+const METRICS_BACKLOG: usize = 16;
+
+impl Stats {
+    /// Encode the snapshot in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP baka_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE baka_{} {}", name, kind);
+            let _ = writeln!(out, "baka_{} {}", name, value);
+        };
+
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Time since the server started.",
+            self.uptime.as_secs_f64().to_string(),
+        );
+        metric(
+            "connected_clients",
+            "gauge",
+            "Connected clients.",
+            self.connected_clients.to_string(),
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "Accepted connections.",
+            self.connections_total.to_string(),
+        );
+        metric(
+            "messages_received_total",
+            "counter",
+            "Messages received from clients.",
+            self.messages_in.to_string(),
+        );
+        metric(
+            "messages_sent_total",
+            "counter",
+            "Messages written to clients.",
+            self.messages_out.to_string(),
+        );
+        metric(
+            "bytes_received_total",
+            "counter",
+            "Bytes received from clients.",
+            self.bytes_in.to_string(),
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Bytes written to clients.",
+            self.bytes_out.to_string(),
+        );
+        metric(
+            "decode_errors_total",
+            "counter",
+            "Received data that could not be decoded.",
+            self.decode_errors.to_string(),
+        );
+        metric(
+            "refused_server_full_total",
+            "counter",
+            "Connections refused because the server was full.",
+            self.refused_server_full.to_string(),
+        );
+        metric(
+            "refused_per_ip_total",
+            "counter",
+            "Connections refused because their address had too many open.",
+            self.refused_per_ip.to_string(),
+        );
+        metric(
+            "oversized_messages_total",
+            "counter",
+            "Clients disconnected for sending a message over the size limit.",
+            self.oversized_messages.to_string(),
+        );
+        metric(
+            "handler_calls_total",
+            "counter",
+            "Event handler calls.",
+            self.handler_calls.to_string(),
+        );
+        metric(
+            "handler_seconds_avg",
+            "gauge",
+            "Average event handler execution time.",
+            self.handler_time_avg.as_secs_f64().to_string(),
+        );
+        metric(
+            "handler_seconds_max",
+            "gauge",
+            "Longest event handler execution time.",
+            self.handler_time_max.as_secs_f64().to_string(),
+        );
+        metric(
+            "queued_messages",
+            "gauge",
+            "Messages waiting in outbound queues.",
+            self.queued_messages.to_string(),
+        );
+        metric(
+            "max_queue_depth",
+            "gauge",
+            "Longest outbound queue.",
+            self.max_queue_depth.to_string(),
+        );
+
+        let _ = writeln!(out, "# HELP baka_disconnects_total Disconnects by reason.");
+        let _ = writeln!(out, "# TYPE baka_disconnects_total counter");
+
+        let mut reasons: Vec<(&String, &u64)> = self.disconnects.iter().collect();
+        reasons.sort();
+
+        for (reason, count) in reasons {
+            let _ = writeln!(
+                out,
+                "baka_disconnects_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        out
+    }
+}
+
+impl Server {
+    /// Serve `Server::stats` in the Prometheus text format at `/metrics` from a background
+    /// thread
+    ///
+    /// Connections are answered by `METRICS_WORKERS` threads and dropped after
+    /// `METRICS_TIMEOUT` without a request, so a stalled scraper does not block the others
+    /// for long. While `METRICS_BACKLOG` connections are already waiting for a worker, new
+    /// ones are closed without an answer.
+    ///
+    /// Example:
+    /// ```rs
+    /// server.serve_metrics("127.0.0.1:9100")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address the HTTP endpoint listens on.
+    pub fn serve_metrics(&self, address: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| Error::new(&format!("Unable to bind {}: {}", address, e)))?;
+        let (sender, receiver) = sync_channel::<TcpStream>(METRICS_BACKLOG);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..METRICS_WORKERS {
+            let receiver = receiver.clone();
+            let server = self.clone();
+
+            thread::spawn(move || loop {
+                let stream = match receiver.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+
+                server.answer_metrics(stream);
+            });
+        }
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Dropping the stream closes it when every worker is busy
+                let _ = sender.try_send(stream);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn answer_metrics(&self, mut stream: TcpStream) {
+        if stream.set_read_timeout(Some(METRICS_TIMEOUT)).is_err()
+            || stream.set_write_timeout(Some(METRICS_TIMEOUT)).is_err()
+        {
+            return;
+        }
+
+        let mut request = [0u8; 1024];
+        let read = stream.read(&mut request).unwrap_or(0);
+        let request = String::from_utf8_lossy(&request[..read]);
+
+        let response = if request.starts_with("GET /metrics ") {
+            let body = self.stats().to_prometheus();
+
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+
+        let _ = stream.write_all(response.as_bytes());
+    }
+}
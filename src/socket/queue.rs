@@ -0,0 +1,294 @@
+use crate::middleware::{Action, Peer, Pipeline};
+use crate::socket::{Error, Metrics, Server, Socket};
+use crate::trace::event;
+
+use bakaproto::proto::*;
+use protobuf::Message;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// ## OverflowPolicy
+///
+/// What the `Outbound` queue does when a message is pushed while it is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room for the new one
+    DropOldest,
+    /// Close the queue, drop its backlog and shut the client's socket down
+    Disconnect,
+    /// Wait until the writer thread frees a slot for replies to the client's own messages.
+    /// Messages the server sends on its own, like `Server::broadcast`, `Server::send` and
+    /// channel messages, are not queued instead, see `Outbound::try_push`
+    Block,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::DropOldest
+    }
+}
+
+struct State {
+    queue: VecDeque<Vec<u8>>,
+    closed: bool,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+    space: Condvar,
+    middleware: Arc<Pipeline>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// ## Outbound
+///
+/// Bounded queue of outgoing data for a single client, drained by its own writer thread.
+///
+/// Properties:
+///
+/// * `shared`: Queue state shared between the handle and the writer thread.
+pub struct Outbound {
+    shared: Arc<Shared>,
+}
+
+impl Clone for Outbound {
+    fn clone(&self) -> Self {
+        Outbound {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Outbound {
+    /// Initialize new instance of the `Outbound` and spawn its writer thread
+    ///
+    /// Example:
+    /// ```rs
+    /// let outbound = Outbound::new(socket.clone(), 256, OverflowPolicy::DropOldest);
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `socket`: The socket the writer thread writes to.
+    /// * `capacity`: Maximum number of queued messages.
+    /// * `policy`: What to do when the queue is full.
+    pub fn new(socket: Socket, capacity: usize, policy: OverflowPolicy) -> Self {
+        Outbound::spawn(socket, capacity, policy, Arc::new(Pipeline::new()), None)
+    }
+
+    /// Initialize new instance of the `Outbound` for a client of a server, using the server's
+    /// queue settings, outbound middleware and metrics
+    pub(crate) fn for_server(socket: Socket, server: &Server) -> Self {
+        Outbound::spawn(
+            socket,
+            server.queue_capacity,
+            server.overflow_policy,
+            server.middleware.clone(),
+            Some(server.metrics.clone()),
+        )
+    }
+
+    fn spawn(
+        socket: Socket,
+        capacity: usize,
+        policy: OverflowPolicy,
+        middleware: Arc<Pipeline>,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::with_capacity(capacity),
+                closed: false,
+                capacity: capacity.max(1),
+                policy: policy,
+            }),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            middleware: middleware,
+            metrics: metrics,
+        });
+
+        let writer = shared.clone();
+
+        thread::spawn(move || Outbound::drain(writer, socket));
+
+        Outbound { shared: shared }
+    }
+
+    /// Queue data for sending
+    ///
+    /// Returns an error if the queue is closed, or if it was full and the policy is
+    /// `OverflowPolicy::Disconnect`.
+    ///
+    /// Arguments:
+    ///
+    /// * `data`: Vec<u8> - The bytes to send.
+    pub fn push(&self, data: Vec<u8>) -> Result<(), Error> {
+        self.enqueue(data, true)
+    }
+
+    /// Queue data for sending without ever waiting for the writer thread
+    ///
+    /// Behaves like `push`, except that under `OverflowPolicy::Block` a full queue returns an
+    /// error and the data is not queued. Used for broadcasts, so one slow client never holds
+    /// up the others.
+    ///
+    /// Arguments:
+    ///
+    /// * `data`: Vec<u8> - The bytes to send.
+    pub fn try_push(&self, data: Vec<u8>) -> Result<(), Error> {
+        self.enqueue(data, false)
+    }
+
+    fn enqueue(&self, data: Vec<u8>, wait: bool) -> Result<(), Error> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.closed {
+            return Err(Error {
+                message: "Outbound queue is closed".to_string(),
+            });
+        }
+
+        while state.queue.len() >= state.capacity {
+            match state.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                }
+                OverflowPolicy::Disconnect => {
+                    // The client is going away, so the backlog is not worth writing
+                    state.closed = true;
+                    state.queue.clear();
+                    self.shared.ready.notify_all();
+                    self.shared.space.notify_all();
+
+                    return Err(Error {
+                        message: "Outbound queue is full, disconnecting client".to_string(),
+                    });
+                }
+                OverflowPolicy::Block if !wait => {
+                    return Err(Error {
+                        message: "Outbound queue is full".to_string(),
+                    });
+                }
+                OverflowPolicy::Block => {
+                    state = self.shared.space.wait(state).unwrap();
+
+                    if state.closed {
+                        return Err(Error {
+                            message: "Outbound queue is closed".to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        state.queue.push_back(data);
+        self.shared.ready.notify_one();
+
+        Ok(())
+    }
+
+    /// Change how many messages the queue holds and what happens when it is full, e.g. once
+    /// a connection turns out to be a link to another server
+    ///
+    /// Arguments:
+    ///
+    /// * `capacity`: Maximum number of queued messages.
+    /// * `policy`: What to do when the queue is full.
+    pub(crate) fn reconfigure(&self, capacity: usize, policy: OverflowPolicy) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.capacity = capacity.max(1);
+        state.policy = policy;
+        self.shared.space.notify_all();
+    }
+
+    /// Number of messages waiting to be written
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+
+    /// Check whether the queue has been closed
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    /// Close the queue, the writer thread exits once it has flushed what is left
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.closed = true;
+        self.shared.ready.notify_all();
+        self.shared.space.notify_all();
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn drain(shared: Arc<Shared>, mut socket: Socket) {
+        let peer = Peer::new(&socket.address.to_string());
+
+        loop {
+            let data = {
+                let mut state = shared.state.lock().unwrap();
+
+                while state.queue.is_empty() && !state.closed {
+                    state = shared.ready.wait(state).unwrap();
+                }
+
+                match state.queue.pop_front() {
+                    Some(data) => {
+                        shared.space.notify_one();
+                        data
+                    }
+                    None => break,
+                }
+            };
+
+            let data = if shared.middleware.is_empty() {
+                data
+            } else {
+                match message::Message::parse_from_bytes(&data) {
+                    Ok(message) => match shared.middleware.outbound(&peer, message) {
+                        Action::Continue(message) | Action::ShortCircuit(message) => {
+                            message.write_to_bytes().unwrap()
+                        }
+                        Action::Drop => continue,
+                    },
+                    Err(_) => data,
+                }
+            };
+
+            let sent = socket.try_send_bytes(data.as_slice());
+
+            if sent.is_ok() {
+                event!(trace, peer = %socket.address, bytes = data.len(), "message sent");
+            }
+
+            if let (Ok(()), Some(metrics)) = (&sent, &shared.metrics) {
+                metrics.sent(data.len());
+            }
+
+            if let Err(e) = sent {
+                event!(
+                    warn,
+                    peer = %socket.address,
+                    error = %e,
+                    "failed to send message"
+                );
+
+                let mut state = shared.state.lock().unwrap();
+
+                state.closed = true;
+                state.queue.clear();
+                shared.space.notify_all();
+                break;
+            }
+        }
+
+        socket.shutdown();
+    }
+}
@@ -0,0 +1,346 @@
+use crate::socket::{
+    BoxEvent, ClientEntry, DisconnectReason, Error, Frame, Server, ServerBuilder, Socket,
+};
+
+use mio::net::TcpListener;
+use mio::{Interest, Poll, Token};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Default interval at which waiting loops, like `ServerHandle::shutdown`, check for progress
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Jobs a worker of the `Backend::Reactor` holds before the connections pinned to it are no
+/// longer read
+pub const WORKER_QUEUE_CAPACITY: usize = 1024;
+
+/// ## Backend
+///
+/// How `ServerBuilder::startup` serves its clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// One readiness-based event loop plus a small worker pool (the default)
+    Reactor,
+    /// One thread per client, blocking on reads from its socket
+    Threaded,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Reactor
+    }
+}
+
+/// Default number of worker threads used by the `Backend::Reactor`
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
+
+enum Job {
+    Message(ClientEntry, String, Frame),
+    Disconnect(ClientEntry, String, DisconnectReason),
+}
+
+struct Connection {
+    source: mio::net::TcpStream,
+    socket: Socket,
+    entry: ClientEntry,
+    address: String,
+    worker: usize,
+    /// Jobs read from the socket that did not fit in the worker's queue yet
+    backlog: VecDeque<Job>,
+    /// Whether the socket was closed and deregistered
+    closed: bool,
+}
+
+/// ## Reactor
+///
+/// Event loop that waits for socket readiness with `mio` and hands the work to a worker pool.
+/// Every client is pinned to one worker, so its events are dispatched in the order they arrived.
+///
+/// Worker queues hold `WORKER_QUEUE_CAPACITY` jobs. A connection whose worker queue is full is
+/// not read until its jobs are handed over, so a client sending faster than its handlers run
+/// waits in its socket buffer instead of growing the server's memory.
+///
+/// Properties:
+///
+/// * `server`: The server being served.
+/// * `events`: The events registered on the `ServerBuilder`.
+/// * `workers`: Senders of the worker threads' job queues.
+pub(crate) struct Reactor {
+    server: Server,
+    events: Arc<RwLock<HashMap<String, BoxEvent>>>,
+    workers: Vec<SyncSender<Job>>,
+}
+
+impl Reactor {
+    /// Initialize new instance of the `Reactor` and spawn its workers
+    ///
+    /// Arguments:
+    ///
+    /// * `server`: The server being served.
+    /// * `events`: The events registered on the `ServerBuilder`.
+    /// * `workers`: Number of worker threads, at least one.
+    pub fn new(
+        server: Server,
+        events: Arc<RwLock<HashMap<String, BoxEvent>>>,
+        workers: usize,
+    ) -> Self {
+        let mut reactor = Reactor {
+            server: server,
+            events: events,
+            workers: vec![],
+        };
+
+        for _ in 0..workers.max(1) {
+            let (sender, receiver) = sync_channel(WORKER_QUEUE_CAPACITY);
+            let server = reactor.server.clone();
+            let events = reactor.events.clone();
+
+            thread::spawn(move || Reactor::work(server, events, receiver));
+
+            reactor.workers.push(sender);
+        }
+
+        reactor
+    }
+
+    /// Run the event loop, blocks the calling thread
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut poll = Poll::new().map_err(Reactor::error)?;
+        let mut events = mio::Events::with_capacity(1024);
+
+        // The listeners take the first tokens, the connections the ones after them
+        let mut listeners = vec![];
+        let mut sources = vec![];
+
+        for (index, listener) in self.server.listeners.iter().enumerate() {
+            let socket = listener
+                .listener
+                .lock()
+                .unwrap()
+                .try_clone()
+                .map_err(Reactor::error)?;
+            socket.set_nonblocking(true).map_err(Reactor::error)?;
+
+            let mut source = TcpListener::from_std(socket.try_clone().map_err(Reactor::error)?);
+            poll.registry()
+                .register(&mut source, Token(index), Interest::READABLE)
+                .map_err(Reactor::error)?;
+
+            listeners.push(socket);
+            sources.push(source);
+        }
+
+        let mut connections: HashMap<Token, Connection> = HashMap::new();
+        let mut paused: HashSet<Token> = HashSet::new();
+        let mut next = listeners.len();
+
+        loop {
+            // Paused connections get no new readiness events, so they are retried on a timer
+            let timeout = if paused.is_empty() {
+                None
+            } else {
+                Some(self.server.poll_interval)
+            };
+
+            if let Err(e) = poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+
+                return Err(Reactor::error(e));
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    // Shutting down, new connections are closed right away
+                    Token(index)
+                        if index < listeners.len()
+                            && !self.server.accepting.load(Ordering::SeqCst) =>
+                    {
+                        while let Ok((stream, _)) = listeners[index].accept() {
+                            drop(stream);
+                        }
+                    }
+                    Token(index) if index < listeners.len() => loop {
+                        match listeners[index].accept() {
+                            Ok((stream, _)) => {
+                                let token = Token(next);
+                                next += 1;
+
+                                let connection = self.server.listeners[index]
+                                    .socket(stream)
+                                    .and_then(|socket| self.accept(&poll, token, socket));
+
+                                if let Ok(connection) = connection {
+                                    connections.insert(token, connection);
+                                }
+                            }
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                            Err(_) => break,
+                        }
+                    },
+                    token => {
+                        if let Some(connection) = connections.get_mut(&token) {
+                            self.read(&poll, connection);
+                        }
+
+                        Reactor::settle(&mut connections, &mut paused, token);
+                    }
+                }
+            }
+
+            for token in paused.clone() {
+                if let Some(connection) = connections.get_mut(&token) {
+                    if self.flush(connection) {
+                        self.read(&poll, connection);
+                    }
+                }
+
+                Reactor::settle(&mut connections, &mut paused, token);
+            }
+        }
+    }
+
+    /// Read what a connection sent and hand it to its worker, unless earlier jobs of the
+    /// connection are still waiting for room in the worker's queue
+    fn read(&self, poll: &Poll, connection: &mut Connection) {
+        if connection.closed || !connection.backlog.is_empty() {
+            return;
+        }
+
+        let reason = match connection.socket.read_available() {
+            Ok((frames, closed)) => {
+                // Nothing after an oversized frame can be read
+                let oversized = frames
+                    .last()
+                    .is_some_and(|frame| matches!(frame, Frame::Oversized(_)));
+
+                for frame in frames {
+                    connection.backlog.push_back(Job::Message(
+                        connection.entry.clone(),
+                        connection.address.clone(),
+                        frame,
+                    ));
+                }
+
+                if closed || oversized {
+                    Some(DisconnectReason::Closed)
+                } else {
+                    None
+                }
+            }
+            Err(e) => Some(DisconnectReason::Error(e.message().to_string())),
+        };
+
+        if let Some(reason) = reason {
+            let _ = poll.registry().deregister(&mut connection.source);
+
+            connection.closed = true;
+            connection.backlog.push_back(Job::Disconnect(
+                connection.entry.clone(),
+                connection.address.clone(),
+                reason,
+            ));
+        }
+
+        self.flush(connection);
+    }
+
+    /// Hand a connection's jobs to its worker until the worker's queue is full, returns `true`
+    /// once none are left
+    fn flush(&self, connection: &mut Connection) -> bool {
+        while let Some(job) = connection.backlog.pop_front() {
+            match self.workers[connection.worker].try_send(job) {
+                Ok(()) => {}
+                Err(TrySendError::Full(job)) => {
+                    connection.backlog.push_front(job);
+                    return false;
+                }
+                Err(TrySendError::Disconnected(_)) => connection.backlog.clear(),
+            }
+        }
+
+        true
+    }
+
+    /// Pause a connection while it has jobs left, drop it once it is closed and they are all
+    /// handed over
+    fn settle(
+        connections: &mut HashMap<Token, Connection>,
+        paused: &mut HashSet<Token>,
+        token: Token,
+    ) {
+        match connections.get(&token) {
+            Some(connection) if !connection.backlog.is_empty() => {
+                paused.insert(token);
+            }
+            Some(connection) if connection.closed => {
+                connections.remove(&token);
+                paused.remove(&token);
+            }
+            _ => {
+                paused.remove(&token);
+            }
+        }
+    }
+
+    fn accept(&self, poll: &Poll, token: Token, mut socket: Socket) -> Result<Connection, Error> {
+        socket.set_nonblocking(true)?;
+        socket.set_buffer_size(self.server.read_buffer);
+        // Raised once the peer proves to be a linked server
+        socket.set_max_frame_size(Some(self.server.max_message_size));
+
+        let mut source = socket.mio_source()?;
+        poll.registry()
+            .register(&mut source, token, Interest::READABLE)
+            .map_err(Reactor::error)?;
+
+        let entry = ServerBuilder::accept(&self.server, &socket);
+
+        ServerBuilder::admit(&self.server, &entry, &socket);
+
+        Ok(Connection {
+            entry: entry,
+            address: socket.address.to_string(),
+            worker: token.0 % self.workers.len(),
+            source: source,
+            socket: socket,
+            backlog: VecDeque::new(),
+            closed: false,
+        })
+    }
+
+    fn work(
+        mut server: Server,
+        events: Arc<RwLock<HashMap<String, BoxEvent>>>,
+        receiver: Receiver<Job>,
+    ) {
+        for job in receiver {
+            match job {
+                Job::Message(entry, address, frame) => {
+                    ServerBuilder::receive(&events, &mut server, &entry, &address, frame);
+                }
+                Job::Disconnect(entry, address, reason) => {
+                    ServerBuilder::close(&events, &mut server, &entry, &address, reason);
+                }
+            }
+        }
+    }
+
+    fn error(e: std::io::Error) -> Error {
+        Error {
+            message: format!("Unexcepted error in event loop: {:?}", e),
+        }
+    }
+}
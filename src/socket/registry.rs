@@ -0,0 +1,236 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+
+/// Default number of shards in a `Registry`
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// ## Registry
+///
+/// Concurrent map split into independently locked shards, so lookups and updates for
+/// different keys rarely contend with each other.
+///
+/// Properties:
+///
+/// * `shards`: The shards, each guarding a part of the key space.
+pub struct Registry<T> {
+    shards: Vec<RwLock<HashMap<String, T>>>,
+}
+
+impl<T: Clone> Registry<T> {
+    /// Initialize new instance of the `Registry` with `DEFAULT_SHARDS` shards
+    ///
+    /// Example:
+    /// ```rs
+    /// let registry: Registry<ClientEntry> = Registry::new();
+    /// ```
+    pub fn new() -> Self {
+        Registry::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Initialize new instance of the `Registry`
+    ///
+    /// Arguments:
+    ///
+    /// * `shards`: The number of shards, at least one.
+    pub fn with_shards(shards: usize) -> Self {
+        Registry {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<HashMap<String, T>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        &self.shards[(hasher.finish() as usize) % self.shards.len()]
+    }
+
+    /// Insert a value unless the key is already taken
+    ///
+    /// Returns the value already stored under the key as the error when it is taken, the new
+    /// value is then dropped, so the caller has to release anything it holds on to.
+    ///
+    /// Arguments:
+    ///
+    /// * `key`: &str - The key to insert under.
+    /// * `value`: T - The value to insert.
+    pub fn insert(&self, key: &str, value: T) -> Result<(), T> {
+        match self.shard(key).write().unwrap().entry(key.to_string()) {
+            Entry::Occupied(entry) => Err(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Get a copy of the value stored under the key
+    pub fn get(&self, key: &str) -> Option<T> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    /// Remove the value stored under the key
+    pub fn remove(&self, key: &str) -> Option<T> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Check whether the key is present
+    pub fn contains(&self, key: &str) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    /// Number of stored values
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    /// Check whether the registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.read().unwrap().is_empty())
+    }
+
+    /// Copy out every key and value
+    ///
+    /// Shards are locked one at a time, so the result is not an atomic snapshot of the
+    /// whole registry, but no lock is held while the caller works with it.
+    pub fn entries(&self) -> Vec<(String, T)> {
+        let mut entries = vec![];
+
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+
+            for (key, value) in shard.iter() {
+                entries.push((key.clone(), value.clone()));
+            }
+        }
+
+        entries
+    }
+
+    /// Copy out every key
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = vec![];
+
+        for shard in &self.shards {
+            keys.extend(shard.read().unwrap().keys().cloned());
+        }
+
+        keys
+    }
+
+    /// Copy out every value
+    pub fn values(&self) -> Vec<T> {
+        self.entries().into_iter().map(|(_, value)| value).collect()
+    }
+}
+
+impl<T: Clone> Default for Registry<T> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+/// ## Connections
+///
+/// Counts open connections in total and by IP address, so admitting a connection does not
+/// have to look at every other one.
+///
+/// Properties:
+///
+/// * `counts`: The total and the count of every address with open connections.
+#[derive(Default)]
+pub struct Connections {
+    counts: Mutex<(usize, HashMap<IpAddr, usize>)>,
+}
+
+impl Connections {
+    /// Initialize new instance of the `Connections` with nothing open
+    pub fn new() -> Self {
+        Connections::default()
+    }
+
+    /// Count a new connection
+    ///
+    /// Returns the open connections in total and from the address, including the new one.
+    ///
+    /// Arguments:
+    ///
+    /// * `ip`: The address of the peer.
+    pub fn open(&self, ip: IpAddr) -> (usize, usize) {
+        let mut counts = self.counts.lock().unwrap();
+        let (total, by_ip) = &mut *counts;
+        let count = by_ip.entry(ip).or_insert(0);
+
+        *total += 1;
+        *count += 1;
+
+        (*total, *count)
+    }
+
+    /// Stop counting a connection counted with `Connections::open`
+    ///
+    /// Arguments:
+    ///
+    /// * `ip`: The address of the peer.
+    pub fn close(&self, ip: IpAddr) {
+        let mut counts = self.counts.lock().unwrap();
+        let (total, by_ip) = &mut *counts;
+
+        if let Entry::Occupied(mut count) = by_ip.entry(ip) {
+            *total -= 1;
+            *count.get_mut() -= 1;
+
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+
+    /// Get the number of open connections
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap().0
+    }
+
+    /// Get the number of open connections from an address
+    ///
+    /// Arguments:
+    ///
+    /// * `ip`: The address of the peer.
+    pub fn from_ip(&self, ip: &IpAddr) -> usize {
+        self.counts.lock().unwrap().1.get(ip).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_connections_by_address() {
+        let connections = Connections::new();
+        let first: IpAddr = "203.0.113.1".parse().unwrap();
+        let second: IpAddr = "203.0.113.2".parse().unwrap();
+
+        assert_eq!(connections.open(first), (1, 1));
+        assert_eq!(connections.open(second), (2, 1));
+        assert_eq!(connections.open(first), (3, 2));
+
+        connections.close(first);
+        connections.close(first);
+
+        assert_eq!(connections.total(), 1);
+        assert_eq!(connections.from_ip(&first), 0);
+        assert_eq!(connections.from_ip(&second), 1);
+
+        // Closing an address that is not counted changes nothing
+        connections.close(first);
+
+        assert_eq!(connections.total(), 1);
+    }
+}
@@ -0,0 +1,560 @@
+use crate::config::{ConfigError, RateLimitConfig, ServerConfig};
+use crate::middleware::RateLimit;
+use crate::socket::{BanMask, Server, ServerBuilder, OPERATOR_FLAG};
+use crate::trace::event;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// ## ReloadReport
+///
+/// What a successful `ServerHandle::reload` changed.
+///
+/// Properties:
+///
+/// * `applied`: The settings that changed: `motd`, `rate_limit`, `operators` or `bans`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+}
+
+/// ## ServerHandle
+///
+/// Changes the settings of a running server without disconnecting its clients, or shuts it
+/// down.
+///
+/// The message of the day, the rate limit, the operators and the bans of a `ServerConfig`
+/// can be reloaded. Every other setting is fixed once the server has started.
+///
+/// Example:
+/// ```rs
+/// let mut server = ServerBuilder::from_config(&ServerConfig::load("server.toml")?)?;
+/// let handle = server.handle();
+///
+/// handle.watch("server.toml", Duration::from_secs(2), |result| match result {
+///     Ok(report) => println!("reloaded {:?}", report.applied),
+///     Err(e) => eprintln!("reload failed: {}", e),
+/// });
+///
+/// server.startup();
+/// ```
+///
+/// Properties:
+///
+/// * `server`: The server being changed.
+/// * `config`: The configuration last applied, shared with the `ServerBuilder`.
+/// * `rate_limit`: The `RateLimit` layer holding the configured limit.
+pub struct ServerHandle {
+    server: Server,
+    config: Arc<Mutex<ServerConfig>>,
+    rate_limit: Arc<RateLimit>,
+}
+
+impl Clone for ServerHandle {
+    fn clone(&self) -> Self {
+        ServerHandle {
+            server: self.server.clone(),
+            config: self.config.clone(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
+}
+
+impl ServerHandle {
+    /// Get the configuration last applied
+    pub fn config(&self) -> ServerConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Apply a new configuration to the running server
+    ///
+    /// Nothing is applied when any setting that needs a restart differs from the running
+    /// server; the error names every such key. Otherwise all reloadable settings are applied
+    /// together, or none of them when the bans cannot be stored: clients matching a new ban
+    /// are kicked and clients whose operator was removed, or given a new password, lose the
+    /// operator flag.
+    ///
+    /// Example:
+    /// ```rs
+    /// let report = handle.reload(&ServerConfig::load("server.toml")?)?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `config`: The new configuration.
+    pub fn reload(&self, config: &ServerConfig) -> Result<ReloadReport, ConfigError> {
+        let mut current = self.config.lock().unwrap();
+        let restart = ServerHandle::restart_keys(&current, config);
+
+        if !restart.is_empty() {
+            event!(warn, keys = ?restart, "configuration reload rejected");
+
+            return Err(ConfigError::new(
+                &restart.join(", "),
+                "cannot be changed without a restart",
+            ));
+        }
+
+        for mask in config.bans.keys() {
+            BanMask::parse(mask)
+                .map_err(|e| ConfigError::new(&format!("bans.{}", mask), e.message()))?;
+        }
+
+        // Bans are the only setting that can fail to apply, so they go first and everything
+        // else is only changed once they are stored
+        let added = if current.bans != config.bans {
+            let lift: Vec<String> = current
+                .bans
+                .keys()
+                .filter(|mask| !config.bans.contains_key(*mask))
+                .cloned()
+                .collect();
+            let add: Vec<(String, String)> = config
+                .bans
+                .iter()
+                .filter(|(mask, reason)| current.bans.get(*mask) != Some(*reason))
+                .map(|(mask, reason)| (mask.clone(), reason.clone()))
+                .collect();
+
+            Some(
+                self.server
+                    .moderation
+                    .update_bans(&lift, &add)
+                    .map_err(|e| ConfigError::new("bans", e.message()))?,
+            )
+        } else {
+            None
+        };
+
+        let mut report = ReloadReport::default();
+
+        if current.motd != config.motd {
+            *self.server.motd.write().unwrap() = config.motd.clone();
+            report.applied.push("motd".to_string());
+        }
+
+        if current.rate_limit != config.rate_limit {
+            self.rate_limit
+                .set_limit(config.rate_limit.as_ref().map(|l| (l.burst, l.per)));
+            report.applied.push("rate_limit".to_string());
+        }
+
+        if current.operators != config.operators {
+            self.operators(&current.operators, &config.operators);
+            report.applied.push("operators".to_string());
+        }
+
+        if let Some(added) = added {
+            for ban in &added {
+                self.server.enforce(ban, None);
+            }
+
+            report.applied.push("bans".to_string());
+        }
+
+        event!(info, applied = ?report.applied, "configuration reloaded");
+
+        *current = config.clone();
+
+        Ok(report)
+    }
+
+    /// Reload the configuration whenever a file changes, from a background thread
+    ///
+    /// The file is read with `ServerConfig::load` and `ServerConfig::with_env`, so environment
+    /// variables keep overriding it.
+    ///
+    /// Arguments:
+    ///
+    /// * `path`: The configuration file.
+    /// * `interval`: How often the file's modification time is checked.
+    /// * `report`: Called with the outcome of every reload.
+    pub fn watch<P, F>(&self, path: P, interval: Duration, report: F)
+    where
+        P: AsRef<Path>,
+        F: Fn(Result<ReloadReport, ConfigError>) + core::marker::Send + 'static,
+    {
+        let handle = self.clone();
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let modified = |path: &Path| -> Option<SystemTime> {
+            fs::metadata(path).and_then(|m| m.modified()).ok()
+        };
+
+        thread::spawn(move || {
+            let mut last = modified(&path);
+
+            loop {
+                thread::sleep(interval);
+
+                let current = modified(&path);
+
+                if current.is_none() || current == last {
+                    continue;
+                }
+
+                last = current;
+
+                report(
+                    ServerConfig::load(&path)
+                        .and_then(|config| config.with_env())
+                        .and_then(|config| handle.reload(&config)),
+                );
+            }
+        });
+    }
+
+    /// Disconnect every client and wait until they are gone, before the process exits
+    ///
+    /// The server stops accepting connections first, see `Server::accepting`. Clients
+    /// receive `KICKED :reason` like any kicked client and `on_client_disconnect` fires for
+    /// each of them. Connections still in the handshake are closed as well. Returns `false`
+    /// when clients remained connected after `grace`.
+    ///
+    /// Example:
+    /// ```rs
+    /// handle.shutdown("Server shutting down", Duration::from_secs(5));
+    /// process::exit(0);
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `reason`: Sent to every client.
+    /// * `grace`: How long to wait for the outbound queues to be flushed.
+    pub fn shutdown(&self, reason: &str, grace: Duration) -> bool {
+        event!(info, clients = self.server.clients.len(), "shutting down");
+
+        self.server.accepting.store(false, Ordering::SeqCst);
+
+        // Clients busy running a handler are not waited for, see `Server::kick_entry`
+        for (address, (entry, _)) in self.server.pending.entries() {
+            self.server.kick_entry(&entry, &address, reason);
+        }
+
+        for (address, entry) in self.server.clients.entries() {
+            self.server.kick_entry(&entry, &address, reason);
+        }
+
+        let deadline = Instant::now() + grace;
+
+        while Instant::now() < deadline {
+            if self.server.clients.is_empty() && self.server.pending.is_empty() {
+                return true;
+            }
+
+            thread::sleep(self.server.poll_interval);
+        }
+
+        false
+    }
+
+    /// Keys whose change needs a restart
+    fn restart_keys(current: &ServerConfig, config: &ServerConfig) -> Vec<String> {
+        let addresses = |bind: &[String]| {
+            bind.iter()
+                .map(|address| address.parse::<SocketAddr>().ok())
+                .collect::<Vec<_>>()
+        };
+
+        let checks = [
+            ("bind", addresses(&current.bind) != addresses(&config.bind)),
+            ("tls", current.tls != config.tls),
+            (
+                "limits.max_clients",
+                current.max_clients != config.max_clients,
+            ),
+            (
+                "limits.max_connections_per_ip",
+                current.max_connections_per_ip != config.max_connections_per_ip,
+            ),
+            (
+                "limits.max_message_size",
+                current.max_message_size != config.max_message_size,
+            ),
+            (
+                "limits.max_link_message_size",
+                current.max_link_message_size != config.max_link_message_size,
+            ),
+            (
+                "limits.read_buffer",
+                current.read_buffer != config.read_buffer,
+            ),
+            (
+                "limits.queue_capacity",
+                current.queue_capacity != config.queue_capacity,
+            ),
+            (
+                "timeouts.poll_interval_ms",
+                current.poll_interval != config.poll_interval,
+            ),
+            (
+                "timeouts.handshake_secs",
+                current.handshake_timeout != config.handshake_timeout,
+            ),
+            (
+                "timeouts.idle_secs",
+                current.idle_timeout != config.idle_timeout,
+            ),
+        ];
+
+        checks
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+
+    /// Replace the operators and take the flag from clients of removed ones, and of the ones
+    /// whose password changed
+    fn operators(&self, current: &HashMap<String, String>, operators: &HashMap<String, String>) {
+        *self.server.operators.write().unwrap() = operators.clone();
+
+        for entry in self.server.clients.values() {
+            let mut client = entry.client.lock().unwrap();
+            let revoked = match client.flags.get(OPERATOR_FLAG) {
+                Some(user) => current
+                    .get(user)
+                    .is_some_and(|hash| operators.get(user) != Some(hash)),
+                None => false,
+            };
+
+            if revoked {
+                client.remove_flag(OPERATOR_FLAG);
+            }
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Get a handle for reloading the server's configuration while it runs
+    ///
+    /// Take the handle once the builder is configured: settings changed on the builder
+    /// afterwards are not seen by it.
+    pub fn handle(&mut self) -> ServerHandle {
+        let rate_limit = match &self.rate_limit {
+            Some(rate_limit) => rate_limit.clone(),
+            None => {
+                let rate_limit = Arc::new(RateLimit::unlimited());
+
+                self.middleware(rate_limit.clone());
+                self.rate_limit = Some(rate_limit.clone());
+                rate_limit
+            }
+        };
+
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => {
+                let config = Arc::new(Mutex::new(ServerBuilder::snapshot(
+                    &self.server,
+                    &rate_limit,
+                )));
+
+                self.config = Some(config.clone());
+                config
+            }
+        };
+
+        ServerHandle {
+            server: self.server.clone(),
+            config: config,
+            rate_limit: rate_limit,
+        }
+    }
+
+    /// Describe a server that was not built from a `ServerConfig`
+    fn snapshot(server: &Server, rate_limit: &RateLimit) -> ServerConfig {
+        ServerConfig {
+            // Certificates are not known here, so only the plain listeners are described
+            bind: server
+                .listeners
+                .iter()
+                .filter(|listener| !listener.is_tls())
+                .map(|listener| listener.address.to_string())
+                .collect(),
+            tls: None,
+            max_clients: server.max_clients,
+            max_connections_per_ip: server.max_connections_per_ip,
+            max_message_size: server.max_message_size,
+            max_link_message_size: server.max_link_message_size,
+            read_buffer: server.read_buffer,
+            queue_capacity: server.queue_capacity,
+            poll_interval: server.poll_interval,
+            handshake_timeout: server.handshake_timeout,
+            idle_timeout: server.idle_timeout,
+            rate_limit: rate_limit.limit().map(|(burst, per)| RateLimitConfig {
+                burst: burst,
+                per: per,
+            }),
+            motd: server.motd.read().unwrap().clone(),
+            operators: server.operators.read().unwrap().clone(),
+            bans: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::CredentialFile;
+    use crate::extensions::string::StringExtension;
+    use crate::io::Read;
+    use crate::lagerung::Lagerung;
+    use crate::protoutils::Hello;
+    use crate::socket::testing;
+    use crate::socket::{Moderation, Socket};
+
+    fn config() -> ServerConfig {
+        ServerConfig {
+            bind: vec!["127.0.0.1:0".to_string()],
+            motd: Some("Hello".to_string()),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn applies_reloadable_settings() {
+        let mut server = ServerBuilder::from_config(&config()).unwrap();
+        let handle = server.handle();
+        let mut changed = config();
+
+        changed.motd = Some("Bye".to_string());
+        changed
+            .bans
+            .insert("*@203.0.113.0/24".to_string(), "Spam".to_string());
+
+        let report = handle.reload(&changed).unwrap();
+
+        assert_eq!(report.applied, vec!["motd", "bans"]);
+        assert_eq!(handle.config(), changed);
+        assert_eq!(handle.server.moderation.bans().len(), 1);
+    }
+
+    #[test]
+    fn rejects_settings_that_need_a_restart() {
+        let mut server = ServerBuilder::from_config(&config()).unwrap();
+        let handle = server.handle();
+        let mut changed = config();
+
+        changed.motd = Some("Bye".to_string());
+        changed.max_message_size *= 2;
+
+        let error = handle.reload(&changed).unwrap_err();
+
+        assert_eq!(error.key, "limits.max_message_size");
+        assert_eq!(handle.config().motd.as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn applies_nothing_when_bans_cannot_be_stored() {
+        // The directory does not exist, so every save fails
+        let path = std::env::temp_dir()
+            .join(format!("baka-missing-{}", String::generate_id()))
+            .join("moderation.db");
+        let mut server = ServerBuilder::from_config(&config()).unwrap();
+
+        server
+            .moderation(Moderation::persistent(Lagerung::open(&path).unwrap()))
+            .unwrap();
+
+        let handle = server.handle();
+        let mut changed = config();
+
+        changed.motd = Some("Bye".to_string());
+        changed
+            .bans
+            .insert("*@203.0.113.0/24".to_string(), "Spam".to_string());
+
+        assert_eq!(handle.reload(&changed).unwrap_err().key, "bans");
+        assert_eq!(handle.config().motd.as_deref(), Some("Hello"));
+        assert_eq!(handle.server.motd.read().unwrap().as_deref(), Some("Hello"));
+        assert!(handle.server.moderation.bans().is_empty());
+    }
+
+    #[test]
+    fn revokes_operators_whose_password_changed() {
+        let mut initial = config();
+
+        for (user, password) in [("alice", "hunter2"), ("bob", "swordfish")] {
+            initial
+                .operators
+                .insert(user.to_string(), CredentialFile::hash(password).unwrap());
+        }
+
+        let mut server = ServerBuilder::from_config(&initial).unwrap();
+        let handle = server.handle();
+        let address = testing::start(server);
+        let mut clients = vec![];
+
+        for (user, password) in [("alice", "hunter2"), ("bob", "swordfish")] {
+            let (mut socket, inbox) = testing::join(&address, user);
+
+            testing::send(&mut socket, &format!("OPER {{{}}} :{}", user, password));
+            testing::expect(&inbox, |message| {
+                message.content == format!("OPER {{{}}}", user)
+            });
+
+            clients.push(socket);
+        }
+
+        let mut changed = initial.clone();
+
+        changed.operators.insert(
+            "alice".to_string(),
+            CredentialFile::hash("hunter3").unwrap(),
+        );
+
+        assert_eq!(handle.reload(&changed).unwrap().applied, vec!["operators"]);
+
+        let operator = |nick: &str| {
+            let entry = handle.server.find(nick).unwrap();
+            let client = entry.client.lock().unwrap();
+
+            client.flags.get(OPERATOR_FLAG).cloned()
+        };
+
+        assert_eq!(operator("alice"), None);
+        assert_eq!(operator("bob").as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn shuts_down_without_waiting_for_busy_clients() {
+        let mut server = ServerBuilder::from_config(&config()).unwrap();
+        let handle = server.handle();
+        let address = testing::start(server);
+        let (_socket, _inbox) = testing::join(&address, "alice");
+
+        testing::wait_for(|| handle.server.find("alice").is_some());
+
+        let entry = handle.server.find("alice").unwrap();
+        // Locked the way a running handler locks it
+        let _busy = entry.client.lock().unwrap();
+
+        // Returns once the grace period is over instead of waiting for the lock
+        handle.shutdown("Bye", Duration::from_millis(100));
+
+        assert!(entry.outbound.is_closed());
+    }
+
+    #[test]
+    fn stops_accepting_on_shutdown() {
+        let mut server = ServerBuilder::from_config(&config()).unwrap();
+        let handle = server.handle();
+        let address = testing::start(server);
+
+        assert!(handle.shutdown("Bye", Duration::from_secs(1)));
+
+        let mut socket = Socket::connect(&address).unwrap();
+        let author = socket.local_address();
+        let _ = socket.send_message(Hello::new(vec![]).to_message(&author));
+
+        // Closed without a `WELCOME`
+        assert!(!matches!(socket.read_stream(), Ok((data, _)) if !data.is_empty()));
+        assert!(handle.server.pending.is_empty());
+    }
+}
@@ -1,26 +1,72 @@
+use crate::auth::{Authenticator, Throttle};
+use crate::config::{ConfigError, ServerConfig, TlsConfig};
+use crate::extensions::names::NameRules;
 use crate::extensions::string::StringExtension;
-use crate::io::Send;
+use crate::middleware::{Middleware, Pipeline, RateLimit};
 use crate::protoutils;
-use crate::socket::{Error, Socket};
+#[cfg(feature = "tls")]
+use crate::socket::TlsAcceptor;
+use crate::socket::{
+    default_workers, Backend, BoxEvent, Channel, Connections, Context, DisconnectReason, Error,
+    Event, Federation, Frame, History, Listener, Metrics, Moderation, Outbound, OverflowPolicy,
+    PresenceTracker, Reactor, Registry, Socket, Stats, DEFAULT_BUFFER_SIZE, DEFAULT_HISTORY_REPLAY,
+    DEFAULT_POLL_INTERVAL, LINK_CAPABILITY,
+};
+use crate::trace::ConnectionSpan;
+use crate::{Clock, Scheduler, SystemClock};
 
-use bakaproto::proto::*;
 use protobuf::Message;
 
 use std::collections::HashMap;
-use std::net::{SocketAddr, TcpListener};
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time;
 
-type BoxEvent = Box<
-    dyn Fn(Arc<Mutex<&mut Server>>, Arc<Mutex<&mut Client>>, Result<message::Message, Error>)
+/// Hook called for every private message, returning `None` vetoes it
+pub type BoxPrivateMessageHook = Box<
+    dyn Fn(&mut Server, protoutils::BakaMessage) -> Option<protoutils::BakaMessage>
         + core::marker::Send
+        + Sync
         + 'static,
 >;
 
+/// Default number of messages a client's outbound queue can hold
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// Default size of the largest message accepted from a client, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 65536;
+
+/// Default size of the largest message accepted from a linked server, in bytes
+pub const DEFAULT_MAX_LINK_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default time a client has to finish the handshake and log in
+pub const DEFAULT_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// ## ClientState
+///
+/// Where a client is in the connection lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientState {
+    /// Waiting for the client's `Hello`
+    Handshake,
+    /// Handshake done, only `LOGIN` and `TOKEN` commands are accepted
+    Unauthenticated,
+    /// Registered in `Server::clients`, events are dispatched
+    Connected,
+    /// A server opening or accepting a link, only the link handshake is accepted
+    Linking,
+    /// A linked server, see `Federation`
+    Link,
+}
+
 pub struct Client {
     pub socket: Socket,
     pub flags: HashMap<String, String>,
+    pub outbound: Outbound,
+    pub state: ClientState,
+    pub kicked: Option<String>,
 }
 
 impl Clone for Client {
@@ -34,6 +80,9 @@ impl Clone for Client {
         Client {
             socket: self.socket.clone(),
             flags: flags,
+            outbound: self.outbound.clone(),
+            state: self.state,
+            kicked: self.kicked.clone(),
         }
     }
 }
@@ -48,46 +97,190 @@ impl Client {
     }
 
     pub fn remove_flag(&mut self, flag: &str) -> bool {
-        self.flags.remove(&flag.to_string())
+        self.flags.remove(&flag.to_string()).is_some()
+    }
+
+    /// Get the client's nickname, the peer address until one is assigned
+    pub fn nick(&self) -> String {
+        match self.flags.get("nick") {
+            Some(nick) => nick.clone(),
+            None => self.socket.address.to_string(),
+        }
+    }
+
+    /// Queue data for the client's writer thread without blocking on the socket
+    ///
+    /// Arguments:
+    ///
+    /// * `data`: Vec<u8> - The bytes to send.
+    pub fn queue(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        self.outbound.push(data)
     }
 }
 
+/// ## ClientEntry
+///
+/// Registry entry for a connected client. The outbound queue is kept beside the locked
+/// client, so broadcasting never waits on a client that is busy running a handler.
+///
+/// Properties:
+///
+/// * `client`: The client, locked only while one of its own events is handled.
+/// * `outbound`: The client's outbound queue.
+/// * `span`: Tracing span of the connection, empty without the `tracing` feature.
+/// * `kicked`: Why the client was kicked while it was busy running a handler.
+#[derive(Clone)]
+pub struct ClientEntry {
+    pub client: Arc<Mutex<Client>>,
+    pub outbound: Outbound,
+    pub(crate) span: ConnectionSpan,
+    pub(crate) kicked: Arc<Mutex<Option<String>>>,
+}
+
 /// ## Sterver
 ///
 /// Properties:
 ///
-/// * `listener`: This is the TCP listener that will listen for incoming connections.
-/// * `address`: The address of the server.
-/// * `clients`: A HashMap that stores the client's username as the key and the client's socket as the
+/// * `listeners`: The addresses the server accepts connections on, see `ServerBuilder::listen`.
+/// * `accepting`: Cleared by `ServerHandle::shutdown`, connections arriving after that are
+///   closed right away.
+/// * `address`: The address of the first listener, with the port picked when bound to port 0.
+/// * `clients`: A sharded registry that stores the client's username as the key and the client as the
 /// value.
+/// * `queue_capacity`: Number of messages each client's outbound queue can hold.
+/// * `overflow_policy`: What to do when a client's outbound queue is full.
+/// * `capabilities`: Capabilities the server offers during the handshake.
+/// * `authenticator`: Checks client logins, clients connect without logging in when `None`.
+/// * `throttle`: Counts failed logins and locks out peers with too many.
+/// * `nicks`: Maps the nicknames of connected clients, by `NameRules::key`, to their keys in
+///   `clients`.
+/// * `private_message_hook`: Called for every private message before it is relayed.
+/// * `history`: Recent messages of every channel and private conversation.
+/// * `history_replay`: Number of messages replayed to a client joining a channel.
+/// * `presence`: Away messages, idle times and last-seen times by nickname.
+/// * `moderation`: Ban list and per-channel mutes.
+/// * `federation`: Links to other servers of the network, `None` for a standalone server.
+/// * `middleware`: Layers every message to and from connected clients passes.
+/// * `metrics`: Counters behind `Server::stats`.
+/// * `motd`: Message of the day sent to every client that connects.
+/// * `operators`: Argon2 password hashes of the users that may become operators with `OPER`.
+/// * `max_clients`: Open connections allowed at once, `None` for no limit.
+/// * `max_connections_per_ip`: Open connections allowed from one IP address, `None` for no
+///   limit.
+/// * `max_message_size`: Largest message accepted from a client, in bytes.
+/// * `max_link_message_size`: Largest message accepted from a linked server, in bytes.
+/// * `read_buffer`: Size of the chunks read from client sockets, in bytes.
+/// * `poll_interval`: How often waiting loops, like `ServerHandle::shutdown`, check for progress.
+/// * `handshake_timeout`: Time a client has to finish the handshake and log in, `None` for no
+///   limit.
+/// * `idle_timeout`: Time a connected client may stay silent before it is kicked, `None` for no
+///   limit.
+/// * `pending`: Connections that are not connected yet, with the time they were accepted.
+/// * `connections`: Open connections admitted by the server, counted by IP address.
+/// * `clock`: Measures the handshake and idle timeouts.
+/// * `nick_rules`: What nicknames chosen with `NICK` and the names of logged-in users may look
+///   like.
+/// * `channel_rules`: What channel names joined with `JOIN` may look like. Channels are kept
+///   by `NameRules::key`, so `#Rust` and `#rust` are the same channel.
 pub struct Server {
-    pub listener: Arc<Mutex<TcpListener>>,
+    pub listeners: Vec<Listener>,
+    pub accepting: Arc<AtomicBool>,
     pub address: SocketAddr,
-    pub clients: Arc<Mutex<HashMap<String, Client>>>,
-    pub channels: Arc<Mutex<HashMap<String, Socket>>>,
+    pub clients: Arc<Registry<ClientEntry>>,
+    pub channels: Arc<RwLock<HashMap<String, Channel>>>,
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub capabilities: Vec<String>,
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub throttle: Arc<Throttle>,
+    pub nicks: Arc<Registry<String>>,
+    pub private_message_hook: Option<Arc<BoxPrivateMessageHook>>,
+    pub history: Arc<History>,
+    pub history_replay: usize,
+    pub presence: Arc<PresenceTracker>,
+    pub moderation: Arc<Moderation>,
+    pub federation: Option<Arc<Federation>>,
+    pub middleware: Arc<Pipeline>,
+    pub metrics: Arc<Metrics>,
+    pub motd: Arc<RwLock<Option<String>>>,
+    pub operators: Arc<RwLock<HashMap<String, String>>>,
+    pub max_clients: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_message_size: usize,
+    pub max_link_message_size: usize,
+    pub read_buffer: usize,
+    pub poll_interval: time::Duration,
+    pub handshake_timeout: Option<time::Duration>,
+    pub idle_timeout: Option<time::Duration>,
+    pub pending: Arc<Registry<(ClientEntry, time::Instant)>>,
+    pub connections: Arc<Connections>,
+    pub clock: Arc<dyn Clock>,
+    pub nick_rules: NameRules,
+    pub channel_rules: NameRules,
 }
 
 impl Clone for Server {
     fn clone(&self) -> Self {
         Server {
-            listener: self.listener.clone(),
+            listeners: self.listeners.clone(),
+            accepting: self.accepting.clone(),
             address: self.address.clone(),
             clients: self.clients.clone(),
             channels: self.channels.clone(),
+            queue_capacity: self.queue_capacity,
+            overflow_policy: self.overflow_policy,
+            capabilities: self.capabilities.clone(),
+            authenticator: self.authenticator.clone(),
+            throttle: self.throttle.clone(),
+            nicks: self.nicks.clone(),
+            private_message_hook: self.private_message_hook.clone(),
+            history: self.history.clone(),
+            history_replay: self.history_replay,
+            presence: self.presence.clone(),
+            moderation: self.moderation.clone(),
+            federation: self.federation.clone(),
+            middleware: self.middleware.clone(),
+            metrics: self.metrics.clone(),
+            motd: self.motd.clone(),
+            operators: self.operators.clone(),
+            max_clients: self.max_clients,
+            max_connections_per_ip: self.max_connections_per_ip,
+            max_message_size: self.max_message_size,
+            max_link_message_size: self.max_link_message_size,
+            read_buffer: self.read_buffer,
+            poll_interval: self.poll_interval,
+            handshake_timeout: self.handshake_timeout,
+            idle_timeout: self.idle_timeout,
+            pending: self.pending.clone(),
+            connections: self.connections.clone(),
+            clock: self.clock.clone(),
+            nick_rules: self.nick_rules.clone(),
+            channel_rules: self.channel_rules.clone(),
         }
     }
 }
 
-
 /// ## ServerBuilder
 ///
 /// Properties:
 ///
 /// * `server`: The server object that will be used to listen for connections.
-/// * `events`: A HashMap of String keys and BoxEvent values.
+/// * `events`: A HashMap of String keys and BoxEvent values, shared read-only by the connection threads.
+/// * `backend`: How connections are served, `Backend::Reactor` by default.
+/// * `workers`: Number of worker threads used by `Backend::Reactor`.
+/// * `links`: Addresses of the servers this one opens links to on startup.
+/// * `config`: The configuration last applied, shared with every `ServerHandle`.
+/// * `rate_limit`: The `RateLimit` layer a reload adjusts.
+/// * `timers`: Runs the timeout sweep, its timers stop when the builder is dropped.
 pub struct ServerBuilder {
-    server: Server,
-    events: Arc<Mutex<HashMap<String, BoxEvent>>>,
+    pub(crate) server: Server,
+    pub(crate) events: Arc<RwLock<HashMap<String, BoxEvent>>>,
+    backend: Backend,
+    workers: usize,
+    pub(crate) links: Vec<String>,
+    pub(crate) config: Option<Arc<Mutex<ServerConfig>>>,
+    pub(crate) rate_limit: Option<Arc<RateLimit>>,
+    pub(crate) timers: Option<Scheduler>,
 }
 
 impl Server {
@@ -103,59 +296,123 @@ impl Server {
     /// * `address`: The address to bind the server to.
     ///
     pub fn new(address: &str) -> Self {
-        let listener = TcpListener::bind(address).unwrap();
+        Server::bind(address).unwrap()
+    }
+
+    /// Initialize new instance of the `Server`, reporting an address that cannot be bound
+    ///
+    /// Example:
+    /// ```rs
+    /// let server = Server::bind("0.0.0.0:65432")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address to bind the server to.
+    pub fn bind(address: &str) -> Result<Self, Error> {
+        Ok(Server::with_listener(Listener::bind(address)?))
+    }
 
+    /// Initialize new instance of the `Server` accepting connections on a listener
+    ///
+    /// Arguments:
+    ///
+    /// * `listener`: The first listener, more are added with `ServerBuilder::listen`.
+    pub fn with_listener(listener: Listener) -> Self {
         Server {
-            listener: Arc::new(Mutex::new(listener)),
-            address: address.parse::<SocketAddr>().unwrap(),
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            channels: Arc::new(Mutex::new(HashMap::new())),
+            address: listener.address,
+            listeners: vec![listener],
+            accepting: Arc::new(AtomicBool::new(true)),
+            clients: Arc::new(Registry::new()),
+            channels: Arc::new(RwLock::new(HashMap::new())),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            capabilities: vec![],
+            authenticator: None,
+            throttle: Arc::new(Throttle::default()),
+            nicks: Arc::new(Registry::new()),
+            private_message_hook: None,
+            history: Arc::new(History::default()),
+            history_replay: DEFAULT_HISTORY_REPLAY,
+            presence: Arc::new(PresenceTracker::new()),
+            moderation: Arc::new(Moderation::new()),
+            federation: None,
+            middleware: Arc::new(Pipeline::new()),
+            metrics: Arc::new(Metrics::new()),
+            motd: Arc::new(RwLock::new(None)),
+            operators: Arc::new(RwLock::new(HashMap::new())),
+            max_clients: None,
+            max_connections_per_ip: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_link_message_size: DEFAULT_MAX_LINK_MESSAGE_SIZE,
+            read_buffer: DEFAULT_BUFFER_SIZE,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            idle_timeout: None,
+            pending: Arc::new(Registry::new()),
+            connections: Arc::new(Connections::new()),
+            clock: SystemClock::shared(),
+            nick_rules: NameRules::nickname(),
+            channel_rules: NameRules::channel(),
         }
     }
 
     /// Broadcast data to the clients
     ///
+    /// Data is pushed onto each client's outbound queue without waiting, so a slow client never
+    /// stalls the others. A client whose queue is full misses the broadcast under
+    /// `OverflowPolicy::Block`, and is shut down by its writer under `OverflowPolicy::Disconnect`,
+    /// see `Outbound::try_push`.
+    ///
     /// Arguments:
     ///
     /// * `data`: &str - The data to broadcast to all clients.
     pub fn broadcast(&mut self, data: &str) {
-        use crate::io::Send;
-
-        let clients = self.clients.clone();
+        let bytes = protoutils::BakaMessage::new(
+            protoutils::MessageKind::Notice,
+            &self.address.to_string(),
+            data,
+        )
+        .build()
+        .write_to_bytes()
+        .unwrap();
 
-        {
-            let mut clients = clients.lock().unwrap();
-
-            for (_name, value) in &mut *clients {
-                value.socket.send_bytes(
-                    protoutils::BakaMessage {
-                        author: self.address.to_string(),
-                        content: data.to_string(),
-                    }
-                    .build()
-                    .write_to_bytes()
-                    .unwrap(),
-                );
-            }
+        for entry in self.clients.values() {
+            let _ = entry.outbound.try_push(bytes.clone());
         }
     }
 
-    pub fn send(&mut self, name: &str, data: &str) {
-        let clients = self.clients.clone();
+    /// Find a connected client by nickname, ignoring case
+    pub fn find(&self, nick: &str) -> Option<ClientEntry> {
+        self.nicks
+            .get(&NameRules::key(nick))
+            .and_then(|address| self.clients.get(&address))
+    }
 
-        {
-            let mut clients = clients.lock().unwrap();
-            let socket = &mut clients.get_mut(name).unwrap().socket;
+    /// Send data to a single client
+    ///
+    /// Like `Server::broadcast`, this never waits for the client: under `OverflowPolicy::Block`
+    /// a full queue returns an error and the data is not sent.
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: &str - The name of the client.
+    /// * `data`: &str - The data to send.
+    pub fn send(&mut self, name: &str, data: &str) -> Result<(), Error> {
+        let bytes = protoutils::BakaMessage::new(
+            protoutils::MessageKind::Notice,
+            &self.address.to_string(),
+            data,
+        )
+        .build()
+        .write_to_bytes()
+        .unwrap();
 
-            socket.send_bytes(
-                protoutils::BakaMessage {
-                    author: self.address.to_string(),
-                    content: data.to_string(),
-                }
-                .build()
-                .write_to_bytes()
-                .unwrap(),
-            );
+        match self.clients.get(name) {
+            Some(entry) => entry.outbound.try_push(bytes),
+            None => Err(Error {
+                message: format!("No such client: {}", name),
+            }),
         }
     }
 }
@@ -173,22 +430,581 @@ impl ServerBuilder {
     /// * `address`: The address to bind the server to.
     ///
     pub fn new(address: &str) -> Self {
+        ServerBuilder::with_server(Server::new(address))
+    }
+
+    /// Initialize new instance of the `ServerBuilder` from a `ServerConfig`
+    ///
+    /// Example:
+    /// ```rs
+    /// let config = ServerConfig::load("server.toml")?.with_env()?;
+    /// let mut server = ServerBuilder::from_config(&config)?;
+    ///
+    /// server.startup();
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `config`: The configuration, its rate limit is added as a `RateLimit` middleware
+    ///   ahead of any added later.
+    pub fn from_config(config: &ServerConfig) -> Result<Self, ConfigError> {
+        let mut listeners = config
+            .bind
+            .iter()
+            .map(|address| {
+                Listener::bind(address).map_err(|e| ConfigError::new("bind", e.message()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(tls) = &config.tls {
+            listeners.extend(ServerBuilder::tls_listeners(tls)?);
+        }
+
+        let mut listeners = listeners.into_iter();
+        let first = listeners
+            .next()
+            .ok_or_else(|| ConfigError::new("bind", "expected at least one address"))?;
+        let mut builder = ServerBuilder::with_server(Server::with_listener(first));
+
+        builder.server.listeners.extend(listeners);
+
+        builder.queue_capacity(config.queue_capacity);
+        builder.max_clients(config.max_clients);
+        builder.max_connections_per_ip(config.max_connections_per_ip);
+        builder.max_message_size(config.max_message_size);
+        builder.max_link_message_size(config.max_link_message_size);
+        builder.read_buffer(config.read_buffer);
+        builder.poll_interval(config.poll_interval);
+        builder.handshake_timeout(config.handshake_timeout);
+        builder.idle_timeout(config.idle_timeout);
+
+        if let Some(motd) = &config.motd {
+            builder.motd(motd);
+        }
+
+        let rate_limit = Arc::new(RateLimit::unlimited());
+
+        rate_limit.set_limit(config.rate_limit.as_ref().map(|l| (l.burst, l.per)));
+        builder.middleware(rate_limit.clone());
+        builder.rate_limit = Some(rate_limit);
+
+        for (user, hash) in &config.operators {
+            builder.operator(user, hash);
+        }
+
+        for (mask, reason) in &config.bans {
+            builder
+                .server
+                .moderation
+                .ban(mask, reason)
+                .map_err(|e| ConfigError::new(&format!("bans.{}", mask), e.message()))?;
+        }
+
+        builder.config = Some(Arc::new(Mutex::new(config.clone())));
+
+        Ok(builder)
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_listeners(tls: &TlsConfig) -> Result<Vec<Listener>, ConfigError> {
+        let acceptor = TlsAcceptor::from_pem(&tls.cert, &tls.key)
+            .map_err(|e| ConfigError::new("tls", e.message()))?;
+
+        tls.bind
+            .iter()
+            .map(|address| {
+                Listener::bind_tls(address, acceptor.clone())
+                    .map_err(|e| ConfigError::new("tls.bind", e.message()))
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls_listeners(_: &TlsConfig) -> Result<Vec<Listener>, ConfigError> {
+        Err(ConfigError::new(
+            "tls",
+            "TLS needs bakalib built with the `tls` feature",
+        ))
+    }
+
+    fn with_server(server: Server) -> Self {
         let events: HashMap<String, BoxEvent> = HashMap::new();
 
-        let s = ServerBuilder {
-            server: Server::new(address),
-            events: Arc::new(Mutex::new(events)),
-        };
+        ServerBuilder {
+            server: server,
+            events: Arc::new(RwLock::new(events)),
+            backend: Backend::default(),
+            workers: default_workers(),
+            links: vec![],
+            config: None,
+            rate_limit: None,
+            timers: None,
+        }
+    }
+
+    /// Accept connections on another address as well
+    ///
+    /// Example:
+    /// ```rs
+    /// let mut server = ServerBuilder::new("0.0.0.0:65432");
+    ///
+    /// server.listen("[::]:65432")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address to listen on.
+    pub fn listen(&mut self, address: &str) -> Result<(), Error> {
+        self.server.listeners.push(Listener::bind(address)?);
+
+        Ok(())
+    }
+
+    /// Accept TLS connections on another address
+    ///
+    /// Example:
+    /// ```rs
+    /// let mut server = ServerBuilder::new("0.0.0.0:65432");
+    ///
+    /// server.listen_tls("0.0.0.0:6697", TlsAcceptor::from_pem("cert.pem", "key.pem")?)?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address to listen on.
+    /// * `acceptor`: The certificate and key presented to clients.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls(&mut self, address: &str, acceptor: TlsAcceptor) -> Result<(), Error> {
+        self.server
+            .listeners
+            .push(Listener::bind_tls(address, acceptor)?);
+
+        Ok(())
+    }
+
+    /// Get the addresses the server listens on
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.server
+            .listeners
+            .iter()
+            .map(|listener| listener.address)
+            .collect()
+    }
+
+    /// Set the number of messages each client's outbound queue can hold
+    ///
+    /// Arguments:
+    ///
+    /// * `capacity`: Maximum number of queued messages per client.
+    pub fn queue_capacity(&mut self, capacity: usize) {
+        self.server.queue_capacity = capacity;
+    }
+
+    /// Set what happens when a client's outbound queue is full
+    ///
+    /// Arguments:
+    ///
+    /// * `policy`: One of `DropOldest`, `Disconnect` or `Block`.
+    pub fn overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.server.overflow_policy = policy;
+    }
+
+    /// Limit the number of open connections, further ones are refused with `SERVER_FULL`
+    ///
+    /// Connections still in the handshake count towards the limit, links this server opens
+    /// do not.
+    ///
+    /// Arguments:
+    ///
+    /// * `max`: Open connections allowed at once, `None` for no limit.
+    pub fn max_clients(&mut self, max: Option<usize>) {
+        self.server.max_clients = max;
+    }
+
+    /// Limit the number of open connections from one IP address, further ones are refused
+    /// with `TOO_MANY_CONNECTIONS`
+    ///
+    /// Arguments:
+    ///
+    /// * `max`: Open connections allowed per address, `None` for no limit.
+    pub fn max_connections_per_ip(&mut self, max: Option<usize>) {
+        self.server.max_connections_per_ip = max;
+    }
+
+    /// Set the largest message accepted from a client, a client sending a larger one is sent
+    /// `MESSAGE_TOO_LARGE` and disconnected
+    ///
+    /// Arguments:
+    ///
+    /// * `size`: Size in bytes, `DEFAULT_MAX_MESSAGE_SIZE` unless changed.
+    pub fn max_message_size(&mut self, size: usize) {
+        self.server.max_message_size = size;
+    }
+
+    /// Set the largest message accepted from a linked server, a server sending a larger one
+    /// is sent `MESSAGE_TOO_LARGE` and its link is closed
+    ///
+    /// Links carry whole `BURST`s, so they usually need more room than clients. A connection
+    /// is held to `Server::max_message_size` until it proves to be a linked server with `LINK`.
+    ///
+    /// Arguments:
+    ///
+    /// * `size`: Size in bytes, `DEFAULT_MAX_LINK_MESSAGE_SIZE` unless changed.
+    pub fn max_link_message_size(&mut self, size: usize) {
+        self.server.max_link_message_size = size;
+    }
+
+    /// Set the size of the chunks read from client sockets
+    ///
+    /// Arguments:
+    ///
+    /// * `size`: Size in bytes, `DEFAULT_BUFFER_SIZE` unless changed.
+    pub fn read_buffer(&mut self, size: usize) {
+        self.server.read_buffer = size.max(1);
+    }
+
+    /// Set how often waiting loops, like `ServerHandle::shutdown`, check for progress
+    ///
+    /// Arguments:
+    ///
+    /// * `interval`: The interval, `DEFAULT_POLL_INTERVAL` unless changed.
+    pub fn poll_interval(&mut self, interval: time::Duration) {
+        self.server.poll_interval = interval;
+    }
+
+    /// Disconnect clients that do not finish the handshake and log in in time
+    ///
+    /// Arguments:
+    ///
+    /// * `timeout`: The time allowed, `DEFAULT_HANDSHAKE_TIMEOUT` unless changed, `None` for no
+    ///   limit.
+    pub fn handshake_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.server.handshake_timeout = timeout;
+    }
+
+    /// Kick connected clients that send nothing for a while
+    ///
+    /// Arguments:
+    ///
+    /// * `timeout`: The time allowed, `None` for no limit.
+    pub fn idle_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.server.idle_timeout = timeout;
+    }
+
+    /// Measure the handshake and idle timeouts, the presence of clients, the login throttle
+    /// and the rate limit of `ServerBuilder::from_config` with another clock
+    ///
+    /// With a `MockClock` the timeouts are enforced when the clock is advanced. A `Throttle`
+    /// set later with `ServerBuilder::throttle` follows this clock as well.
+    ///
+    /// Example:
+    /// ```rs
+    /// let clock = Arc::new(MockClock::new());
+    ///
+    /// server.idle_timeout(Some(Duration::from_secs(300)));
+    /// server.clock(clock.clone());
+    ///
+    /// // Kicks the clients that were silent for five minutes
+    /// clock.advance(Duration::from_secs(301));
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `clock`: The clock, set before the server starts.
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) {
+        self.server.presence = Arc::new(PresenceTracker::with_clock(clock.clone()));
+        self.server.throttle.set_clock(clock.clone());
+
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.set_clock(clock.clone());
+        }
+
+        self.server.clock = clock;
+    }
+
+    /// Set what nicknames chosen with `NICK` and the names of logged-in users may look like,
+    /// `NameRules::nickname` by default
+    ///
+    /// Example:
+    /// ```rs
+    /// server.nick_rules(NameRules {
+    ///     max_length: 16,
+    ///     ..NameRules::nickname()
+    /// });
+    /// ```
+    pub fn nick_rules(&mut self, rules: NameRules) {
+        self.server.nick_rules = rules;
+    }
+
+    /// Set what channel names joined with `JOIN` may look like, `NameRules::channel` by default
+    ///
+    /// Example:
+    /// ```rs
+    /// server.channel_rules(NameRules {
+    ///     mixed_script: true,
+    ///     ..NameRules::channel()
+    /// });
+    /// ```
+    pub fn channel_rules(&mut self, rules: NameRules) {
+        self.server.channel_rules = rules;
+    }
+
+    /// Set the message of the day, sent as a notice to every client that connects
+    ///
+    /// Arguments:
+    ///
+    /// * `motd`: The message.
+    pub fn motd(&mut self, motd: &str) {
+        *self.server.motd.write().unwrap() = Some(motd.to_string());
+    }
+
+    /// Allow a user to become an operator with `OPER {user} :password`
+    ///
+    /// Example:
+    /// ```rs
+    /// server.operator("alice", &CredentialFile::hash("hunter2")?);
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `user`: The operator's user name.
+    /// * `hash`: Argon2 hash of the operator's password.
+    pub fn operator(&mut self, user: &str, hash: &str) {
+        self.server
+            .operators
+            .write()
+            .unwrap()
+            .insert(user.to_string(), hash.to_string());
+    }
+
+    /// Choose how connections are served
+    ///
+    /// Arguments:
+    ///
+    /// * `backend`: `Backend::Reactor` (default) or `Backend::Threaded`.
+    pub fn backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /// Set the number of worker threads dispatching events for `Backend::Reactor`
+    ///
+    /// Arguments:
+    ///
+    /// * `workers`: Number of worker threads, at least one.
+    pub fn workers(&mut self, workers: usize) {
+        self.workers = workers;
+    }
+
+    /// Set the capabilities offered to clients during the handshake
+    ///
+    /// Arguments:
+    ///
+    /// * `capabilities`: The capability names.
+    pub fn capabilities(&mut self, capabilities: &[&str]) {
+        self.server.capabilities = capabilities.iter().map(|c| c.to_string()).collect();
+    }
 
-        return s;
+    /// Require clients to log in before they are connected
+    ///
+    /// Example:
+    /// ```rs
+    /// let auth = MemoryAuthenticator::new();
+    /// auth.add_user("alice", "hunter2");
+    /// server.authenticator(auth);
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `authenticator`: Checks the credentials sent with `LOGIN` or `TOKEN`.
+    pub fn authenticator<A: Authenticator + 'static>(&mut self, authenticator: A) {
+        self.server.authenticator = Some(Arc::new(authenticator));
+    }
+
+    /// Set how failed logins are throttled, measured by `Server::clock`
+    ///
+    /// Arguments:
+    ///
+    /// * `throttle`: The throttle, `Throttle::default()` unless changed.
+    pub fn throttle(&mut self, throttle: Throttle) {
+        self.server.throttle = Arc::new(throttle.with_clock(self.server.clock.clone()));
+    }
+
+    /// Set the hook called for every private message between clients
+    ///
+    /// The hook receives the message with the sender's nickname as author and may return it
+    /// unchanged, return a rewritten message, or return `None` to drop it.
+    ///
+    /// Example:
+    /// ```rs
+    /// server.on_private_message(Box::new(|server: &mut Server, message: BakaMessage| {
+    ///     if message.content.contains("spam") { None } else { Some(message) }
+    /// }));
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `hook`: The function that will be called for every private message.
+    pub fn on_private_message(&mut self, hook: BoxPrivateMessageHook) {
+        self.server.private_message_hook = Some(Arc::new(hook));
+    }
+
+    /// Set where channel and private message history is kept
+    ///
+    /// Example:
+    /// ```rs
+    /// server.history(History::persistent(500, Lagerung::open("history.db")?));
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `history`: In-memory or `Lagerung`-backed history.
+    pub fn history(&mut self, history: History) {
+        self.server.history = Arc::new(history);
+    }
+
+    /// Set how many messages are replayed to a client joining a channel
+    ///
+    /// Arguments:
+    ///
+    /// * `count`: Number of messages, `0` disables the replay.
+    pub fn history_replay(&mut self, count: usize) {
+        self.server.history_replay = count;
+    }
+
+    /// Set where bans and mutes are kept
+    ///
+    /// Fails, keeping the previous moderation state, when the bans of the `ServerConfig`
+    /// cannot be added to it.
+    ///
+    /// Example:
+    /// ```rs
+    /// server.moderation(Moderation::persistent(Lagerung::open("moderation.db")?))?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `moderation`: In-memory or `Lagerung`-backed moderation state, the bans of a
+    ///   `ServerConfig` are added to it.
+    pub fn moderation(&mut self, moderation: Moderation) -> Result<(), Error> {
+        if let Some(config) = &self.config {
+            let bans: Vec<(String, String)> = config
+                .lock()
+                .unwrap()
+                .bans
+                .iter()
+                .map(|(mask, reason)| (mask.clone(), reason.clone()))
+                .collect();
+
+            if !bans.is_empty() {
+                moderation.update_bans(&[], &bans)?;
+            }
+        }
+
+        self.server.moderation = Arc::new(moderation);
+
+        Ok(())
+    }
+
+    /// Add a middleware layer for messages exchanged with clients
+    ///
+    /// Inbound layers see every message from a connected client before the server routes it
+    /// or dispatches `on_message`; outbound layers see every message queued for a client just
+    /// before it is written.
+    ///
+    /// Example:
+    /// ```rs
+    /// server.middleware(Logging::new("main"));
+    /// server.middleware(RateLimit::new(10, Duration::from_secs(5)));
+    /// server.middleware(ProfanityFilter::new(&["heck"]));
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `layer`: The layer, run after the ones added before it.
+    pub fn middleware<M: Middleware + 'static>(&mut self, layer: M) {
+        let mut pipeline = (*self.server.middleware).clone();
+
+        pipeline.push(layer);
+        self.server.middleware = Arc::new(pipeline);
+    }
+
+    /// Take a snapshot of the server's metrics, see `Server::stats`
+    pub fn stats(&self) -> Stats {
+        self.server.stats()
+    }
+
+    /// Serve the server's metrics in the Prometheus text format at `/metrics`
+    ///
+    /// Example:
+    /// ```rs
+    /// server.serve_metrics("127.0.0.1:9100")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address the HTTP endpoint listens on.
+    #[cfg(feature = "prometheus")]
+    pub fn serve_metrics(&self, address: &str) -> Result<(), Error> {
+        self.server.serve_metrics(address)
+    }
+
+    /// Make the server part of a network of linked servers
+    ///
+    /// Every server of the network needs a unique name and the same secret. Clients of any
+    /// server see the joins, parts and messages of the whole network and can send private
+    /// messages to clients of other servers.
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: This server's name, unique in the network.
+    /// * `secret`: Shared secret every server of the network links with.
+    pub fn federation(&mut self, name: &str, secret: &str) {
+        self.server.federation = Some(Arc::new(Federation::new(name, secret)));
+
+        if !self
+            .server
+            .capabilities
+            .iter()
+            .any(|c| c == LINK_CAPABILITY)
+        {
+            self.server.capabilities.push(LINK_CAPABILITY.to_string());
+        }
+    }
+
+    /// Open a link to another server of the network on startup
+    ///
+    /// The link is reopened whenever it drops. Servers of a network should be linked as a
+    /// tree; a cycle works, but every frame then travels it until it is recognised as seen.
+    ///
+    /// Example:
+    /// ```rs
+    /// let mut a = ServerBuilder::new("127.0.0.1:7001");
+    /// let mut b = ServerBuilder::new("127.0.0.1:7002");
+    ///
+    /// a.federation("a", "secret");
+    /// b.federation("b", "secret");
+    /// b.link("127.0.0.1:7001");
+    ///
+    /// thread::spawn(move || a.startup());
+    /// thread::spawn(move || b.startup());
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address of the other server.
+    pub fn link(&mut self, address: &str) {
+        self.links.push(address.to_string());
     }
 
     /// Add delegate function as server event
     ///
+    /// Events are `on_client_connect`, `on_message`, `on_error` and `on_client_disconnect`,
+    /// see `Event` for what each one receives.
+    ///
     /// Example:
     /// ```rs
-    /// server.event("on_connect", Box::new(|s: &Socket, data: Result<Message, baka::socket::Error>| {
-    ///     println!("Succefully connected!");
+    /// server.event("on_client_connect", Box::new(|ctx: &mut Context, event: Event| {
+    ///     println!("{} connected", ctx.nick());
     /// }));
     /// ```
     ///
@@ -197,114 +1013,136 @@ impl ServerBuilder {
     /// * `name`: The name of the event.
     /// * `delegate`: The function that will be called when the event is triggered.
     pub fn event(&mut self, name: &str, delegate: BoxEvent) {
-        let events = self.events.clone();
-        let name = String::from(name).clone();
-
-        thread::spawn(move || {
-            let mut events = events.lock().unwrap();
-
-            events.entry(name).or_insert(delegate);
-        });
+        self.events
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert(delegate);
     }
 
-    /// Start the event loop thread
+    /// Start the event loop, blocks the calling thread
+    ///
+    /// With `Backend::Reactor` a single thread waits for socket readiness and a worker pool
+    /// dispatches events as soon as data arrives. With `Backend::Threaded` every connection gets
+    /// its own thread blocking on reads. Either way only the client being served is locked while its
+    /// handlers run, so handlers for different clients execute in parallel.
     pub fn startup(&mut self) {
-        use crate::io::Read;
+        self.connect_links();
+        self.sweep_timeouts();
+        self.measure_rates();
+
+        match self.backend {
+            Backend::Reactor => {
+                Reactor::new(self.server.clone(), self.events.clone(), self.workers)
+                    .run()
+                    .unwrap();
+            }
+            Backend::Threaded => self.startup_threaded(),
+        }
+    }
 
-        let server = self.server.clone();
+    fn startup_threaded(&mut self) {
+        let mut listeners = self.server.listeners.clone().into_iter();
+        let first = listeners.next().unwrap();
 
-        for stream in server.listener.lock().unwrap().incoming() {
+        for listener in listeners {
             let events = self.events.clone();
-            let mut server = self.server.clone();
-            let clients = server.clients.clone();
+            let server = self.server.clone();
 
-            thread::spawn(move || {
-                let stream = stream.unwrap();
-                let mut socket = Socket::from(stream);
+            thread::spawn(move || ServerBuilder::accept_threaded(&events, &server, &listener));
+        }
 
-                let address = socket.address.to_string();
+        ServerBuilder::accept_threaded(&self.events, &self.server, &first);
+    }
 
-                {
-                    let events = events.lock().unwrap();
-                    let mut clients = clients.lock().unwrap();
+    /// Accept connections on one listener, each served by a thread of its own
+    fn accept_threaded(
+        events: &Arc<RwLock<HashMap<String, BoxEvent>>>,
+        server: &Server,
+        listener: &Listener,
+    ) {
+        for stream in listener.listener.lock().unwrap().incoming() {
+            let events = events.clone();
+            let mut server = server.clone();
+            let listener = listener.clone();
 
-                    if !clients.contains_key(&address.clone()) {
-                        let mut client = Client {
-                            socket: socket.clone(),
-                            flags: HashMap::new(),
-                        };
+            if !server.accepting.load(Ordering::SeqCst) {
+                // Shutting down, the connection is dropped and closed
+                continue;
+            }
 
-                        clients.insert(address.clone(), client.clone());
-                    }
+            thread::spawn(move || {
+                let mut socket = match stream
+                    .map_err(|e| Error::new(&e.to_string()))
+                    .and_then(|stream| listener.socket(stream))
+                {
+                    Ok(socket) => socket,
+                    Err(_) => return,
+                };
 
-                    let mut client = clients.get_mut(&address.clone()).unwrap();
+                socket.set_buffer_size(server.read_buffer);
+                // Raised once the peer proves to be a linked server
+                socket.set_max_frame_size(Some(server.max_message_size));
 
-                    events[&"on_client_connect".to_string()](
-                        Arc::new(Mutex::new(&mut server)),
-                        Arc::new(Mutex::new(client)),
-                        Ok(protoutils::BakaMessage {
-                            author: address.clone(),
-                            content: "Succefully connected".to_string(),
-                        }
-                        .build()),
-                    );
-                }
+                let address = socket.address.to_string();
+                let entry = ServerBuilder::accept(&server, &socket);
 
+                ServerBuilder::admit(&server, &entry, &socket);
+
+                // The stream is blocking, so each read waits for the client without polling
                 loop {
-                    thread::sleep(time::Duration::from_millis(100));
-
-                    if let Ok(buffer) = socket.read() {
-                        {
-                            let events = events.lock().unwrap();
-                            let mut clients = clients.lock().unwrap();
-                            let mut client = clients.get_mut(&address.clone()).unwrap();
-
-                            if buffer.len() < 1 {
-                                {
-                                    events[&"on_client_disconnect".to_string()](
-                                        Arc::new(Mutex::new(&mut server)),
-                                        Arc::new(Mutex::new(client)),
-                                        Ok(protoutils::BakaMessage {
-                                            author: address.clone(),
-                                            content: "Disconnected".to_string(),
-                                        }
-                                        .build()),
-                                    );
-                                    clients.remove(&address);
-                                }
-                                break;
-                            }
-
-                            events[&"on_message".to_string()](
-                                Arc::new(Mutex::new(&mut server)),
-                                Arc::new(Mutex::new(client)),
-                                Ok(protoutils::BakaMessage::from(buffer).build()),
-                            );
+                    match socket.read_frame() {
+                        Ok(Some(frame @ Frame::Message(_))) => {
+                            ServerBuilder::receive(&events, &mut server, &entry, &address, frame);
                         }
-                    } else {
-                        {
-                            let events = events.lock().unwrap();
-                            let mut clients = clients.lock().unwrap();
-                            let mut client = clients.get_mut(&address.clone()).unwrap();
-
-                            events[&"on_client_disconnect".to_string()](
-                                Arc::new(Mutex::new(&mut server)),
-                                Arc::new(Mutex::new(client)),
-                                Ok(protoutils::BakaMessage {
-                                    author: address.clone(),
-                                    content: "Disconnected".to_string(),
+                        result => {
+                            let reason = match result {
+                                Ok(Some(frame)) => {
+                                    // Nothing after an oversized frame can be read
+                                    ServerBuilder::receive(
+                                        &events,
+                                        &mut server,
+                                        &entry,
+                                        &address,
+                                        frame,
+                                    );
+
+                                    DisconnectReason::Closed
                                 }
-                                .build()),
-                            );
-                            clients.remove(&address);
+                                Err(e) => DisconnectReason::Error(e.message().to_string()),
+                                Ok(None) => DisconnectReason::Closed,
+                            };
+
+                            ServerBuilder::close(&events, &mut server, &entry, &address, reason);
+                            break;
                         }
-                        break;
                     }
                 }
             });
         }
     }
 
+    /// Call the named event for a client, holding only that client's lock
+    pub(crate) fn dispatch(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        name: &str,
+        server: &mut Server,
+        entry: &ClientEntry,
+        event: Event,
+    ) {
+        let events = events.read().unwrap();
+
+        if let Some(delegate) = events.get(name) {
+            let metrics = server.metrics.clone();
+            let mut client = entry.client.lock().unwrap();
+            let started = time::Instant::now();
+
+            delegate(&mut Context::new(server, &mut client), event);
+
+            metrics.handler(started.elapsed());
+        }
+    }
+
     /// Start the polling loop (block the all threads)
     pub fn polling(&mut self) {
         loop {}
@@ -0,0 +1,843 @@
+use crate::auth::Credentials;
+use crate::command::CommandParser;
+use crate::extensions::names::NameRules;
+use crate::middleware::{Action, Peer};
+use crate::protoutils;
+use crate::socket::{
+    Ban, BoxEvent, Client, ClientEntry, ClientState, DisconnectReason, Error, Event, Frame,
+    History, Outbound, Server, ServerBuilder, Socket, LINK_CAPABILITY,
+};
+use crate::trace::{event, ConnectionSpan};
+
+use protobuf::Message;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+impl ServerBuilder {
+    /// Create the registry entry for a freshly accepted connection
+    ///
+    /// The entry is only added to `Server::clients` once the client completes the handshake
+    /// and, if the server has an authenticator, logs in. A connection from a banned address
+    /// is sent the reason and closed right away.
+    pub(crate) fn accept(server: &Server, socket: &Socket) -> ClientEntry {
+        let span = ConnectionSpan::new(&socket.address.to_string());
+        let _entered = span.enter();
+
+        event!(info, "accepted connection");
+
+        let outbound = Outbound::for_server(socket.clone(), server);
+
+        server.metrics.connection();
+
+        let entry = ClientEntry {
+            client: Arc::new(Mutex::new(Client {
+                socket: socket.clone(),
+                flags: HashMap::new(),
+                outbound: outbound.clone(),
+                state: ClientState::Handshake,
+                kicked: None,
+            })),
+            outbound: outbound,
+            span: span.clone(),
+            kicked: Arc::new(Mutex::new(None)),
+        };
+
+        if server
+            .pending
+            .insert(
+                &socket.address.to_string(),
+                (entry.clone(), server.clock.now()),
+            )
+            .is_err()
+        {
+            // A connection from the same address and port is still being torn down
+            entry.outbound.close();
+        }
+
+        if let Some(ban) = server.moderation.banned(None, &socket.address.ip()) {
+            ServerBuilder::banned(server, &entry, &ban);
+        }
+
+        entry
+    }
+
+    /// Refuse a freshly accepted connection over `Server::max_clients` or
+    /// `Server::max_connections_per_ip`
+    ///
+    /// The connection is counted in `Server::connections` until it is closed, refused or not.
+    pub(crate) fn admit(server: &Server, entry: &ClientEntry, socket: &Socket) {
+        let (total, from_ip) = server.connections.open(socket.address.ip());
+
+        entry.client.lock().unwrap().add_flag("admitted", "true");
+
+        let refusal = match (server.max_clients, server.max_connections_per_ip) {
+            (Some(max), _) if total > max => {
+                server.metrics.refused_full();
+
+                Some(("SERVER_FULL", "Too many clients, try again later"))
+            }
+            (_, Some(max)) if from_ip > max => {
+                server.metrics.refused_per_ip();
+
+                Some((
+                    "TOO_MANY_CONNECTIONS",
+                    "Too many connections from your address",
+                ))
+            }
+            _ => None,
+        };
+
+        if let Some((code, reason)) = refusal {
+            let _entered = entry.span.enter();
+
+            event!(warn, code = code, "refused connection");
+
+            ServerBuilder::fail(server, entry, code, &Error::new(reason));
+            entry.outbound.close();
+        }
+    }
+
+    /// Tell a client its message is over the limit and close its connection
+    fn too_large(server: &Server, entry: &ClientEntry, size: usize, limit: usize) {
+        event!(warn, bytes = size, "message too large");
+
+        server.metrics.oversized();
+
+        ServerBuilder::fail(
+            server,
+            entry,
+            "MESSAGE_TOO_LARGE",
+            &Error::new(&format!("Messages are limited to {} bytes", limit)),
+        );
+
+        entry.outbound.close();
+    }
+
+    /// Tell a client speaking protocol version 1 that it is too old and close its connection
+    ///
+    /// Version 1 sent messages without their length, so the first one reads as a frame far
+    /// over the limit. The rejection is written without a frame header, so the client can
+    /// read it.
+    fn unframed(server: &Server, entry: &ClientEntry) {
+        event!(warn, "refused unframed client");
+
+        let error = Error::new(&format!(
+            "Unsupported protocol version 1, server accepts {} to {}: messages must be framed with their length",
+            protoutils::MIN_PROTOCOL_VERSION,
+            protoutils::PROTOCOL_VERSION
+        ));
+        let bytes = protoutils::reject(&server.address.to_string(), &error)
+            .build()
+            .write_to_bytes()
+            .unwrap();
+        let mut socket = entry.client.lock().unwrap().socket.clone();
+
+        let _ = socket.send_unframed(&bytes);
+
+        entry.outbound.close();
+    }
+
+    /// Tell a banned client why and close its connection
+    pub(crate) fn banned(server: &Server, entry: &ClientEntry, ban: &Ban) {
+        event!(info, mask = %ban.mask.mask, "refused banned client");
+
+        ServerBuilder::fail(
+            server,
+            entry,
+            &format!("BANNED {{{}}}", ban.mask.mask),
+            &Error::new(&ban.reason),
+        );
+
+        entry.outbound.close();
+    }
+
+    /// Handle a frame received from a client
+    ///
+    /// The first message must be a `Hello`; a rejected client is sent the reason and
+    /// disconnected. No events are dispatched for a client until it is connected. A client
+    /// sending a message over `Server::max_message_size`, or a linked server one over
+    /// `Server::max_link_message_size`, is sent `MESSAGE_TOO_LARGE` and disconnected. An
+    /// oversized first message comes from a version 1 client, which is rejected.
+    pub(crate) fn receive(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        frame: Frame,
+    ) {
+        let _entered = entry.span.enter();
+        let (state, link) = {
+            let mut client = entry.client.lock().unwrap();
+
+            // A server this one links to sends its burst along with `LINKED`
+            (
+                client.state,
+                client.state == ClientState::Link || client.has_flag("link_outgoing"),
+            )
+        };
+        let limit = if link {
+            server.max_link_message_size
+        } else {
+            server.max_message_size
+        };
+
+        let buffer = match frame {
+            Frame::Message(buffer) if buffer.len() <= limit => buffer,
+            Frame::Message(buffer) => {
+                return ServerBuilder::too_large(server, entry, buffer.len(), limit)
+            }
+            Frame::Oversized(_) if state == ClientState::Handshake => {
+                return ServerBuilder::unframed(server, entry)
+            }
+            Frame::Oversized(size) => return ServerBuilder::too_large(server, entry, size, limit),
+        };
+        let buffer = buffer.as_slice();
+
+        let message = protoutils::BakaMessage::parse(buffer).map_err(|e| Error {
+            message: format!("Unexcepted error while decoding message: {}", e),
+        });
+
+        match &message {
+            Ok(_) => server.metrics.received(buffer.len()),
+            Err(_) => server.metrics.decode_error(),
+        }
+
+        #[cfg(feature = "tracing")]
+        match &message {
+            Ok(message) => {
+                event!(
+                    debug,
+                    kind = ?message.kind,
+                    bytes = buffer.len(),
+                    state = ?state,
+                    "message received"
+                );
+            }
+            Err(e) => {
+                event!(
+                    warn,
+                    error = %e,
+                    bytes = buffer.len(),
+                    "failed to decode message"
+                );
+            }
+        }
+
+        if state == ClientState::Connected {
+            let nick = entry.client.lock().unwrap().nick();
+
+            server.presence.touch(&nick);
+        }
+
+        let message = match message {
+            Ok(mut message) if state == ClientState::Connected && !server.middleware.is_empty() => {
+                let peer = {
+                    let client = entry.client.lock().unwrap();
+
+                    Peer {
+                        address: address.to_string(),
+                        nick: Some(client.nick()),
+                        user: client.flags.get("user").cloned(),
+                    }
+                };
+
+                match server.middleware.inbound(&peer, message.build()) {
+                    Action::Continue(message) => Ok(protoutils::BakaMessage::from(message)),
+                    Action::Drop => return,
+                    Action::ShortCircuit(reply) => {
+                        let mut reply = protoutils::BakaMessage::from(reply);
+
+                        if reply.author.is_empty() {
+                            reply.author = server.address.to_string();
+                        }
+
+                        ServerBuilder::reply(entry, reply);
+                        return;
+                    }
+                }
+            }
+            message => message,
+        };
+
+        match state {
+            ClientState::Connected => match message {
+                Ok(message) if ServerBuilder::is_private(&message) => {
+                    ServerBuilder::private_message(server, entry, message);
+                }
+                Ok(message) if ServerBuilder::is_channel(&message) => {
+                    ServerBuilder::channel_message(server, entry, address, message);
+                }
+                Ok(message)
+                    if message.kind == protoutils::MessageKind::Command
+                        && ServerBuilder::command(server, entry, address, &message) => {}
+                Ok(message) => {
+                    ServerBuilder::dispatch(
+                        events,
+                        "on_message",
+                        server,
+                        entry,
+                        Event::Message(message.stamped(&*server.clock).build()),
+                    );
+                }
+                Err(e) => {
+                    ServerBuilder::dispatch(events, "on_error", server, entry, Event::Error(e));
+                }
+            },
+            ClientState::Handshake => {
+                ServerBuilder::handshake(events, server, entry, address, message);
+            }
+            ClientState::Unauthenticated => {
+                ServerBuilder::login(events, server, entry, address, message);
+            }
+            ClientState::Linking => {
+                if let Ok(message) = message {
+                    ServerBuilder::linking(server, entry, message);
+                }
+            }
+            ClientState::Link => {
+                let link = entry.client.lock().unwrap().flags.get("link").cloned();
+
+                if let (Ok(message), Some(link)) = (message, link) {
+                    ServerBuilder::link_frame(server, &link, message);
+                }
+            }
+        }
+    }
+
+    /// Handle a closed connection
+    ///
+    /// A client kicked by the server is reported as `DisconnectReason::Kicked`, whatever the
+    /// connection reported.
+    pub(crate) fn close(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        reason: DisconnectReason,
+    ) {
+        let _entered = entry.span.enter();
+        let (state, kicked, admitted, ip) = {
+            let mut client = entry.client.lock().unwrap();
+
+            (
+                client.state,
+                client.kicked.clone(),
+                client.remove_flag("admitted"),
+                client.socket.address.ip(),
+            )
+        };
+
+        let reason = kicked
+            .or_else(|| entry.kicked.lock().unwrap().clone())
+            .map(DisconnectReason::Kicked)
+            .unwrap_or(reason);
+
+        server.pending.remove(address);
+
+        if admitted {
+            server.connections.close(ip);
+        }
+
+        if state == ClientState::Connected {
+            server.metrics.disconnect(&reason);
+        }
+
+        event!(info, reason = ?reason, state = ?state, "client disconnected");
+
+        if state == ClientState::Connected {
+            ServerBuilder::part_all(server, entry, address);
+            ServerBuilder::dispatch(
+                events,
+                "on_client_disconnect",
+                server,
+                entry,
+                Event::Disconnected { reason: reason },
+            );
+
+            server.clients.remove(address);
+
+            let nick = entry.client.lock().unwrap().nick();
+
+            let key = NameRules::key(&nick);
+
+            if server.nicks.get(&key).as_deref() == Some(address) {
+                server.nicks.remove(&key);
+                server.presence.disconnect(&nick);
+
+                ServerBuilder::announce(server, &format!("QUIT {{{}}} :Disconnected", nick));
+            }
+        }
+
+        if state == ClientState::Link {
+            let link = entry.client.lock().unwrap().flags.get("link").cloned();
+
+            if let Some(link) = link {
+                ServerBuilder::split(server, &link);
+            }
+        }
+
+        entry.outbound.close();
+    }
+
+    fn handshake(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        message: Result<protoutils::BakaMessage, Error>,
+    ) {
+        let welcome = message
+            .and_then(|m| protoutils::Hello::parse(&m))
+            .and_then(|hello| hello.negotiate(&server.capabilities));
+
+        match welcome {
+            Ok(welcome) => {
+                event!(
+                    info,
+                    version = welcome.version,
+                    capabilities = ?welcome.capabilities,
+                    "handshake accepted"
+                );
+
+                {
+                    let mut client = entry.client.lock().unwrap();
+
+                    client.add_flag("protocol", &welcome.version.to_string());
+                    client.add_flag("capabilities", &welcome.capabilities.join(" "));
+                }
+
+                ServerBuilder::reply(entry, welcome.to_message(&server.address.to_string()));
+
+                if server.federation.is_some() && welcome.has_capability(LINK_CAPABILITY) {
+                    ServerBuilder::challenge(server, entry);
+                } else if server.authenticator.is_some() {
+                    entry.client.lock().unwrap().state = ClientState::Unauthenticated;
+                } else {
+                    ServerBuilder::connect(events, server, entry, address);
+                }
+            }
+            Err(e) => {
+                event!(warn, error = %e, "handshake rejected");
+
+                ServerBuilder::reply(entry, protoutils::reject(&server.address.to_string(), &e));
+
+                entry.outbound.close();
+            }
+        }
+    }
+
+    /// Accept `LOGIN {user} :password` or `TOKEN :token` from an unauthenticated client
+    fn login(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+        message: Result<protoutils::BakaMessage, Error>,
+    ) {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                ServerBuilder::fail(server, entry, "ERROR", &e);
+                return;
+            }
+        };
+
+        let mut parser = CommandParser::new(message.content.clone());
+        let secret = parser.text().to_string();
+
+        let credentials = match parser.command() {
+            "LOGIN" => Credentials::Password {
+                user: parser.args().first().cloned().unwrap_or_default(),
+                password: secret,
+            },
+            "TOKEN" => Credentials::Token(secret),
+            _ => {
+                ServerBuilder::fail(
+                    server,
+                    entry,
+                    "AUTH_REQUIRED",
+                    &Error::new("Login required"),
+                );
+                return;
+            }
+        };
+
+        let peer = entry.client.lock().unwrap().socket.address.ip().to_string();
+
+        if let Err(e) = server.throttle.check(&peer) {
+            ServerBuilder::fail(server, entry, "AUTH_FAILED", &e);
+            return;
+        }
+
+        let authenticator = server.authenticator.clone().unwrap();
+
+        match authenticator.authenticate(&credentials) {
+            Ok(user) => {
+                event!(info, user = %user, "login succeeded");
+
+                server.throttle.success(&peer);
+                entry.client.lock().unwrap().add_flag("user", &user);
+
+                ServerBuilder::reply(
+                    entry,
+                    protoutils::BakaMessage::new(
+                        protoutils::MessageKind::Command,
+                        &server.address.to_string(),
+                        &format!("AUTHENTICATED {{{}}}", user),
+                    ),
+                );
+                ServerBuilder::connect(events, server, entry, address);
+            }
+            Err(e) => {
+                event!(warn, error = %e, "login failed");
+
+                server.throttle.failure(&peer);
+                ServerBuilder::fail(server, entry, "AUTH_FAILED", &e);
+            }
+        }
+    }
+
+    /// Register the client and dispatch `on_client_connect`
+    ///
+    /// The client's nickname is its user name when it logged in and that name is free, and its
+    /// address otherwise. A client whose nickname is banned is disconnected instead. The message
+    /// of the day, if any, is sent before `on_client_connect` fires.
+    fn connect(
+        events: &RwLock<HashMap<String, BoxEvent>>,
+        server: &mut Server,
+        entry: &ClientEntry,
+        address: &str,
+    ) {
+        let ban = {
+            let client = entry.client.lock().unwrap();
+
+            let nick = client.flags.get("user").cloned().unwrap_or(client.nick());
+
+            server
+                .moderation
+                .banned(Some(&nick), &client.socket.address.ip())
+        };
+
+        if let Some(ban) = ban {
+            ServerBuilder::banned(server, entry, &ban);
+            return;
+        }
+
+        server.pending.remove(address);
+
+        if server.clients.insert(address, entry.clone()).is_err() {
+            event!(warn, "client already registered");
+
+            entry.outbound.close();
+            return;
+        }
+
+        {
+            let mut client = entry.client.lock().unwrap();
+            // A user name that is not a valid nickname, or looks like a taken one, connects
+            // under the address like a client that did not log in
+            let user = client
+                .flags
+                .get("user")
+                .and_then(|user| server.nick_rules.validate(user).ok())
+                .filter(|user| ServerBuilder::lookalike(server, user, address).is_none());
+
+            let nick = match user {
+                Some(user) if ServerBuilder::claim(server, &user, address) => user,
+                _ => {
+                    ServerBuilder::claim(server, address, address);
+                    address.to_string()
+                }
+            };
+
+            client.add_flag("nick", &nick);
+            client.state = ClientState::Connected;
+            entry.span.nick(&nick);
+
+            event!(info, "client connected");
+
+            server.presence.connect(&nick, address);
+
+            ServerBuilder::announce(server, &format!("NICK {{{}}}", nick));
+        }
+
+        let motd = server.motd.read().unwrap().clone();
+
+        if let Some(motd) = motd {
+            ServerBuilder::reply(
+                entry,
+                protoutils::BakaMessage::new(
+                    protoutils::MessageKind::Notice,
+                    &server.address.to_string(),
+                    &motd,
+                ),
+            );
+        }
+
+        ServerBuilder::dispatch(events, "on_client_connect", server, entry, Event::Connected);
+    }
+
+    /// Find a nickname in the network that looks like `nick` without being the same, held by
+    /// a client other than `address`
+    pub(crate) fn lookalike(server: &Server, nick: &str, address: &str) -> Option<String> {
+        let mut names: Vec<String> = server
+            .nicks
+            .entries()
+            .into_iter()
+            .filter(|(_, holder)| holder != address)
+            .map(|(key, _)| key)
+            .collect();
+
+        if let Some(federation) = &server.federation {
+            names.extend(federation.remote_users().into_iter().map(|user| user.nick));
+        }
+
+        NameRules::confusable(nick, names.iter())
+    }
+
+    /// Register a nickname for a client, returns `false` if another client holds it
+    pub(crate) fn claim(server: &Server, nick: &str, address: &str) -> bool {
+        match server
+            .nicks
+            .insert(&NameRules::key(nick), address.to_string())
+        {
+            Ok(()) => true,
+            Err(holder) => holder == address,
+        }
+    }
+
+    /// Enforce `Server::handshake_timeout` and `Server::idle_timeout` from a background thread
+    ///
+    /// Once a second, connections still in the handshake or login are closed with `TIMEOUT`
+    /// and connected clients idle for too long are kicked. The sweep runs on
+    /// `ServerBuilder::timers`, following `Server::clock`.
+    pub(crate) fn sweep_timeouts(&mut self) {
+        if self.server.handshake_timeout.is_none() && self.server.idle_timeout.is_none() {
+            return;
+        }
+
+        let server = self.server.clone();
+        let timers = self
+            .timers
+            .get_or_insert_with(|| crate::Scheduler::with_clock(server.clock.clone()));
+
+        timers.set_interval(
+            Duration::from_secs(1),
+            Box::new(move |_| ServerBuilder::sweep(&server)),
+        );
+    }
+
+    fn sweep(server: &Server) {
+        if let Some(timeout) = server.handshake_timeout {
+            for (address, (entry, accepted)) in server.pending.entries() {
+                let state = entry.client.lock().unwrap().state;
+
+                if state == ClientState::Connected
+                    || state == ClientState::Link
+                    || entry.outbound.is_closed()
+                {
+                    server.pending.remove(&address);
+                } else if server.clock.now().saturating_duration_since(accepted) > timeout {
+                    let _entered = entry.span.enter();
+
+                    event!(info, "handshake timed out");
+
+                    ServerBuilder::fail(
+                        server,
+                        &entry,
+                        "TIMEOUT",
+                        &Error::new("Handshake timed out"),
+                    );
+
+                    entry.outbound.close();
+                    server.pending.remove(&address);
+                }
+            }
+        }
+
+        if let Some(timeout) = server.idle_timeout {
+            for entry in server.clients.values() {
+                let mut client = entry.client.lock().unwrap();
+                let idle = server
+                    .presence
+                    .get(&client.nick())
+                    .map(|p| server.presence.idle(&p));
+
+                if idle.is_some_and(|idle| idle > timeout) && client.kicked.is_none() {
+                    server.kick(&mut client, "Idle timeout");
+                }
+            }
+        }
+    }
+
+    /// Check whether a message is addressed to a nickname rather than a channel
+    fn is_private(message: &protoutils::BakaMessage) -> bool {
+        message.kind == protoutils::MessageKind::Chat
+            && match &message.target {
+                Some(target) => !target.is_empty() && !target.starts_with('#'),
+                None => false,
+            }
+    }
+
+    /// Check whether a message is addressed to a channel
+    fn is_channel(message: &protoutils::BakaMessage) -> bool {
+        message.kind == protoutils::MessageKind::Chat
+            && match &message.target {
+                Some(target) => target.starts_with('#'),
+                None => false,
+            }
+    }
+
+    /// Relay a message from one client to another by nickname
+    ///
+    /// The author is replaced with the sender's nickname so it cannot be spoofed, then the
+    /// private message hook may veto or rewrite the message before it is delivered.
+    fn private_message(
+        server: &mut Server,
+        entry: &ClientEntry,
+        mut message: protoutils::BakaMessage,
+    ) {
+        let user = {
+            let client = entry.client.lock().unwrap();
+
+            message.author = client.nick();
+            client.flags.get("user").cloned()
+        };
+
+        let message = match server.private_message_hook.clone() {
+            Some(hook) => match hook(server, message.stamped(&*server.clock)) {
+                Some(message) => message,
+                None => return,
+            },
+            None => message.stamped(&*server.clock),
+        };
+
+        let target = message.target.clone().unwrap_or_default();
+
+        match server.find(&target) {
+            Some(recipient) => {
+                // Kept by user name between logged-in users only, nicknames can be taken over
+                let peer = recipient.client.lock().unwrap().flags.get("user").cloned();
+
+                if let (Some(user), Some(peer)) = (&user, &peer) {
+                    let _ = server
+                        .history
+                        .record(&History::conversation(user, peer), &message);
+                }
+
+                ServerBuilder::reply(&recipient, message);
+            }
+            // Who a client of another server logged in as is not known, so nothing is recorded
+            None if ServerBuilder::relay_private(server, &message) => {}
+            None => ServerBuilder::fail(
+                server,
+                entry,
+                &format!("NO_SUCH_NICK {{{}}}", target),
+                &Error::new("No such nick"),
+            ),
+        }
+    }
+
+    /// Queue a message from the server for a single client
+    pub(crate) fn reply(entry: &ClientEntry, mut message: protoutils::BakaMessage) {
+        let _ = entry
+            .outbound
+            .push(message.build().write_to_bytes().unwrap());
+    }
+
+    /// Queue a message for one of many recipients, skipping a client whose queue is full
+    /// instead of waiting for it, see `Outbound::try_push`
+    pub(crate) fn offer(entry: &ClientEntry, mut message: protoutils::BakaMessage) {
+        let _ = entry
+            .outbound
+            .try_push(message.build().write_to_bytes().unwrap());
+    }
+
+    /// Queue an error reply in the form `CODE :reason`
+    pub(crate) fn fail(server: &Server, entry: &ClientEntry, code: &str, error: &Error) {
+        ServerBuilder::reply(
+            entry,
+            protoutils::BakaMessage::new(
+                protoutils::MessageKind::Error,
+                &server.address.to_string(),
+                &format!("{} :{}", code, error.message()),
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protoutils::{BakaMessage, Hello, Welcome};
+    use crate::socket::{testing, ServerBuilder, Socket};
+    use crate::MockClock;
+
+    use protobuf::Message;
+
+    use std::io::{Read as _, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn rejects_unframed_clients() {
+        let address = testing::start(ServerBuilder::new("127.0.0.1:0"));
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let mut reply = vec![];
+
+        // Version 1 sent its `HELLO` without a length
+        let hello = Hello {
+            version: 1,
+            capabilities: vec![],
+        }
+        .to_message("client")
+        .build()
+        .write_to_bytes()
+        .unwrap();
+
+        stream.set_read_timeout(Some(testing::TIMEOUT)).unwrap();
+        stream.write_all(&hello).unwrap();
+        stream.read_to_end(&mut reply).unwrap();
+
+        let error = Welcome::parse(&BakaMessage::parse(&reply).unwrap()).unwrap_err();
+
+        assert!(error
+            .message()
+            .starts_with("Handshake rejected: Unsupported protocol version 1"));
+    }
+
+    #[test]
+    fn times_out_by_the_server_clock() {
+        let clock = Arc::new(MockClock::new());
+        let mut server = ServerBuilder::new("127.0.0.1:0");
+
+        server.handshake_timeout(Some(Duration::from_secs(10)));
+        server.idle_timeout(Some(Duration::from_secs(300)));
+        server.clock(clock.clone());
+
+        let handle = server.handle();
+        let address = testing::start(server);
+        let silent = Socket::connect(&address).unwrap();
+        let idle = testing::connect(&address);
+        let (silent, idle) = (testing::inbox(&silent), testing::inbox(&idle));
+
+        // Both connections are known to the server before its clock moves
+        testing::wait_for(|| handle.server.pending.len() == 1 && handle.server.clients.len() == 1);
+
+        clock.advance(Duration::from_secs(11));
+
+        assert_eq!(
+            testing::first_error(&silent).as_deref(),
+            Some("TIMEOUT :Handshake timed out")
+        );
+
+        clock.advance(Duration::from_secs(290));
+
+        assert_eq!(
+            testing::first_error(&idle).as_deref(),
+            Some("KICKED :Idle timeout")
+        );
+    }
+}
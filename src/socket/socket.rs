@@ -1,17 +1,60 @@
+use crate::auth::Credentials;
 use crate::io;
+use crate::middleware::{Action, Middleware, Peer, Pipeline};
 use crate::protoutils;
-use crate::socket::Error;
+#[cfg(feature = "tls")]
+use crate::socket::TlsConnector;
+use crate::socket::{Error, Session};
 
 use bakaproto::proto::*;
 
-use std::io::{Read, Write};
+use mio::{Interest, Poll, Token};
+
+use std::io::{ErrorKind, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default size of the chunks a `Socket` reads from its stream, in bytes
+pub const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// Longest a write to a non-blocking stream waits for the peer before trying again
+const WRITABLE_WAIT: Duration = Duration::from_secs(1);
+
+/// Size of the big-endian length every frame starts with, in bytes
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Largest frame a `Socket` reads unless changed with `Socket::set_max_frame_size`, in bytes
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
 
 type BoxEvent = Box<dyn Fn(&mut Socket, Result<message::Message, Error>) + Send + 'static>;
 
 pub struct Socket {
     stream: TcpStream,
     pub address: SocketAddr,
+    middleware: Option<Arc<Pipeline>>,
+    buffer_size: usize,
+    max_frame_size: Arc<AtomicUsize>,
+    received: Vec<u8>,
+    writable: Option<Writable>,
+    tls: Option<Arc<Session>>,
+}
+
+/// A frame read from a `Socket`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    /// A complete message
+    Message(Vec<u8>),
+    /// A frame announcing more than the maximum frame size, its data is not read
+    Oversized(usize),
+}
+
+/// Readiness poll a non-blocking stream's writer sleeps on until the peer drains its buffer
+struct Writable {
+    poll: Poll,
+    events: mio::Events,
+    _source: mio::net::TcpStream,
 }
 
 pub struct Events {
@@ -24,16 +67,75 @@ pub struct Events {
 pub struct SocketBuilder {
     socket: Socket,
     events: Events,
+    capabilities: Vec<String>,
+    welcome: Option<protoutils::Welcome>,
+    credentials: Option<Credentials>,
+    login_rejected: bool,
 }
 
 impl Socket {
     pub fn new(address: &str) -> Self {
-        let stream = TcpStream::connect(address).unwrap();
+        Socket::connect(address).unwrap()
+    }
 
-        Socket {
+    /// Connect to a server, reporting an address that cannot be reached
+    ///
+    /// Example:
+    /// ```rs
+    /// let socket = Socket::connect("127.0.0.1:65432")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address of the server.
+    pub fn connect(address: &str) -> Result<Self, Error> {
+        let stream = TcpStream::connect(address)
+            .map_err(|e| Error::new(&format!("Unable to connect to {}: {}", address, e)))?;
+        let peer = stream
+            .peer_addr()
+            .map_err(|e| Error::new(&format!("Unable to connect to {}: {}", address, e)))?;
+
+        Ok(Socket {
             stream: stream,
-            address: address.parse::<SocketAddr>().unwrap(),
-        }
+            address: peer,
+            middleware: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_frame_size: Arc::new(AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE)),
+            received: vec![],
+            writable: None,
+            tls: None,
+        })
+    }
+
+    /// Connect to a server over TLS
+    ///
+    /// The handshake happens with the first message sent, a server whose certificate is not
+    /// trusted or not valid for `name` makes the first read fail.
+    ///
+    /// Example:
+    /// ```rs
+    /// let connector = TlsConnector::from_pem("ca.pem")?;
+    /// let socket = Socket::connect_tls("127.0.0.1:6697", &connector, "chat.example.org")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address of the server.
+    /// * `connector`: The certificate authorities trusted.
+    /// * `name`: The name the server's certificate must be valid for.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(address: &str, connector: &TlsConnector, name: &str) -> Result<Self, Error> {
+        let mut socket = Socket::connect(address)?;
+
+        socket.secure(connector.session(name)?);
+
+        Ok(socket)
+    }
+
+    /// Encrypt everything read from and written to the stream from now on
+    #[cfg(feature = "tls")]
+    pub(crate) fn secure(&mut self, session: Session) {
+        self.tls = Some(Arc::new(session));
     }
 
     pub fn shutdown(&mut self) {
@@ -46,6 +148,309 @@ impl Socket {
     pub fn peer_address(&mut self) -> String {
         self.stream.peer_addr().unwrap().to_string()
     }
+
+    /// Set the size of the chunks read from the stream
+    ///
+    /// Arguments:
+    ///
+    /// * `size`: Chunk size in bytes, `DEFAULT_BUFFER_SIZE` unless changed.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer_size = size.max(1);
+    }
+
+    /// Refuse frames larger than a size
+    ///
+    /// A frame announcing more than `size` bytes is reported as soon as its length is read,
+    /// so the peer cannot make the reader allocate without bound. The limit is shared by every
+    /// clone of the socket, so it can be changed while another thread reads.
+    ///
+    /// Arguments:
+    ///
+    /// * `size`: Largest frame in bytes, not counting its length, `None` for no limit.
+    ///   `DEFAULT_MAX_FRAME_SIZE` unless changed.
+    pub fn set_max_frame_size(&mut self, size: Option<usize>) {
+        self.max_frame_size
+            .store(size.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    /// Take the next frame out of the bytes received so far
+    ///
+    /// Returns `None` while the frame is incomplete. An oversized frame stays in the buffer,
+    /// the connection cannot be read any further.
+    fn next_frame(&mut self) -> Option<Frame> {
+        if self.received.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+
+        header.copy_from_slice(&self.received[..FRAME_HEADER_SIZE]);
+
+        let size = u32::from_be_bytes(header) as usize;
+
+        if size > self.max_frame_size.load(Ordering::SeqCst) {
+            return Some(Frame::Oversized(size));
+        }
+
+        if self.received.len() < FRAME_HEADER_SIZE + size {
+            return None;
+        }
+
+        let data = self.received[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + size].to_vec();
+
+        self.received.drain(..FRAME_HEADER_SIZE + size);
+
+        Some(Frame::Message(data))
+    }
+
+    /// Send a message built with `protoutils::BakaMessage`
+    ///
+    /// The message passes the outbound side of the middleware set with
+    /// `SocketBuilder::middleware` first, and is not sent if a layer drops it.
+    pub fn send_message(&mut self, mut message: protoutils::BakaMessage) -> Result<(), Error> {
+        use protobuf::Message;
+
+        let message = match &self.middleware {
+            Some(pipeline) => {
+                match pipeline.outbound(&Peer::new(&self.address.to_string()), message.build()) {
+                    Action::Continue(message) | Action::ShortCircuit(message) => message,
+                    Action::Drop => return Ok(()),
+                }
+            }
+            None => message.build(),
+        };
+
+        self.try_send_bytes(message.write_to_bytes().unwrap().as_slice())
+    }
+
+    /// Send a private message to another client, relayed by the server
+    ///
+    /// Example:
+    /// ```rs
+    /// socket.private_message("alice", "hi!")?;
+    /// ```
+    ///
+    /// Arguments:
+    ///
+    /// * `nick`: The nickname of the recipient.
+    /// * `content`: The message text.
+    pub fn private_message(&mut self, nick: &str, content: &str) -> Result<(), Error> {
+        let author = self.local_address();
+
+        self.send_message(
+            protoutils::BakaMessage::new(protoutils::MessageKind::Chat, &author, content)
+                .with_target(nick),
+        )
+    }
+
+    /// Write data to the stream as one frame, reporting failures instead of panicking
+    ///
+    /// The data is prefixed with its length, see `FRAME_HEADER_SIZE`. Also works on
+    /// non-blocking streams, where it sleeps until the stream is writable again.
+    pub(crate) fn try_send_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| Error::new("Unexcepted error while writing stream data: too large"))?;
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+
+        frame.extend_from_slice(&size.to_be_bytes());
+        frame.extend_from_slice(data);
+
+        self.write_all(&frame)
+    }
+
+    /// Write data to the stream as it is, without a frame header
+    ///
+    /// Only for peers that do not speak the framed protocol, see `protoutils::PROTOCOL_VERSION`.
+    pub(crate) fn send_unframed(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_all(data)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Some(session) = self.tls.clone() {
+            session.write(data).map_err(|e| {
+                Error::new(&format!(
+                    "Unexcepted error while writing stream data: {:?}",
+                    e
+                ))
+            })?;
+
+            return self.flush_tls(&session);
+        }
+
+        let mut written = 0usize;
+
+        while written < data.len() {
+            match self.stream.write(&data[written..]) {
+                Ok(0) => {
+                    return Err(Error {
+                        message: "Unexcepted error while writing stream data: connection closed"
+                            .to_string(),
+                    });
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => self.wait_writable()?,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(Error {
+                        message: format!("Unexcepted error while writing stream data: {:?}", e),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the encrypted data a TLS session has waiting, sleeping while a non-blocking
+    /// stream is full
+    fn flush_tls(&mut self, session: &Session) -> Result<(), Error> {
+        loop {
+            match session.write_tls(&self.stream) {
+                Ok(false) => return Ok(()),
+                Ok(true) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => self.wait_writable()?,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(Error {
+                        message: format!("Unexcepted error while writing stream data: {:?}", e),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Read some data from the stream, decrypted when the socket uses TLS
+    fn read_some(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.tls.clone() {
+            Some(session) => {
+                let read = session.read(&self.stream, buffer);
+
+                // Reading may leave handshake messages for the peer
+                self.flush_tls(&session)?;
+
+                read
+            }
+            None => self.stream.read(buffer),
+        }
+    }
+
+    /// Sleep until a non-blocking stream has room for more data
+    ///
+    /// The stream is registered for write readiness with a poll of its own the first time,
+    /// so the writer does not share the reactor's poll.
+    fn wait_writable(&mut self) -> Result<(), Error> {
+        let error = |e: std::io::Error| Error {
+            message: format!("Unexcepted error while waiting for stream: {:?}", e),
+        };
+
+        if self.writable.is_none() {
+            let poll = Poll::new().map_err(error)?;
+            let mut source = self.mio_source()?;
+
+            poll.registry()
+                .register(&mut source, Token(0), Interest::WRITABLE)
+                .map_err(error)?;
+
+            self.writable = Some(Writable {
+                poll: poll,
+                events: mio::Events::with_capacity(1),
+                _source: source,
+            });
+        }
+
+        let writable = self.writable.as_mut().unwrap();
+
+        match writable
+            .poll
+            .poll(&mut writable.events, Some(WRITABLE_WAIT))
+        {
+            Err(e) if e.kind() != ErrorKind::Interrupted => Err(error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read everything currently available on a non-blocking stream
+    ///
+    /// Returns the complete frames read and whether the peer has closed the connection. An
+    /// incomplete frame is kept for the next call. Stops early at a frame over the maximum
+    /// frame size, see `Socket::set_max_frame_size`.
+    pub(crate) fn read_available(&mut self) -> Result<(Vec<Frame>, bool), Error> {
+        let mut frames = vec![];
+        let mut buffer = vec![0; self.buffer_size];
+
+        loop {
+            match self.read_some(&mut buffer) {
+                Ok(0) => return Ok((frames, true)),
+                Ok(n) => {
+                    self.received.extend_from_slice(&buffer[..n]);
+
+                    while let Some(frame) = self.next_frame() {
+                        let oversized = matches!(frame, Frame::Oversized(_));
+
+                        frames.push(frame);
+
+                        if oversized {
+                            return Ok((frames, false));
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((frames, false)),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(Error {
+                        message: format!("Unexcepted error while reading stream data: {:?}", e),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Read the next frame from a blocking stream
+    ///
+    /// Returns `None` once the peer has closed the connection between two frames.
+    pub(crate) fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let mut buffer = vec![0; self.buffer_size];
+
+        loop {
+            if let Some(frame) = self.next_frame() {
+                return Ok(Some(frame));
+            }
+
+            match self.read_some(&mut buffer) {
+                Ok(0) if self.received.is_empty() => return Ok(None),
+                Ok(0) => {
+                    return Err(Error::new(
+                        "Unexcepted error while reading stream data: connection closed mid-frame",
+                    ))
+                }
+                Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(Error {
+                        message: format!("Unexcepted error while reading stream data: {:?}", e),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Switch the underlying stream between blocking and non-blocking mode
+    pub(crate) fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Error> {
+        self.stream.set_nonblocking(nonblocking).map_err(|e| Error {
+            message: format!("Unexcepted error while configuring stream: {:?}", e),
+        })
+    }
+
+    /// Clone the underlying stream as a `mio` source for readiness polling
+    pub(crate) fn mio_source(&self) -> Result<mio::net::TcpStream, Error> {
+        match self.stream.try_clone() {
+            Ok(stream) => Ok(mio::net::TcpStream::from_std(stream)),
+            Err(e) => Err(Error {
+                message: format!("Unexcepted error while cloning stream: {:?}", e),
+            }),
+        }
+    }
 }
 
 impl Clone for Socket {
@@ -53,6 +458,12 @@ impl Clone for Socket {
         Socket {
             stream: self.stream.try_clone().unwrap(),
             address: self.address,
+            middleware: self.middleware.clone(),
+            buffer_size: self.buffer_size,
+            max_frame_size: self.max_frame_size.clone(),
+            received: vec![],
+            writable: None,
+            tls: self.tls.clone(),
         }
     }
 }
@@ -64,6 +475,12 @@ impl std::convert::From<TcpStream> for Socket {
         Socket {
             stream: stream,
             address: address.unwrap(),
+            middleware: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_frame_size: Arc::new(AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE)),
+            received: vec![],
+            writable: None,
+            tls: None,
         }
     }
 }
@@ -86,7 +503,7 @@ impl io::Send for Socket {
     }
 
     fn send_bytes(&mut self, data: Vec<u8>) {
-        self.stream.write_all(data.as_slice()).unwrap();
+        self.try_send_bytes(data.as_slice()).unwrap();
     }
 
     fn send_string(&mut self, data: String) {
@@ -95,39 +512,20 @@ impl io::Send for Socket {
 }
 
 impl io::Read for Socket {
+    /// Read the next frame, an empty buffer once the peer has closed the connection
     fn read_stream(&mut self) -> Result<(Vec<u8>, usize), Error> {
-        let buffer_size = 4096;
-
-        let mut request_buffer = vec![];
-        let mut request_len = 0usize;
-
-        loop {
-            let mut buffer = vec![0; buffer_size];
+        match self.read_frame()? {
+            Some(Frame::Message(data)) => {
+                let size = data.len();
 
-            match self.stream.read(&mut buffer) {
-                Ok(n) => {
-                    if n == 0 {
-                        break;
-                    } else {
-                        request_len += n;
-
-                        if n < buffer_size {
-                            request_buffer.append(&mut buffer[..n].to_vec());
-                            break;
-                        } else {
-                            request_buffer.append(&mut buffer);
-                        }
-                    }
-                }
-                Err(e) => {
-                    return Err(Error {
-                        message: format!("Unexcepted error while reading stream data: {:?}", e),
-                    });
-                }
+                Ok((data, size))
             }
+            Some(Frame::Oversized(size)) => Err(Error::new(&format!(
+                "Unexcepted error while reading stream data: frame of {} bytes is too large",
+                size
+            ))),
+            None => Ok((vec![], 0)),
         }
-
-        Ok((request_buffer, request_len))
     }
 
     fn read(&mut self) -> Result<String, Error> {
@@ -147,49 +545,334 @@ impl io::Read for Socket {
 
 impl SocketBuilder {
     pub fn new(address: &str, events: Events) -> Self {
-        let socket = Socket::new(address);
+        SocketBuilder::connect(address, events).unwrap()
+    }
+
+    /// Initialize new instance of the `SocketBuilder`, reporting a server that cannot be
+    /// reached
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address of the server.
+    /// * `events`: The functions called for the connection's events.
+    pub fn connect(address: &str, events: Events) -> Result<Self, Error> {
+        Ok(SocketBuilder::with_socket(
+            Socket::connect(address)?,
+            events,
+        ))
+    }
 
+    /// Initialize new instance of the `SocketBuilder` connected over TLS, see
+    /// `Socket::connect_tls`
+    ///
+    /// Arguments:
+    ///
+    /// * `address`: The address of the server.
+    /// * `events`: The functions called for the connection's events.
+    /// * `connector`: The certificate authorities trusted.
+    /// * `name`: The name the server's certificate must be valid for.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        address: &str,
+        events: Events,
+        connector: &TlsConnector,
+        name: &str,
+    ) -> Result<Self, Error> {
+        Ok(SocketBuilder::with_socket(
+            Socket::connect_tls(address, connector, name)?,
+            events,
+        ))
+    }
+
+    fn with_socket(socket: Socket, events: Events) -> Self {
         SocketBuilder {
             socket: socket,
             events: events,
+            capabilities: vec![],
+            welcome: None,
+            credentials: None,
+            login_rejected: false,
+        }
+    }
+
+    /// Set the capabilities requested from the server during the handshake
+    ///
+    /// Arguments:
+    ///
+    /// * `capabilities`: The capability names.
+    pub fn capabilities(&mut self, capabilities: &[&str]) {
+        self.capabilities = capabilities.iter().map(|c| c.to_string()).collect();
+    }
+
+    /// Log in with these credentials right after the handshake
+    ///
+    /// Arguments:
+    ///
+    /// * `credentials`: A user name and password, or a token.
+    pub fn credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// Refuse messages from the server larger than a size, see `Socket::set_max_frame_size`
+    ///
+    /// Arguments:
+    ///
+    /// * `size`: Largest message in bytes, `None` for no limit.
+    pub fn max_frame_size(&mut self, size: Option<usize>) {
+        self.socket.set_max_frame_size(size);
+    }
+
+    /// Add a middleware layer for messages exchanged with the server
+    ///
+    /// Inbound layers run before `on_message`, outbound layers run in `Socket::send_message`.
+    ///
+    /// Arguments:
+    ///
+    /// * `layer`: The layer, run after the ones added before it.
+    pub fn middleware<M: Middleware + 'static>(&mut self, layer: M) {
+        let mut pipeline = self
+            .socket
+            .middleware
+            .as_deref()
+            .cloned()
+            .unwrap_or_default();
+
+        pipeline.push(layer);
+        self.socket.middleware = Some(Arc::new(pipeline));
+    }
+
+    /// Get the protocol version and capabilities accepted by the server
+    pub fn welcome(&self) -> Option<&protoutils::Welcome> {
+        self.welcome.as_ref()
+    }
+
+    /// Check whether the server refused the credentials, connecting again with the same ones
+    /// would fail as well
+    pub fn login_rejected(&self) -> bool {
+        self.login_rejected
+    }
+
+    /// Send a message and wait for the server's reply
+    fn request(
+        &mut self,
+        mut message: protoutils::BakaMessage,
+    ) -> Result<protoutils::BakaMessage, Error> {
+        use crate::io::Read;
+        use protobuf::Message;
+
+        self.socket
+            .try_send_bytes(message.build().write_to_bytes().unwrap().as_slice())?;
+
+        let (buffer, _) = self.socket.read_stream()?;
+
+        if buffer.len() < 1 {
+            return Err(Error::new("Connection closed by server"));
+        }
+
+        protoutils::BakaMessage::parse(buffer.as_slice()).map_err(|e| Error {
+            message: format!("Unexcepted error while decoding message: {}", e),
+        })
+    }
+
+    fn handshake(&mut self) -> Result<protoutils::Welcome, Error> {
+        let author = self.socket.local_address();
+        let hello = protoutils::Hello::new(self.capabilities.clone());
+        let reply = self.request(hello.to_message(&author))?;
+
+        protoutils::Welcome::parse(&reply)
+    }
+
+    fn login(&mut self, credentials: Credentials) -> Result<(), Error> {
+        let author = self.socket.local_address();
+        let content = match credentials {
+            Credentials::Password { user, password } => format!("LOGIN {{{}}} :{}", user, password),
+            Credentials::Token(token) => format!("TOKEN :{}", token),
+        };
+        let reply = self.request(protoutils::BakaMessage::new(
+            protoutils::MessageKind::Command,
+            &author,
+            &content,
+        ))?;
+
+        if reply.kind == protoutils::MessageKind::Error {
+            self.login_rejected = true;
+
+            return Err(Error::new(&format!("Login failed: {}", reply.content)));
         }
+
+        Ok(())
     }
 
+    /// Perform the handshake, log in if credentials were set, and start the event loop,
+    /// blocks the calling thread
+    ///
+    /// If the server rejects the handshake or the login, `on_error` receives the reason and the
+    /// connection is closed without `on_connect` being called. A read error, like an oversized
+    /// frame, is passed to `on_error` before the connection is closed. Returns once the
+    /// connection is closed, after `on_disconnect`.
     pub fn startup(&mut self) {
         use crate::io::Read;
 
         let address = self.socket.address.to_string();
 
+        let connected = self.handshake().and_then(|welcome| {
+            self.welcome = Some(welcome);
+
+            match self.credentials.take() {
+                Some(credentials) => self.login(credentials),
+                None => Ok(()),
+            }
+        });
+
+        if let Err(e) = connected {
+            (self.events.on_error)(&mut self.socket, Err(e));
+            self.socket.shutdown();
+            return;
+        }
+
         (self.events.on_connect)(
             &mut self.socket,
-            Ok(protoutils::BakaMessage {
-                author: address.clone(),
-                content: "Succefully connected".to_string(),
-            }
+            Ok(protoutils::BakaMessage::new(
+                protoutils::MessageKind::Join,
+                &address,
+                "Succefully connected",
+            )
             .build()),
         );
 
         loop {
-            if let Ok(buffer) = self.socket.read() {
-                if buffer.len() < 1 {
-                    continue;
+            let message = match self.socket.read_stream() {
+                Ok((buffer, _)) if buffer.len() > 0 => protoutils::BakaMessage::parse(&buffer),
+                Ok(_) => break,
+                Err(e) => {
+                    (self.events.on_error)(&mut self.socket, Err(e));
+                    break;
                 }
+            };
+
+            if let Ok(mut message) = message {
+                let message = message.build();
+                let message = match self.socket.middleware.clone() {
+                    Some(pipeline) => match pipeline.inbound(&Peer::new(&address), message) {
+                        Action::Continue(message) => message,
+                        Action::Drop => continue,
+                        Action::ShortCircuit(reply) => {
+                            let _ = self
+                                .socket
+                                .send_message(protoutils::BakaMessage::from(reply));
+                            continue;
+                        }
+                    },
+                    None => message,
+                };
 
-                (self.events.on_message)(
-                    &mut self.socket,
-                    Ok(protoutils::BakaMessage::from(buffer).build()),
-                );
-            } else {
-                (self.events.on_disconnect)(
+                (self.events.on_message)(&mut self.socket, Ok(message));
+            } else if let Err(e) = message {
+                (self.events.on_error)(
                     &mut self.socket,
-                    Ok(protoutils::BakaMessage {
-                        author: address.clone(),
-                        content: "Disconnected".to_string(),
-                    }
-                    .build()),
+                    Err(Error::new(&format!(
+                        "Unexcepted error while decoding message: {}",
+                        e
+                    ))),
                 );
-                break;
             }
         }
+
+        (self.events.on_disconnect)(
+            &mut self.socket,
+            Ok(protoutils::BakaMessage::new(
+                protoutils::MessageKind::Part,
+                &address,
+                "Disconnected",
+            )
+            .build()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    /// Connect two sockets over the loopback interface
+    fn pair() -> (Socket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = Socket::connect(&listener.local_addr().unwrap().to_string()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        (socket, stream)
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn splits_coalesced_frames() {
+        let (mut socket, mut peer) = pair();
+        let mut data = frame(b"first");
+
+        data.extend(frame(b"second"));
+        peer.write_all(&data).unwrap();
+
+        assert_eq!(
+            socket.read_frame().unwrap(),
+            Some(Frame::Message(b"first".to_vec()))
+        );
+        assert_eq!(
+            socket.read_frame().unwrap(),
+            Some(Frame::Message(b"second".to_vec()))
+        );
+    }
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let (mut socket, mut peer) = pair();
+        let data = frame(b"split in two");
+
+        socket.set_nonblocking(true).unwrap();
+        peer.write_all(&data[..6]).unwrap();
+        peer.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(socket.read_available().unwrap(), (vec![], false));
+
+        peer.write_all(&data[6..]).unwrap();
+        drop(peer);
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(
+            socket.read_available().unwrap(),
+            (vec![Frame::Message(b"split in two".to_vec())], true)
+        );
+    }
+
+    #[test]
+    fn refuses_oversized_frames_from_their_length() {
+        let (mut socket, mut peer) = pair();
+
+        socket.set_max_frame_size(Some(16));
+        peer.write_all(&(1u32 << 30).to_be_bytes()).unwrap();
+
+        assert_eq!(
+            socket.read_frame().unwrap(),
+            Some(Frame::Oversized(1 << 30))
+        );
+    }
+
+    #[test]
+    fn sends_length_prefixed_frames() {
+        let (mut socket, mut peer) = pair();
+        let mut data = vec![0; FRAME_HEADER_SIZE + 5];
+
+        socket.try_send_bytes(b"hello").unwrap();
+        peer.read_exact(&mut data).unwrap();
+
+        assert_eq!(data, frame(b"hello"));
     }
 }
@@ -0,0 +1,134 @@
+//! Helpers shared by the socket tests
+//!
+//! Servers listen on `127.0.0.1:0`, so tests running in parallel never fight over a port, and
+//! the listener is bound before `start` returns, so clients can connect right away.
+
+use crate::io::Read;
+use crate::protoutils::{BakaMessage, Hello, MessageKind, Welcome};
+use crate::socket::{ServerBuilder, Socket};
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a test waits for a message before failing
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run a server on its own thread, returns the address it listens on
+pub fn start(mut server: ServerBuilder) -> String {
+    let address = server.server.address.to_string();
+
+    thread::spawn(move || server.startup());
+
+    address
+}
+
+/// Connect to a server and finish the handshake
+pub fn connect(address: &str) -> Socket {
+    handshake(Socket::connect(address).unwrap())
+}
+
+/// Finish the handshake on a connected socket
+pub fn handshake(mut socket: Socket) -> Socket {
+    let author = socket.local_address();
+
+    socket
+        .send_message(Hello::new(vec![]).to_message(&author))
+        .unwrap();
+
+    let (reply, _) = socket.read_stream().unwrap();
+
+    Welcome::parse(&BakaMessage::parse(&reply).unwrap()).unwrap();
+
+    socket
+}
+
+/// Connect to a server and pick a nickname, returns the socket and the messages it receives
+pub fn join(address: &str, nick: &str) -> (Socket, Receiver<BakaMessage>) {
+    let mut socket = connect(address);
+    let receiver = inbox(&socket);
+
+    send(&mut socket, &format!("NICK {{{}}}", nick));
+
+    (socket, receiver)
+}
+
+/// Send a command to the server
+pub fn send(socket: &mut Socket, command: &str) {
+    let author = socket.local_address();
+
+    socket
+        .send_message(BakaMessage::new(MessageKind::Command, &author, command))
+        .unwrap();
+}
+
+/// Read the messages a socket receives on another thread, the receiver disconnects once the
+/// connection is closed
+pub fn inbox(socket: &Socket) -> Receiver<BakaMessage> {
+    let (sender, receiver) = mpsc::channel();
+    let mut reader = socket.clone();
+
+    thread::spawn(move || {
+        while let Ok((data, _)) = reader.read_stream() {
+            if data.is_empty() {
+                return;
+            }
+
+            if let Ok(message) = BakaMessage::parse(&data) {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Wait for a message matching the predicate, panics after `TIMEOUT`
+pub fn expect<F: Fn(&BakaMessage) -> bool>(
+    receiver: &Receiver<BakaMessage>,
+    matches: F,
+) -> BakaMessage {
+    let deadline = Instant::now() + TIMEOUT;
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let message = receiver.recv_timeout(left).expect("no matching message");
+
+        if matches(&message) {
+            return message;
+        }
+    }
+}
+
+/// Wait for the first error, `None` if the connection closes without one
+pub fn first_error(receiver: &Receiver<BakaMessage>) -> Option<String> {
+    let deadline = Instant::now() + TIMEOUT;
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+
+        match receiver.recv_timeout(left) {
+            Ok(message) if message.kind == MessageKind::Error => return Some(message.content),
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Disconnected) => return None,
+            Err(RecvTimeoutError::Timeout) => panic!("no error within {:?}", TIMEOUT),
+        }
+    }
+}
+
+/// Wait until a condition holds, checking it every few milliseconds, panics after `TIMEOUT`
+pub fn wait_for<F: FnMut() -> bool>(mut condition: F) {
+    let deadline = Instant::now() + TIMEOUT;
+
+    while !condition() {
+        assert!(
+            Instant::now() < deadline,
+            "condition not met within {:?}",
+            TIMEOUT
+        );
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
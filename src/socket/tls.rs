@@ -0,0 +1,336 @@
+//! TLS for `Socket`, built on `rustls` when the `tls` feature is enabled.
+//!
+//! A `Session` wraps the socket's stream: encrypted bytes are read from the stream and
+//! decrypted as frames are read, and every frame sent is encrypted before it is written. The
+//! handshake happens along the way, so a TLS socket is served like any other.
+
+#[cfg(feature = "tls")]
+pub use enabled::*;
+
+#[cfg(not(feature = "tls"))]
+pub(crate) use disabled::*;
+
+#[cfg(feature = "tls")]
+mod enabled {
+    use crate::socket::Error;
+
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConnection};
+
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// Smallest chunk of encrypted data read from the stream at once, in bytes
+    const MIN_READ: usize = 4096;
+
+    fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+
+    fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let certificates = CertificateDer::pem_file_iter(path)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| Error::new(&format!("Unable to read {}: {}", path.display(), e)))?;
+
+        if certificates.is_empty() {
+            return Err(Error::new(&format!(
+                "Unable to read {}: no certificate found",
+                path.display()
+            )));
+        }
+
+        Ok(certificates)
+    }
+
+    /// ## TlsAcceptor
+    ///
+    /// Certificate and key a server presents to the clients connecting over TLS, see
+    /// `ServerBuilder::listen_tls`.
+    #[derive(Clone)]
+    pub struct TlsAcceptor {
+        config: Arc<rustls::ServerConfig>,
+    }
+
+    impl TlsAcceptor {
+        /// Load the certificate chain and private key from PEM files
+        ///
+        /// Example:
+        /// ```rs
+        /// let acceptor = TlsAcceptor::from_pem("cert.pem", "key.pem")?;
+        /// ```
+        ///
+        /// Arguments:
+        ///
+        /// * `cert`: The certificate chain, the server's own certificate first.
+        /// * `key`: The private key of the server's certificate.
+        pub fn from_pem<P: AsRef<Path>, K: AsRef<Path>>(cert: P, key: K) -> Result<Self, Error> {
+            let key = key.as_ref();
+            let certificates = certificates(cert.as_ref())?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| Error::new(&format!("Unable to read {}: {}", key.display(), e)))?;
+
+            let config = rustls::ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .and_then(|builder| {
+                    builder
+                        .with_no_client_auth()
+                        .with_single_cert(certificates, key)
+                })
+                .map_err(|e| Error::new(&format!("Invalid certificate: {}", e)))?;
+
+            Ok(TlsAcceptor {
+                config: Arc::new(config),
+            })
+        }
+
+        /// Start the server side of a new connection
+        pub(crate) fn session(&self) -> Result<Session, Error> {
+            ServerConnection::new(self.config.clone())
+                .map(|connection| Session::new(connection.into()))
+                .map_err(|e| Error::new(&format!("Unable to start TLS: {}", e)))
+        }
+    }
+
+    /// ## TlsConnector
+    ///
+    /// Certificate authorities a client trusts when connecting over TLS, see
+    /// `Socket::connect_tls`.
+    #[derive(Clone)]
+    pub struct TlsConnector {
+        config: Arc<ClientConfig>,
+    }
+
+    impl TlsConnector {
+        /// Trust the certificate authorities in a PEM file
+        ///
+        /// Example:
+        /// ```rs
+        /// let connector = TlsConnector::from_pem("ca.pem")?;
+        /// let socket = Socket::connect_tls("127.0.0.1:6697", &connector, "chat.example.org")?;
+        /// ```
+        ///
+        /// Arguments:
+        ///
+        /// * `ca`: The certificates of the trusted authorities.
+        pub fn from_pem<P: AsRef<Path>>(ca: P) -> Result<Self, Error> {
+            let mut roots = RootCertStore::empty();
+
+            for certificate in certificates(ca.as_ref())? {
+                roots
+                    .add(certificate)
+                    .map_err(|e| Error::new(&format!("Invalid certificate: {}", e)))?;
+            }
+
+            let config = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(|e| Error::new(&format!("Invalid certificate: {}", e)))?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            Ok(TlsConnector {
+                config: Arc::new(config),
+            })
+        }
+
+        /// Start the client side of a new connection
+        ///
+        /// Arguments:
+        ///
+        /// * `name`: The name the server's certificate must be valid for.
+        pub(crate) fn session(&self, name: &str) -> Result<Session, Error> {
+            let name = ServerName::try_from(name.to_string())
+                .map_err(|e| Error::new(&format!("Invalid server name {}: {}", name, e)))?;
+
+            ClientConnection::new(self.config.clone(), name)
+                .map(|connection| Session::new(connection.into()))
+                .map_err(|e| Error::new(&format!("Unable to start TLS: {}", e)))
+        }
+    }
+
+    /// The TLS state of a connection, shared by every clone of its `Socket`
+    pub(crate) struct Session {
+        state: Mutex<State>,
+    }
+
+    /// Properties:
+    ///
+    /// * `connection`: The `rustls` connection.
+    /// * `received`: Encrypted bytes read from the stream the connection has not taken yet.
+    struct State {
+        connection: Connection,
+        received: Vec<u8>,
+    }
+
+    impl Session {
+        fn new(mut connection: Connection) -> Self {
+            // Frames are queued by the caller already, they are never cut short here
+            connection.set_buffer_limit(None);
+
+            Session {
+                state: Mutex::new(State {
+                    connection: connection,
+                    received: vec![],
+                }),
+            }
+        }
+
+        /// Read decrypted data into `buffer`, `0` once the peer has closed the connection
+        ///
+        /// Handshake messages owed to the peer are written before waiting for it. The lock is
+        /// not held while reading from the stream, so writers are not held up by a reader
+        /// waiting for the peer.
+        pub(crate) fn read(&self, mut stream: &TcpStream, buffer: &mut [u8]) -> io::Result<usize> {
+            let mut encrypted = vec![0; buffer.len().max(MIN_READ)];
+
+            loop {
+                {
+                    let mut state = self.state.lock().unwrap();
+                    let State {
+                        connection,
+                        received,
+                    } = &mut *state;
+
+                    match connection.reader().read(buffer) {
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                        result => return result,
+                    }
+
+                    if !received.is_empty() {
+                        let taken = connection.read_tls(&mut received.as_slice())?;
+
+                        received.drain(..taken);
+                        connection
+                            .process_new_packets()
+                            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                        continue;
+                    }
+
+                    // A full stream is flushed by the caller, see `Socket::read_some`
+                    while connection.wants_write() {
+                        match connection.write_tls(&mut stream) {
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            result => result.map(|_| ())?,
+                        }
+                    }
+                }
+
+                match stream.read(&mut encrypted)? {
+                    0 => return Ok(0),
+                    n => self
+                        .state
+                        .lock()
+                        .unwrap()
+                        .received
+                        .extend_from_slice(&encrypted[..n]),
+                }
+            }
+        }
+
+        /// Queue data to be encrypted, see `Session::write_tls`
+        pub(crate) fn write(&self, data: &[u8]) -> io::Result<()> {
+            self.state
+                .lock()
+                .unwrap()
+                .connection
+                .writer()
+                .write_all(data)
+        }
+
+        /// Write some of the encrypted data waiting to be sent, `false` once none is left
+        pub(crate) fn write_tls(&self, mut stream: &TcpStream) -> io::Result<bool> {
+            let mut state = self.state.lock().unwrap();
+
+            if !state.connection.wants_write() {
+                return Ok(false);
+            }
+
+            state.connection.write_tls(&mut stream).map(|_| true)
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod disabled {
+    use std::io;
+    use std::net::TcpStream;
+
+    /// Without the `tls` feature no socket has a session
+    pub(crate) enum Session {}
+
+    impl Session {
+        pub(crate) fn read(&self, _: &TcpStream, _: &mut [u8]) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub(crate) fn write(&self, _: &[u8]) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub(crate) fn write_tls(&self, _: &TcpStream) -> io::Result<bool> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+
+    use crate::io::Read;
+    use crate::socket::testing;
+    use crate::socket::{ServerBuilder, Socket};
+
+    const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/ca.pem");
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/localhost.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/localhost.key");
+
+    fn acceptor() -> TlsAcceptor {
+        TlsAcceptor::from_pem(CERT, KEY).unwrap()
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        assert!(TlsAcceptor::from_pem("missing.pem", KEY).is_err());
+        assert!(TlsAcceptor::from_pem(CERT, CERT).is_err());
+        assert!(TlsConnector::from_pem(KEY).is_err());
+    }
+
+    #[test]
+    fn serves_clients_over_tls() {
+        let mut server = ServerBuilder::new("127.0.0.1:0");
+
+        server.listen_tls("127.0.0.1:0", acceptor()).unwrap();
+
+        let address = server.server.listeners[1].address.to_string();
+        let connector = TlsConnector::from_pem(CA).unwrap();
+
+        testing::start(server);
+
+        let socket = Socket::connect_tls(&address, &connector, "localhost").unwrap();
+
+        // Panics unless the server welcomes the client
+        testing::handshake(socket);
+    }
+
+    #[test]
+    fn refuses_certificates_for_other_names() {
+        let mut server = ServerBuilder::new("127.0.0.1:0");
+
+        server.listen_tls("127.0.0.1:0", acceptor()).unwrap();
+
+        let address = server.server.listeners[1].address.to_string();
+        let connector = TlsConnector::from_pem(CA).unwrap();
+
+        testing::start(server);
+
+        // The certificate is only valid for localhost
+        let mut socket = Socket::connect_tls(&address, &connector, "example.org").unwrap();
+
+        socket.try_send_bytes(b"hello").unwrap();
+
+        assert!(socket.read_stream().is_err());
+    }
+}
@@ -1,18 +1,860 @@
+use crate::clock::{Clock, SystemClock, Waiter};
+use crate::socket::Error;
+use crate::trace::event;
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub fn set_interval(interval: i32, mut delegate: Box<dyn FnMut(i32) -> bool + Send>) {
-    thread::spawn(move || {
-        let mut i = 0;
+/// Task run by a `Scheduler`, receives the handle of its own timer so it can cancel itself
+pub type BoxTask = Box<dyn FnMut(&TimerHandle) + Send + 'static>;
+
+/// Shortest time between two runs of a `Schedule::Every` timer
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How far ahead a `Cron` schedule is searched for its next run
+const CRON_SEARCH_DAYS: i64 = 366 * 5;
+
+/// ## Schedule
+///
+/// When a timer runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Once, after the delay
+    Once(Duration),
+    /// Repeatedly, the first time after one interval
+    Every(Duration),
+    /// At the minutes matching the expression, in UTC
+    Cron(Cron),
+}
+
+/// ## Cron
+///
+/// Parsed cron expression: `minute hour day-of-month month day-of-week`.
+///
+/// Every field accepts `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`) and lists of
+/// those (`0,30`). Day of week counts from Sunday (`0` or `7`). When both day fields are
+/// restricted a day matching either runs the job, as in cron. `@hourly`, `@daily`,
+/// `@weekly`, `@monthly` and `@yearly` are accepted too.
+///
+/// Example:
+/// ```rs
+/// let nightly = Cron::parse("30 3 * * *")?;
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+struct Timer {
+    schedule: Schedule,
+    task: Arc<Mutex<BoxTask>>,
+    generation: u64,
+    /// Wall-clock time a `Schedule::Cron` timer is due at
+    at: Option<SystemTime>,
+    /// Time left when the timer was paused
+    paused: Option<Duration>,
+}
+
+struct State {
+    heap: BinaryHeap<Reverse<(Instant, u64, u64)>>,
+    timers: HashMap<u64, Timer>,
+    next_id: u64,
+    stopped: bool,
+    clock: Arc<dyn Clock>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+/// What the earliest timer of a `Scheduler` needs
+enum Next {
+    /// Run the task of the timer now
+    Run(u64, Arc<Mutex<BoxTask>>),
+    /// Wait until the time
+    Wait(Instant),
+    /// No timers
+    Idle,
+}
+
+/// ## Scheduler
+///
+/// Runs delayed, repeating and cron timers on a single thread.
+///
+/// Due times are kept in a min-heap, so the thread sleeps until the earliest timer is due.
+/// Tasks run one after another on that thread: a task that blocks delays every other timer
+/// of the scheduler, so hand long work to another thread. A task that panics is dropped with
+/// its timer, the other timers keep running. With a `MockClock` there is no thread, the
+/// timers run from `MockClock::advance`.
+///
+/// Clones share the timers. Once the last clone is dropped every timer is cancelled and the
+/// thread stops, as with `Scheduler::shutdown`.
+///
+/// Example:
+/// ```rs
+/// let scheduler = Scheduler::new();
+///
+/// let timer = scheduler.set_interval(Duration::from_secs(30), Box::new(|_| {
+///     println!("tick");
+/// }));
+///
+/// scheduler.schedule(Schedule::Cron(Cron::parse("0 * * * *")?), Box::new(|_| {
+///     println!("hourly");
+/// }));
+///
+/// timer.cancel();
+/// ```
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    _owner: Arc<Owner>,
+}
+
+/// Shuts the scheduler down when the last `Scheduler` clone drops it
+struct Owner(Arc<Shared>);
+
+/// ## TimerHandle
+///
+/// Controls one timer of a `Scheduler`. Dropping the handle leaves the timer running.
+pub struct TimerHandle {
+    id: u64,
+    shared: Weak<Shared>,
+}
+
+impl Clone for Scheduler {
+    fn clone(&self) -> Self {
+        Scheduler {
+            shared: self.shared.clone(),
+            _owner: self._owner.clone(),
+        }
+    }
+}
+
+impl Clone for TimerHandle {
+    fn clone(&self) -> Self {
+        TimerHandle {
+            id: self.id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    /// Initialize new instance of the `Scheduler` following the real time, starting its timer
+    /// thread
+    pub fn new() -> Self {
+        Scheduler::with_clock(SystemClock::shared())
+    }
+
+    /// Initialize new instance of the `Scheduler` following a clock
+    ///
+    /// Arguments:
+    ///
+    /// * `clock`: Decides when timers are due. A `MockClock` runs the timers itself when it is
+    ///   advanced, any other clock gets a timer thread.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                heap: BinaryHeap::new(),
+                timers: HashMap::new(),
+                next_id: 0,
+                stopped: false,
+                clock: clock.clone(),
+            }),
+            wake: Condvar::new(),
+        });
+
+        match clock.as_mock() {
+            Some(mock) => mock.attach(shared.clone()),
+            None => {
+                let worker = shared.clone();
+
+                thread::spawn(move || Shared::run(worker));
+            }
+        }
+
+        Scheduler {
+            shared: shared.clone(),
+            _owner: Arc::new(Owner(shared)),
+        }
+    }
+
+    /// Get the scheduler shared by the whole process, started on first use
+    pub fn global() -> &'static Scheduler {
+        static GLOBAL: OnceLock<Scheduler> = OnceLock::new();
+
+        GLOBAL.get_or_init(Scheduler::new)
+    }
+
+    /// Run a task once after a delay
+    ///
+    /// Arguments:
+    ///
+    /// * `delay`: How long to wait.
+    /// * `task`: The task to run.
+    pub fn set_timeout(&self, delay: Duration, task: BoxTask) -> TimerHandle {
+        self.schedule(Schedule::Once(delay), task)
+    }
+
+    /// Run a task repeatedly, the first time after one interval
+    ///
+    /// Arguments:
+    ///
+    /// * `interval`: Time between runs. A run that is late does not shift the later ones.
+    /// * `task`: The task to run.
+    pub fn set_interval(&self, interval: Duration, task: BoxTask) -> TimerHandle {
+        self.schedule(Schedule::Every(interval), task)
+    }
+
+    /// Run a task on a schedule
+    ///
+    /// A `Schedule::Cron` that never matches, e.g. `0 0 30 2 *`, never runs.
+    ///
+    /// Arguments:
+    ///
+    /// * `schedule`: When to run the task.
+    /// * `task`: The task to run.
+    pub fn schedule(&self, schedule: Schedule, task: BoxTask) -> TimerHandle {
+        self.add(schedule, None, task)
+    }
+
+    fn add(&self, schedule: Schedule, delay: Option<Duration>, task: BoxTask) -> TimerHandle {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+
+        state.next_id += 1;
+        state.timers.insert(
+            id,
+            Timer {
+                schedule: schedule,
+                task: Arc::new(Mutex::new(task)),
+                generation: 0,
+                at: None,
+                paused: None,
+            },
+        );
+        Scheduler::arm(&mut state, id, delay);
+
+        self.shared.wake.notify_one();
+
+        TimerHandle {
+            id: id,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
 
+    /// Get the number of timers that have not finished or been cancelled
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().timers.len()
+    }
+
+    /// Check whether the scheduler has no timers
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cancel every timer and stop the timer thread
+    ///
+    /// A task that is running finishes first. Timers added afterwards never run.
+    pub fn shutdown(&self) {
+        self.shared.stop();
+    }
+
+    /// Push the next due time of a timer onto the heap, or drop a timer that will not run again
+    ///
+    /// Arguments:
+    ///
+    /// * `delay`: Time until the timer is due, `None` for the start of its schedule.
+    fn arm(state: &mut State, id: u64, delay: Option<Duration>) {
+        let timer = match state.timers.get_mut(&id) {
+            Some(timer) => timer,
+            None => return,
+        };
+
+        timer.generation += 1;
+        timer.paused = None;
+
+        let now = state.clock.now();
+        let due = match (&timer.schedule, delay) {
+            (_, Some(delay)) => Some(now + delay),
+            (Schedule::Once(delay), None) | (Schedule::Every(delay), None) => Some(now + *delay),
+            (Schedule::Cron(cron), None) => {
+                let wall = state.clock.system_time();
+                let after = timer.at.map_or(wall, |at| at.max(wall));
+
+                timer.at = cron.next_after(after);
+                timer
+                    .at
+                    .map(|at| now + at.duration_since(wall).unwrap_or_default())
+            }
+        };
+
+        match due {
+            Some(due) => {
+                let generation = timer.generation;
+
+                state.heap.push(Reverse((due, id, generation)));
+            }
+            None => {
+                state.timers.remove(&id);
+            }
+        }
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+impl Shared {
+    /// Cancel every timer and wake the timer thread so it exits
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.stopped = true;
+        state.timers.clear();
+        state.heap.clear();
+
+        self.wake.notify_one();
+    }
+
+    /// Take the earliest timer if it is due, moving it to its next run
+    fn next(&self, state: &mut State) -> Next {
+        let now = state.clock.now();
+
+        while let Some(Reverse((due, id, generation))) = state.heap.peek().copied() {
+            if due > now {
+                return Next::Wait(due);
+            }
+
+            state.heap.pop();
+
+            let timer = match state.timers.get(&id) {
+                Some(timer) if timer.generation == generation && timer.paused.is_none() => timer,
+                _ => continue,
+            };
+            let task = timer.task.clone();
+
+            match timer.schedule.clone() {
+                Schedule::Once(_) => {
+                    state.timers.remove(&id);
+                }
+                Schedule::Every(interval) => {
+                    // An empty interval would run the task forever without letting time pass
+                    let next = (due + interval.max(MIN_INTERVAL)).max(now);
+
+                    Scheduler::arm(state, id, Some(next - now));
+                }
+                Schedule::Cron(_) => Scheduler::arm(state, id, None),
+            }
+
+            return Next::Run(id, task);
+        }
+
+        Next::Idle
+    }
+
+    /// Run the task of a timer, dropping the timer if the task panics
+    fn execute(self: &Arc<Self>, id: u64, task: Arc<Mutex<BoxTask>>) {
+        let handle = TimerHandle {
+            id: id,
+            shared: Arc::downgrade(self),
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Ok(mut task) = task.lock() {
+                task(&handle);
+            }
+        }));
+
+        if result.is_err() {
+            event!(warn, timer = id, "timer task panicked, timer dropped");
+
+            self.state.lock().unwrap().timers.remove(&id);
+        }
+    }
+
+    /// Timer thread of a scheduler following a clock that moves by itself
+    fn run(shared: Arc<Shared>) {
+        let mut state = shared.state.lock().unwrap();
+
+        while !state.stopped {
+            match shared.next(&mut state) {
+                Next::Run(id, task) => {
+                    drop(state);
+                    shared.execute(id, task);
+                    state = shared.state.lock().unwrap();
+                }
+                Next::Wait(due) => {
+                    let wait = due.saturating_duration_since(state.clock.now());
+
+                    state = shared.wake.wait_timeout(state, wait).unwrap().0;
+                }
+                Next::Idle => state = shared.wake.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+impl Waiter for Shared {
+    fn next_due(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+
+        match state.stopped {
+            true => None,
+            false => state.heap.peek().map(|Reverse((due, _, _))| *due),
+        }
+    }
+
+    fn tick(self: Arc<Self>) {
         loop {
+            let mut state = self.state.lock().unwrap();
+
+            if state.stopped {
+                return;
+            }
+
+            match self.next(&mut state) {
+                Next::Run(id, task) => {
+                    drop(state);
+                    self.execute(id, task);
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+impl TimerHandle {
+    fn update<F: FnOnce(&mut State) -> bool>(&self, update: F) -> bool {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return false,
+        };
+
+        let mut state = shared.state.lock().unwrap();
+
+        if !state.timers.contains_key(&self.id) {
+            return false;
+        }
+
+        let updated = update(&mut state);
+
+        shared.wake.notify_one();
+        updated
+    }
+
+    /// Stop the timer for good, returns `false` if it already finished or was cancelled
+    pub fn cancel(&self) -> bool {
+        self.update(|state| state.timers.remove(&self.id).is_some())
+    }
+
+    /// Stop the timer until `resume`, returns `false` if it is not active
+    pub fn pause(&self) -> bool {
+        self.update(|state| {
+            let due = state
+                .heap
+                .iter()
+                .map(|Reverse(entry)| *entry)
+                .find(|(_, id, generation)| {
+                    *id == self.id
+                        && Some(*generation) == state.timers.get(id).map(|t| t.generation)
+                })
+                .map(|(due, _, _)| due);
+
+            let timer = state.timers.get_mut(&self.id).unwrap();
+
+            if timer.paused.is_some() {
+                return false;
+            }
+
+            timer.paused = Some(due.map_or(Duration::ZERO, |due| {
+                due.saturating_duration_since(state.clock.now())
+            }));
+
+            true
+        })
+    }
+
+    /// Continue a paused timer with the time it had left, a cron timer continues at its next
+    /// matching minute
+    pub fn resume(&self) -> bool {
+        self.update(|state| {
+            let timer = state.timers.get_mut(&self.id).unwrap();
+            let remaining = match timer.paused {
+                Some(remaining) => remaining,
+                None => return false,
+            };
+            let delay = match timer.schedule {
+                Schedule::Cron(_) => None,
+                _ => Some(remaining),
+            };
+
+            Scheduler::arm(state, self.id, delay);
+            true
+        })
+    }
+
+    /// Replace the timer's schedule, starting it over from now
+    ///
+    /// A paused timer is resumed.
+    ///
+    /// Arguments:
+    ///
+    /// * `schedule`: The new schedule.
+    pub fn reschedule(&self, schedule: Schedule) -> bool {
+        self.update(|state| {
+            let timer = state.timers.get_mut(&self.id).unwrap();
+
+            timer.schedule = schedule;
+            timer.at = None;
+
+            Scheduler::arm(state, self.id, None);
+            true
+        })
+    }
+
+    /// Check whether the timer will run again
+    pub fn is_active(&self) -> bool {
+        match self.shared.upgrade() {
+            Some(shared) => match shared.state.lock().unwrap().timers.get(&self.id) {
+                Some(timer) => timer.paused.is_none(),
+                None => false,
+            },
+            None => false,
+        }
+    }
+}
+
+impl Cron {
+    /// Parse a cron expression
+    ///
+    /// Arguments:
+    ///
+    /// * `expression`: Five fields or one of the `@` shorthands.
+    pub fn parse(expression: &str) -> Result<Self, Error> {
+        let expression = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expression => expression,
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            return Err(Error::new(&format!(
+                "Invalid cron expression '{}': expected 5 fields",
+                expression
+            )));
+        }
+
+        let weekdays = Cron::field(fields[4], 0, 7)?;
+
+        Ok(Cron {
+            minutes: Cron::field(fields[0], 0, 59)?,
+            hours: Cron::field(fields[1], 0, 23)?,
+            days: Cron::field(fields[2], 1, 31)?,
+            months: Cron::field(fields[3], 1, 12)?,
+            // Sunday is both 0 and 7
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// Parse one field into a bit set of the values it matches
+    fn field(field: &str, min: u64, max: u64) -> Result<u64, Error> {
+        let invalid = || Error::new(&format!("Invalid cron field '{}'", field));
+        let number = |s: &str| s.parse::<u64>().map_err(|_| invalid());
+        let mut bits = 0;
+
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, number(step)?),
+                None => (part, 1),
+            };
+
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => (number(start)?, number(end)?),
+                    None if step > 1 => (number(range)?, max),
+                    None => (number(range)?, number(range)?),
+                },
+            };
+
+            if step == 0 || start < min || end > max || start > end {
+                return Err(invalid());
+            }
+
+            for value in (start..=end).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+
+        Ok(bits)
+    }
+
+    fn matches_day(&self, day: u64, month: u64, weekday: u64) -> bool {
+        let day_matches = self.days & 1 << day != 0;
+        let weekday_matches = self.weekdays & 1 << weekday != 0;
+
+        self.months & 1 << month != 0
+            && match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (true, false) => weekday_matches,
+                (false, true) => day_matches,
+                (false, false) => day_matches || weekday_matches,
+            }
+    }
+
+    /// Get the first matching minute strictly after a time, `None` if nothing matches within
+    /// five years
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        let start = seconds / 60 + 1;
+        let first_day = start / 1440;
+
+        for day in first_day..first_day + CRON_SEARCH_DAYS {
+            let (_, month, date) = civil_from_days(day);
+            let weekday = (day + 4).rem_euclid(7) as u64;
+
+            if !self.matches_day(date, month, weekday) {
+                continue;
+            }
+
+            let first_minute = if day == first_day { start % 1440 } else { 0 };
+
+            for minute in first_minute..1440 {
+                if self.hours & 1 << (minute / 60) != 0 && self.minutes & 1 << (minute % 60) != 0 {
+                    let at = (day * 1440 + minute) as u64 * 60;
+
+                    return Some(UNIX_EPOCH + Duration::from_secs(at));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Convert days since the Unix epoch to a `(year, month, day)` date
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Run a delegate every `interval` seconds until it returns `true`, starting right away
+///
+/// Arguments:
+///
+/// * `interval`: Seconds between runs.
+/// * `delegate`: Receives the number of the run, starting at 0.
+#[deprecated(note = "use `Scheduler::set_interval`, which takes a `Duration` and can be cancelled")]
+pub fn set_interval(interval: i32, mut delegate: Box<dyn FnMut(i32) -> bool + Send>) {
+    let interval = Duration::from_secs(interval.max(0) as u64);
+    let mut i = 0;
+
+    // The first run happens right away, as it always did
+    Scheduler::global().add(
+        Schedule::Every(interval),
+        Some(Duration::ZERO),
+        Box::new(move |timer| {
             if delegate(i) {
-                break;
+                timer.cancel();
             }
 
             i += 1;
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Wall-clock time of a date and time in UTC
+    fn at(days: u64, hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60)
+    }
 
-            thread::sleep(Duration::from_millis((interval * 1000).try_into().unwrap()));
+    #[test]
+    fn parses_cron_fields() {
+        let cron = Cron::parse("*/15 9-17 1,15 * 1-5").unwrap();
+
+        assert_eq!(cron.minutes, 1 << 0 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, 0b11_1111_1110_0000_0000);
+        assert_eq!(cron.days, 1 << 1 | 1 << 15);
+        assert_eq!(cron.months, 0b1_1111_1111_1110);
+        assert_eq!(cron.weekdays, 0b011_1110);
+        assert!(!cron.any_day && !cron.any_weekday);
+
+        assert_eq!(
+            Cron::parse("5/20 * * * *").unwrap().minutes,
+            1 << 5 | 1 << 25 | 1 << 45
+        );
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().weekdays, 1);
+        assert_eq!(
+            Cron::parse("@daily").unwrap(),
+            Cron::parse("0 0 * * *").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_cron_expressions() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "@never",
+        ] {
+            assert!(Cron::parse(expression).is_err(), "{}", expression);
         }
-    });
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        // 1970-01-01 was a Thursday
+        let weekdays = Cron::parse("30 9 * * 1-5").unwrap();
+
+        assert_eq!(weekdays.next_after(at(0, 8, 0)), Some(at(0, 9, 30)));
+        assert_eq!(weekdays.next_after(at(0, 9, 30)), Some(at(1, 9, 30)));
+        assert_eq!(weekdays.next_after(at(1, 10, 0)), Some(at(4, 9, 30)));
+
+        // Day of month or day of week, as in cron: the 13th, or Fridays
+        let either = Cron::parse("0 0 13 * 5").unwrap();
+
+        assert_eq!(either.next_after(at(0, 0, 0)), Some(at(1, 0, 0)));
+        assert_eq!(either.next_after(at(1, 0, 0)), Some(at(8, 0, 0)));
+        assert_eq!(either.next_after(at(8, 0, 0)), Some(at(12, 0, 0)));
+
+        assert_eq!(
+            Cron::parse("0 0 30 2 *").unwrap().next_after(at(0, 0, 0)),
+            None
+        );
+    }
+
+    /// Task counting its runs
+    fn counting(runs: &Arc<AtomicUsize>) -> BoxTask {
+        let runs = runs.clone();
+
+        Box::new(move |_| {
+            runs.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn runs_intervals_as_the_clock_advances() {
+        let clock = Arc::new(MockClock::new());
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let once = Arc::new(AtomicUsize::new(0));
+
+        let interval = scheduler.set_interval(Duration::from_secs(10), counting(&ticks));
+        scheduler.set_timeout(Duration::from_secs(15), counting(&once));
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(ticks.load(Ordering::SeqCst), 0);
+
+        clock.advance(Duration::from_secs(21));
+        assert_eq!(ticks.load(Ordering::SeqCst), 3);
+        assert_eq!(once.load(Ordering::SeqCst), 1);
+        assert_eq!(scheduler.len(), 1);
+
+        interval.cancel();
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(ticks.load(Ordering::SeqCst), 3);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn runs_cron_timers_at_matching_minutes() {
+        let clock = Arc::new(MockClock::new());
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        scheduler.schedule(
+            Schedule::Cron(Cron::parse("30 9 * * *").unwrap()),
+            counting(&runs),
+        );
+
+        clock.advance(Duration::from_secs(9 * 3600 + 29 * 60));
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(2 * 86400));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn drops_a_panicking_timer() {
+        let clock = Arc::new(MockClock::new());
+        let scheduler = Scheduler::with_clock(clock.clone());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let panicking =
+            scheduler.set_interval(Duration::from_secs(1), Box::new(|_| panic!("task failed")));
+        scheduler.set_interval(Duration::from_secs(1), counting(&runs));
+
+        clock.advance(Duration::from_secs(3));
+
+        assert!(!panicking.is_active());
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn stops_once_the_last_clone_is_dropped() {
+        let scheduler = Scheduler::new();
+        let clone = scheduler.clone();
+        let timer = scheduler.set_interval(Duration::from_secs(60), Box::new(|_| {}));
+
+        drop(scheduler);
+        assert!(timer.is_active());
+
+        drop(clone);
+        assert!(!timer.is_active());
+    }
 }
@@ -0,0 +1,59 @@
+//! Instrumentation that compiles to nothing unless the `tracing` feature is enabled.
+
+/// Emit a `tracing` event, e.g. `event!(info, peer = %address, "accepted connection")`
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        {
+            tracing::$level!($($arg)+);
+        }
+    };
+}
+
+pub(crate) use event;
+
+/// ## ConnectionSpan
+///
+/// Span covering everything that happens on one connection, with the peer address and, once
+/// the client is connected, its nickname.
+#[derive(Clone)]
+pub(crate) struct ConnectionSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Guard returned by `ConnectionSpan::enter`, the span is exited when it is dropped
+pub(crate) struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::Entered<'a>,
+    #[cfg(not(feature = "tracing"))]
+    _span: std::marker::PhantomData<&'a ()>,
+}
+
+impl ConnectionSpan {
+    /// Open the span of a freshly accepted connection
+    #[allow(unused_variables)]
+    pub(crate) fn new(peer: &str) -> Self {
+        ConnectionSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("connection", peer = %peer, nick = tracing::field::Empty),
+        }
+    }
+
+    /// Enter the span for the current scope
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        Entered {
+            #[cfg(feature = "tracing")]
+            _entered: self.span.enter(),
+            #[cfg(not(feature = "tracing"))]
+            _span: std::marker::PhantomData,
+        }
+    }
+
+    /// Record the client's nickname once it is assigned
+    #[allow(unused_variables)]
+    pub(crate) fn nick(&self, nick: &str) {
+        #[cfg(feature = "tracing")]
+        self.span.record("nick", nick);
+    }
+}